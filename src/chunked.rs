@@ -0,0 +1,234 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use crate::config::fnv1a;
+use crate::errors;
+use crate::path::{self, PathSegment};
+use crate::sink::FlatSink;
+use crate::traverse::{self, KeyPath};
+
+
+/// Flattens a JSON Value lazily, in chunks of at most `chunk_size` flat key-value pairs.
+///
+/// The document is walked only as far as needed to fill the next chunk. Keys that `flatten` would merge into
+/// an array are only merged when they fall in the same chunk.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be flattened (`serde_json::Value`).
+/// * `chunk_size` - The maximum number of entries of each chunk, at least 1.
+///
+/// # Returns
+///
+/// An iterator over the chunks (`serde_json::Map<String, Value>`), yielding a single error (`errors::Error`)
+/// if the value cannot be flattened.
+///
+pub fn flatten_chunked(value: &Value, chunk_size: usize) -> impl Iterator<Item = Result<Map<String, Value>, errors::Error>> + '_ {
+    let error = match value {
+        Value::Object(_) | Value::Array(_) => None,
+        _ => Some(errors::Error::NotAnObject),
+    };
+    Chunks { leaves: flat_leaves(value), chunk_size: chunk_size.max(1), error }
+}
+
+/// Position of the next leaf to be returned by `flatten_page`.
+///
+/// The cursor records the path of the next leaf, as a flat key that `path::parse_flat_key` reads back, and a digest
+/// of the objects and arrays along that path, so it cannot be used to resume a document where that leaf moved.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cursor {
+    next: String,
+    digest: String,
+}
+
+/// Flattens a page of at most `page_size` leaves of a JSON Value, in document order.
+///
+/// Resuming from a cursor only walks the objects and arrays along the path of its leaf, not the previous pages.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be flattened (`serde_json::Value`).
+/// * `cursor` - The cursor returned with the previous page, or `None` for the first page.
+/// * `page_size` - The maximum number of leaves of the page, at least 1.
+///
+/// # Returns
+///
+/// A Result containing the page (`serde_json::Map<String, Value>`) and the cursor of the next page, if any leaves
+/// are left, or an error (`errors::Error`): `InvalidCursor` if the leaf recorded by the cursor is not found in the
+/// document at the same position.
+///
+pub fn flatten_page(value: &Value, cursor: Option<Cursor>, page_size: usize) -> Result<(Map<String, Value>, Option<Cursor>), errors::Error> {
+    if !value.is_object() && !value.is_array() {
+        return Err(errors::Error::NotAnObject);
+    }
+    let mut leaves: Box<dyn Iterator<Item = (KeyPath, &Value)>> = match cursor {
+        Some(cursor) => {
+            let segments = path::parse_flat_key(&cursor.next).map_err(|_| errors::Error::InvalidCursor)?;
+            if path_digest(value, &segments) != cursor.digest {
+                return Err(errors::Error::InvalidCursor);
+            }
+            Box::new(traverse::leaves_from(value, &segments).ok_or(errors::Error::InvalidCursor)?)
+        }
+        None => Box::new(traverse::leaves(value)),
+    };
+
+    let mut page = Map::new();
+    for (key, value) in leaves.by_ref().take(page_size.max(1)) {
+        page.put(&key.to_flat_key(), value.clone())?;
+    }
+
+    let next = leaves.next().map(|(key, _)| Cursor {
+        next: key.to_string(),
+        digest: path_digest(value, key.segments()),
+    });
+    Ok((page, next))
+}
+
+/// Digest of the shape of the objects and arrays along a path: their length and the names of their properties.
+fn path_digest(value: &Value, segments: &[PathSegment]) -> String {
+    let mut bytes = vec![];
+    let mut node = Some(value);
+    for segment in segments {
+        match node {
+            Some(Value::Object(map)) => {
+                bytes.extend(format!("{{{}", map.len()).as_bytes());
+                for key in map.keys() {
+                    bytes.extend(Value::from(key.as_str()).to_string().as_bytes());
+                }
+            }
+            Some(Value::Array(array)) => bytes.extend(format!("[{}", array.len()).as_bytes()),
+            _ => break,
+        }
+        node = node.and_then(|node| path::lookup(node, std::slice::from_ref(segment)));
+    }
+    format!("{:016x}", fnv1a(&bytes))
+}
+
+/// The leaves of a document with their keys, as written by `flatten`. A scalar document has none.
+fn flat_leaves(value: &Value) -> impl Iterator<Item = (String, &Value)> {
+    traverse::leaves(value).filter(|(path, _)| !path.is_empty()).map(|(path, leaf)| (path.to_flat_key(), leaf))
+}
+
+struct Chunks<I> {
+    leaves: I,
+    chunk_size: usize,
+    error: Option<errors::Error>,
+}
+
+impl<'a, I: Iterator<Item = (String, &'a Value)>> Iterator for Chunks<I> {
+    type Item = Result<Map<String, Value>, errors::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.error.take() {
+            return Some(Err(e));
+        }
+
+        let mut chunk = Map::new();
+        while chunk.len() < self.chunk_size {
+            let Some((key, value)) = self.leaves.next() else { break };
+            if let Err(e) = chunk.put(&key, value.clone()) {
+                return Some(Err(e));
+            }
+        }
+
+        if chunk.is_empty() {
+            None
+        } else {
+            Some(Ok(chunk))
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use crate::flattening::flatten;
+    use super::*;
+
+    #[test]
+    fn flattening_in_chunks() {
+        let json = json!({
+            "a": { "b": [1, { "c": null }], "d": {} },
+            "e": "f",
+            "g": [[true], []],
+            "h": 2,
+            "": { "a": 1 }
+        });
+
+        let chunks: Vec<Map<String, Value>> = flatten_chunked(&json, 2).collect::<Result<_, _>>().unwrap();
+        assert_eq!(chunks.iter().map(|chunk| chunk.len()).collect::<Vec<_>>(), vec![2, 2, 2]);
+
+        let merged: Map<String, Value> = chunks.into_iter().flatten().collect();
+        let expected = flatten(&json).unwrap();
+        assert_eq!(merged.keys().collect::<Vec<_>>(), expected.keys().collect::<Vec<_>>());
+        assert_eq!(merged, expected);
+    }
+
+    #[test]
+    fn flattening_in_pages() {
+        let json = json!({ "a": { "b": [1, { "c": null }] }, "d": "e", "f": [true] });
+
+        let mut pages = vec![];
+        let mut cursor = None;
+        loop {
+            let (page, next) = flatten_page(&json, cursor, 2).unwrap();
+            pages.push(page);
+            match next {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(pages.iter().map(|page| page.len()).collect::<Vec<_>>(), vec![2, 2]);
+        assert_eq!(pages.into_iter().flatten().collect::<Map<String, Value>>(), flatten(&json).unwrap());
+    }
+
+    #[test]
+    fn resuming_pages_of_modified_documents() {
+        let json = json!({ "a": 1, "b": 2, "c": 3 });
+        let (_, cursor) = flatten_page(&json, None, 1).unwrap();
+        assert_eq!(cursor.as_ref().unwrap().next, "b");
+
+        for modified in [json!({ "a": 1, "c": 3 }), json!({ "a": 1, "b": 2, "c": 3, "d": 4 }), json!({ "a": 1, "b": {}, "c": 3 })] {
+            assert_eq!(
+                flatten_page(&modified, cursor.clone(), 1).err().unwrap().to_string(),
+                errors::Error::InvalidCursor.to_string()
+            );
+        }
+        let (page, _) = flatten_page(&json!({ "a": [0], "b": 5, "c": 3 }), cursor, 1).unwrap();
+        assert_eq!(Value::Object(page), json!({ "b": 5 }));
+
+        let json = json!({ "x": 1, "a.b": { "c": [2, 3] } });
+        let (_, cursor) = flatten_page(&json, None, 2).unwrap();
+        assert_eq!(cursor.as_ref().unwrap().next, "[\"a.b\"].c[1]");
+        let (page, next) = flatten_page(&json, cursor, 2).unwrap();
+        assert_eq!(Value::Object(page), json!({ "a.b.c[1]": 3 }));
+        assert!(next.is_none());
+    }
+
+    #[test]
+    fn flattening_invalid_values_in_chunks() {
+        let json = json!("a");
+        let mut chunks = flatten_chunked(&json, 10);
+        assert_eq!(chunks.next().unwrap().err().unwrap().to_string(), errors::Error::NotAnObject.to_string());
+        assert!(chunks.next().is_none());
+
+        assert_eq!(flatten_chunked(&json!({}), 10).count(), 0);
+    }
+}
@@ -0,0 +1,618 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+
+
+
+use std::collections::{HashSet, VecDeque};
+use serde_json::Value;
+use crate::errors;
+
+
+/// The structure inferred from sample JSON documents, shared by the code generators.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Shape {
+    /// Nothing is known, e.g. the items of an empty array.
+    Unknown,
+    Null,
+    Bool,
+    Integer,
+    Number,
+    String,
+    Array(Box<Shape>),
+    Object(Vec<Field>),
+    /// The alternatives, at most one of each kind (an integer being a number).
+    Union(Vec<Shape>),
+}
+
+/// A property of an inferred object.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Field {
+    pub name: String,
+    pub shape: Shape,
+    /// Whether the property is missing from some of the samples.
+    pub optional: bool,
+}
+
+impl Shape {
+    /// Infers the shape of a JSON document, which must be an object or an array as for `flatten`.
+    pub fn of_document(value: &Value) -> Result<Shape, errors::Error> {
+        match value {
+            Value::Object(_) | Value::Array(_) => Ok(Shape::of(value)),
+            _ => Err(errors::Error::NotAnObject),
+        }
+    }
+
+    /// Infers the shape of a JSON Value, merging the shapes of the items of arrays.
+    pub fn of(value: &Value) -> Shape {
+        match value {
+            Value::Null => Shape::Null,
+            Value::Bool(_) => Shape::Bool,
+            Value::Number(n) if n.is_f64() => Shape::Number,
+            Value::Number(_) => Shape::Integer,
+            Value::String(_) => Shape::String,
+            Value::Array(array) => Shape::Array(Box::new(array.iter().map(Shape::of).fold(Shape::Unknown, Shape::merge))),
+            Value::Object(map) => Shape::Object(map.iter()
+                .map(|(k, v)| Field { name: k.clone(), shape: Shape::of(v), optional: false })
+                .collect()),
+        }
+    }
+
+    /// Returns the shape accepting both `self` and `other`.
+    pub fn merge(self, other: Shape) -> Shape {
+        match (self, other) {
+            (Shape::Unknown, shape) | (shape, Shape::Unknown) => shape,
+            (Shape::Integer, Shape::Number) | (Shape::Number, Shape::Integer) => Shape::Number,
+            (Shape::Array(a), Shape::Array(b)) => Shape::Array(Box::new(a.merge(*b))),
+            (Shape::Object(a), Shape::Object(mut b)) => {
+                let mut fields: Vec<Field> = a.into_iter().map(|mut field| {
+                    match b.iter().position(|other| other.name == field.name) {
+                        Some(i) => {
+                            let other = b.remove(i);
+                            field.shape = field.shape.merge(other.shape);
+                            field.optional |= other.optional;
+                        }
+                        None => field.optional = true,
+                    }
+                    field
+                }).collect();
+                fields.extend(b.into_iter().map(|field| Field { optional: true, ..field }));
+                Shape::Object(fields)
+            }
+            (a, b) if a == b => a,
+            (a, b) => {
+                let mut members = a.into_members();
+                for member in b.into_members() {
+                    match members.iter().position(|m| m.kind() == member.kind()) {
+                        Some(i) => members[i] = std::mem::replace(&mut members[i], Shape::Unknown).merge(member),
+                        None => members.push(member),
+                    }
+                }
+                Shape::Union(members)
+            }
+        }
+    }
+
+    /// Splits a nullable shape into the shape of its non-null values, `None` when it is always null.
+    pub fn non_null(&self) -> Option<Shape> {
+        match self {
+            Shape::Null => None,
+            Shape::Union(members) => {
+                let mut rest: Vec<Shape> = members.iter().filter(|m| **m != Shape::Null).cloned().collect();
+                match rest.len() {
+                    0 => None,
+                    1 => rest.pop(),
+                    _ => Some(Shape::Union(rest)),
+                }
+            }
+            shape => Some(shape.clone()),
+        }
+    }
+
+    /// Whether `null` is one of the accepted values.
+    pub fn is_nullable(&self) -> bool {
+        match self {
+            Shape::Null => true,
+            Shape::Union(members) => members.contains(&Shape::Null),
+            _ => false,
+        }
+    }
+
+    fn into_members(self) -> Vec<Shape> {
+        match self {
+            Shape::Union(members) => members,
+            shape => vec![shape],
+        }
+    }
+
+    fn kind(&self) -> u8 {
+        match self {
+            Shape::Unknown => 0,
+            Shape::Null => 1,
+            Shape::Bool => 2,
+            Shape::Integer | Shape::Number => 3,
+            Shape::String => 4,
+            Shape::Array(_) => 5,
+            Shape::Object(_) => 6,
+            Shape::Union(_) => 7,
+        }
+    }
+}
+
+/// Whether a property name can be written unquoted in JavaScript and TypeScript.
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '$')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+}
+
+fn property_name(name: &str) -> String {
+    if is_identifier(name) { name.to_owned() } else { Value::from(name).to_string() }
+}
+
+/// Generates a TypeScript `zod` schema validating documents with the structure of a JSON Value.
+///
+/// Properties missing from some items of an array are `.optional()`, values that are sometimes null are
+/// `.nullable()` and values of different types become a `z.union`.
+///
+/// # Arguments
+///
+/// * `value` - The sample JSON document (`serde_json::Value`).
+///
+/// # Returns
+///
+/// A Result containing the schema expression (e.g. `z.object({ name: z.string() })`) or an error (`errors::Error`),
+/// `NotAnObject` if the document is neither an object nor an array.
+///
+pub fn flatten_to_zod_schema(value: &Value) -> Result<String, errors::Error> {
+    Ok(zod(&Shape::of_document(value)?))
+}
+
+fn zod(shape: &Shape) -> String {
+    if shape.is_nullable() {
+        return match shape.non_null() {
+            Some(rest) => format!("{}.nullable()", zod(&rest)),
+            None => "z.null()".to_owned(),
+        };
+    }
+    match shape {
+        Shape::Unknown => "z.unknown()".to_owned(),
+        Shape::Null => "z.null()".to_owned(),
+        Shape::Bool => "z.boolean()".to_owned(),
+        Shape::Integer | Shape::Number => "z.number()".to_owned(),
+        Shape::String => "z.string()".to_owned(),
+        Shape::Array(items) => format!("z.array({})", zod(items)),
+        Shape::Object(fields) if fields.is_empty() => "z.object({})".to_owned(),
+        Shape::Object(fields) => {
+            let fields: Vec<String> = fields.iter().map(|field| format!(
+                "{}: {}{}",
+                property_name(&field.name),
+                zod(&field.shape),
+                if field.optional { ".optional()" } else { "" }
+            )).collect();
+            format!("z.object({{ {} }})", fields.join(", "))
+        }
+        Shape::Union(members) => format!("z.union([{}])", members.iter().map(zod).collect::<Vec<String>>().join(", ")),
+    }
+}
+
+
+/// Turns a property name into a type name, e.g. `billing_address` into `BillingAddress`.
+fn type_name(name: &str) -> String {
+    let name: String = name.split(|c: char| !c.is_ascii_alphanumeric())
+        .flat_map(|word| {
+            let mut chars = word.chars();
+            chars.next().map(|c| c.to_ascii_uppercase()).into_iter().chain(chars)
+        })
+        .collect();
+    match name.chars().next() {
+        None => "Field".to_owned(),
+        Some(c) if c.is_ascii_digit() => format!("T{}", name),
+        Some(_) => name,
+    }
+}
+
+/// The type declarations left to write, named after the properties holding them.
+#[derive(Default)]
+struct Declarations {
+    names: HashSet<String>,
+    pending: VecDeque<(String, Vec<Field>)>,
+}
+
+impl Declarations {
+    /// Queues the declaration of an object type, returning its name, made unique with a numeric suffix.
+    fn declare(&mut self, name: &str, fields: &[Field]) -> String {
+        let base = type_name(name);
+        let mut name = base.clone();
+        for i in 2.. {
+            if self.names.insert(name.clone()) {
+                break;
+            }
+            name = format!("{}{}", base, i);
+        }
+        self.pending.push_back((name.clone(), fields.to_vec()));
+        name
+    }
+}
+
+/// Generates TypeScript interface declarations for the objects of a JSON Value, starting from `Root`.
+///
+/// Nested objects are declared as interfaces named after their property (`User` for `user`, `ItemsItem` for the
+/// objects of `items`), properties missing from some items of an array are optional (`name?:`) and values of
+/// different types become unions (`string | null`). A root array is declared as `type Root = RootItem[];`.
+///
+/// # Arguments
+///
+/// * `value` - The sample JSON document (`serde_json::Value`).
+///
+/// # Returns
+///
+/// A Result containing the declarations (`String`) or an error (`errors::Error`), `NotAnObject` if the document
+/// is neither an object nor an array.
+///
+pub fn flatten_to_typescript_types(value: &Value) -> Result<String, errors::Error> {
+    let shape = Shape::of_document(value)?;
+    let mut declarations = Declarations::default();
+    let mut output = String::new();
+    let root_array = matches!(shape, Shape::Array(_));
+    if root_array {
+        declarations.names.insert("Root".to_owned());
+    }
+    let root = typescript(&shape, "Root", &mut declarations);
+    if root_array {
+        output.push_str(&format!("type Root = {};\n", root));
+    }
+
+    while let Some((name, fields)) = declarations.pending.pop_front() {
+        if !output.is_empty() {
+            output.push('\n');
+        }
+        if fields.is_empty() {
+            output.push_str(&format!("interface {} {{}}\n", name));
+            continue;
+        }
+        output.push_str(&format!("interface {} {{\n", name));
+        for field in fields {
+            let field_type = typescript(&field.shape, &field.name, &mut declarations);
+            output.push_str(&format!(
+                "  {}{}: {};\n",
+                property_name(&field.name),
+                if field.optional { "?" } else { "" },
+                field_type
+            ));
+        }
+        output.push_str("}\n");
+    }
+    Ok(output)
+}
+
+fn typescript(shape: &Shape, name: &str, declarations: &mut Declarations) -> String {
+    match shape {
+        Shape::Unknown => "unknown".to_owned(),
+        Shape::Null => "null".to_owned(),
+        Shape::Bool => "boolean".to_owned(),
+        Shape::Integer | Shape::Number => "number".to_owned(),
+        Shape::String => "string".to_owned(),
+        Shape::Array(items) => {
+            let item_type = typescript(items, &format!("{}Item", type_name(name)), declarations);
+            match **items {
+                Shape::Union(_) => format!("({})[]", item_type),
+                _ => format!("{}[]", item_type),
+            }
+        }
+        Shape::Object(fields) => declarations.declare(name, fields),
+        Shape::Union(members) => members.iter()
+            .map(|member| typescript(member, name, declarations))
+            .collect::<Vec<String>>()
+            .join(" | "),
+    }
+}
+
+
+/// Keywords that cannot name a Rust field.
+const RUST_KEYWORDS: &[&str] = &[
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "crate", "do", "dyn", "else",
+    "enum", "extern", "false", "final", "fn", "for", "gen", "if", "impl", "in", "let", "loop", "macro", "match", "mod",
+    "move", "mut", "override", "priv", "pub", "ref", "return", "self", "Self", "static", "struct", "super", "trait", "true",
+    "try", "type", "typeof", "unsafe", "unsized", "use", "virtual", "where", "while", "yield",
+];
+
+/// Type names that a generated struct would shadow or cannot take, from the language and its prelude.
+const RUST_RESERVED_TYPES: &[&str] = &[
+    "AsMut", "AsRef", "Box", "Clone", "Copy", "Debug", "Default", "Deserialize", "DoubleEndedIterator", "Drop", "Eq",
+    "Err", "ExactSizeIterator", "Extend", "Fn", "FnMut", "FnOnce", "From", "FromIterator", "Into", "IntoIterator",
+    "Iterator", "None", "Ok", "Option", "Ord", "PartialEq", "PartialOrd", "Result", "Self", "Send", "Serialize",
+    "Sized", "Some", "String", "Sync", "ToOwned", "ToString", "TryFrom", "TryInto", "Unpin", "Vec",
+];
+
+/// Turns a property name into a field name, e.g. `firstName` into `first_name` and `type` into `type_`.
+fn field_name(name: &str) -> String {
+    let mut field = String::new();
+    let mut previous: Option<char> = None;
+    for c in name.chars() {
+        if c.is_ascii_uppercase() && previous.is_some_and(|p| p.is_ascii_lowercase() || p.is_ascii_digit()) {
+            field.push('_');
+        }
+        field.push(if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' });
+        previous = Some(c);
+    }
+    match field.chars().next() {
+        None => "field".to_owned(),
+        Some(c) if c.is_ascii_digit() => format!("_{}", field),
+        Some(_) if RUST_KEYWORDS.contains(&field.as_str()) => format!("{}_", field),
+        Some(_) if field == "_" => "field".to_owned(),
+        Some(_) => field,
+    }
+}
+
+/// Generates Rust struct definitions deriving serde's `Serialize` and `Deserialize` for a JSON Value.
+///
+/// Nested objects become structs named after their property (`User` for `user`, `ItemsItem` for the objects of
+/// `items`). Integers are `i64` and other numbers `f64`, values that may be null or missing are `Option<T>`, and
+/// values of different types or with nothing known about them are `serde_json::Value`. Fields are named in
+/// snake case, with a `#[serde(rename)]` when the name differs from the property. Structs that would take the
+/// name of a prelude type get a numeric suffix (`String2` for `string`). A root array is declared as
+/// `pub type Root = Vec<RootItem>;`.
+///
+/// # Arguments
+///
+/// * `value` - The sample JSON document (`serde_json::Value`).
+/// * `struct_name` - The name of the root type.
+///
+/// # Returns
+///
+/// A Result containing the definitions (`String`) or an error (`errors::Error`), `NotAnObject` if the document
+/// is neither an object nor an array, `InvalidKeyName` if `struct_name` is not a valid type name.
+///
+pub fn flatten_to_rust_struct(value: &Value, struct_name: &str) -> Result<String, errors::Error> {
+    if type_name(struct_name) != struct_name || RUST_RESERVED_TYPES.contains(&struct_name) {
+        return Err(errors::Error::InvalidKeyName(struct_name.to_owned()));
+    }
+    let shape = Shape::of_document(value)?;
+    let mut declarations = Declarations::default();
+    declarations.names.extend(RUST_RESERVED_TYPES.iter().map(|name| name.to_string()));
+    let mut output = "use serde::{Deserialize, Serialize};\n".to_owned();
+    let root_array = matches!(shape, Shape::Array(_));
+    if root_array {
+        declarations.names.insert(struct_name.to_owned());
+    }
+    let root = rust(&shape, struct_name, &mut declarations);
+    if root_array {
+        output.push_str(&format!("\npub type {} = {};\n", struct_name, root));
+    }
+
+    while let Some((name, fields)) = declarations.pending.pop_front() {
+        output.push_str("\n#[derive(Debug, Clone, Serialize, Deserialize)]\n");
+        if fields.is_empty() {
+            output.push_str(&format!("pub struct {} {{}}\n", name));
+            continue;
+        }
+        output.push_str(&format!("pub struct {} {{\n", name));
+        let mut used = HashSet::new();
+        for field in fields {
+            let mut field_type = rust(&field.shape, &field.name, &mut declarations);
+            if field.optional && !field_type.starts_with("Option<") {
+                field_type = format!("Option<{}>", field_type);
+            }
+            let base = field_name(&field.name);
+            let mut name = base.clone();
+            for i in 2.. {
+                if used.insert(name.clone()) {
+                    break;
+                }
+                name = format!("{}_{}", base, i);
+            }
+            if name != field.name {
+                output.push_str(&format!("    #[serde(rename = {})]\n", Value::from(field.name.as_str())));
+            }
+            output.push_str(&format!("    pub {}: {},\n", name, field_type));
+        }
+        output.push_str("}\n");
+    }
+    Ok(output)
+}
+
+fn rust(shape: &Shape, name: &str, declarations: &mut Declarations) -> String {
+    match shape {
+        Shape::Unknown => "serde_json::Value".to_owned(),
+        Shape::Bool => "bool".to_owned(),
+        Shape::Integer => "i64".to_owned(),
+        Shape::Number => "f64".to_owned(),
+        Shape::String => "String".to_owned(),
+        Shape::Array(items) => format!("Vec<{}>", rust(items, &format!("{}Item", type_name(name)), declarations)),
+        Shape::Object(fields) => declarations.declare(name, fields),
+        Shape::Null | Shape::Union(_) => match shape.non_null() {
+            Some(Shape::Union(_)) => "serde_json::Value".to_owned(),
+            Some(rest) if shape.is_nullable() => format!("Option<{}>", rust(&rest, name, declarations)),
+            _ if shape.is_nullable() => "Option<serde_json::Value>".to_owned(),
+            _ => "serde_json::Value".to_owned(),
+        },
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    #[test]
+    fn generating_zod_schema() {
+        let json = json!({ "user": { "name": "John", "age": 30 } });
+        assert_eq!(
+            flatten_to_zod_schema(&json).unwrap(),
+            "z.object({ user: z.object({ name: z.string(), age: z.number() }) })"
+        );
+
+        let json = json!({
+            "items": [{ "id": 1, "note": null }, { "id": 2.5, "note": "x", "tags": [] }],
+            "first-name": "John",
+            "mixed": [1, "a", true],
+            "empty": {}
+        });
+        assert_eq!(
+            flatten_to_zod_schema(&json).unwrap(),
+            "z.object({ items: z.array(z.object({ id: z.number(), note: z.string().nullable(), \
+             tags: z.array(z.unknown()).optional() })), \"first-name\": z.string(), \
+             mixed: z.array(z.union([z.number(), z.string(), z.boolean()])), empty: z.object({}) })"
+        );
+
+        assert_eq!(
+            flatten_to_zod_schema(&json!("John")).err().unwrap().to_string(),
+            errors::Error::NotAnObject.to_string()
+        );
+    }
+
+    #[test]
+    fn generating_typescript_types() {
+        let json = json!({ "user": { "name": "John", "age": 30 } });
+        assert_eq!(
+            flatten_to_typescript_types(&json).unwrap(),
+            "interface Root {\n  user: User;\n}\n\ninterface User {\n  name: string;\n  age: number;\n}\n"
+        );
+
+        let json = json!({
+            "items": [{ "id": 1, "note": null }, { "id": 2, "note": "x", "user": { "id": 3 } }],
+            "user": { "first-name": "John", "tags": [1, "a"] },
+            "meta": {}
+        });
+        let expected = "\
+interface Root {
+  items: ItemsItem[];
+  user: User;
+  meta: Meta;
+}
+
+interface ItemsItem {
+  id: number;
+  note: null | string;
+  user?: User2;
+}
+
+interface User {
+  \"first-name\": string;
+  tags: (number | string)[];
+}
+
+interface Meta {}
+
+interface User2 {
+  id: number;
+}
+";
+        assert_eq!(flatten_to_typescript_types(&json).unwrap(), expected);
+
+        assert_eq!(
+            flatten_to_typescript_types(&json!([{ "id": 1 }])).unwrap(),
+            "type Root = RootItem[];\n\ninterface RootItem {\n  id: number;\n}\n"
+        );
+    }
+
+    #[test]
+    fn generating_rust_structs() {
+        let json = json!({
+            "user": { "firstName": "John", "age": 30, "score": 4.5, "nickname": null },
+            "items": [{ "id": 1, "type": "a" }, { "id": 2, "type": "b", "note": "x" }],
+            "extra": [1, "a"],
+            "first-name": "John"
+        });
+        let expected = "\
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiResponse {
+    pub user: User,
+    pub items: Vec<ItemsItem>,
+    pub extra: Vec<serde_json::Value>,
+    #[serde(rename = \"first-name\")]
+    pub first_name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    #[serde(rename = \"firstName\")]
+    pub first_name: String,
+    pub age: i64,
+    pub score: f64,
+    pub nickname: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ItemsItem {
+    pub id: i64,
+    #[serde(rename = \"type\")]
+    pub type_: String,
+    pub note: Option<String>,
+}
+";
+        assert_eq!(flatten_to_rust_struct(&json, "ApiResponse").unwrap(), expected);
+
+        let json = json!([{ "first_name": "John", "first-name": null }, { "first_name": "Ann", "first-name": "A" }]);
+        let expected = "\
+use serde::{Deserialize, Serialize};
+
+pub type Root = Vec<RootItem>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RootItem {
+    pub first_name: String,
+    #[serde(rename = \"first-name\")]
+    pub first_name_2: Option<String>,
+}
+";
+        assert_eq!(flatten_to_rust_struct(&json, "Root").unwrap(), expected);
+
+        let json = json!({ "string": { "a": "x" }, "self": { "b": 1 }, "option": { "c": null }, "-": 1, "_": 2 });
+        let expected = "\
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Root {
+    pub string: String2,
+    #[serde(rename = \"self\")]
+    pub self_: Self2,
+    pub option: Option2,
+    #[serde(rename = \"-\")]
+    pub field: i64,
+    #[serde(rename = \"_\")]
+    pub field_2: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct String2 {
+    pub a: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Self2 {
+    pub b: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Option2 {
+    pub c: Option<serde_json::Value>,
+}
+";
+        assert_eq!(flatten_to_rust_struct(&json, "Root").unwrap(), expected);
+
+        for name in ["root", "Self", "String"] {
+            assert_eq!(
+                flatten_to_rust_struct(&json, name).err().unwrap().to_string(),
+                errors::Error::InvalidKeyName(name.to_owned()).to_string()
+            );
+        }
+    }
+}
@@ -0,0 +1,512 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+
+use std::collections::BTreeMap;
+use serde_json::{Map, Value};
+use crate::errors;
+use crate::flattening::flatten;
+use crate::path::{self, PathSegment};
+use crate::pattern::PathPattern;
+
+
+/// Options controlling how two JSON documents are compared.
+#[derive(Debug, Clone, Default)]
+pub struct CompareOptions {
+    unordered: Vec<PathPattern>,
+    ignored: Vec<PathPattern>,
+}
+
+impl CompareOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Marks the arrays whose flat path matches `pattern` as order-insensitive (e.g. `tags` or `items[*].tags`).
+    pub fn unordered(mut self, pattern: PathPattern) -> Self {
+        self.unordered.push(pattern);
+        self
+    }
+
+    /// Ignores the leaves whose flat key matches `pattern` (e.g. `**.updated_at` or `meta.request_id`).
+    pub fn ignore(mut self, pattern: PathPattern) -> Self {
+        self.ignored.push(pattern);
+        self
+    }
+
+    fn is_unordered(&self, path: &[PathSegment]) -> bool {
+        self.unordered.iter().any(|p| p.matches(path))
+    }
+
+    fn is_ignored(&self, path: &[PathSegment]) -> bool {
+        self.ignored.iter().any(|p| p.matches(path))
+    }
+
+    fn may_ignore_below(&self, path: &[PathSegment]) -> bool {
+        self.ignored.iter().any(|p| p.matches_prefix(path))
+    }
+}
+
+/// Differences between two JSON documents, expressed with flat keys.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Diff {
+    /// Flat keys present only in the left document.
+    pub left_only: Map<String, Value>,
+    /// Flat keys present only in the right document.
+    pub right_only: Map<String, Value>,
+    /// Flat keys present in both documents with different values, as `(key, left, right)`.
+    pub changed: Vec<(String, Value, Value)>,
+    /// Order-insensitive arrays whose elements differ.
+    pub unordered: Vec<ElementsDiff>,
+}
+
+/// Elements of an order-insensitive array that are not matched on the other side.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ElementsDiff {
+    /// Flat path of the array.
+    pub path: String,
+    /// Elements of the right array missing from the left one.
+    pub missing_from_left: Vec<Value>,
+    /// Elements of the left array missing from the right one.
+    pub missing_from_right: Vec<Value>,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.left_only.is_empty() && self.right_only.is_empty() && self.changed.is_empty() && self.unordered.is_empty()
+    }
+}
+
+/// Checks whether two JSON documents are equivalent, ignoring the order of the arrays marked in `opts`.
+///
+/// # Arguments
+///
+/// * `a` - The first JSON Value.
+/// * `b` - The second JSON Value.
+/// * `opts` - The comparison options (`CompareOptions`).
+///
+/// # Returns
+///
+/// `true` if the documents are equivalent, `false` otherwise.
+///
+pub fn equivalent(a: &Value, b: &Value, opts: &CompareOptions) -> bool {
+    diff(a, b, opts).is_empty()
+}
+
+/// Computes the differences between two JSON documents, ignoring the order of the arrays marked in `opts`.
+///
+/// Both documents are flattened: flat keys outside order-insensitive arrays are compared one by one,
+/// while the elements of order-insensitive arrays are compared as multisets of their canonical forms.
+///
+/// # Arguments
+///
+/// * `a` - The left JSON Value.
+/// * `b` - The right JSON Value.
+/// * `opts` - The comparison options (`CompareOptions`).
+///
+/// # Returns
+///
+/// The differences found (`Diff`), empty if the documents are equivalent.
+///
+pub fn diff(a: &Value, b: &Value, opts: &CompareOptions) -> Diff {
+    let mut result = Diff::default();
+
+    let (flat_a, flat_b) = match (flatten(a), flatten(b)) {
+        (Ok(flat_a), Ok(flat_b)) => (flat_a, flat_b),
+        _ => {
+            if canonical(a, &mut vec![], opts) != canonical(b, &mut vec![], opts) {
+                result.changed.push((String::new(), a.clone(), b.clone()));
+            }
+            return result;
+        }
+    };
+
+    let mut arrays: Vec<Vec<PathSegment>> = vec![];
+    let mut plain_a = Map::new();
+    let mut plain_b = Map::new();
+
+    for (flat, plain) in [(&flat_a, &mut plain_a), (&flat_b, &mut plain_b)] {
+        for (key, value) in flat {
+            let segments = path::parse_flat_key(key).ok();
+            if segments.as_ref().is_some_and(|s| opts.is_ignored(s)) {
+                continue;
+            }
+            match segments.and_then(|segments| unordered_prefix(&segments, opts)) {
+                Some(prefix) => {
+                    if !arrays.contains(&prefix) {
+                        arrays.push(prefix);
+                    }
+                }
+                None => {
+                    plain.insert(key.clone(), value.clone());
+                }
+            }
+        }
+    }
+
+    for (key, value) in &plain_a {
+        match plain_b.get(key) {
+            Some(other) if other == value => {}
+            Some(other) => result.changed.push((key.clone(), value.clone(), other.clone())),
+            None => {
+                result.left_only.insert(key.clone(), value.clone());
+            }
+        }
+    }
+    for (key, value) in &plain_b {
+        if !plain_a.contains_key(key) {
+            result.right_only.insert(key.clone(), value.clone());
+        }
+    }
+
+    for segments in arrays {
+        let elements_diff = diff_elements(a, b, &segments, opts);
+        if !elements_diff.missing_from_left.is_empty() || !elements_diff.missing_from_right.is_empty() {
+            result.unordered.push(elements_diff);
+        }
+    }
+
+    result
+}
+
+fn diff_elements(a: &Value, b: &Value, segments: &[PathSegment], opts: &CompareOptions) -> ElementsDiff {
+    let mut counts: BTreeMap<String, (Vec<&Value>, Vec<&Value>)> = BTreeMap::new();
+
+    for (side, value) in [a, b].into_iter().enumerate() {
+        let elements = path::lookup(value, segments).and_then(Value::as_array).map(Vec::as_slice).unwrap_or_default();
+        for (i, element) in elements.iter().enumerate() {
+            let mut element_path = segments.to_vec();
+            element_path.push(PathSegment::Index(i));
+            let entry = counts.entry(canonical(element, &mut element_path, opts)).or_default();
+            if side == 0 {
+                entry.0.push(element);
+            } else {
+                entry.1.push(element);
+            }
+        }
+    }
+
+    let mut result = ElementsDiff {
+        path: path::render_flat_key(segments),
+        ..Default::default()
+    };
+    for (left, right) in counts.values() {
+        if left.len() > right.len() {
+            result.missing_from_right.extend(left[right.len()..].iter().map(|v| (*v).clone()));
+        } else {
+            result.missing_from_left.extend(right[left.len()..].iter().map(|v| (*v).clone()));
+        }
+    }
+    result
+}
+
+/// Checks whether two JSON documents are equal once the leaves matching any of the `ignore` patterns are dropped.
+///
+/// Useful for snapshot tests where timestamps and identifiers change on every run. Subtrees that no pattern
+/// can reach are compared directly, and the comparison stops at the first difference.
+///
+/// # Arguments
+///
+/// * `a` - The first JSON Value.
+/// * `b` - The second JSON Value.
+/// * `ignore` - Glob patterns over flat keys (see `PathPattern`), e.g. `**.updated_at` or `meta.request_id`.
+///
+/// # Returns
+///
+/// A Result containing `true` if the documents are equal outside the ignored leaves, `false` otherwise, or an error
+/// (`errors::Error`) if a pattern is not valid.
+///
+pub fn equals_ignoring(a: &Value, b: &Value, ignore: &[&str]) -> Result<bool, errors::Error> {
+    Ok(equal_subtrees(Some(a), Some(b), &mut vec![], &ignore_options(ignore)?))
+}
+
+/// Computes the differences between two JSON documents, skipping the leaves matching any of the `ignore` patterns.
+///
+/// # Arguments
+///
+/// * `a` - The left JSON Value.
+/// * `b` - The right JSON Value.
+/// * `ignore` - Glob patterns over flat keys (see `PathPattern`).
+///
+/// # Returns
+///
+/// A Result containing the differences found (`Diff`) or an error (`errors::Error`) if a pattern is not valid.
+///
+pub fn diff_ignoring(a: &Value, b: &Value, ignore: &[&str]) -> Result<Diff, errors::Error> {
+    Ok(diff(a, b, &ignore_options(ignore)?))
+}
+
+/// Compares two JSON documents, ignoring the order of the keys of their objects and of the elements of their arrays.
+///
+/// Arrays are compared as multisets, at any depth: `[1, [2, 3]]` equals `[[3, 2], 1]`. Use
+/// `flatten_deep_equal_strict` to compare arrays element by element, or `equivalent` with
+/// `CompareOptions::unordered` to ignore the order of some arrays only.
+///
+/// # Arguments
+///
+/// * `a` - The first JSON Value.
+/// * `b` - The second JSON Value.
+///
+/// # Returns
+///
+/// `true` if the documents hold the same data, `false` otherwise.
+///
+pub fn flatten_deep_equal(a: &Value, b: &Value) -> bool {
+    let opts = CompareOptions::new().unordered(PathPattern::compile("**").unwrap());
+    canonical(a, &mut vec![], &opts) == canonical(b, &mut vec![], &opts)
+}
+
+/// Compares two JSON documents, ignoring the order of the keys of their objects but not of the elements of their
+/// arrays.
+///
+/// Besides what `flatten_deep_equal` checks, array elements must appear in the same order, so both documents
+/// flatten to the same entries. This is the equality of `serde_json::Value`.
+///
+/// # Arguments
+///
+/// * `a` - The first JSON Value.
+/// * `b` - The second JSON Value.
+///
+/// # Returns
+///
+/// `true` if the documents hold the same data with their arrays in the same order, `false` otherwise.
+///
+pub fn flatten_deep_equal_strict(a: &Value, b: &Value) -> bool {
+    a == b
+}
+
+fn ignore_options(ignore: &[&str]) -> Result<CompareOptions, errors::Error> {
+    ignore.iter().try_fold(CompareOptions::new(), |opts, p| Ok(opts.ignore(PathPattern::compile(p)?)))
+}
+
+fn equal_subtrees(a: Option<&Value>, b: Option<&Value>, path: &mut Vec<PathSegment>, opts: &CompareOptions) -> bool {
+    if !opts.may_ignore_below(path) {
+        return match (a, b) {
+            (Some(a), Some(b)) => a == b,
+            (Some(v), None) | (None, Some(v)) => !has_leaves(v),
+            (None, None) => true,
+        };
+    }
+
+    match (a, b) {
+        (Some(Value::Object(map_a)), Some(Value::Object(map_b))) => {
+            let keys: Vec<&String> = map_a.keys().chain(map_b.keys().filter(|k| !map_a.contains_key(*k))).collect();
+            keys.into_iter().all(|k| {
+                path.push(PathSegment::Key(k.clone()));
+                let equal = equal_subtrees(map_a.get(k), map_b.get(k), path, opts);
+                path.pop();
+                equal
+            })
+        }
+        (Some(Value::Array(array_a)), Some(Value::Array(array_b))) => {
+            (0..array_a.len().max(array_b.len())).all(|i| {
+                path.push(PathSegment::Index(i));
+                let equal = equal_subtrees(array_a.get(i), array_b.get(i), path, opts);
+                path.pop();
+                equal
+            })
+        }
+        (Some(a), Some(b)) if !is_container(a) && !is_container(b) => opts.is_ignored(path) || a == b,
+        (a, b) => !has_visible_leaves(a, path, opts) && !has_visible_leaves(b, path, opts),
+    }
+}
+
+fn is_container(value: &Value) -> bool {
+    value.is_object() || value.is_array()
+}
+
+fn has_leaves(value: &Value) -> bool {
+    match value {
+        Value::Object(map) => map.values().any(has_leaves),
+        Value::Array(array) => array.iter().any(has_leaves),
+        _ => true,
+    }
+}
+
+fn has_visible_leaves(value: Option<&Value>, path: &mut Vec<PathSegment>, opts: &CompareOptions) -> bool {
+    let visit = |segment: PathSegment, child: &Value, path: &mut Vec<PathSegment>| {
+        path.push(segment);
+        let visible = has_visible_leaves(Some(child), path, opts);
+        path.pop();
+        visible
+    };
+    match value {
+        None => false,
+        Some(Value::Object(map)) => map.iter().any(|(k, v)| visit(PathSegment::Key(k.clone()), v, path)),
+        Some(Value::Array(array)) => array.iter().enumerate().any(|(i, v)| visit(PathSegment::Index(i), v, path)),
+        Some(_) => !opts.is_ignored(path),
+    }
+}
+
+/// Returns the path of the outermost order-insensitive array containing the flat key, if any.
+fn unordered_prefix(segments: &[PathSegment], opts: &CompareOptions) -> Option<Vec<PathSegment>> {
+    segments.iter().enumerate()
+        .filter(|(_, s)| matches!(s, PathSegment::Index(_)))
+        .map(|(i, _)| &segments[..i])
+        .find(|prefix| opts.is_unordered(prefix))
+        .map(|prefix| prefix.to_vec())
+}
+
+/// Canonical encoding of a value: object keys sorted, order-insensitive arrays sorted by their elements' encodings.
+pub(crate) fn canonical(value: &Value, path: &mut Vec<PathSegment>, opts: &CompareOptions) -> String {
+    match value {
+        Value::Object(map) => {
+            let mut entries: Vec<(String, String)> = map.iter().filter_map(|(k, v)| {
+                path.push(PathSegment::Key(k.clone()));
+                let ignored = !(v.is_object() || v.is_array()) && opts.is_ignored(path);
+                let encoded = canonical(v, path, opts);
+                path.pop();
+                (!ignored).then(|| (Value::String(k.clone()).to_string(), encoded))
+            }).collect();
+            entries.sort();
+            let entries: Vec<String> = entries.into_iter().map(|(k, v)| format!("{}:{}", k, v)).collect();
+            format!("{{{}}}", entries.join(","))
+        }
+        Value::Array(array) => {
+            let mut items: Vec<String> = array.iter().enumerate().map(|(i, v)| {
+                path.push(PathSegment::Index(i));
+                let encoded = canonical(v, path, opts);
+                path.pop();
+                encoded
+            }).collect();
+            if opts.is_unordered(path) {
+                items.sort();
+            }
+            format!("[{}]", items.join(","))
+        }
+        _ => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    fn p(pattern: &str) -> PathPattern {
+        PathPattern::compile(pattern).unwrap()
+    }
+
+    #[test]
+    fn comparing_unordered_arrays() {
+        let a = json!({
+            "name": "x",
+            "tags": ["a", "b", "c"],
+            "items": [
+                { "id": 1, "roles": ["r1", "r2"] },
+                { "id": 2, "roles": [] }
+            ]
+        });
+        let b = json!({
+            "name": "x",
+            "tags": ["c", "a", "b"],
+            "items": [
+                { "id": 1, "roles": ["r2", "r1"] },
+                { "id": 2, "roles": [] }
+            ]
+        });
+
+        assert!(!equivalent(&a, &b, &CompareOptions::new()));
+        assert!(!equivalent(&a, &b, &CompareOptions::new().unordered(p("tags"))));
+        assert!(equivalent(&a, &b, &CompareOptions::new().unordered(p("tags")).unordered(p("items[*].roles"))));
+        assert!(equivalent(&a, &b, &CompareOptions::new().unordered(p("**"))));
+    }
+
+    #[test]
+    fn comparing_unordered_arrays_of_objects() {
+        let a = json!({ "items": [{ "id": 1, "tags": ["x", "y"] }, { "id": 2 }] });
+        let b = json!({ "items": [{ "id": 2 }, { "tags": ["y", "x"], "id": 1 }] });
+
+        assert!(!equivalent(&a, &b, &CompareOptions::new().unordered(p("items"))));
+        assert!(equivalent(&a, &b, &CompareOptions::new().unordered(p("items")).unordered(p("items[*].tags"))));
+    }
+
+    #[test]
+    fn comparing_ignoring_volatile_paths() {
+        let a = json!({
+            "id": 1,
+            "meta": { "request_id": "abc", "updated_at": "2023-01-01" },
+            "items": [{ "name": "x", "updated_at": "2023-01-01" }]
+        });
+        let b = json!({
+            "id": 1,
+            "meta": { "request_id": "def" },
+            "items": [{ "name": "x", "updated_at": "2023-02-02" }]
+        });
+
+        assert!(!equals_ignoring(&a, &b, &[]).unwrap());
+        assert!(!equals_ignoring(&a, &b, &["meta.request_id"]).unwrap());
+        assert!(equals_ignoring(&a, &b, &["meta.request_id", "**.updated_at"]).unwrap());
+        assert!(!equals_ignoring(&a, &json!({ "id": 2 }), &["meta.**", "items.**"]).unwrap());
+        assert!(equals_ignoring(&a, &json!({ "id": 1 }), &["meta.**", "items.**"]).unwrap());
+        assert_eq!(
+            equals_ignoring(&a, &a, &["a..b"]).err().unwrap().to_string(),
+            errors::Error::InvalidPattern.to_string()
+        );
+    }
+
+    #[test]
+    fn diffing_ignoring_volatile_paths() {
+        let a = json!({ "id": 1, "meta": { "request_id": "abc" }, "name": "x" });
+        let b = json!({ "id": 1, "meta": { "request_id": "def" }, "name": "y" });
+
+        let diff = diff_ignoring(&a, &b, &["meta.request_id"]).unwrap();
+        assert_eq!(diff.changed, vec![("name".to_owned(), json!("x"), json!("y"))]);
+        assert!(diff.left_only.is_empty() && diff.right_only.is_empty());
+
+        assert_eq!(
+            diff_ignoring(&a, &b, &["a["]).err().unwrap().to_string(),
+            errors::Error::InvalidPattern.to_string()
+        );
+    }
+
+    #[test]
+    fn diffing_unordered_arrays() {
+        let a = json!({ "id": 1, "tags": ["a", "b", "b"], "old": true });
+        let b = json!({ "id": 2, "tags": ["b", "c", "a"], "new": true });
+
+        let diff = diff(&a, &b, &CompareOptions::new().unordered(p("tags")));
+
+        println!("got:\n{:#?}\n", diff);
+
+        assert_eq!(diff.changed, vec![("id".to_owned(), json!(1), json!(2))]);
+        assert_eq!(Value::Object(diff.left_only), json!({ "old": true }));
+        assert_eq!(Value::Object(diff.right_only), json!({ "new": true }));
+        assert_eq!(diff.unordered, vec![ElementsDiff {
+            path: "tags".to_owned(),
+            missing_from_left: vec![json!("c")],
+            missing_from_right: vec![json!("b")],
+        }]);
+    }
+
+    #[test]
+    fn comparing_deeply() {
+        let a = json!({ "a": 1, "b": { "c": [1, 2], "d": null } });
+        let reordered = json!({ "b": { "d": null, "c": [1, 2] }, "a": 1 });
+        let swapped = json!({ "a": 1, "b": { "c": [2, 1], "d": null } });
+        let changed = json!({ "a": 1, "b": { "c": [1, 1], "d": null } });
+
+        assert!(flatten_deep_equal(&a, &reordered));
+        assert!(flatten_deep_equal(&a, &swapped));
+        assert!(!flatten_deep_equal(&a, &changed));
+        assert!(flatten_deep_equal(&json!([1, [2, 3], { "x": [4, 5] }]), &json!([{ "x": [5, 4] }, [3, 2], 1])));
+        assert!(!flatten_deep_equal(&json!([[1, 2], [3]]), &json!([[1], [2, 3]])));
+
+        assert!(flatten_deep_equal_strict(&a, &a.clone()));
+        assert!(flatten_deep_equal_strict(&a, &reordered));
+        assert!(!flatten_deep_equal_strict(&a, &swapped));
+        assert!(!flatten_deep_equal_strict(&a, &changed));
+    }
+}
@@ -0,0 +1,315 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use crate::errors;
+use crate::flattening::{self, FlattenOptions};
+use crate::path::{self, KeyStyle};
+use crate::unflattening::{self, PathConflicts, UnflattenOptions};
+
+
+/// Configuration shared by both directions, so that data is unflattened with the settings it was flattened with.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub flatten: FlattenOptions,
+    pub unflatten: UnflattenOptions,
+    /// Notation of the flat keys, converted from and to the keys written by `flatten`.
+    pub key_style: KeyStyle,
+}
+
+impl Config {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the configuration matching the npm `flat` package (version 5) with its default options.
+    ///
+    /// Keys are written `a.b.0.c`, empty objects and arrays are kept as leaves and conflicting keys are resolved
+    /// as `flat` does with `overwrite: false` (see `PathConflicts::KeepShallowest`). Property names that `flat`
+    /// would read back differently (containing `.` or made of digits) are rejected with `InvalidKeyName`, and keys
+    /// starting with an index unflatten to an array, where `flat` returns an object.
+    pub fn npm_flat_compat() -> Self {
+        Config {
+            flatten: FlattenOptions::new().keep_empty_containers(true),
+            unflatten: UnflattenOptions::new().path_conflicts(PathConflicts::KeepShallowest),
+            key_style: KeyStyle::DotIndex,
+        }
+    }
+
+    /// Returns the configuration matching python's `flatten_json` package with its default separator.
+    ///
+    /// Keys are written `a_b_0_c`, empty objects and arrays are kept as leaves and properties are unflattened in
+    /// sorted order, as `unflatten_list` returns them. The notation is lossy, as in
+    /// `flatten_json`: property names containing `_` are read back as nested properties (`first_name` unflattens to
+    /// `{ "first": { "name": .. } }`), see `KeyStyle::UnderscoreIndex`. Names that collide once written, such as
+    /// `a_b` and `a.b`, fail with `DuplicateKey` where `flatten_json` keeps the last one.
+    pub fn python_flatten_json() -> Self {
+        Config {
+            flatten: FlattenOptions::new().keep_empty_containers(true),
+            unflatten: UnflattenOptions::new().sort_keys(true),
+            key_style: KeyStyle::UnderscoreIndex,
+        }
+    }
+
+    /// Returns the configuration matching the columns of pandas' `json_normalize`.
+    ///
+    /// Keys are written `a.b`, arrays are kept as leaves and empty objects are dropped. The columns built from
+    /// nested objects come after the top-level ones in pandas, while `flatten` keeps the document order.
+    pub fn pandas_normalize() -> Self {
+        Config {
+            flatten: FlattenOptions::new().keep_arrays(true),
+            ..Default::default()
+        }
+    }
+
+    /// Loads a configuration from its serialized JSON form.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The serialized configuration (`serde_json::Value`).
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the configuration (`Config`) or an error (`errors::Error`).
+    ///
+    pub fn from_value(value: &Value) -> Result<Self, errors::Error> {
+        let config: Self = serde_json::from_value(value.clone()).map_err(|_| errors::Error::FormatError)?;
+        flattening::check_options_version(config.flatten.version)?;
+        flattening::check_options_version(config.unflatten.version)?;
+        Ok(config)
+    }
+
+    /// Returns a short string identifying the settings of this configuration.
+    ///
+    /// Every setting is taken into account, together with `OPTIONS_VERSION`, so fingerprints stored alongside
+    /// flat data no longer match once the options, or their defaults, change.
+    pub fn fingerprint(&self) -> String {
+        let settings = serde_json::to_value(self).ok()
+            .and_then(|value| flattening::flatten(&value).ok())
+            .unwrap_or_default();
+
+        let mut lines: Vec<String> = settings.into_iter()
+            .filter(|(key, _)| !key.ends_with("version"))
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+        lines.sort();
+        lines.insert(0, format!("version={}", flattening::OPTIONS_VERSION));
+
+        format!("{:016x}", fnv1a(lines.join("\n").as_bytes()))
+    }
+
+    /// Checks that data stored with `fingerprint` can be read with this configuration.
+    ///
+    /// # Arguments
+    ///
+    /// * `fingerprint` - The fingerprint stored alongside the flat data.
+    ///
+    /// # Returns
+    ///
+    /// A Result that is an error (`errors::Error::ConfigMismatch`) when the fingerprints differ.
+    ///
+    pub fn verify_fingerprint(&self, fingerprint: &str) -> Result<(), errors::Error> {
+        if fingerprint != self.fingerprint() {
+            return Err(errors::Error::ConfigMismatch(fingerprint.to_owned()));
+        }
+        Ok(())
+    }
+}
+
+/// FNV-1a, which unlike the std hashers is stable across Rust releases.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325u64, |hash, b| (hash ^ *b as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Flattens a JSON Value into a key-value map using a shared configuration.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be flattened (`serde_json::Value`).
+/// * `config` - The configuration, to be used again when unflattening (`Config`).
+///
+/// # Returns
+///
+/// A Result containing a flattened JSON structure (`serde_json::Map<String, Value>`) or an error (`errors::Error`).
+///
+pub fn flatten_with(value: &Value, config: &Config) -> Result<Map<String, Value>, errors::Error> {
+    let flat = flattening::flatten_with_options(value, &config.flatten)?;
+    match config.key_style {
+        KeyStyle::Dotted => Ok(flat),
+        style => path::convert_keys(&flat, &KeyStyle::Dotted, &style),
+    }
+}
+
+/// Unflattens a flattened JSON structure using a shared configuration.
+///
+/// # Arguments
+///
+/// * `data` - The flattened JSON structure (`serde_json::Map<String, Value>`).
+/// * `config` - The configuration the structure was flattened with (`Config`).
+///
+/// # Returns
+///
+/// A Result containing the reconstructed original JSON object (`serde_json::Value`) or an error (`errors::Error`).
+///
+pub fn unflatten_with(data: &Map<String, Value>, config: &Config) -> Result<Value, errors::Error> {
+    match config.key_style {
+        KeyStyle::Dotted => unflattening::unflatten_with_options(data, &config.unflatten),
+        style => unflattening::unflatten_with_options(&path::convert_keys(data, &style, &KeyStyle::Quoted)?, &config.unflatten),
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    #[test]
+    fn roundtripping_with_config() {
+        let config = Config { flatten: FlattenOptions::new().quote_keys(true), ..Default::default() };
+        let json = json!({ "a": { "b.c": [1, { "d": null }] } });
+
+        let flat = flatten_with(&json, &config).unwrap();
+        assert_eq!(unflatten_with(&flat, &config).unwrap(), json);
+    }
+
+    #[test]
+    fn fingerprinting_configs() {
+        let config = Config::new();
+        assert_eq!(config.fingerprint(), Config::from_value(&json!({})).unwrap().fingerprint());
+        assert_eq!(config.fingerprint(), Config::from_value(&json!({ "flatten": { "version": 1 } })).unwrap().fingerprint());
+
+        let quoted = Config { flatten: FlattenOptions::new().quote_keys(true), ..Default::default() };
+        assert_ne!(config.fingerprint(), quoted.fingerprint());
+        let sorted = Config { unflatten: UnflattenOptions::new().sort_keys(true), ..Default::default() };
+        assert_ne!(config.fingerprint(), sorted.fingerprint());
+        assert_ne!(Config::python_flatten_json().fingerprint(), Config::pandas_normalize().fingerprint());
+        assert!(quoted.verify_fingerprint(&quoted.fingerprint()).is_ok());
+        assert_eq!(
+            config.verify_fingerprint(&quoted.fingerprint()).err().unwrap().to_string(),
+            errors::Error::ConfigMismatch(quoted.fingerprint()).to_string()
+        );
+    }
+
+    #[test]
+    fn conforming_to_npm_flat() {
+        // Documents with the output of `JSON.stringify(flatten(doc))` from flat@5, whose `unflatten` returns them.
+        let fixtures = [
+            (r#"{"hello":{"world":"good morning"}}"#, r#"{"hello.world":"good morning"}"#),
+            (r#"{"hello":{"world":1234.99,"flag":true,"none":null}}"#, r#"{"hello.world":1234.99,"hello.flag":true,"hello.none":null}"#),
+            (r#"{"hello":{"empty":{"nested":{}}},"list":[]}"#, r#"{"hello.empty.nested":{},"list":[]}"#),
+            (r#"{"z":[{"b":1},{"c":[2,[3,4]]}],"a":"last"}"#, r#"{"z.0.b":1,"z.1.c.0":2,"z.1.c.1.0":3,"z.1.c.1.1":4,"a":"last"}"#),
+            (
+                r#"{"name":"x","tags":["a","b"],"meta":{"created":"2023","owner":{"id":7}}}"#,
+                r#"{"name":"x","tags.0":"a","tags.1":"b","meta.created":"2023","meta.owner.id":7}"#
+            ),
+        ];
+        let config = Config::npm_flat_compat();
+
+        for (document, flat) in fixtures {
+            let value: Value = serde_json::from_str(document).unwrap();
+            assert_eq!(serde_json::to_string(&flatten_with(&value, &config).unwrap()).unwrap(), flat);
+
+            let data: Map<String, Value> = serde_json::from_str(flat).unwrap();
+            assert_eq!(serde_json::to_string(&unflatten_with(&data, &config).unwrap()).unwrap(), document);
+        }
+
+        // Conflicting keys, with the output of `JSON.stringify(unflatten(flat))` from flat@5.
+        let conflicts = [
+            (r#"{"a":1,"a.b":2}"#, r#"{"a":1}"#),
+            (r#"{"a.b":2,"a":1}"#, r#"{"a":1}"#),
+            (r#"{"a.0":1,"a.b":2,"c":null,"c.d":3}"#, r#"{"a":[1],"c":null}"#),
+            (r#"{"e":{},"e.f":1,"g.h":1,"g":[]}"#, r#"{"e":{"f":1},"g":[]}"#),
+        ];
+        for (flat, document) in conflicts {
+            let data: Map<String, Value> = serde_json::from_str(flat).unwrap();
+            assert_eq!(serde_json::to_string(&unflatten_with(&data, &config).unwrap()).unwrap(), document, "{}", flat);
+        }
+
+        assert_eq!(
+            flatten_with(&json!({ "a": { "1": true } }), &config).err().unwrap().to_string(),
+            errors::Error::InvalidKeyName("a.1".to_owned()).to_string()
+        );
+    }
+
+    #[test]
+    fn conforming_to_python_flatten_json() {
+        // Documents with the output of `flatten(doc)` from flatten_json and of `unflatten_list` on it.
+        let fixtures = [
+            (
+                r#"{"user":{"name":"John","tags":["a","b"]},"id":7}"#,
+                r#"{"user_name":"John","user_tags_0":"a","user_tags_1":"b","id":7}"#,
+                r#"{"id":7,"user":{"name":"John","tags":["a","b"]}}"#
+            ),
+            (
+                r#"{"items":[{"sku":"x","qty":2},{"sku":"y","dims":[1,2]}],"meta":{},"none":[],"flag":false}"#,
+                r#"{"items_0_sku":"x","items_0_qty":2,"items_1_sku":"y","items_1_dims_0":1,"items_1_dims_1":2,"meta":{},"none":[],"flag":false}"#,
+                r#"{"flag":false,"items":[{"qty":2,"sku":"x"},{"dims":[1,2],"sku":"y"}],"meta":{},"none":[]}"#
+            ),
+            (
+                r#"{"first_name":"John","address":{"zip_code":"01234"}}"#,
+                r#"{"first_name":"John","address_zip_code":"01234"}"#,
+                r#"{"address":{"zip":{"code":"01234"}},"first":{"name":"John"}}"#
+            ),
+        ];
+        let config = Config::python_flatten_json();
+
+        for (document, flat, unflattened) in fixtures {
+            let value: Value = serde_json::from_str(document).unwrap();
+            assert_eq!(serde_json::to_string(&flatten_with(&value, &config).unwrap()).unwrap(), flat);
+
+            let data: Map<String, Value> = serde_json::from_str(flat).unwrap();
+            assert_eq!(serde_json::to_string(&unflatten_with(&data, &config).unwrap()).unwrap(), unflattened);
+        }
+
+        assert_eq!(
+            flatten_with(&json!({ "a_b": 1, "a": { "b": 2 } }), &config).err().unwrap().to_string(),
+            errors::Error::DuplicateKey("a_b".to_owned()).to_string()
+        );
+    }
+
+    #[test]
+    fn conforming_to_pandas_normalize() {
+        // Documents with the columns of `json_normalize(doc)` from pandas.
+        let fixtures = [
+            (
+                json!({ "user": { "name": "John", "tags": ["a", "b"] }, "id": 7 }),
+                json!({ "id": 7, "user.name": "John", "user.tags": ["a", "b"] })
+            ),
+            (
+                json!({ "items": [{ "sku": "x", "qty": 2 }, { "sku": "y", "dims": [1, 2] }], "meta": {}, "none": [], "flag": false }),
+                json!({ "items": [{ "sku": "x", "qty": 2 }, { "sku": "y", "dims": [1, 2] }], "none": [], "flag": false })
+            ),
+            (
+                json!({ "first_name": "John", "address": { "zip_code": "01234" } }),
+                json!({ "first_name": "John", "address.zip_code": "01234" })
+            ),
+        ];
+        let config = Config::pandas_normalize();
+
+        for (document, columns) in fixtures {
+            let flat = flatten_with(&document, &config).unwrap();
+            assert_eq!(Value::Object(flat.clone()), columns);
+
+            let mut expected = document.clone();
+            expected.as_object_mut().unwrap().retain(|_, value| value != &json!({}));
+            assert_eq!(unflatten_with(&flat, &config).unwrap(), expected);
+        }
+    }
+}
@@ -0,0 +1,122 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+
+use serde_json::{Map, Value};
+use crate::errors;
+use crate::flattening::flatten;
+use crate::unflattening::unflatten;
+
+
+/// Flattens a JSON Value into `key.path=value` lines, for human-readable dumps.
+///
+/// Values are written as JSON, except strings which are written unquoted unless they would be read back as
+/// another value (e.g. `"true"` or `"42"`), start with a quote or span several lines.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be flattened (`serde_json::Value`).
+///
+/// # Returns
+///
+/// A Result containing the lines (`String`) or an error (`errors::Error`), `InvalidKeyName` if a flat key
+/// contains `=` or a line break.
+///
+pub fn flatten_to_dotted_string(value: &Value) -> Result<String, errors::Error> {
+    let mut output = String::new();
+    for (key, leaf) in flatten(value)? {
+        if key.contains(['=', '\n', '\r']) {
+            return Err(errors::Error::InvalidKeyName(key));
+        }
+        let text = match leaf {
+            Value::String(s) if !s.contains(['\n', '\r']) && parse_value(&s).as_str() == Some(&s) => s,
+            leaf => leaf.to_string(),
+        };
+        output.push_str(&key);
+        output.push('=');
+        output.push_str(&text);
+        output.push('\n');
+    }
+    Ok(output)
+}
+
+/// Unflattens `key.path=value` lines written by `flatten_to_dotted_string`.
+///
+/// Only the first `=` of a line separates the key from the value. Values that are not valid JSON are read as
+/// strings, and empty lines are skipped.
+///
+/// # Arguments
+///
+/// * `s` - The lines to be unflattened.
+///
+/// # Returns
+///
+/// A Result containing the reconstructed JSON object (`serde_json::Value`) or an error (`errors::Error`),
+/// `FormatError` if a line has no `=`.
+///
+pub fn unflatten_from_dotted_string(s: &str) -> Result<Value, errors::Error> {
+    let mut flat = Map::new();
+    for line in s.lines().filter(|line| !line.trim().is_empty()) {
+        let (key, text) = line.split_once('=').ok_or(errors::Error::FormatError)?;
+        flat.insert(key.to_owned(), parse_value(text));
+    }
+    unflatten(&flat)
+}
+
+fn parse_value(text: &str) -> Value {
+    match serde_json::from_str::<Value>(text) {
+        // Containers are never written by `flatten_to_dotted_string`.
+        Ok(value) if !value.is_object() && !value.is_array() => value,
+        _ => Value::String(text.to_owned()),
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    #[test]
+    fn flattening_to_dotted_string() {
+        let json = json!({
+            "db": { "host": "localhost", "port": 5432, "url": "a=b" },
+            "flags": [true, null],
+            "quoted": ["true", "42", "\"x\"", "line\nbreak", ""]
+        });
+
+        let dotted = flatten_to_dotted_string(&json).unwrap();
+        let expected = "db.host=localhost\ndb.port=5432\ndb.url=a=b\nflags[0]=true\nflags[1]=null\n\
+            quoted[0]=\"true\"\nquoted[1]=\"42\"\nquoted[2]=\"\\\"x\\\"\"\nquoted[3]=\"line\\nbreak\"\nquoted[4]=\n";
+
+        println!("got:\n{}\nexpected:\n{}\n", dotted, expected);
+
+        assert_eq!(dotted, expected);
+        assert_eq!(unflatten_from_dotted_string(&dotted).unwrap(), json);
+    }
+
+    #[test]
+    fn unflattening_from_invalid_dotted_string() {
+        assert_eq!(
+            unflatten_from_dotted_string("a=1\nb\n").err().unwrap().to_string(),
+            errors::Error::FormatError.to_string()
+        );
+        assert_eq!(
+            flatten_to_dotted_string(&json!({ "a=b": 1 })).err().unwrap().to_string(),
+            errors::Error::InvalidKeyName("a=b".to_owned()).to_string()
+        );
+    }
+}
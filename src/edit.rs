@@ -0,0 +1,403 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+
+use std::ops::Range;
+use serde_json::{Map, Value, json};
+use crate::errors;
+use crate::flat_map::DuplicateKeyPolicy;
+use crate::path::{self, PathSegment};
+
+
+/// Options for moving subtrees with `move_path`.
+#[derive(Debug, Clone)]
+pub struct MoveOptions {
+    /// What happens when something is already found at the destination, `DuplicateKeyPolicy::Error` by default.
+    pub conflicts: DuplicateKeyPolicy,
+}
+
+impl Default for MoveOptions {
+    fn default() -> Self {
+        MoveOptions { conflicts: DuplicateKeyPolicy::Error }
+    }
+}
+
+/// Moves the subtree found at a flat key to another flat key, creating the missing objects and arrays on the way.
+///
+/// The subtree is removed first, so array elements after `from` shift down and `to` is resolved in the document
+/// without it. Array slots past the end of an array are reached by padding it with `null`. On error the document is
+/// left unchanged.
+///
+/// # Arguments
+///
+/// * `doc` - The JSON Value to be edited (`serde_json::Value`).
+/// * `from` - The flat key of the subtree to be moved.
+/// * `to` - The flat key the subtree is moved to.
+/// * `opts` - The move options (`MoveOptions`).
+///
+/// # Returns
+///
+/// A Result that is an error (`errors::Error`): `PathNotFound` if there is nothing at `from`, `MoveIntoDescendant`
+/// if `to` is below `from`, `ConflictingTypes` if a node on the way to `to` is not the needed container, or
+/// `DuplicateKey` if `to` is taken under `DuplicateKeyPolicy::Error`.
+///
+pub fn move_path(doc: &mut Value, from: &str, to: &str, opts: &MoveOptions) -> Result<(), errors::Error> {
+    let from_segments = path::parse_flat_key(from)?;
+    let to_segments = path::parse_flat_key(to)?;
+    if from_segments.is_empty() || to_segments.is_empty() {
+        return Err(errors::Error::InvalidProperty);
+    }
+    if to_segments.starts_with(&from_segments) {
+        if to_segments.len() > from_segments.len() {
+            return Err(errors::Error::MoveIntoDescendant(from.to_owned()));
+        }
+        return path::lookup(doc, &from_segments).map(|_| ()).ok_or_else(|| errors::Error::PathNotFound(from.to_owned()));
+    }
+
+    let (value, position) = take(doc, &from_segments).ok_or_else(|| errors::Error::PathNotFound(from.to_owned()))?;
+    if let Err((e, value)) = place(doc, &to_segments, value, opts.conflicts) {
+        restore(doc, &from_segments, position, value);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Applies flat key-value updates to a nested JSON Value in place, creating the missing objects and arrays on the
+/// way and replacing the values already present.
+///
+/// Updates are applied in the order of `updates`; when one fails, the ones before it are kept.
+///
+/// # Arguments
+///
+/// * `target` - The JSON Value to be updated (`serde_json::Value`).
+/// * `updates` - The flat keys mapped to their new values (`serde_json::Map<String, Value>`).
+///
+/// # Returns
+///
+/// A Result that is an error (`errors::Error`): `ConflictingTypes` if an update would go through a node that is not
+/// the needed object or array (e.g. `a.b` when `a` is a string or an array).
+///
+pub fn flatten_update(target: &mut Value, updates: &Map<String, Value>) -> Result<(), errors::Error> {
+    for (key, value) in updates {
+        let segments = path::parse_flat_key(key)?;
+        place(target, &segments, value.clone(), DuplicateKeyPolicy::KeepLast).map_err(|(e, _)| e)?;
+    }
+    Ok(())
+}
+
+/// What happens to indices past the end of an array in `insert_at` and `splice`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutOfRange {
+    /// The edit fails with `Error::IndexOutOfRange`.
+    #[default]
+    Error,
+    /// The index is moved back to the end of the array.
+    Clamp,
+}
+
+/// Options for editing arrays with `insert_at` and `splice`.
+#[derive(Debug, Clone, Default)]
+pub struct ArrayEditOptions {
+    /// How indices past the end of the array are handled, `OutOfRange::Error` by default.
+    pub out_of_range: OutOfRange,
+}
+
+/// Inserts a value in an array before the element at a flat key (`items[2]`), shifting the following elements.
+///
+/// The index can be the length of the array, to append the value.
+///
+/// # Arguments
+///
+/// * `doc` - The JSON Value to be edited (`serde_json::Value`).
+/// * `key` - The flat key of the array slot, ending with an index.
+/// * `value` - The value to be inserted (`serde_json::Value`).
+/// * `opts` - The array edit options (`ArrayEditOptions`).
+///
+/// # Returns
+///
+/// A Result that is an error (`errors::Error`): `PathNotFound` if there is no array, `InvalidType` if something
+/// other than an array is found, or `IndexOutOfRange` if the index is past the end under `OutOfRange::Error`.
+///
+pub fn insert_at(doc: &mut Value, key: &str, value: Value, opts: &ArrayEditOptions) -> Result<(), errors::Error> {
+    let segments = path::parse_flat_key(key)?;
+    let Some((PathSegment::Index(index), parent)) = segments.split_last() else {
+        return Err(errors::Error::InvalidProperty);
+    };
+    let array = array_at(doc, parent)?;
+    if *index > array.len() && opts.out_of_range == OutOfRange::Error {
+        return Err(errors::Error::IndexOutOfRange(key.to_owned()));
+    }
+    array.insert((*index).min(array.len()), value);
+    Ok(())
+}
+
+/// Replaces a range of the elements of the array at a flat key with other elements.
+///
+/// # Arguments
+///
+/// * `doc` - The JSON Value to be edited (`serde_json::Value`).
+/// * `key` - The flat key of the array.
+/// * `range` - The indices of the elements to be replaced, empty to only insert.
+/// * `replacement` - The elements put in place of the range (`Vec<serde_json::Value>`).
+/// * `opts` - The array edit options (`ArrayEditOptions`).
+///
+/// # Returns
+///
+/// A Result containing the removed elements (`Vec<serde_json::Value>`) or an error (`errors::Error`), as for
+/// `insert_at`; a range ending before it starts is out of range.
+///
+pub fn splice(doc: &mut Value, key: &str, range: Range<usize>, replacement: Vec<Value>, opts: &ArrayEditOptions) -> Result<Vec<Value>, errors::Error> {
+    let segments = path::parse_flat_key(key)?;
+    let array = array_at(doc, &segments)?;
+    if (range.end > array.len() || range.start > range.end) && opts.out_of_range == OutOfRange::Error {
+        return Err(errors::Error::IndexOutOfRange(key.to_owned()));
+    }
+    let end = range.end.min(array.len());
+    let start = range.start.min(end);
+    Ok(array.splice(start..end, replacement).collect())
+}
+
+fn array_at<'a>(doc: &'a mut Value, segments: &[PathSegment]) -> Result<&'a mut Vec<Value>, errors::Error> {
+    path::lookup_mut(doc, segments)
+        .ok_or_else(|| errors::Error::PathNotFound(path::render_flat_key(segments)))?
+        .as_array_mut()
+        .ok_or(errors::Error::InvalidType)
+}
+
+/// Removes the node at a path, returning it with its position in its parent.
+fn take(doc: &mut Value, segments: &[PathSegment]) -> Option<(Value, usize)> {
+    let (last, parent) = segments.split_last()?;
+    match (path::lookup_mut(doc, parent)?, last) {
+        (Value::Object(map), PathSegment::Key(k)) => {
+            let position = map.keys().position(|key| key == k)?;
+            map.shift_remove(k).map(|value| (value, position))
+        }
+        (Value::Array(array), PathSegment::Index(i)) if *i < array.len() => Some((array.remove(*i), *i)),
+        _ => None,
+    }
+}
+
+/// Puts back a node removed by `take`.
+fn restore(doc: &mut Value, segments: &[PathSegment], position: usize, value: Value) {
+    let Some((last, parent)) = segments.split_last() else { return };
+    match (path::lookup_mut(doc, parent), last) {
+        (Some(Value::Object(map)), PathSegment::Key(k)) => {
+            map.shift_insert(position, k.clone(), value);
+        }
+        (Some(Value::Array(array)), PathSegment::Index(_)) => array.insert(position, value),
+        _ => {}
+    }
+}
+
+/// Stores a value at a path, creating the missing containers; on error, the value is handed back and nothing has
+/// been changed.
+fn place(doc: &mut Value, segments: &[PathSegment], value: Value, policy: DuplicateKeyPolicy) -> Result<(), (errors::Error, Value)> {
+    let Some((last, parents)) = segments.split_last() else { return Err((errors::Error::InvalidProperty, value)) };
+    let mut current = doc;
+    for (i, segment) in parents.iter().enumerate() {
+        let template = match segments[i + 1] {
+            PathSegment::Key(_) => json!({}),
+            PathSegment::Index(_) => json!([]),
+        };
+        current = match child_or_insert(current, segment, template) {
+            Some(child) => child,
+            None => return Err((errors::Error::ConflictingTypes(path::render_flat_key(&segments[..i])), value)),
+        };
+    }
+
+    let existing = match (current, last) {
+        (Value::Object(map), PathSegment::Key(k)) => match map.get_mut(k) {
+            Some(existing) => existing,
+            None => {
+                map.insert(k.clone(), value);
+                return Ok(());
+            }
+        },
+        (Value::Array(array), PathSegment::Index(i)) if *i >= array.len() => {
+            array.resize(*i, Value::Null);
+            array.push(value);
+            return Ok(());
+        }
+        (Value::Array(array), PathSegment::Index(i)) => &mut array[*i],
+        _ => return Err((errors::Error::ConflictingTypes(path::render_flat_key(parents)), value)),
+    };
+
+    match policy {
+        DuplicateKeyPolicy::Merge => match existing {
+            Value::Array(array) => array.push(value),
+            _ => *existing = json!([existing.take(), value]),
+        },
+        DuplicateKeyPolicy::KeepFirst => {}
+        DuplicateKeyPolicy::KeepLast => *existing = value,
+        DuplicateKeyPolicy::Error => return Err((errors::Error::DuplicateKey(path::render_flat_key(segments)), value)),
+    }
+    Ok(())
+}
+
+/// Returns the child of a container, inserting `template` if it is missing, or `None` if the node is not the
+/// container the segment needs.
+fn child_or_insert<'a>(node: &'a mut Value, segment: &PathSegment, template: Value) -> Option<&'a mut Value> {
+    match (node, segment) {
+        (Value::Object(map), PathSegment::Key(k)) => Some(map.entry(k.clone()).or_insert(template)),
+        (Value::Array(array), PathSegment::Index(i)) => {
+            if *i >= array.len() {
+                array.resize(*i, Value::Null);
+                array.push(template);
+            }
+            Some(&mut array[*i])
+        }
+        _ => None,
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moving_subtrees() {
+        let mut json = json!({
+            "user": { "name": "John", "address": { "city": "Turin" } },
+            "items": [{ "id": 1 }, { "id": 2 }]
+        });
+
+        move_path(&mut json, "user.address", "profile.location", &MoveOptions::default()).unwrap();
+        move_path(&mut json, "items[0]", "first", &MoveOptions::default()).unwrap();
+        move_path(&mut json, "user.name", "items[2].owner", &MoveOptions::default()).unwrap();
+        let expected = json!({
+            "user": {},
+            "items": [{ "id": 2 }, null, { "owner": "John" }],
+            "profile": { "location": { "city": "Turin" } },
+            "first": { "id": 1 }
+        });
+
+        println!(
+            "got:\n{}\nexpected:\n{}\n",
+            serde_json::to_string_pretty(&json).unwrap(),
+            serde_json::to_string_pretty(&expected).unwrap()
+        );
+
+        assert_eq!(json, expected);
+    }
+
+    #[test]
+    fn moving_subtrees_with_conflicts() {
+        let json = json!({ "a": { "b": 1 }, "c": 2, "d": "x" });
+
+        for (policy, expected) in [
+            (DuplicateKeyPolicy::Merge, json!({ "c": [2, { "b": 1 }], "d": "x" })),
+            (DuplicateKeyPolicy::KeepFirst, json!({ "c": 2, "d": "x" })),
+            (DuplicateKeyPolicy::KeepLast, json!({ "c": { "b": 1 }, "d": "x" })),
+        ] {
+            let mut moved = json.clone();
+            move_path(&mut moved, "a", "c", &MoveOptions { conflicts: policy }).unwrap();
+            assert_eq!(moved, expected);
+        }
+
+        let mut unchanged = json.clone();
+        for (from, to, error) in [
+            ("a", "c", errors::Error::DuplicateKey("c".to_owned())),
+            ("a", "a.b.e", errors::Error::MoveIntoDescendant("a".to_owned())),
+            ("a", "d.e", errors::Error::ConflictingTypes("d".to_owned())),
+            ("a", "c[0]", errors::Error::ConflictingTypes("c".to_owned())),
+            ("e", "f", errors::Error::PathNotFound("e".to_owned())),
+        ] {
+            assert_eq!(move_path(&mut unchanged, from, to, &MoveOptions::default()).err().unwrap().to_string(), error.to_string());
+            assert_eq!(serde_json::to_string(&unchanged).unwrap(), serde_json::to_string(&json).unwrap());
+        }
+    }
+
+    #[test]
+    fn updating_in_place() {
+        let mut json = json!({ "user": { "name": "John", "tags": ["a"] }, "n": 1 });
+        let updates = json!({
+            "user.name": "Ann",
+            "user.tags[1]": "b",
+            "user.address.city": "Turin",
+            "items[0].id": 7,
+            "n": { "m": 2 }
+        });
+
+        flatten_update(&mut json, updates.as_object().unwrap()).unwrap();
+        let expected = json!({
+            "user": { "name": "Ann", "tags": ["a", "b"], "address": { "city": "Turin" } },
+            "n": { "m": 2 },
+            "items": [{ "id": 7 }]
+        });
+
+        println!(
+            "got:\n{}\nexpected:\n{}\n",
+            serde_json::to_string_pretty(&json).unwrap(),
+            serde_json::to_string_pretty(&expected).unwrap()
+        );
+
+        assert_eq!(json, expected);
+
+        for (key, path) in [("user.name.first", "user.name"), ("user.tags.x", "user.tags"), ("user[0]", "user")] {
+            let updates = json!({ key: 1 });
+            assert_eq!(
+                flatten_update(&mut json, updates.as_object().unwrap()).err().unwrap().to_string(),
+                errors::Error::ConflictingTypes(path.to_owned()).to_string()
+            );
+        }
+    }
+
+    #[test]
+    fn inserting_into_arrays() {
+        let mut json = json!({ "items": ["a", "b", "c"], "name": "list" });
+        let opts = ArrayEditOptions::default();
+
+        insert_at(&mut json, "items[2]", json!("x"), &opts).unwrap();
+        insert_at(&mut json, "items[4]", json!("y"), &opts).unwrap();
+        assert_eq!(json["items"], json!(["a", "b", "x", "c", "y"]));
+
+        assert_eq!(
+            insert_at(&mut json, "items[9]", json!("z"), &opts).err().unwrap().to_string(),
+            errors::Error::IndexOutOfRange("items[9]".to_owned()).to_string()
+        );
+        insert_at(&mut json, "items[9]", json!("z"), &ArrayEditOptions { out_of_range: OutOfRange::Clamp }).unwrap();
+        assert_eq!(json["items"], json!(["a", "b", "x", "c", "y", "z"]));
+
+        assert_eq!(insert_at(&mut json, "name[0]", json!(1), &opts).err().unwrap().to_string(), errors::Error::InvalidType.to_string());
+        assert_eq!(
+            insert_at(&mut json, "tags[0]", json!(1), &opts).err().unwrap().to_string(),
+            errors::Error::PathNotFound("tags".to_owned()).to_string()
+        );
+        assert_eq!(insert_at(&mut json, "items", json!(1), &opts).err().unwrap().to_string(), errors::Error::InvalidProperty.to_string());
+    }
+
+    #[test]
+    fn splicing_arrays() {
+        let mut json = json!({ "rows": [[0, 1, 2, 3]] });
+        let opts = ArrayEditOptions::default();
+
+        let removed = splice(&mut json, "rows[0]", 1..3, vec![json!("a"), json!("b"), json!("c")], &opts).unwrap();
+        assert_eq!(removed, vec![json!(1), json!(2)]);
+        assert_eq!(json, json!({ "rows": [[0, "a", "b", "c", 3]] }));
+
+        splice(&mut json, "rows[0]", 0..0, vec![json!("start")], &opts).unwrap();
+        assert_eq!(json, json!({ "rows": [["start", 0, "a", "b", "c", 3]] }));
+
+        assert_eq!(
+            splice(&mut json, "rows[0]", 4..10, vec![], &opts).err().unwrap().to_string(),
+            errors::Error::IndexOutOfRange("rows[0]".to_owned()).to_string()
+        );
+        let removed = splice(&mut json, "rows[0]", 4..10, vec![], &ArrayEditOptions { out_of_range: OutOfRange::Clamp }).unwrap();
+        assert_eq!(removed, vec![json!("c"), json!(3)]);
+        assert_eq!(json, json!({ "rows": [["start", 0, "a", "b"]] }));
+    }
+}
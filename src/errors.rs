@@ -22,8 +22,8 @@ pub enum Error {
     #[error("This is not an Object, cannot be flattened!")]
     NotAnObject,
 
-    #[error("This should be a Value")]
-    NotAValue,
+    #[error("This should be a Value, found a container at {0}")]
+    NotAValue(String),
 
     #[error("The property is not valid")]
     InvalidProperty,
@@ -40,4 +40,58 @@ pub enum Error {
     #[error("JSON format error")]
     FormatError,
 
+    #[error("The path pattern is not valid")]
+    InvalidPattern,
+
+    #[error("Invalid key name at {0}")]
+    InvalidKeyName(String),
+
+    #[error("Unterminated quote at position {0}")]
+    UnterminatedQuote(usize),
+
+    #[error("Options version {0} is not supported")]
+    UnsupportedOptionsVersion(u32),
+
+    #[error("Required field {0} is missing")]
+    SchemaValidationFailed(String),
+
+    #[error("{} documents could not be processed", .0.len())]
+    BatchErrors(Vec<(usize, Error)>),
+
+    #[error("Data was produced with configuration {0}, which does not match the current one")]
+    ConfigMismatch(String),
+
+    #[error("Required path {0} is missing")]
+    RequiredPathMissing(String),
+
+    #[error("Duplicate key {0}")]
+    DuplicateKey(String),
+
+    #[error("The cursor does not belong to this document")]
+    InvalidCursor,
+
+    #[error("The operation was cancelled")]
+    Cancelled,
+
+    #[error("Key {0} exceeds the configured limits")]
+    KeyLimitExceeded(String),
+
+    #[error("Path {0} was not found")]
+    PathNotFound(String),
+
+    #[error("Conflicting types at {0}")]
+    ConflictingTypes(String),
+
+    #[error("Index out of range at {0}")]
+    IndexOutOfRange(String),
+
+    #[error("Cannot move {0} into its own descendant")]
+    MoveIntoDescendant(String),
+
+    #[error("The input looks already flattened, found the flat key {0}")]
+    AlreadyFlat(String),
+
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
 }
\ No newline at end of file
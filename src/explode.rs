@@ -0,0 +1,177 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+
+use serde_json::{Map, Value};
+use crate::errors;
+use crate::flattening::flatten;
+use crate::path::{self, PathSegment};
+use crate::pattern::PathPattern;
+
+
+/// Explodes the records of a JSON Value into flat rows, like pandas' `json_normalize(record_path, meta)`.
+///
+/// Every array matching `record_path` (e.g. `orders[*].items`) holds records: each record, which must be an
+/// object, is flattened into a row. Wildcards in the record path expand over every element, so nested record
+/// paths produce one row per combination. The `meta` columns are added to every row; their `[*]` wildcards
+/// take, in order, the indices matched by the `[*]` wildcards of `record_path`, so `orders[*].id` is the id of the
+/// record's order.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value holding the records (`serde_json::Value`).
+/// * `record_path` - Glob pattern (see `PathPattern`) of the arrays holding the records.
+/// * `meta` - The flat keys of the values repeated in every row, which are also the column names.
+///
+/// # Returns
+///
+/// A Result containing the rows in document order (`Vec<serde_json::Map<String, Value>>`) or an error
+/// (`errors::Error`): `NotAnObject` for a record that is not an object, `RequiredPathMissing` for a meta path
+/// that cannot be found and `DuplicateKey` for a meta column also found in a record.
+///
+pub fn explode(value: &Value, record_path: &str, meta: &[&str]) -> Result<Vec<Map<String, Value>>, errors::Error> {
+    let pattern = PathPattern::compile(record_path)?;
+    let mut arrays = vec![];
+    find_arrays(value, &mut vec![], &pattern, &mut arrays);
+
+    let mut rows = vec![];
+    for (segments, records) in arrays {
+        let indices = pattern.captured_indices(&segments).unwrap_or_default();
+
+        let mut columns = Map::new();
+        for meta_path in meta {
+            let column = lookup_meta(value, meta_path, &indices)?;
+            columns.insert(meta_path.to_string(), column.clone());
+        }
+
+        for record in records {
+            if !record.is_object() {
+                return Err(errors::Error::NotAnObject);
+            }
+            let mut row = flatten(record)?;
+            for (column, meta_value) in &columns {
+                if row.contains_key(column) {
+                    return Err(errors::Error::DuplicateKey(column.clone()));
+                }
+                row.insert(column.clone(), meta_value.clone());
+            }
+            rows.push(row);
+        }
+    }
+    Ok(rows)
+}
+
+fn find_arrays<'a>(value: &'a Value, segments: &mut Vec<PathSegment>, pattern: &PathPattern, arrays: &mut Vec<(Vec<PathSegment>, &'a [Value])>) {
+    if let Value::Array(array) = value {
+        if pattern.matches(segments) {
+            arrays.push((segments.clone(), array));
+        }
+    }
+    let children: Box<dyn Iterator<Item = (PathSegment, &Value)>> = match value {
+        Value::Object(map) => Box::new(map.iter().map(|(k, v)| (PathSegment::Key(k.clone()), v))),
+        Value::Array(array) => Box::new(array.iter().enumerate().map(|(i, v)| (PathSegment::Index(i), v))),
+        _ => return,
+    };
+    for (segment, child) in children {
+        segments.push(segment);
+        if pattern.matches_prefix(segments) {
+            find_arrays(child, segments, pattern, arrays);
+        }
+        segments.pop();
+    }
+}
+
+fn lookup_meta<'a>(value: &'a Value, meta_path: &str, indices: &[usize]) -> Result<&'a Value, errors::Error> {
+    PathPattern::compile(meta_path).ok()
+        .and_then(|pattern| pattern.bind_indices(indices))
+        .and_then(|segments| path::lookup(value, &segments))
+        .ok_or_else(|| errors::Error::RequiredPathMissing(meta_path.to_owned()))
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    #[test]
+    fn exploding_records() {
+        let json = json!({
+            "user": { "id": 7 },
+            "orders": [
+                { "id": "a", "items": [{ "sku": "x", "qty": 1 }, { "sku": "y", "qty": 2 }] },
+                { "id": "b", "items": [{ "sku": "z", "qty": 3, "tags": ["new"] }] },
+                { "id": "c", "items": [] }
+            ]
+        });
+
+        let rows = explode(&json, "orders[*].items", &["user.id", "orders[*].id"]).unwrap();
+        let expected = vec![
+            json!({ "sku": "x", "qty": 1, "user.id": 7, "orders[*].id": "a" }),
+            json!({ "sku": "y", "qty": 2, "user.id": 7, "orders[*].id": "a" }),
+            json!({ "sku": "z", "qty": 3, "tags[0]": "new", "user.id": 7, "orders[*].id": "b" }),
+        ];
+
+        println!(
+            "got:\n{}\nexpected:\n{}\n",
+            serde_json::to_string_pretty(&rows).unwrap(),
+            serde_json::to_string_pretty(&expected).unwrap()
+        );
+
+        assert_eq!(serde_json::to_value(&rows).unwrap(), Value::from(expected));
+    }
+
+    #[test]
+    fn exploding_nested_records() {
+        let json = json!({
+            "stores": [
+                { "name": "s1", "shelves": [{ "n": 1, "books": [{ "t": "A" }, { "t": "B" }] }, { "n": 2, "books": [{ "t": "C" }] }] },
+                { "name": "s2", "shelves": [{ "n": 1, "books": [{ "t": "D" }] }] }
+            ]
+        });
+
+        let rows = explode(&json, "stores[*].shelves[*].books", &["stores[*].name", "stores[*].shelves[*].n"]).unwrap();
+        let summary: Vec<(Value, Value, Value)> = rows.iter()
+            .map(|row| (row["stores[*].name"].clone(), row["stores[*].shelves[*].n"].clone(), row["t"].clone()))
+            .collect();
+        assert_eq!(summary, vec![
+            (json!("s1"), json!(1), json!("A")),
+            (json!("s1"), json!(1), json!("B")),
+            (json!("s1"), json!(2), json!("C")),
+            (json!("s2"), json!(1), json!("D")),
+        ]);
+
+        assert_eq!(
+            explode(&json, "stores[*].shelves", &["owner"]).err().unwrap().to_string(),
+            errors::Error::RequiredPathMissing("owner".to_owned()).to_string()
+        );
+        assert_eq!(
+            explode(&json, "stores[*].shelves", &["stores[*].name"]).unwrap().len(),
+            3
+        );
+
+        let rows = explode(&json, "stores[0].shelves[*].books", &["stores[0].shelves[*].n", "stores[1].name"]).unwrap();
+        let summary: Vec<(Value, Value, Value)> = rows.iter()
+            .map(|row| (row["stores[0].shelves[*].n"].clone(), row["stores[1].name"].clone(), row["t"].clone()))
+            .collect();
+        assert_eq!(summary, vec![
+            (json!(1), json!("s2"), json!("A")),
+            (json!(1), json!("s2"), json!("B")),
+            (json!(2), json!("s2"), json!("C")),
+        ]);
+        assert_eq!(explode(&json, "stores[1].shelves[*].books", &["stores[1].shelves[*].n"]).unwrap()[0]["t"], json!("D"));
+    }
+}
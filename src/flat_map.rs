@@ -0,0 +1,547 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use crate::compare::{self, CompareOptions};
+use crate::errors;
+use crate::flattening::{Collision, CollisionTracker, flatten, flatten_into};
+use crate::path::{self, PathSegment};
+use crate::pattern::PathPattern;
+use crate::sink::FlatSink;
+
+
+/// What happens when a value is inserted under a key that is already present.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateKeyPolicy {
+    /// The values are merged into an array, as `flatten` does.
+    #[default]
+    Merge,
+    /// The value already present is kept.
+    KeepFirst,
+    /// The new value replaces the one already present.
+    KeepLast,
+    /// The insertion fails with `Error::DuplicateKey`.
+    Error,
+}
+
+/// A flattened JSON structure that applies a `DuplicateKeyPolicy` when keys collide.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FlatMap {
+    entries: Map<String, Value>,
+    duplicates: DuplicateKeyPolicy,
+}
+
+impl FlatMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the policy applied when keys collide.
+    pub fn with_duplicate_policy(mut self, duplicates: DuplicateKeyPolicy) -> Self {
+        self.duplicates = duplicates;
+        self
+    }
+
+    pub fn duplicate_policy(&self) -> DuplicateKeyPolicy {
+        self.duplicates
+    }
+
+    /// Inserts a value, applying the duplicate key policy if the key is already present.
+    pub fn insert(&mut self, key: String, value: Value) -> Result<(), errors::Error> {
+        if !self.entries.contains_key(&key) {
+            self.entries.insert(key, value);
+            return Ok(());
+        }
+        match self.duplicates {
+            DuplicateKeyPolicy::Merge => self.entries.put(&key, value)?,
+            DuplicateKeyPolicy::KeepFirst => {}
+            DuplicateKeyPolicy::KeepLast => {
+                self.entries.insert(key, value);
+            }
+            DuplicateKeyPolicy::Error => return Err(errors::Error::DuplicateKey(key)),
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.entries.get(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> serde_json::map::Iter<'_> {
+        self.entries.iter()
+    }
+
+    pub fn as_map(&self) -> &Map<String, Value> {
+        &self.entries
+    }
+
+    pub fn into_map(self) -> Map<String, Value> {
+        self.entries
+    }
+
+    /// Returns a copy of the map with every flat key transformed by `key_fn` (e.g. `user.first_name` to `user.firstName`).
+    ///
+    /// # Arguments
+    ///
+    /// * `key_fn` - The transformation applied to each full flat key.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the renamed map (`FlatMap`) or an error (`errors::Error`) when two keys are renamed
+    /// to the same key under `DuplicateKeyPolicy::Error`.
+    ///
+    pub fn rename_all(&self, key_fn: impl Fn(&str) -> String) -> Result<FlatMap, errors::Error> {
+        self.rename_all_with_report(key_fn).map(|(renamed, _)| renamed)
+    }
+
+    /// Renames every flat key, see `rename_all`, reporting the keys that several original keys were renamed to.
+    ///
+    /// # Arguments
+    ///
+    /// * `key_fn` - The transformation applied to each full flat key.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the renamed map (`FlatMap`) with the collisions (`Vec<Collision>`), or an error
+    /// (`errors::Error`) under `DuplicateKeyPolicy::Error`.
+    ///
+    pub fn rename_all_with_report(&self, key_fn: impl Fn(&str) -> String) -> Result<(FlatMap, Vec<Collision>), errors::Error> {
+        let mut renamed = FlatMap::new().with_duplicate_policy(self.duplicates);
+        let mut sources = CollisionTracker::default();
+        for (key, value) in &self.entries {
+            let new_key = key_fn(key);
+            sources.record(&new_key, key.clone());
+            renamed.insert(new_key, value.clone())?;
+        }
+        Ok((renamed, sources.into_collisions()))
+    }
+}
+
+/// Flattens a JSON Value and renames its flat keys according to `key_map`, e.g. for schema migrations.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be flattened (`serde_json::Value`).
+/// * `key_map` - The new flat key of each renamed flat key; other keys are kept as they are.
+///
+/// # Returns
+///
+/// A Result containing the renamed flat structure (`serde_json::Map<String, Value>`) or an error (`errors::Error`),
+/// `DuplicateKey` if a renamed key collides with another key.
+///
+pub fn flatten_key_map(value: &Value, key_map: &HashMap<String, String>) -> Result<Map<String, Value>, errors::Error> {
+    flatten_key_map_with_policy(value, key_map, DuplicateKeyPolicy::Error)
+}
+
+/// Flattens a JSON Value and renames its flat keys, see `flatten_key_map`, resolving collisions with `policy`.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be flattened (`serde_json::Value`).
+/// * `key_map` - The new flat key of each renamed flat key; other keys are kept as they are.
+/// * `policy` - The handling of colliding keys (`DuplicateKeyPolicy`).
+///
+/// # Returns
+///
+/// A Result containing the renamed flat structure (`serde_json::Map<String, Value>`) or an error (`errors::Error`).
+///
+pub fn flatten_key_map_with_policy(value: &Value, key_map: &HashMap<String, String>, policy: DuplicateKeyPolicy) -> Result<Map<String, Value>, errors::Error> {
+    let flat = FlatMap::from(flatten(value)?).with_duplicate_policy(policy);
+    let renamed = flat.rename_all(|key| key_map.get(key).cloned().unwrap_or_else(|| key.to_owned()))?;
+    Ok(renamed.into_map())
+}
+
+/// Flattens a JSON Value and copies the entries whose flat key matches a pattern under an alias key, e.g. to serve
+/// `user.first_name` also as `user.firstName`. The original keys are kept.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be flattened (`serde_json::Value`).
+/// * `aliases` - The patterns of the aliased flat keys with their alias key, every matching alias being added.
+///
+/// # Returns
+///
+/// A Result containing the flat structure with the aliases (`serde_json::Map<String, Value>`) or an error
+/// (`errors::Error`), `DuplicateKey` if an alias collides with another key.
+///
+pub fn flatten_with_aliases(value: &Value, aliases: &[(PathPattern, &str)]) -> Result<Map<String, Value>, errors::Error> {
+    flatten_with_aliases_with_policy(value, aliases, DuplicateKeyPolicy::Error)
+}
+
+/// Flattens a JSON Value and adds alias keys, see `flatten_with_aliases`, resolving collisions with `policy`.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be flattened (`serde_json::Value`).
+/// * `aliases` - The patterns of the aliased flat keys with their alias key, every matching alias being added.
+/// * `policy` - The handling of aliases colliding with other keys (`DuplicateKeyPolicy`).
+///
+/// # Returns
+///
+/// A Result containing the flat structure with the aliases (`serde_json::Map<String, Value>`) or an error (`errors::Error`).
+///
+pub fn flatten_with_aliases_with_policy(value: &Value, aliases: &[(PathPattern, &str)], policy: DuplicateKeyPolicy) -> Result<Map<String, Value>, errors::Error> {
+    let flat = flatten(value)?;
+    let mut aliased = FlatMap::from(flat.clone()).with_duplicate_policy(policy);
+    for (key, leaf) in flat {
+        let Ok(segments) = path::parse_flat_key(&key) else { continue };
+        for (_, alias) in aliases.iter().filter(|(pattern, _)| pattern.matches(&segments)) {
+            aliased.insert(alias.to_string(), leaf.clone())?;
+        }
+    }
+    Ok(aliased.into_map())
+}
+
+/// Options for normalizing half-flattened documents.
+#[derive(Debug, Clone)]
+pub struct NormalizeOptions {
+    /// How a path given both in flat and nested form is handled, `DuplicateKeyPolicy::Error` by default.
+    pub duplicates: DuplicateKeyPolicy,
+}
+
+impl Default for NormalizeOptions {
+    fn default() -> Self {
+        NormalizeOptions { duplicates: DuplicateKeyPolicy::Error }
+    }
+}
+
+/// Flattens a document mixing flat and nested forms (e.g. `{ "a.b": 1, "c": { "d": 2 } }`) into a fully flat map.
+///
+/// The dotted keys of the input are kept as they are, so that unflattening the result restores the fully nested
+/// document. A path given both in flat and nested form is resolved with the duplicate key policy, and paths that
+/// cannot be nested together (a value under `a` and under `a.b`, or `a[0]` and `a.b`) are rejected.
+///
+/// # Arguments
+///
+/// * `value` - The half-flattened JSON Value (`serde_json::Value`).
+/// * `opts` - The normalization options (`NormalizeOptions`).
+///
+/// # Returns
+///
+/// A Result containing the fully flat structure (`serde_json::Map<String, Value>`) or an error (`errors::Error`),
+/// `DuplicateKey` naming a conflicting path.
+///
+pub fn normalize(value: &Value, opts: &NormalizeOptions) -> Result<Map<String, Value>, errors::Error> {
+    let mut flat = FlatMap::new().with_duplicate_policy(opts.duplicates);
+    flatten_into(value, &mut flat)?;
+
+    let mut paths = flat.iter()
+        .map(|(key, _)| Ok((path::parse_flat_key(key)?, key)))
+        .collect::<Result<Vec<(Vec<PathSegment>, &String)>, errors::Error>>()?;
+    paths.sort();
+    for pair in paths.windows(2) {
+        let ((a, key), (b, _)) = (&pair[0], &pair[1]);
+        let conflict = match a.iter().zip(b).position(|(x, y)| x != y) {
+            Some(i) => matches!((&a[i], &b[i]), (PathSegment::Key(_), PathSegment::Index(_))),
+            None => true,
+        };
+        if conflict {
+            return Err(errors::Error::DuplicateKey(key.to_string()));
+        }
+    }
+
+    Ok(flat.into_map())
+}
+
+/// Options for cleaning up flat keys with `normalize_keys`.
+#[derive(Debug, Clone)]
+pub struct NormalizeKeysOptions {
+    /// Whether whitespace around keys, property names and indices is removed, `true` by default.
+    pub trim_whitespace: bool,
+    /// Whether empty segments (`a..b`, `.a`, `a.[0]`) are dropped instead of rejected, `true` by default.
+    pub collapse_separators: bool,
+}
+
+impl Default for NormalizeKeysOptions {
+    fn default() -> Self {
+        NormalizeKeysOptions { trim_whitespace: true, collapse_separators: true }
+    }
+}
+
+/// Rewrites the keys of a flat map in their canonical form, e.g. for maps written by hand in configuration files.
+///
+/// Besides the cleanups selected in `opts`, indices lose their leading zeros (`[007]` to `[7]`) and property names
+/// are quoted only when needed (`a['b']` to `a.b`). On error the map is left unchanged.
+///
+/// # Arguments
+///
+/// * `data` - The flattened JSON structure represented as a key-value map (`serde_json::Map<String, Value>`).
+/// * `opts` - The cleanups to apply (`NormalizeKeysOptions`).
+///
+/// # Returns
+///
+/// A Result containing the rewritten keys, each with its new form (`Vec<(String, String)>`), or an error
+/// (`errors::Error`): `DuplicateKey` if two keys get the same canonical form, or the error of a key that cannot
+/// be read.
+///
+pub fn normalize_keys(data: &mut Map<String, Value>, opts: &NormalizeKeysOptions) -> Result<Vec<(String, String)>, errors::Error> {
+    let mut normalized = Map::new();
+    let mut changes = Vec::new();
+    for (key, value) in data.iter() {
+        let segments = path::parse_flat_key_lenient(key, opts.trim_whitespace, opts.collapse_separators)?;
+        let new_key = path::render_flat_key(&segments);
+        if normalized.contains_key(&new_key) {
+            return Err(errors::Error::DuplicateKey(new_key));
+        }
+        if new_key != *key {
+            changes.push((key.clone(), new_key.clone()));
+        }
+        normalized.insert(new_key, value.clone());
+    }
+    *data = normalized;
+    Ok(changes)
+}
+
+impl From<Map<String, Value>> for FlatMap {
+    fn from(entries: Map<String, Value>) -> Self {
+        FlatMap { entries, duplicates: DuplicateKeyPolicy::default() }
+    }
+}
+
+/// Hashes the entries sorted by key, and the values in their canonical form with object keys sorted, so that maps
+/// holding the same entries hash alike whatever their insertion order.
+impl Hash for FlatMap {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let mut entries: Vec<(&String, &Value)> = self.entries.iter().collect();
+        entries.sort_by_key(|(key, _)| *key);
+        state.write_usize(entries.len());
+        let opts = CompareOptions::new();
+        for (key, value) in entries {
+            key.hash(state);
+            compare::canonical(value, &mut vec![], &opts).hash(state);
+        }
+        self.duplicates.hash(state);
+    }
+}
+
+impl FlatSink for FlatMap {
+    fn put(&mut self, key: &str, value: Value) -> Result<(), errors::Error> {
+        self.insert(key.to_owned(), value)
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    fn camel_case(key: &str) -> String {
+        let mut upper = false;
+        key.chars().filter_map(|c| match c {
+            '_' => {
+                upper = true;
+                None
+            }
+            c if upper => {
+                upper = false;
+                Some(c.to_ascii_uppercase())
+            }
+            c => Some(c),
+        }).collect()
+    }
+
+    #[test]
+    fn renaming_flat_keys() {
+        let mut flat = FlatMap::new();
+        flatten_into(&json!({ "user": { "first_name": "John", "last_name": "Doe" } }), &mut flat).unwrap();
+
+        let renamed = flat.rename_all(camel_case).unwrap();
+        assert_eq!(Value::Object(renamed.into_map()), json!({ "user.firstName": "John", "user.lastName": "Doe" }));
+    }
+
+    #[test]
+    fn renaming_colliding_flat_keys() {
+        let flat = FlatMap::from(json!({ "first_name": "a", "firstName": "b" }).as_object().unwrap().clone());
+
+        let cases = [
+            (DuplicateKeyPolicy::Merge, json!({ "firstName": ["a", "b"] })),
+            (DuplicateKeyPolicy::KeepFirst, json!({ "firstName": "a" })),
+            (DuplicateKeyPolicy::KeepLast, json!({ "firstName": "b" })),
+        ];
+        for (policy, expected) in cases {
+            let renamed = flat.clone().with_duplicate_policy(policy).rename_all(camel_case).unwrap();
+            assert_eq!(Value::Object(renamed.into_map()), expected);
+        }
+
+        let (_, collisions) = flat.rename_all_with_report(camel_case).unwrap();
+        assert_eq!(collisions, vec![Collision {
+            output_key: "firstName".to_owned(),
+            source_paths: vec!["first_name".to_owned(), "firstName".to_owned()],
+        }]);
+
+        let flat = flat.with_duplicate_policy(DuplicateKeyPolicy::Error);
+        assert_eq!(
+            flat.rename_all(camel_case).err().unwrap().to_string(),
+            errors::Error::DuplicateKey("firstName".to_owned()).to_string()
+        );
+    }
+
+    #[test]
+    fn flattening_with_key_map() {
+        let json = json!({ "user": { "fname": "John", "tags": ["a"] }, "name": "old" });
+        let key_map = HashMap::from([
+            ("user.fname".to_owned(), "user.first_name".to_owned()),
+            ("user.tags[0]".to_owned(), "user.tag".to_owned()),
+        ]);
+
+        let flat = flatten_key_map(&json, &key_map).unwrap();
+        assert_eq!(Value::Object(flat), json!({ "user.first_name": "John", "user.tag": "a", "name": "old" }));
+
+        let key_map = HashMap::from([("user.fname".to_owned(), "name".to_owned())]);
+        assert_eq!(
+            flatten_key_map(&json, &key_map).err().unwrap().to_string(),
+            errors::Error::DuplicateKey("name".to_owned()).to_string()
+        );
+        let flat = flatten_key_map_with_policy(&json, &key_map, DuplicateKeyPolicy::KeepFirst).unwrap();
+        assert_eq!(flat["name"], json!("John"));
+    }
+
+    #[test]
+    fn flattening_with_aliases() {
+        let json = json!({ "user": { "first_name": "John", "id": 1 }, "userId": 2 });
+        let aliases = [
+            (PathPattern::compile("user.first_name").unwrap(), "user.firstName"),
+            (PathPattern::compile("user.id").unwrap(), "userId"),
+        ];
+
+        assert_eq!(
+            flatten_with_aliases(&json, &aliases[..1]).unwrap(),
+            *json!({ "user.first_name": "John", "user.id": 1, "userId": 2, "user.firstName": "John" }).as_object().unwrap()
+        );
+        assert_eq!(
+            flatten_with_aliases(&json, &aliases).err().unwrap().to_string(),
+            errors::Error::DuplicateKey("userId".to_owned()).to_string()
+        );
+        let flat = flatten_with_aliases_with_policy(&json, &aliases, DuplicateKeyPolicy::Merge).unwrap();
+        assert_eq!(flat["userId"], json!([2, 1]));
+        assert_eq!(flat["user.id"], json!(1));
+    }
+
+    #[test]
+    fn normalizing_keys() {
+        let mut data: Map<String, Value> = serde_json::from_str(r#"{
+            " server.port ": 8080,
+            "server..host": "localhost",
+            "server.tags.[007]": "a",
+            "server['name']": "web",
+            "server . tls": true,
+            "server.id": 1
+        }"#).unwrap();
+
+        let changes = normalize_keys(&mut data, &NormalizeKeysOptions::default()).unwrap();
+        assert_eq!(changes, vec![
+            (" server.port ".to_owned(), "server.port".to_owned()),
+            ("server..host".to_owned(), "server.host".to_owned()),
+            ("server.tags.[007]".to_owned(), "server.tags[7]".to_owned()),
+            ("server['name']".to_owned(), "server.name".to_owned()),
+            ("server . tls".to_owned(), "server.tls".to_owned()),
+        ]);
+        assert_eq!(
+            Value::Object(data),
+            json!({
+                "server.port": 8080,
+                "server.host": "localhost",
+                "server.tags[7]": "a",
+                "server.name": "web",
+                "server.tls": true,
+                "server.id": 1
+            })
+        );
+
+        let mut data: Map<String, Value> = serde_json::from_str(r#"{ "a..b": 1 }"#).unwrap();
+        let opts = NormalizeKeysOptions { trim_whitespace: true, collapse_separators: false };
+        assert_eq!(normalize_keys(&mut data, &opts).err().unwrap().to_string(), errors::Error::InvalidProperty.to_string());
+
+        let mut data: Map<String, Value> = serde_json::from_str(r#"{ "a.b": 1, "a . b": 2 }"#).unwrap();
+        assert_eq!(
+            normalize_keys(&mut data, &NormalizeKeysOptions::default()).err().unwrap().to_string(),
+            errors::Error::DuplicateKey("a.b".to_owned()).to_string()
+        );
+        assert_eq!(data.len(), 2);
+    }
+
+    #[test]
+    fn normalizing_mixed_documents() {
+        let json = json!({ "a.b": 1, "c": { "d": 2, "e.f": [3] }, "c.g": null });
+
+        let flat = normalize(&json, &NormalizeOptions::default()).unwrap();
+        assert_eq!(
+            crate::unflattening::unflatten(&flat).unwrap(),
+            json!({ "a": { "b": 1 }, "c": { "d": 2, "e": { "f": [3] }, "g": null } })
+        );
+    }
+
+    #[test]
+    fn normalizing_conflicting_documents() {
+        let conflicts = [
+            (json!({ "a.b": 1, "a": { "b": 2 } }), "a.b"),
+            (json!({ "a": 1, "a.b": 2 }), "a"),
+            (json!({ "a": [1], "a.b": 2 }), "a.b"),
+        ];
+        for (json, key) in conflicts {
+            assert_eq!(
+                normalize(&json, &NormalizeOptions::default()).err().unwrap().to_string(),
+                errors::Error::DuplicateKey(key.to_owned()).to_string()
+            );
+        }
+
+        let opts = NormalizeOptions { duplicates: DuplicateKeyPolicy::KeepLast };
+        let flat = normalize(&json!({ "a.b": 1, "a": { "b": 2 } }), &opts).unwrap();
+        assert_eq!(Value::Object(flat), json!({ "a.b": 2 }));
+    }
+
+    #[test]
+    fn hashing_flat_maps() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::collections::HashSet;
+
+        let hash = |flat: &FlatMap| {
+            let mut hasher = DefaultHasher::new();
+            flat.hash(&mut hasher);
+            hasher.finish()
+        };
+        let a = FlatMap::from(json!({ "a.b": 1, "c[0]": "d", "e": null }).as_object().unwrap().clone());
+        let b = FlatMap::from(json!({ "e": null, "c[0]": "d", "a.b": 1 }).as_object().unwrap().clone());
+        let c = FlatMap::from(json!({ "a.b": 2, "c[0]": "d", "e": null }).as_object().unwrap().clone());
+
+        assert_eq!(a, b);
+        assert_eq!(hash(&a), hash(&b));
+        assert_ne!(hash(&a), hash(&c));
+        assert_eq!(HashSet::from([a, b, c]).len(), 2);
+
+        let a = FlatMap::from(json!({ "k": { "x": 1, "y": [2, { "p": 3, "q": 4 }] } }).as_object().unwrap().clone());
+        let b = FlatMap::from(json!({ "k": { "y": [2, { "q": 4, "p": 3 }], "x": 1 } }).as_object().unwrap().clone());
+        let c = FlatMap::from(json!({ "k": { "x": 1, "y": [{ "p": 3, "q": 4 }, 2] } }).as_object().unwrap().clone());
+        assert_eq!(a, b);
+        assert_eq!(hash(&a), hash(&b));
+        assert_ne!(a, c);
+        assert_ne!(hash(&a), hash(&c));
+    }
+}
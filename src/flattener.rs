@@ -0,0 +1,199 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+
+use serde_json::{Map, Value};
+use crate::errors;
+use crate::flattening::{self, FlattenOptions};
+use crate::sink::{FlatSink, FlatSource};
+use crate::unflattening::{self, UnflattenCache, UnflattenOptions};
+
+
+/// Maximum number of parsed keys remembered while unflattening a batch.
+const BATCH_CACHE_CAPACITY: usize = 4096;
+
+/// Reusable flattening and unflattening state.
+///
+/// A single `Flattener` should be reused when processing many documents in a loop.
+#[derive(Debug, Clone)]
+pub struct Flattener {
+    options: FlattenOptions,
+    unflatten_options: UnflattenOptions,
+}
+
+impl Flattener {
+    /// Creates a `Flattener` that unflattens with the options matching `options`, see `unflatten_options_for`.
+    pub fn new(options: FlattenOptions) -> Self {
+        let unflatten_options = unflatten_options_for(&options);
+        Flattener { options, unflatten_options }
+    }
+
+    /// Creates a `Flattener` with explicit options for both directions, e.g. to give the
+    /// `UnflattenOptions::single_element_arrays` hints matching `FlattenOptions::single_element_arrays_as_scalar`.
+    pub fn with_options(options: FlattenOptions, unflatten_options: UnflattenOptions) -> Self {
+        Flattener { options, unflatten_options }
+    }
+
+    pub fn options(&self) -> &FlattenOptions {
+        &self.options
+    }
+
+    pub fn unflatten_options(&self) -> &UnflattenOptions {
+        &self.unflatten_options
+    }
+
+    /// Flattens a JSON Value into a key-value map, see `flattening::flatten`.
+    pub fn flatten(&self, value: &Value) -> Result<Map<String, Value>, errors::Error> {
+        flattening::flatten_with_options(value, &self.options)
+    }
+
+    /// Flattens a JSON Value into a sink, see `flattening::flatten_into`.
+    pub fn flatten_into(&self, value: &Value, sink: &mut impl FlatSink) -> Result<(), errors::Error> {
+        flattening::flatten_into_with_options(value, &self.options, sink)
+    }
+
+    /// Flattens a JSON Value, collecting the errors of single entries, see `flattening::flatten_collect_errors`.
+    pub fn flatten_collect_errors(&self, value: &Value) -> (Map<String, Value>, Vec<(String, errors::Error)>) {
+        flattening::flatten_collect_errors_with_options(value, &self.options)
+    }
+
+    /// Unflattens a flattened JSON structure into the original JSON object, see `unflattening::unflatten`.
+    pub fn unflatten(&self, data: &Map<String, Value>) -> Result<Value, errors::Error> {
+        unflattening::unflatten_with_options(data, &self.unflatten_options)
+    }
+
+    /// Flattens a batch of JSON Values, see `flattening::flatten_many`.
+    pub fn flatten_many<'a>(&self, values: impl IntoIterator<Item = &'a Value>) -> Vec<Result<Map<String, Value>, errors::Error>> {
+        let mut capacity = 0;
+        values.into_iter().map(|value| {
+            // Documents of a batch usually have a similar number of keys.
+            let mut flat = Map::with_capacity(capacity);
+            flattening::flatten_into_with_options(value, &self.options, &mut flat)?;
+            capacity = flat.len();
+            Ok(flat)
+        }).collect()
+    }
+
+    /// Unflattens a batch of flattened JSON structures, see `unflattening::unflatten_many`.
+    pub fn unflatten_many<'a>(&self, data: impl IntoIterator<Item = &'a Map<String, Value>>) -> Vec<Result<Value, errors::Error>> {
+        let mut cache = UnflattenCache::new(BATCH_CACHE_CAPACITY).options(self.unflatten_options.clone());
+        data.into_iter().map(|flat| cache.unflatten(flat)).collect()
+    }
+
+    /// Unflattens flat key-value pairs read from a source, see `unflattening::unflatten_from`.
+    pub fn unflatten_from(&self, source: &impl FlatSource) -> Result<Value, errors::Error> {
+        unflattening::unflatten_with_options(source, &self.unflatten_options)
+    }
+}
+
+/// Returns the options reading back the flat keys written with `options`.
+///
+/// The array index offset and the handling of whitespace around property names are carried over, and quoted
+/// segments are always accepted. Arrays collapsed by `single_element_arrays_as_scalar` cannot be told apart from
+/// scalars, so their `UnflattenOptions::single_element_arrays` hints have to be added by the caller.
+///
+/// # Arguments
+///
+/// * `options` - The options the flat keys were written with (`FlattenOptions`).
+///
+/// # Returns
+///
+/// The matching options (`UnflattenOptions`).
+///
+pub fn unflatten_options_for(options: &FlattenOptions) -> UnflattenOptions {
+    UnflattenOptions::new()
+        .array_index_offset(options.array_index_offset)
+        .segment_whitespace(options.segment_whitespace)
+}
+
+impl Default for Flattener {
+    fn default() -> Self {
+        Flattener::new(FlattenOptions::default())
+    }
+}
+
+thread_local! {
+    static DEFAULT_FLATTENER: Flattener = Flattener::default();
+}
+
+/// Runs `f` with the thread-local default `Flattener` used by the free functions.
+pub(crate) fn with_default<R>(f: impl FnOnce(&Flattener) -> R) -> R {
+    DEFAULT_FLATTENER.with(f)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    #[test]
+    fn reusing_a_flattener() {
+        let flattener = Flattener::default();
+
+        for i in 0..10 {
+            let json = json!({ "id": i, "tags": [{ "name": "a" }, { "name": "b" }] });
+            let flat = flattener.flatten(&json).unwrap();
+            assert_eq!(flat, flattening::flatten(&json).unwrap());
+
+            let unflat = flattener.unflatten(&flat).unwrap();
+            assert_eq!(unflat, json);
+        }
+    }
+
+    #[test]
+    fn processing_batches() {
+        let flattener = Flattener::default();
+        let values = vec![json!({ "a": [1, 2] }), json!("b"), json!({ "a": [3], "c": null })];
+
+        let flat = flattener.flatten_many(&values);
+        assert_eq!(flat.len(), 3);
+        assert_eq!(flat[0].as_ref().unwrap(), &flattening::flatten(&values[0]).unwrap());
+        assert_eq!(flat[1].as_ref().err().unwrap().to_string(), errors::Error::NotAnObject.to_string());
+        assert_eq!(flat[2].as_ref().unwrap(), &flattening::flatten(&values[2]).unwrap());
+
+        let mut maps: Vec<Map<String, Value>> = flat.into_iter().filter_map(Result::ok).collect();
+        maps.push(json!({ "a": 1, "a.b": 2 }).as_object().unwrap().clone());
+        let unflat = flattener.unflatten_many(&maps);
+        assert_eq!(unflat[0].as_ref().unwrap(), &values[0]);
+        assert_eq!(unflat[1].as_ref().unwrap(), &values[2]);
+        assert_eq!(unflat[2].as_ref().err().unwrap().to_string(), unflattening::unflatten(&maps[2]).err().unwrap().to_string());
+    }
+
+    #[test]
+    fn roundtripping_with_options() {
+        let json = json!({ "a.b": 1, "c": { "d[0]": [1, { "e": [2, 3] }] } });
+        let flattener = Flattener::new(FlattenOptions::new().quote_keys(true));
+        assert_eq!(flattener.unflatten(&flattener.flatten(&json).unwrap()).unwrap(), json);
+
+        let flattener = Flattener::new(FlattenOptions::new().array_index_offset(1));
+        let json = json!({ "a": [1, 2, { "b": [3] }], "c": [[4]] });
+        let flat = flattener.flatten(&json).unwrap();
+        assert!(flat.contains_key("a[3].b[1]"));
+        assert_eq!(flattener.unflatten(&flat).unwrap(), json);
+        assert_eq!(flattener.unflatten_many([&flat])[0].as_ref().unwrap(), &json);
+        assert_eq!(flattener.unflatten_from(&flat).unwrap(), json);
+
+        let json = json!({ "a": [1], "b": [{ "c": ["x"] }], "d": [2, 3] });
+        let flattener = Flattener::with_options(
+            FlattenOptions::new().single_element_arrays_as_scalar(true),
+            UnflattenOptions::new().single_element_array("a").single_element_array("b").single_element_array("b.c"),
+        );
+        let flat = flattener.flatten(&json).unwrap();
+        assert_eq!(flat, *json!({ "a": 1, "b.c": "x", "d[0]": 2, "d[1]": 3 }).as_object().unwrap());
+        assert_eq!(flattener.unflatten(&flat).unwrap(), json);
+    }
+}
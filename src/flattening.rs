@@ -14,89 +14,1089 @@
 
 
 
+use std::collections::{BTreeMap, HashMap, HashSet};
+use serde::{Deserialize, Serialize};
 use serde_json::{Value, Map, json};
 use crate::errors;
+use crate::flattener;
+use crate::path::{self, PathSegment};
+use crate::pattern::PathPattern;
+use crate::progress::{CancelToken, ProgressHook, Tracker};
+use crate::sink::FlatSink;
+use crate::traverse::{self, KeyPath};
 
 
+/// Version of the options format, bumped whenever options are added.
+pub const OPTIONS_VERSION: u32 = 14;
+
+pub(crate) fn options_version() -> u32 {
+    OPTIONS_VERSION
+}
+
+/// Options controlling how JSON Values are flattened.
+///
+/// Options can be stored in configuration files: fields missing from a serialized configuration
+/// take their default value, while configurations written by a newer version are rejected by `from_value`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FlattenOptions {
+    /// Version of the options format that produced these options.
+    #[serde(default = "options_version")]
+    pub version: u32,
+    /// Whether property names containing `.`, `[` or `]` are emitted as quoted segments (`a["b.c"]`).
+    pub quote_keys: bool,
+    /// Whether arrays with exactly one element are flattened as that element (`a` instead of `a[0]`).
+    ///
+    /// The flat keys no longer tell these arrays apart, so unflattening needs the matching
+    /// `UnflattenOptions::single_element_arrays` hints.
+    pub single_element_arrays_as_scalar: bool,
+    /// How the elements of arrays are ordered in the flat keys.
+    pub array_ordering: ArrayOrdering,
+    /// Glob patterns (see `PathPattern`) of the arrays reordered by `ArrayOrdering::CanonicalSort`, matched
+    /// against the flat key of the array (e.g. `items[*].tags`). All arrays are reordered when empty.
+    pub sorted_arrays: Vec<String>,
+    /// Maximum length, in characters, of string leaves: longer strings are truncated (e.g. for logging).
+    pub max_value_length: Option<usize>,
+    /// How strings longer than `max_value_length` are replaced.
+    pub truncation: TruncationStyle,
+    /// Whether strings longer than `max_value_length` that look like binary data (base64 or hex) are
+    /// replaced without keeping a prefix.
+    pub replace_binary: bool,
+    /// Offset added to the array indices of the flat keys, e.g. 1 for 1-based indices (`a[1]` for the first element).
+    ///
+    /// Unflattening needs the same `UnflattenOptions::array_index_offset`.
+    pub array_index_offset: i64,
+    /// Whether distinct paths producing the same flat key (e.g. `{ "a.b": 1, "a": { "b": 2 } }`) fail with
+    /// `Error::DuplicateKey` instead of having their values merged into an array.
+    pub reject_collisions: bool,
+    /// Whether input that looks already flattened is rejected with `Error::AlreadyFlat`, see `assert_nested`.
+    pub reject_flat_input: bool,
+    /// The thresholds used by `reject_flat_input`.
+    pub flat_input: FlatInputThresholds,
+    /// How property names with leading or trailing whitespace are handled. Names trimmed to the same name collide.
+    pub segment_whitespace: SegmentWhitespace,
+    /// Whether empty objects and arrays are kept as leaf values (`"a": {}`) instead of being dropped.
+    pub keep_empty_containers: bool,
+    /// Whether arrays below the root are kept as leaf values (`"tags": ["a", "b"]`) instead of being flattened.
+    pub keep_arrays: bool,
+    /// Callback notified of the progress of the operation, not serialized.
+    #[serde(skip)]
+    pub progress: Option<ProgressHook>,
+    /// Flag aborting the operation with `Error::Cancelled`, not serialized.
+    ///
+    /// A sink passed to `Flattener::flatten_into` keeps the complete entries it received before the cancellation.
+    #[serde(skip)]
+    pub cancel: Option<CancelToken>,
+}
+
+/// Order of the array elements in the flat keys.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArrayOrdering {
+    /// Elements keep their position in the array.
+    #[default]
+    Preserve,
+    /// Elements are sorted by their canonical encoding, so that arrays with set semantics holding the same
+    /// elements produce identical flat maps.
+    ///
+    /// Scalars are compared by their JSON text (with integral floats written as integers) and containers by the
+    /// same encoding applied to their sorted entries. The original order is lost: unflattening returns the sorted
+    /// arrays.
+    CanonicalSort,
+}
+
+/// Handling of leading and trailing whitespace in property names (e.g. `" user "` from spreadsheet headers).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SegmentWhitespace {
+    /// Property names are kept as they are.
+    #[default]
+    Preserve,
+    /// Whitespace around property names is removed. Quoted segments of flat keys (`[" a "]`) keep their spaces.
+    /// Names trimmed to the same path are collisions, their values are merged into an array.
+    Trim,
+    /// Property names with whitespace around them are rejected with `Error::InvalidKeyName`. Quoted segments of
+    /// flat keys are accepted.
+    Error,
+}
+
+/// Replacement of the strings longer than `FlattenOptions::max_value_length`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TruncationStyle {
+    /// A string made of the prefix followed by the original length, e.g. `"aGVs… (2097152 chars)"`.
+    #[default]
+    Text,
+    /// An object leaf, e.g. `{ "$truncated": true, "len": 2097152, "prefix": "aGVs" }`.
+    ///
+    /// Unflattening such leaves requires `LeafPolicy::Lenient`.
+    Object,
+}
+
+/// Thresholds above which an object is considered already flattened, see `assert_nested`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FlatInputThresholds {
+    /// Minimum number of keys written in flat key syntax (`a.b`, `a[0]`).
+    pub min_keys: usize,
+    /// Minimum share of the keys written in flat key syntax, between 0 and 1.
+    pub min_ratio: f64,
+}
+
+impl Default for FlatInputThresholds {
+    fn default() -> Self {
+        FlatInputThresholds { min_keys: 1, min_ratio: 0.5 }
+    }
+}
+
+/// Changes made while flattening, collected by `flatten_with_report`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FlattenReport {
+    /// Number of strings truncated because of `FlattenOptions::max_value_length`.
+    pub truncated: usize,
+    /// Number of those strings replaced entirely because they looked like binary data.
+    pub replaced_binary: usize,
+    /// Flat keys produced by more than one path, whose values were merged into an array.
+    pub collisions: Vec<Collision>,
+}
+
+/// Output key produced by several source paths.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Collision {
+    pub output_key: String,
+    /// The source paths, in the order they were visited.
+    pub source_paths: Vec<String>,
+}
+
+/// Source paths of each output key, used to find collisions.
+#[derive(Debug, Default)]
+pub(crate) struct CollisionTracker {
+    index: HashMap<String, usize>,
+    keys: Vec<Collision>,
+}
+
+impl CollisionTracker {
+    /// Records that `source_path` produced `output_key`, returning whether the key was already produced.
+    pub(crate) fn record(&mut self, output_key: &str, source_path: String) -> bool {
+        match self.index.get(output_key) {
+            Some(i) => {
+                self.keys[*i].source_paths.push(source_path);
+                true
+            }
+            None => {
+                self.index.insert(output_key.to_owned(), self.keys.len());
+                self.keys.push(Collision { output_key: output_key.to_owned(), source_paths: vec![source_path] });
+                false
+            }
+        }
+    }
+
+    pub(crate) fn into_collisions(self) -> Vec<Collision> {
+        self.keys.into_iter().filter(|key| key.source_paths.len() > 1).collect()
+    }
+}
+
+impl Default for FlattenOptions {
+    fn default() -> Self {
+        FlattenOptions {
+            version: OPTIONS_VERSION,
+            quote_keys: false,
+            single_element_arrays_as_scalar: false,
+            array_ordering: ArrayOrdering::default(),
+            sorted_arrays: vec![],
+            max_value_length: None,
+            truncation: TruncationStyle::default(),
+            replace_binary: false,
+            reject_collisions: false,
+            array_index_offset: 0,
+            reject_flat_input: false,
+            flat_input: FlatInputThresholds::default(),
+            segment_whitespace: SegmentWhitespace::default(),
+            keep_empty_containers: false,
+            keep_arrays: false,
+            progress: None,
+            cancel: None,
+        }
+    }
+}
+
+impl FlattenOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets whether property names that cannot be written plainly are emitted as quoted segments,
+    /// so that unflattening restores them instead of splitting them.
+    pub fn quote_keys(mut self, quote_keys: bool) -> Self {
+        self.quote_keys = quote_keys;
+        self
+    }
+
+    /// Sets whether arrays with exactly one element are flattened as that element.
+    pub fn single_element_arrays_as_scalar(mut self, single_element_arrays_as_scalar: bool) -> Self {
+        self.single_element_arrays_as_scalar = single_element_arrays_as_scalar;
+        self
+    }
+
+    /// Sets how the elements of arrays are ordered.
+    pub fn array_ordering(mut self, array_ordering: ArrayOrdering) -> Self {
+        self.array_ordering = array_ordering;
+        self
+    }
+
+    /// Restricts `ArrayOrdering::CanonicalSort` to the arrays matching a glob pattern, can be called repeatedly.
+    pub fn sorted_array(mut self, pattern: &str) -> Self {
+        self.sorted_arrays.push(pattern.to_owned());
+        self
+    }
+
+    /// Sets the maximum length, in characters, of string leaves.
+    pub fn max_value_length(mut self, max_value_length: usize) -> Self {
+        self.max_value_length = Some(max_value_length);
+        self
+    }
+
+    /// Sets how strings longer than the maximum length are replaced.
+    pub fn truncation(mut self, truncation: TruncationStyle) -> Self {
+        self.truncation = truncation;
+        self
+    }
+
+    /// Sets whether long strings that look like binary data are replaced without keeping a prefix.
+    pub fn replace_binary(mut self, replace_binary: bool) -> Self {
+        self.replace_binary = replace_binary;
+        self
+    }
+
+    /// Sets whether distinct paths producing the same flat key are rejected.
+    pub fn reject_collisions(mut self, reject_collisions: bool) -> Self {
+        self.reject_collisions = reject_collisions;
+        self
+    }
+
+    /// Sets the offset added to the array indices of the flat keys.
+    pub fn array_index_offset(mut self, array_index_offset: i64) -> Self {
+        self.array_index_offset = array_index_offset;
+        self
+    }
+
+    /// Sets whether input that looks already flattened is rejected.
+    pub fn reject_flat_input(mut self, reject_flat_input: bool) -> Self {
+        self.reject_flat_input = reject_flat_input;
+        self
+    }
+
+    /// Sets how property names with leading or trailing whitespace are handled.
+    pub fn segment_whitespace(mut self, segment_whitespace: SegmentWhitespace) -> Self {
+        self.segment_whitespace = segment_whitespace;
+        self
+    }
+
+    /// Sets whether empty objects and arrays are kept as leaf values.
+    pub fn keep_empty_containers(mut self, keep_empty_containers: bool) -> Self {
+        self.keep_empty_containers = keep_empty_containers;
+        self
+    }
+
+    /// Sets whether arrays below the root are kept as leaf values.
+    pub fn keep_arrays(mut self, keep_arrays: bool) -> Self {
+        self.keep_arrays = keep_arrays;
+        self
+    }
+
+    /// Sets the callback notified of the progress of the operation.
+    pub fn progress(mut self, progress: ProgressHook) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Sets the flag that cancels the operation.
+    pub fn cancel(mut self, cancel: CancelToken) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// Loads options from their serialized JSON form.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The serialized options (`serde_json::Value`).
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the options (`FlattenOptions`) or an error (`errors::Error`).
+    ///
+    pub fn from_value(value: &Value) -> Result<Self, errors::Error> {
+        let options: Self = serde_json::from_value(value.clone()).map_err(|_| errors::Error::FormatError)?;
+        check_options_version(options.version)?;
+        Ok(options)
+    }
+}
+
+pub(crate) fn check_options_version(version: u32) -> Result<(), errors::Error> {
+    if version > OPTIONS_VERSION {
+        return Err(errors::Error::UnsupportedOptionsVersion(version));
+    }
+    Ok(())
+}
+
 /// Flattens a JSON Value into a key-value map.
 ///
+/// The root can be an object or an array, in which case the flat keys start with an index (`[0].a`).
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be flattened (`serde_json::Value`).
+///
+/// # Returns
+///
+/// A Result containing a flattened JSON structure (`serde_json::Map<String, Value>`) or an error (`errors::Error`).
+///
+pub fn flatten(value: &Value) -> Result<Map<String, Value>, errors::Error> {
+    flattener::with_default(|flattener| flattener.flatten(value))
+}
+
+/// Flattens a batch of JSON Values, sharing buffers and state across the batch.
+///
+/// # Arguments
+///
+/// * `values` - The JSON Values to be flattened (`serde_json::Value`).
+///
+/// # Returns
+///
+/// The result of flattening each value, in order, identical to calling `flatten` on each of them.
+///
+pub fn flatten_many(values: &[Value]) -> Vec<Result<Map<String, Value>, errors::Error>> {
+    flattener::with_default(|flattener| flattener.flatten_many(values))
+}
+
+/// Flattens a JSON Value into a key-value map, collecting the errors of single entries instead of failing.
+///
+/// An error that prevents flattening the whole document is collected under the empty key.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be flattened (`serde_json::Value`).
+///
+/// # Returns
+///
+/// The flattened JSON structure (`serde_json::Map<String, Value>`) of the entries that could be flattened, with the
+/// flat keys of the other entries and their errors (`errors::Error`).
+///
+pub fn flatten_collect_errors(value: &Value) -> (Map<String, Value>, Vec<(String, errors::Error)>) {
+    flattener::with_default(|flattener| flattener.flatten_collect_errors(value))
+}
+
+pub(crate) fn flatten_with_options(value: &Value, options: &FlattenOptions) -> Result<Map<String, Value>, errors::Error> {
+    let mut flattened_json = Map::<String, Value>::new();
+    flatten_into_with_options(value, options, &mut flattened_json)?;
+    Ok(flattened_json)
+}
+
+/// Flattens a JSON Value into a key-value map, adding an offset to the array indices (e.g. `a[1]` for the first
+/// element with an offset of 1).
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be flattened (`serde_json::Value`).
+/// * `array_index_offset` - The offset added to every array index.
+///
+/// # Returns
+///
+/// A Result containing a flattened JSON structure (`serde_json::Map<String, Value>`) or an error (`errors::Error`),
+/// `InvalidProperty` if an offset index is negative.
+///
+pub fn flatten_with_index_offset(value: &Value, array_index_offset: i64) -> Result<Map<String, Value>, errors::Error> {
+    flatten_with_options(value, &FlattenOptions::new().array_index_offset(array_index_offset))
+}
+
+/// Flattens a JSON Value, passing each flat key-value pair to a sink instead of collecting them in a map.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be flattened (`serde_json::Value`).
+/// * `sink` - The destination of the flat key-value pairs (`FlatSink`).
+///
+/// # Returns
+///
+/// A Result that is an error (`errors::Error`) if the value cannot be flattened or the sink fails.
+///
+pub fn flatten_into(value: &Value, sink: &mut impl FlatSink) -> Result<(), errors::Error> {
+    flattener::with_default(|flattener| flattener.flatten_into(value, sink))
+}
+
+/// Flattens a JSON Value into a key-value map, reporting the changes made to the leaves.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be flattened (`serde_json::Value`).
+/// * `options` - The options controlling the flattening (`FlattenOptions`).
+///
+/// # Returns
+///
+/// A Result containing a flattened JSON structure (`serde_json::Map<String, Value>`) with the changes made
+/// (`FlattenReport`), or an error (`errors::Error`).
+///
+pub fn flatten_with_report(value: &Value, options: &FlattenOptions) -> Result<(Map<String, Value>, FlattenReport), errors::Error> {
+    let mut flattened_json = Map::<String, Value>::new();
+    let report = flatten_reporting(value, options, &mut flattened_json, true, None)?;
+    Ok((flattened_json, report))
+}
+
+pub(crate) fn flatten_into_with_options(value: &Value, options: &FlattenOptions, sink: &mut impl FlatSink) -> Result<(), errors::Error> {
+    flatten_reporting(value, options, sink, false, None).map(|_| ())
+}
+
+pub(crate) fn flatten_collect_errors_with_options(value: &Value, options: &FlattenOptions) -> (Map<String, Value>, Vec<(String, errors::Error)>) {
+    let mut flattened_json = Map::<String, Value>::new();
+    let mut collected = Vec::new();
+    if let Err(e) = flatten_reporting(value, options, &mut flattened_json, false, Some(&mut collected)) {
+        collected.push((String::new(), e));
+    }
+    (flattened_json, collected)
+}
+
+fn flatten_reporting(value: &Value, options: &FlattenOptions, sink: &mut impl FlatSink, report: bool, collected: Option<&mut Vec<(String, errors::Error)>>) -> Result<FlattenReport, errors::Error> {
+    if options.reject_flat_input {
+        assert_nested(value, &options.flat_input)?;
+    }
+    let mut ctx = Context {
+        quote_keys: options.quote_keys,
+        single_element_arrays_as_scalar: options.single_element_arrays_as_scalar,
+        max_value_length: options.max_value_length,
+        truncation: options.truncation,
+        replace_binary: options.replace_binary,
+        reject_collisions: options.reject_collisions,
+        array_index_offset: options.array_index_offset,
+        segment_whitespace: options.segment_whitespace,
+        keep_empty_containers: options.keep_empty_containers,
+        keep_arrays: options.keep_arrays,
+        sources: (report || options.reject_collisions).then(CollisionTracker::default),
+        tracker: Tracker::new(options.progress.as_ref(), options.cancel.as_ref()),
+        collected,
+        ..Default::default()
+    };
+    if options.array_ordering == ArrayOrdering::CanonicalSort {
+        ctx.sorted_arrays = Some(options.sorted_arrays.iter()
+            .map(|pattern| PathPattern::compile(pattern))
+            .collect::<Result<Vec<PathPattern>, errors::Error>>()?);
+    }
+
+    match value {
+        Value::Object(map) => flatten_object(sink, None, map, &mut ctx),
+        Value::Array(array) => flatten_array(sink, "", array, &mut ctx),
+        _ => Err(errors::Error::NotAnObject),
+    }?;
+    ctx.tracker.finish();
+    if let Some(sources) = ctx.sources {
+        ctx.report.collisions = sources.into_collisions();
+    }
+    Ok(ctx.report)
+}
+
+/// Checks that a JSON Value does not look like the output of `flatten`, to catch accidental double flattening.
+///
+/// An object is considered flat when none of its values is a container while enough of its keys are written in
+/// flat key syntax (`a.b`, `a[0]`). Documents without such keys, or with nested containers, always pass.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be checked (`serde_json::Value`).
+/// * `thresholds` - The thresholds above which the value is considered flat (`FlatInputThresholds`).
+///
+/// # Returns
+///
+/// A Result that is an error (`errors::Error::AlreadyFlat`) naming a flat key when the value looks flattened.
+///
+pub fn assert_nested(value: &Value, thresholds: &FlatInputThresholds) -> Result<(), errors::Error> {
+    let Value::Object(map) = value else { return Ok(()) };
+    if map.values().any(|v| v.is_object() || v.is_array()) {
+        return Ok(());
+    }
+
+    let flat_keys: Vec<&String> = map.keys()
+        .filter(|key| path::parse_flat_key(key).is_ok_and(|segments| {
+            segments.len() > 1 || segments.iter().any(|segment| matches!(segment, PathSegment::Index(_)))
+        }))
+        .collect();
+    let ratio = flat_keys.len() as f64 / map.len().max(1) as f64;
+    match flat_keys.first() {
+        Some(key) if flat_keys.len() >= thresholds.min_keys && ratio >= thresholds.min_ratio => {
+            Err(errors::Error::AlreadyFlat(key.to_string()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Flattens a JSON Value, keeping only the leaves whose flat key matches a pattern.
+///
+/// Subtrees that the pattern cannot match are not visited at all. Like `flatten`, an array root gives keys starting
+/// with an index (`[0].id`).
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be flattened (`serde_json::Value`).
+/// * `pattern` - The compiled glob pattern the flat keys have to match (`PathPattern`).
+///
+/// # Returns
+///
+/// A Result containing a flattened JSON structure (`serde_json::Map<String, Value>`) or an error (`errors::Error`).
+///
+pub fn flatten_keys_matching(value: &Value, pattern: &PathPattern) -> Result<Map<String, Value>, errors::Error> {
+    if !value.is_object() && !value.is_array() {
+        return Err(errors::Error::NotAnObject);
+    }
+    let mut flattened_json = Map::<String, Value>::new();
+    for (path, leaf) in traverse::leaves_matching(value, pattern) {
+        flattened_json.put(&path.to_flat_key(), leaf.clone())?;
+    }
+    Ok(flattened_json)
+}
+
+/// Returns the leaf values found under a path, in document order, i.e. the values of the flat keys starting with
+/// `path_prefix` (`user.tags` gives the values of `user.tags[*]`).
+///
+/// The prefix is matched segment by segment: `user.tag` does not select `user.tags[0]`.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be projected (`serde_json::Value`).
+/// * `path_prefix` - The flat key of the subtree, the empty key selecting the whole document.
+///
+/// # Returns
+///
+/// A Result containing the leaf values (`Vec<serde_json::Value>`), empty if nothing is found under the path, or an
+/// error (`errors::Error`) if the value is not an object or an array or the path is not a valid flat key.
+///
+pub fn flatten_values_at_path(value: &Value, path_prefix: &str) -> Result<Vec<Value>, errors::Error> {
+    if !value.is_object() && !value.is_array() {
+        return Err(errors::Error::NotAnObject);
+    }
+    let segments = path::parse_flat_key(path_prefix)?;
+    Ok(path::lookup(value, &segments)
+        .map(|subtree| traverse::leaves(subtree).map(|(_, leaf)| leaf.clone()).collect())
+        .unwrap_or_default())
+}
+
+/// Returns the property names of the object at a path, in document order.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be inspected (`serde_json::Value`).
+/// * `path` - The flat key of the object, the empty key selecting the root.
+///
+/// # Returns
+///
+/// A Result containing the property names (`Vec<String>`) or an error (`errors::Error`): `PathNotFound` if nothing
+/// is found at the path, `NotAnObject` if something other than an object is.
+///
+pub fn flatten_object_keys_at_path(value: &Value, path: &str) -> Result<Vec<String>, errors::Error> {
+    let segments = path::parse_flat_key(path)?;
+    path::lookup(value, &segments)
+        .ok_or_else(|| errors::Error::PathNotFound(path.to_owned()))?
+        .as_object()
+        .map(|object| object.keys().cloned().collect())
+        .ok_or(errors::Error::NotAnObject)
+}
+
+/// Returns the length of the array at a path.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be inspected (`serde_json::Value`).
+/// * `path` - The flat key of the array, the empty key selecting the root.
+///
+/// # Returns
+///
+/// A Result containing the length of the array (`usize`) or an error (`errors::Error`): `PathNotFound` if nothing
+/// is found at the path, `InvalidType` if something other than an array is.
+///
+pub fn flatten_array_length_at_path(value: &Value, path: &str) -> Result<usize, errors::Error> {
+    let segments = path::parse_flat_key(path)?;
+    path::lookup(value, &segments)
+        .ok_or_else(|| errors::Error::PathNotFound(path.to_owned()))?
+        .as_array()
+        .map(Vec::len)
+        .ok_or(errors::Error::InvalidType)
+}
+
+/// What `flatten_conditional` does with a node of the document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlattenAction {
+    /// Flattens the node as `flatten` would, visiting its children.
+    Flatten,
+    /// Stores the node as a single value under its flat key, without visiting its children.
+    AsLeaf,
+    /// Omits the node and all of its descendants.
+    Skip,
+}
+
+/// Flattens a JSON Value, letting a condition decide, for each node, whether it is flattened, kept whole or
+/// skipped.
+///
+/// The condition is called with the flat key and the value of every node below the root, before its children.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be flattened (`serde_json::Value`).
+/// * `condition` - The function deciding the action for a node (`FlattenAction`) from its flat key and value.
+///
+/// # Returns
+///
+/// A Result containing a flattened JSON structure (`serde_json::Map<String, Value>`) or an error (`errors::Error`).
+///
+pub fn flatten_conditional(value: &Value, condition: impl Fn(&str, &Value) -> FlattenAction) -> Result<Map<String, Value>, errors::Error> {
+    if !value.is_object() && !value.is_array() {
+        return Err(errors::Error::NotAnObject);
+    }
+    let mut flattened_json = Map::<String, Value>::new();
+    flatten_node_conditional(&mut flattened_json, "", value, &condition)?;
+    Ok(flattened_json)
+}
+
+fn flatten_node_conditional(result: &mut Map<String, Value>, property: &str, value: &Value, condition: &impl Fn(&str, &Value) -> FlattenAction) -> Result<(), errors::Error> {
+    match value {
+        Value::Object(map) => {
+            for (prop, value) in map {
+                let key = if property.is_empty() { prop.clone() } else { format!("{}.{}", property, prop) };
+                flatten_child_conditional(result, &key, value, condition)?;
+            }
+        }
+        Value::Array(array) => {
+            for (i, value) in array.iter().enumerate() {
+                flatten_child_conditional(result, &format!("{}[{}]", property, i), value, condition)?;
+            }
+        }
+        _ => result.put(property, value.clone())?,
+    }
+    Ok(())
+}
+
+fn flatten_child_conditional(result: &mut Map<String, Value>, property: &str, value: &Value, condition: &impl Fn(&str, &Value) -> FlattenAction) -> Result<(), errors::Error> {
+    match condition(property, value) {
+        FlattenAction::Flatten => flatten_node_conditional(result, property, value, condition),
+        FlattenAction::AsLeaf => result.put(property, value.clone()),
+        FlattenAction::Skip => Ok(()),
+    }
+}
+
+/// Extracts the arrays of a JSON Value, keyed by their flat path, without flattening their elements.
+///
+/// Arrays nested in arrays are extracted too, with bracket-qualified keys (`matrix[0]`), as well as
+/// arrays found in the objects of an array (`items[0].tags`). The root array, if any, has the empty key.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value whose arrays are extracted (`serde_json::Value`).
+///
+/// # Returns
+///
+/// A Result containing the arrays by flat key (`BTreeMap<String, Vec<Value>>`) or an error (`errors::Error`).
+///
+pub fn flatten_extract_arrays(value: &Value) -> Result<BTreeMap<String, Vec<Value>>, errors::Error> {
+    if !value.is_object() && !value.is_array() {
+        return Err(errors::Error::NotAnObject);
+    }
+    let mut arrays = BTreeMap::new();
+    extract_arrays(value, "", &mut arrays);
+    Ok(arrays)
+}
+
+fn extract_arrays(value: &Value, property: &str, arrays: &mut BTreeMap<String, Vec<Value>>) {
+    match value {
+        Value::Object(map) => {
+            for (prop, value) in map {
+                let key = if property.is_empty() { prop.clone() } else { format!("{}.{}", property, prop) };
+                extract_arrays(value, &key, arrays);
+            }
+        }
+        Value::Array(array) => {
+            arrays.insert(property.to_owned(), array.clone());
+            for (i, value) in array.iter().enumerate() {
+                extract_arrays(value, &format!("{}[{}]", property, i), arrays);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Flattens a JSON Value into a multi-valued map, dropping the array indices from the flat keys so that the leaves
+/// of all the elements of an array are collected under the same key (`a.b[0]` and `a.b[1]` under `a.b`).
+///
+/// Every key maps to a list of values, in document order, with a single value for leaves outside arrays.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be flattened (`serde_json::Value`).
+///
+/// # Returns
+///
+/// A Result containing the values by flat key, in order of first appearance (`MultiValueMap`), or an error
+/// (`errors::Error`).
+///
+pub fn flatten_without_array_indices(value: &Value) -> Result<MultiValueMap, errors::Error> {
+    if !value.is_object() && !value.is_array() {
+        return Err(errors::Error::NotAnObject);
+    }
+    let mut multi_valued = MultiValueMap::new();
+    let mut positions = HashMap::<String, usize>::new();
+    for (path, leaf) in traverse::leaves(value) {
+        let key = KeyPath::from(path.segments().iter()
+            .filter(|segment| matches!(segment, PathSegment::Key(_)))
+            .cloned()
+            .collect::<Vec<PathSegment>>());
+        let key = key.to_flat_key();
+        match positions.get(&key) {
+            Some(&i) => multi_valued[i].1.push(leaf.clone()),
+            None => {
+                positions.insert(key.clone(), multi_valued.len());
+                multi_valued.push((key, vec![leaf.clone()]));
+            }
+        }
+    }
+    Ok(multi_valued)
+}
+
+/// Flattens a JSON Value into a canonical JSON string, suitable as a fingerprint or cache key.
+///
+/// Keys are sorted, no whitespace is emitted and numbers use their shortest form, floats with an integral value
+/// being written as integers, so that logically identical documents produce identical strings.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be flattened (`serde_json::Value`).
+///
+/// # Returns
+///
+/// A Result containing the canonical string (`String`) or an error (`errors::Error`).
+///
+pub fn flatten_canonicalize(value: &Value) -> Result<String, errors::Error> {
+    let sorted: BTreeMap<String, Value> = flatten(value)?.into_iter()
+        .map(|(key, leaf)| (key, canonical_number(leaf)))
+        .collect();
+    serde_json::to_string(&sorted).map_err(|_| errors::Error::FormatError)
+}
+
+fn canonical_number(value: Value) -> Value {
+    match value {
+        // Integral floats below 2^53 are exactly representable as integers.
+        Value::Number(n) => match n.as_f64() {
+            Some(f) if n.is_f64() && f.fract() == 0.0 && f.abs() < 9007199254740992.0 => Value::from(f as i64),
+            _ => Value::Number(n),
+        },
+        // Values merged under a duplicate key.
+        Value::Array(array) => Value::Array(array.into_iter().map(canonical_number).collect()),
+        other => other,
+    }
+}
+
+/// Whether a string looks like base64 or hex encoded data rather than text.
+fn looks_binary(s: &str) -> bool {
+    s.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'+' | b'/' | b'=' | b'-' | b'_'))
+}
+
+fn canonical_sort_key(value: &Value) -> String {
+    match value {
+        Value::Array(array) => {
+            let mut keys: Vec<String> = array.iter().map(canonical_sort_key).collect();
+            keys.sort();
+            format!("[{}]", keys.join(","))
+        }
+        Value::Object(map) => {
+            let mut entries: Vec<(&String, &Value)> = map.iter().collect();
+            entries.sort_by_key(|(key, _)| *key);
+            let entries: Vec<String> = entries.into_iter()
+                .map(|(key, value)| format!("{}:{}", Value::from(key.as_str()), canonical_sort_key(value)))
+                .collect();
+            format!("{{{}}}", entries.join(","))
+        }
+        _ => canonical_number(value.clone()).to_string(),
+    }
+}
+
+/// Flat keys without array indices with all their values, in document order.
+pub type MultiValueMap = Vec<(String, Vec<Value>)>;
+
+/// Mapping from sanitized column names to the original flat keys.
+pub type KeyMapping = BTreeMap<String, String>;
+
+/// Flat structure with sanitized keys, with the mapping to the original keys and the collisions found.
+pub type SanitizedOutput = (Map<String, Value>, KeyMapping, Vec<Collision>);
+
+/// Options for sanitizing flat keys into column names.
+#[derive(Debug, Clone)]
+pub struct SanitizeOptions {
+    /// Character used in place of `.`, `[`, `]` and any other character that is not ASCII alphanumeric or `_`.
+    pub replacement: char,
+    /// Maximum length of a column name, in characters.
+    pub max_length: Option<usize>,
+    /// Whether colliding column names fail with `Error::DuplicateKey` instead of being disambiguated.
+    pub reject_collisions: bool,
+}
+
+impl Default for SanitizeOptions {
+    fn default() -> Self {
+        SanitizeOptions { replacement: '_', max_length: None, reject_collisions: false }
+    }
+}
+
+/// Flattens a JSON Value into a key-value map whose keys are sanitized to be used as column names.
+///
+/// Colliding column names (e.g. after truncation) are disambiguated with numeric suffixes (`_1`, `_2`, ...),
+/// assigned in the order the keys are produced, so the same input always yields the same names.
+///
 /// # Arguments
 ///
 /// * `value` - The JSON Value to be flattened (`serde_json::Value`).
+/// * `opts` - The sanitization options (`SanitizeOptions`).
 ///
 /// # Returns
 ///
-/// A Result containing a flattened JSON structure (`serde_json::Map<String, Value>`) or an error (`errors::Error`).
+/// A Result containing the flattened JSON structure with sanitized keys and the mapping from sanitized to original keys
+/// (`KeyMapping`), or an error (`errors::Error`).
 ///
-pub fn flatten(value: &Value) -> Result<Map<String, Value>, errors::Error> {
-    let mut flattened_json = Map::<String, Value>::new();
+pub fn flatten_sanitized(value: &Value, opts: &SanitizeOptions) -> Result<(Map<String, Value>, KeyMapping), errors::Error> {
+    flatten_sanitized_with_report(value, opts).map(|(sanitized_json, mapping, _)| (sanitized_json, mapping))
+}
 
-    match value {
-        Value::Object(map) => {
-            if map.is_empty() {
-                return Ok(flattened_json);
+/// Flattens a JSON Value with sanitized keys, see `flatten_sanitized`, reporting the column names that collided.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be flattened (`serde_json::Value`).
+/// * `opts` - The sanitization options (`SanitizeOptions`).
+///
+/// # Returns
+///
+/// A Result containing the flattened JSON structure with sanitized keys, the mapping from sanitized to original keys
+/// and the collisions, each naming the column before disambiguation (`SanitizedOutput`), or an error (`errors::Error`).
+///
+pub fn flatten_sanitized_with_report(value: &Value, opts: &SanitizeOptions) -> Result<SanitizedOutput, errors::Error> {
+    let flattened_json = flatten(value)?;
+    let mut sanitized_json = Map::new();
+    let mut mapping = KeyMapping::new();
+    let mut used = HashSet::new();
+    let mut sources = CollisionTracker::default();
+
+    for (key, value) in flattened_json {
+        let candidate = sanitize_base(&key, opts, opts.max_length);
+        if sources.record(&candidate, key.clone()) && opts.reject_collisions {
+            return Err(errors::Error::DuplicateKey(candidate));
+        }
+        let sanitized = sanitize_key(&key, opts, &used);
+        used.insert(sanitized.clone());
+        sanitized_json.insert(sanitized.clone(), value);
+        mapping.insert(sanitized, key);
+    }
+
+    Ok((sanitized_json, mapping, sources.into_collisions()))
+}
+
+fn sanitize_base(key: &str, opts: &SanitizeOptions, len: Option<usize>) -> String {
+    let base = key.chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { opts.replacement });
+    match len {
+        Some(len) => base.take(len).collect(),
+        None => base.collect(),
+    }
+}
+
+fn sanitize_key(key: &str, opts: &SanitizeOptions, used: &HashSet<String>) -> String {
+    let candidate = sanitize_base(key, opts, opts.max_length);
+    if !used.contains(&candidate) {
+        return candidate;
+    }
+
+    (1..).map(|n| {
+        let suffix = format!("{}{}", opts.replacement, n);
+        let len = opts.max_length.map(|max| max.saturating_sub(suffix.chars().count()));
+        format!("{}{}", sanitize_base(key, opts, len), suffix)
+    }).find(|candidate| !used.contains(candidate)).unwrap()
+}
+
+#[derive(Default)]
+struct Context<'a> {
+    path: Vec<PathSegment>,
+    quote_keys: bool,
+    single_element_arrays_as_scalar: bool,
+    /// Patterns of the arrays to sort, all of them when empty, or `None` when arrays are not sorted.
+    sorted_arrays: Option<Vec<PathPattern>>,
+    max_value_length: Option<usize>,
+    truncation: TruncationStyle,
+    replace_binary: bool,
+    reject_collisions: bool,
+    array_index_offset: i64,
+    segment_whitespace: SegmentWhitespace,
+    keep_empty_containers: bool,
+    keep_arrays: bool,
+    /// Source paths of the flat keys, tracked to report or reject collisions.
+    sources: Option<CollisionTracker>,
+    tracker: Tracker<'a>,
+    report: FlattenReport,
+    /// Errors of single entries, collected instead of failing when set.
+    collected: Option<&'a mut Vec<(String, errors::Error)>>,
+}
+
+impl Context<'_> {
+    /// Enters a child node, tracking its path when the source paths of the flat keys are recorded.
+    fn enter(&mut self, segment: impl FnOnce() -> PathSegment) {
+        if self.sources.is_some() {
+            self.path.push(segment());
+        }
+    }
+
+    /// Records the source path of a flat key, failing if it collides and collisions are rejected.
+    fn record(&mut self, property: &str) -> Result<(), errors::Error> {
+        let Some(sources) = self.sources.as_mut() else { return Ok(()) };
+        if sources.record(property, path::render_flat_key(&self.path)) && self.reject_collisions {
+            return Err(errors::Error::DuplicateKey(property.to_owned()));
+        }
+        Ok(())
+    }
+
+    fn leave(&mut self) {
+        self.path.pop();
+    }
+
+    /// Collects the error of an entry when errors are collected, otherwise returns it.
+    fn recover(&mut self, property: &str, result: Result<(), errors::Error>) -> Result<(), errors::Error> {
+        match (result, self.collected.as_mut()) {
+            (Err(errors::Error::Cancelled), _) => Err(errors::Error::Cancelled),
+            (Err(e), Some(collected)) => {
+                collected.push((property.to_owned(), e));
+                Ok(())
             }
-            flatten_object(&mut flattened_json, None, map)?;
+            (result, _) => result,
+        }
+    }
+
+    /// Returns whether a container is stored as a leaf value instead of being flattened.
+    fn keeps_container(&self, value: &Value) -> bool {
+        (self.keep_empty_containers && is_empty_container(value)) || (self.keep_arrays && value.is_array())
+    }
+
+    /// Returns the order in which the elements of an array are visited, when they are sorted.
+    fn sorted_order(&self, property: &str, array: &[Value]) -> Option<Vec<usize>> {
+        let patterns = self.sorted_arrays.as_ref()?;
+        if !patterns.is_empty() {
+            let segments = path::parse_flat_key(property).ok()?;
+            if !patterns.iter().any(|pattern| pattern.matches(&segments)) {
+                return None;
+            }
+        }
+        let mut order: Vec<usize> = (0..array.len()).collect();
+        order.sort_by_cached_key(|i| canonical_sort_key(&array[*i]));
+        Some(order)
+    }
+
+    /// Replaces a string leaf longer than the maximum length.
+    fn summarize(&mut self, val: Value) -> Value {
+        let (Some(max), Value::String(s)) = (self.max_value_length, &val) else { return val };
+        let len = s.chars().count();
+        if len <= max {
+            return val;
+        }
+
+        self.report.truncated += 1;
+        let prefix: String = if self.replace_binary && looks_binary(s) {
+            self.report.replaced_binary += 1;
+            String::new()
+        } else {
+            s.chars().take(max).collect()
+        };
+        match self.truncation {
+            TruncationStyle::Text => Value::String(format!("{}… ({} chars)", prefix, len)),
+            TruncationStyle::Object => json!({ "$truncated": true, "len": len, "prefix": prefix }),
         }
-        _ => return Err(errors::Error::NotAnObject),
     }
-    
-    
-    Ok(flattened_json)
 }
 
-fn flatten_object(result: &mut Map<String, Value>, property: Option<&str>, nested_json: &Map<String, Value>) -> Result<(), errors::Error>{
-    for (prop, value) in nested_json {
-        let flattened_prop = property.map_or_else(|| prop.clone(), |parent_key| format!("{}.{}", parent_key, prop));
+fn flatten_object(result: &mut impl FlatSink, property: Option<&str>, nested_json: &Map<String, Value>, ctx: &mut Context) -> Result<(), errors::Error>{
+    for (raw_prop, value) in nested_json {
+        ctx.enter(|| PathSegment::Key(raw_prop.clone()));
+        let prop = match ctx.segment_whitespace {
+            SegmentWhitespace::Trim => raw_prop.trim(),
+            _ => raw_prop.as_str(),
+        };
+        let rejected = ctx.segment_whitespace == SegmentWhitespace::Error && prop.trim().len() != prop.len();
+        let flattened_prop = match property {
+            _ if ctx.quote_keys && path::needs_quoting(prop) => format!("{}{}", property.unwrap_or_default(), path::quote_key(prop)),
+            Some(parent_key) => format!("{}.{}", parent_key, prop),
+            None => prop.to_owned(),
+        };
 
         match value {
-            Value::Array(array) => flatten_array(result, &flattened_prop, array),
-            Value::Object(sub_json) => flatten_object(result, Some(&flattened_prop), sub_json),
-            _ => flatten_value(result, &flattened_prop, value.clone()),
-        }?
+            _ if rejected => ctx.recover(&flattened_prop, Err(errors::Error::InvalidKeyName(flattened_prop.clone()))),
+            _ if ctx.keeps_container(value) => flatten_value(result, &flattened_prop, value.clone(), ctx),
+            Value::Array(array) => flatten_array(result, &flattened_prop, array, ctx),
+            Value::Object(sub_json) => flatten_object(result, Some(&flattened_prop), sub_json, ctx),
+            _ => flatten_value(result, &flattened_prop, value.clone(), ctx),
+        }?;
+        ctx.leave();
     }
 
     Ok(())
 }
 
-fn flatten_array(result: &mut Map<String, Value>, property: &str, array: &Vec<Value>) -> Result<(), errors::Error> {
-    for (i, value) in array.iter().enumerate() {
-        let flattened_prop = format!("{}[{}]", property, i);
+fn flatten_array(result: &mut impl FlatSink, property: &str, array: &[Value], ctx: &mut Context) -> Result<(), errors::Error> {
+    if ctx.single_element_arrays_as_scalar && array.len() == 1 && !property.is_empty() {
+        return match &array[0] {
+            Value::Object(sub_json) => flatten_object(result, Some(property), sub_json, ctx),
+            Value::Array(sub_array) => flatten_array(result, property, sub_array, ctx),
+            value => flatten_value(result, property, value.clone(), ctx),
+        };
+    }
+
+    let order = ctx.sorted_order(property, array);
+    for i in 0..array.len() {
+        let value = &array[order.as_ref().map_or(i, |order| order[i])];
+        ctx.enter(|| PathSegment::Index(i));
+        let index = i as i64 + ctx.array_index_offset;
+        let flattened_prop = format!("{}[{}]", property, index);
 
         match value {
-            Value::Object(sub_json) => flatten_object(result, Some(&flattened_prop), sub_json),
-            Value::Array(sub_array) => flatten_array(result, &flattened_prop, sub_array),
-            _ => flatten_value(result, &flattened_prop, value.clone()),
-        }?
+            _ if index < 0 => ctx.recover(&flattened_prop, Err(errors::Error::InvalidProperty)),
+            _ if ctx.keeps_container(value) => flatten_value(result, &flattened_prop, value.clone(), ctx),
+            Value::Object(sub_json) => flatten_object(result, Some(&flattened_prop), sub_json, ctx),
+            Value::Array(sub_array) => flatten_array(result, &flattened_prop, sub_array, ctx),
+            _ => flatten_value(result, &flattened_prop, value.clone(), ctx),
+        }?;
+        ctx.leave();
     }
 
     Ok(())
 }
 
-fn flatten_value(result: &mut Map<String, Value>, property: &str, val: Value) -> Result<(), errors::Error> {
+fn flatten_value(result: &mut impl FlatSink, property: &str, val: Value, ctx: &mut Context) -> Result<(), errors::Error> {
+    let stored = store_value(result, property, val, ctx);
+    ctx.recover(property, stored)
+}
 
-    if val.is_object() || val.is_array() {
-        return Err(errors::Error::NotAValue);
+fn is_empty_container(value: &Value) -> bool {
+    match value {
+        Value::Object(map) => map.is_empty(),
+        Value::Array(array) => array.is_empty(),
+        _ => false,
     }
+}
 
-    if let Some(v) = result.get_mut(property) {
-        if let Some(existing_array) = v.as_array_mut() {
-            existing_array.push(val);
-        } else {
-            let v = v.take();
-            result[property] = json!([v, val]);
-        }
-    } else {
-        result.insert(property.to_string(), json!(val));
+fn store_value(result: &mut impl FlatSink, property: &str, val: Value, ctx: &mut Context) -> Result<(), errors::Error> {
+
+    if (val.is_object() || val.is_array()) && !ctx.keeps_container(&val) {
+        return Err(errors::Error::NotAValue(property.to_owned()));
     }
+    ctx.record(property)?;
+    let val = ctx.summarize(val);
+    ctx.tracker.leaf(&val)?;
 
-    Ok(())
+    result.put(property, val)
 }
 
 
 
 #[cfg(test)]
 mod tests {
+    use serde_json::json;
+    use crate::flattener::Flattener;
     use super::*;
 
 
@@ -186,6 +1186,452 @@ mod tests {
     }
 
 
+    #[test]
+    fn serializing_flatten_options() {
+        let options = FlattenOptions::new();
+        let serialized = serde_json::to_value(&options).unwrap();
+        assert_eq!(serialized["version"], json!(OPTIONS_VERSION));
+        assert_eq!(FlattenOptions::from_value(&serialized).unwrap(), options);
+
+        assert_eq!(FlattenOptions::from_value(&json!({})).unwrap(), options);
+        assert_eq!(
+            FlattenOptions::from_value(&json!({ "version": OPTIONS_VERSION + 1 })).err().unwrap().to_string(),
+            errors::Error::UnsupportedOptionsVersion(OPTIONS_VERSION + 1).to_string()
+        );
+    }
+
+
+    #[test]
+    fn flattening_with_quoted_keys() {
+        let json: Value = json!({ "a": { "b.c": 1, "d": [{ "e[0]": 2 }] }, "f.g": 3 });
+
+        let flat = flatten_with_options(&json, &FlattenOptions::new().quote_keys(true)).unwrap();
+        let expected = json!({ "a[\"b.c\"]": 1, "a.d[0][\"e[0]\"]": 2, "[\"f.g\"]": 3 });
+
+        println!(
+            "got:\n{}\nexpected:\n{}\n",
+            serde_json::to_string_pretty(&flat).unwrap(),
+            serde_json::to_string_pretty(&expected).unwrap()
+        );
+
+        assert_eq!(serde_json::to_value(&flat).unwrap(), expected);
+        assert_eq!(crate::unflattening::unflatten(&flat).unwrap(), json);
+    }
+
+
+    #[test]
+    fn flattening_canonically() {
+        let a: Value = serde_json::from_str(r#"{ "b": { "y": 1.0, "x": [0.1, -0.0] }, "a": 1e2 }"#).unwrap();
+        let b: Value = serde_json::from_str(r#"{ "a": 100, "b": { "x": [1e-1, 0], "y": 1 } }"#).unwrap();
+
+        let canonical = flatten_canonicalize(&a).unwrap();
+        assert_eq!(canonical, r#"{"a":100,"b.x[0]":0.1,"b.x[1]":0,"b.y":1}"#);
+        assert_eq!(canonical, flatten_canonicalize(&b).unwrap());
+        assert_ne!(canonical, flatten_canonicalize(&json!({ "a": 100.5 })).unwrap());
+    }
+
+
+    #[test]
+    fn flattening_with_sorted_arrays() {
+        let options = FlattenOptions::new().array_ordering(ArrayOrdering::CanonicalSort);
+        let a = json!({ "tags": ["b", 2.0, "a", null], "members": [{ "id": 2, "roles": ["y", "x"] }, { "id": 1 }] });
+        let b = json!({ "tags": [null, "a", 2.0, "b"], "members": [{ "id": 1 }, { "roles": ["x", "y"], "id": 2 }] });
+
+        let flat = flatten_with_options(&a, &options).unwrap();
+        let expected = json!({
+            "tags[0]": "a",
+            "tags[1]": "b",
+            "tags[2]": 2.0,
+            "tags[3]": null,
+            "members[0].id": 1,
+            "members[1].id": 2,
+            "members[1].roles[0]": "x",
+            "members[1].roles[1]": "y"
+        });
+
+        println!(
+            "got:\n{}\nexpected:\n{}\n",
+            serde_json::to_string_pretty(&flat).unwrap(),
+            serde_json::to_string_pretty(&expected).unwrap()
+        );
+
+        assert_eq!(serde_json::to_value(&flat).unwrap(), expected);
+        for _ in 0..10 {
+            assert_eq!(flatten_with_options(&b, &options).unwrap(), flat);
+        }
+
+        let options = options.sorted_array("members[*].roles");
+        let flat = flatten_with_options(&a, &options).unwrap();
+        assert_eq!(flat["tags[0]"], json!("b"));
+        assert_eq!(flat["members[0].roles[0]"], json!("x"));
+    }
+
+    #[test]
+    fn flattening_with_truncated_values() {
+        let json = json!({ "a": "short", "b": { "text": "hello world", "blob": "aGVsbG8gd29ybGQ=" } });
+
+        let options = FlattenOptions::new().max_value_length(5);
+        let (flat, report) = flatten_with_report(&json, &options).unwrap();
+        let expected = json!({
+            "a": "short",
+            "b.text": "hello… (11 chars)",
+            "b.blob": "aGVsb… (16 chars)"
+        });
+
+        println!(
+            "got:\n{}\nexpected:\n{}\n",
+            serde_json::to_string_pretty(&flat).unwrap(),
+            serde_json::to_string_pretty(&expected).unwrap()
+        );
+
+        assert_eq!(serde_json::to_value(&flat).unwrap(), expected);
+        assert_eq!(report, FlattenReport { truncated: 2, replaced_binary: 0, collisions: vec![] });
+
+        let options = options.truncation(TruncationStyle::Object).replace_binary(true);
+        let (flat, report) = flatten_with_report(&json, &options).unwrap();
+        assert_eq!(flat["b.text"], json!({ "$truncated": true, "len": 11, "prefix": "hello" }));
+        assert_eq!(flat["b.blob"], json!({ "$truncated": true, "len": 16, "prefix": "" }));
+        assert_eq!(report, FlattenReport { truncated: 2, replaced_binary: 1, collisions: vec![] });
+    }
+
+    #[test]
+    fn reporting_key_collisions() {
+        let json = json!({ "a.b": 1, "a": { "b": 2, "c": 3 }, "d": [{ "e.f": 4 }, { "e": { "f": 5 } }] });
+
+        let (flat, report) = flatten_with_report(&json, &FlattenOptions::new()).unwrap();
+        assert_eq!(flat, flatten(&json).unwrap());
+        assert_eq!(report.collisions, vec![
+            Collision { output_key: "a.b".to_owned(), source_paths: vec!["[\"a.b\"]".to_owned(), "a.b".to_owned()] },
+        ]);
+
+        let options = FlattenOptions::new().reject_collisions(true);
+        assert_eq!(
+            flatten_with_options(&json, &options).err().unwrap().to_string(),
+            errors::Error::DuplicateKey("a.b".to_owned()).to_string()
+        );
+    }
+
+    #[test]
+    fn flattening_with_segment_whitespace() {
+        let json = json!({ " user ": { "name ": "John", "name": "Ann" }, "id": 1 });
+
+        let options = FlattenOptions::new().segment_whitespace(SegmentWhitespace::Trim);
+        let (flat, report) = flatten_with_report(&json, &options).unwrap();
+        assert_eq!(serde_json::to_value(&flat).unwrap(), json!({ "user.name": ["John", "Ann"], "id": 1 }));
+        assert_eq!(report.collisions, vec![
+            Collision { output_key: "user.name".to_owned(), source_paths: vec![" user .name ".to_owned(), " user .name".to_owned()] },
+        ]);
+
+        let options = FlattenOptions::new().segment_whitespace(SegmentWhitespace::Error);
+        assert_eq!(
+            flatten_with_options(&json, &options).err().unwrap().to_string(),
+            errors::Error::InvalidKeyName(" user ".to_owned()).to_string()
+        );
+        assert_eq!(flatten_with_options(&json, &FlattenOptions::new()).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn reporting_sanitized_key_collisions() {
+        let json = json!({ "customer": { "address_line": "x", "address_city": "y" }, "id": 1 });
+
+        let mut opts = SanitizeOptions { max_length: Some(16), ..Default::default() };
+        let (flat, _, collisions) = flatten_sanitized_with_report(&json, &opts).unwrap();
+        assert_eq!(flat.keys().collect::<Vec<_>>(), vec!["customer_address", "customer_addre_1", "id"]);
+        assert_eq!(collisions, vec![Collision {
+            output_key: "customer_address".to_owned(),
+            source_paths: vec!["customer.address_line".to_owned(), "customer.address_city".to_owned()],
+        }]);
+
+        opts.reject_collisions = true;
+        assert_eq!(
+            flatten_sanitized(&json, &opts).err().unwrap().to_string(),
+            errors::Error::DuplicateKey("customer_address".to_owned()).to_string()
+        );
+    }
+
+    #[test]
+    fn rejecting_flat_input() {
+        let options = FlattenOptions::new().reject_flat_input(true);
+        let json = json!({ "user": { "name": "John", "tags": ["a"] }, "id": 1 });
+
+        let flat = flatten_with_options(&json, &options).unwrap();
+        assert_eq!(
+            flatten_with_options(&Value::Object(flat), &options).err().unwrap().to_string(),
+            errors::Error::AlreadyFlat("user.name".to_owned()).to_string()
+        );
+
+        assert!(flatten_with_options(&json!({ "id": 1, "name": "John" }), &options).is_ok());
+        assert!(flatten_with_options(&json!({ "a.b": 1, "c": { "d": 2 } }), &options).is_ok());
+
+        let mixed = json!({ "app.version": "1.2", "id": 1, "name": "John" });
+        assert!(flatten_with_options(&mixed, &options).is_ok());
+        assert!(assert_nested(&mixed, &FlatInputThresholds { min_keys: 1, min_ratio: 0.3 }).is_err());
+        assert!(assert_nested(&mixed, &FlatInputThresholds { min_keys: 2, min_ratio: 0.3 }).is_ok());
+    }
+
+    #[test]
+    fn extracting_arrays() {
+        let json = json!({
+            "user": { "name": "John", "tags": ["a", "b"] },
+            "matrix": [[1, 2], [3]],
+            "items": [{ "id": 1, "tags": [] }]
+        });
+
+        let arrays = flatten_extract_arrays(&json).unwrap();
+        let expected = BTreeMap::from([
+            ("user.tags".to_owned(), vec![json!("a"), json!("b")]),
+            ("matrix".to_owned(), vec![json!([1, 2]), json!([3])]),
+            ("matrix[0]".to_owned(), vec![json!(1), json!(2)]),
+            ("matrix[1]".to_owned(), vec![json!(3)]),
+            ("items".to_owned(), vec![json!({ "id": 1, "tags": [] })]),
+            ("items[0].tags".to_owned(), vec![]),
+        ]);
+        assert_eq!(arrays, expected);
+
+        assert_eq!(flatten_extract_arrays(&json!(1)).err().unwrap().to_string(), errors::Error::NotAnObject.to_string());
+    }
+
+    #[test]
+    fn flattening_values_at_path() {
+        let json = json!({
+            "user": { "tags": ["a", { "b": "c" }, []], "tagsx": ["d"], "name": "John" },
+            "ids": [1, 2]
+        });
+
+        assert_eq!(flatten_values_at_path(&json, "user.tags").unwrap(), vec![json!("a"), json!("c")]);
+        assert_eq!(flatten_values_at_path(&json, "user.name").unwrap(), vec![json!("John")]);
+        assert_eq!(flatten_values_at_path(&json, "user.tag").unwrap(), Vec::<Value>::new());
+        assert_eq!(flatten_values_at_path(&json, "ids[1]").unwrap(), vec![json!(2)]);
+        assert_eq!(flatten_values_at_path(&json, "").unwrap().len(), 6);
+        assert_eq!(flatten_values_at_path(&json, "ids[").err().unwrap().to_string(), errors::Error::InvalidProperty.to_string());
+    }
+
+    #[test]
+    fn listing_object_keys_at_path() {
+        let json = json!({ "user": { "name": "John", "age": 30, "tags": [{ "k": 1 }] } });
+
+        assert_eq!(flatten_object_keys_at_path(&json, "user").unwrap(), vec!["name", "age", "tags"]);
+        assert_eq!(flatten_object_keys_at_path(&json, "user.tags[0]").unwrap(), vec!["k"]);
+        assert_eq!(flatten_object_keys_at_path(&json, "").unwrap(), vec!["user"]);
+        assert_eq!(
+            flatten_object_keys_at_path(&json, "user.tags").err().unwrap().to_string(),
+            errors::Error::NotAnObject.to_string()
+        );
+        assert_eq!(
+            flatten_object_keys_at_path(&json, "user.email").err().unwrap().to_string(),
+            errors::Error::PathNotFound("user.email".to_owned()).to_string()
+        );
+    }
+
+    #[test]
+    fn measuring_arrays_at_path() {
+        let json = json!({ "items": [{ "tags": ["a", "b"] }, {}], "name": "John" });
+
+        assert_eq!(flatten_array_length_at_path(&json, "items").unwrap(), 2);
+        assert_eq!(flatten_array_length_at_path(&json, "items[0].tags").unwrap(), 2);
+        assert_eq!(flatten_array_length_at_path(&json!([[], 1]), "[0]").unwrap(), 0);
+        assert_eq!(
+            flatten_array_length_at_path(&json, "name").err().unwrap().to_string(),
+            errors::Error::InvalidType.to_string()
+        );
+        assert_eq!(
+            flatten_array_length_at_path(&json, "items[2]").err().unwrap().to_string(),
+            errors::Error::PathNotFound("items[2]".to_owned()).to_string()
+        );
+    }
+
+    #[test]
+    fn flattening_and_collecting_errors() {
+        let json = json!({ "a.b": 1, "a": { "b": 2 }, "c": [1, 2] });
+
+        let flattener = Flattener::new(FlattenOptions::new().reject_collisions(true).array_index_offset(-1));
+        let (flat, errors) = flattener.flatten_collect_errors(&json);
+        assert_eq!(serde_json::to_value(&flat).unwrap(), json!({ "a.b": 1, "c[0]": 2 }));
+        assert_eq!(
+            errors.iter().map(|(key, e)| (key.as_str(), e.to_string())).collect::<Vec<_>>(),
+            vec![
+                ("a.b", errors::Error::DuplicateKey("a.b".to_owned()).to_string()),
+                ("c[-1]", errors::Error::InvalidProperty.to_string()),
+            ]
+        );
+
+        let (flat, errors) = flatten_collect_errors(&json);
+        assert_eq!(flat, flatten(&json).unwrap());
+        assert!(errors.is_empty());
+
+        let (flat, errors) = flatten_collect_errors(&json!(1));
+        assert!(flat.is_empty());
+        assert_eq!(errors[0].0, "");
+        assert_eq!(errors[0].1.to_string(), errors::Error::NotAnObject.to_string());
+    }
+
+    #[test]
+    fn flattening_conditionally() {
+        let json = json!({
+            "id": 1,
+            "secret": { "token": "x" },
+            "geo": { "lat": 45.0, "lon": 7.6 },
+            "items": [{ "id": 2, "raw": { "a": [1] } }],
+            "deep": { "a": { "b": { "c": 1 } } }
+        });
+
+        let flat = flatten_conditional(&json, |key, _| match key {
+            "secret" => FlattenAction::Skip,
+            "geo" => FlattenAction::AsLeaf,
+            _ if key.ends_with(".raw") => FlattenAction::AsLeaf,
+            _ if key.matches('.').count() >= 2 => FlattenAction::AsLeaf,
+            _ => FlattenAction::Flatten,
+        }).unwrap();
+        let expected = json!({
+            "id": 1,
+            "geo": { "lat": 45.0, "lon": 7.6 },
+            "items[0].id": 2,
+            "items[0].raw": { "a": [1] },
+            "deep.a.b": { "c": 1 }
+        });
+
+        println!(
+            "got:\n{}\nexpected:\n{}\n",
+            serde_json::to_string_pretty(&flat).unwrap(),
+            serde_json::to_string_pretty(&expected).unwrap()
+        );
+
+        assert_eq!(serde_json::to_value(&flat).unwrap(), expected);
+        assert_eq!(flatten_conditional(&json, |_, _| FlattenAction::Flatten).unwrap(), flatten(&json).unwrap());
+    }
+
+    #[test]
+    fn flattening_with_empty_containers() {
+        let json = json!({ "a": {}, "b": [[], { "c": {} }], "d": 1 });
+
+        let flat = flatten_with_options(&json, &FlattenOptions::new().keep_empty_containers(true)).unwrap();
+        let expected = json!({ "a": {}, "b[0]": [], "b[1].c": {}, "d": 1 });
+
+        println!(
+            "got:\n{}\nexpected:\n{}\n",
+            serde_json::to_string_pretty(&flat).unwrap(),
+            serde_json::to_string_pretty(&expected).unwrap()
+        );
+
+        assert_eq!(serde_json::to_value(&flat).unwrap(), expected);
+        assert_eq!(flatten(&json).unwrap(), *json!({ "d": 1 }).as_object().unwrap());
+    }
+
+    #[test]
+    fn flattening_without_array_indices() {
+        let json = json!({
+            "user": { "name": "John", "mail": ["a@example.com", "b@example.com"] },
+            "groups": [{ "cn": "admins" }, { "cn": "users", "members": [[1, 2], [3]] }],
+            "empty": []
+        });
+
+        let multi_valued = flatten_without_array_indices(&json).unwrap();
+        let expected = vec![
+            ("user.name".to_owned(), vec![json!("John")]),
+            ("user.mail".to_owned(), vec![json!("a@example.com"), json!("b@example.com")]),
+            ("groups.cn".to_owned(), vec![json!("admins"), json!("users")]),
+            ("groups.members".to_owned(), vec![json!(1), json!(2), json!(3)]),
+        ];
+        assert_eq!(multi_valued, expected);
+
+        assert_eq!(
+            flatten_without_array_indices(&json!([{ "a": 1 }, { "a": 2 }])).unwrap(),
+            vec![("a".to_owned(), vec![json!(1), json!(2)])]
+        );
+        assert_eq!(
+            flatten_without_array_indices(&json!({ "": [{ "a": 1 }] })).unwrap(),
+            vec![(".a".to_owned(), vec![json!(1)])]
+        );
+    }
+
+    #[test]
+    fn flattening_keys_matching() {
+        let json: Value = json!({
+            "id": 1,
+            "items": [
+                { "id": 2, "tags": ["a", "b"] },
+                { "id": 3, "owner": { "id": 4 } }
+            ],
+            "meta": { "id": 5 }
+        });
+
+        let pattern = PathPattern::compile("items[*].id").unwrap();
+        let flat = flatten_keys_matching(&json, &pattern).unwrap();
+        assert_eq!(serde_json::to_value(&flat).unwrap(), json!({ "items[0].id": 2, "items[1].id": 3 }));
+
+        let pattern = PathPattern::compile("**.id").unwrap();
+        let flat = flatten_keys_matching(&json, &pattern).unwrap();
+        let expected = json!({
+            "id": 1,
+            "items[0].id": 2,
+            "items[1].id": 3,
+            "items[1].owner.id": 4,
+            "meta.id": 5
+        });
+        assert_eq!(serde_json::to_value(&flat).unwrap(), expected);
+
+        let pattern = PathPattern::compile("[*].id").unwrap();
+        let flat = flatten_keys_matching(&json["items"], &pattern).unwrap();
+        assert_eq!(serde_json::to_value(&flat).unwrap(), json!({ "[0].id": 2, "[1].id": 3 }));
+        assert_eq!(
+            flatten_keys_matching(&json!(1), &pattern).err().unwrap().to_string(),
+            errors::Error::NotAnObject.to_string()
+        );
+    }
+
+
+    #[test]
+    fn flattening_nulls_in_arrays() {
+        let json: Value = json!({
+            "arr": [null, 1],
+            "nested": [[null], { "x": null }]
+        });
+
+        let flat = flatten(&json).unwrap();
+        let expected = json!({
+            "arr[0]": null,
+            "arr[1]": 1,
+            "nested[0][0]": null,
+            "nested[1].x": null
+        });
+
+        assert_eq!(serde_json::to_value(&flat).unwrap(), expected);
+    }
+
+
+    #[test]
+    fn flattening_sanitized_keys() {
+        let json: Value = json!({
+            "user": { "first.name": "John", "first_name": "Jane" },
+            "tags": ["a", "b"],
+            "a_very_long_property_1": 1,
+            "a_very_long_property_2": 2
+        });
+
+        let opts = SanitizeOptions { max_length: Some(20), ..Default::default() };
+        let (flat, mapping) = flatten_sanitized(&json, &opts).unwrap();
+        let expected = json!({
+            "user_first_name": "John",
+            "user_first_name_1": "Jane",
+            "tags_0_": "a",
+            "tags_1_": "b",
+            "a_very_long_property": 1,
+            "a_very_long_proper_1": 2
+        });
+
+        println!(
+            "got:\n{}\nexpected:\n{}\n",
+            serde_json::to_string_pretty(&flat).unwrap(),
+            serde_json::to_string_pretty(&expected).unwrap()
+        );
+
+        assert_eq!(serde_json::to_value(&flat).unwrap(), expected);
+        assert_eq!(mapping["user_first_name_1"], "user.first_name");
+        assert_eq!(mapping["a_very_long_proper_1"], "a_very_long_property_2");
+        assert_eq!(flatten_sanitized(&json, &opts).unwrap().1, mapping);
+    }
+
+
     #[test]
     fn flattening_nested_arrays_and_objects_3() {
         let json: Value = json!({
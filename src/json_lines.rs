@@ -0,0 +1,192 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+
+use std::io::{BufRead, Write};
+use serde_json::Value;
+use crate::errors;
+use crate::flattening::flatten;
+use crate::unflattening::unflatten;
+
+
+/// Options for reading JSON Lines.
+#[derive(Debug, Clone, Default)]
+pub struct JsonLinesOptions {
+    /// Skips the lines that cannot be parsed or unflattened instead of failing the whole batch.
+    pub skip_errors: bool,
+}
+
+/// Documents read from JSON Lines, with the position and error of each line that was skipped.
+pub type JsonLinesOutput = (Vec<Value>, Vec<(usize, errors::Error)>);
+
+
+/// Flattens a stream of JSON Values, writing each one as a single-line JSON object (JSON Lines).
+///
+/// Documents that cannot be flattened are skipped, so the remaining ones are still written.
+///
+/// # Arguments
+///
+/// * `values` - The JSON Values to be flattened.
+/// * `writer` - The destination of the JSON Lines output.
+///
+/// # Returns
+///
+/// A Result containing the number of documents written, or an error (`errors::Error`): `BatchErrors` listing the
+/// position and error of every document that was skipped, or `Io` if writing failed.
+///
+pub fn flatten_to_json_lines(values: impl Iterator<Item = Value>, writer: &mut impl Write) -> Result<usize, errors::Error> {
+    let mut written = 0;
+    let mut failures = vec![];
+
+    for (i, value) in values.enumerate() {
+        match flatten(&value) {
+            Ok(flat) => {
+                serde_json::to_writer(&mut *writer, &flat).map_err(std::io::Error::from)?;
+                writer.write_all(b"\n")?;
+                written += 1;
+            }
+            Err(e) => failures.push((i, e)),
+        }
+    }
+
+    if !failures.is_empty() {
+        return Err(errors::Error::BatchErrors(failures));
+    }
+    Ok(written)
+}
+
+/// Reads JSON Lines where each line is a flat JSON object, unflattening every line independently.
+///
+/// # Arguments
+///
+/// * `reader` - The source of the JSON Lines input.
+///
+/// # Returns
+///
+/// A Result containing the unflattened documents (`Vec<serde_json::Value>`) or an error (`errors::Error`):
+/// `BatchErrors` listing the line index and error of every invalid line, or `Io` if reading failed.
+///
+pub fn unflatten_from_json_lines(reader: &mut impl BufRead) -> Result<Vec<Value>, errors::Error> {
+    unflatten_from_json_lines_with(reader, &JsonLinesOptions::default()).map(|(values, _)| values)
+}
+
+/// Reads JSON Lines where each line is a flat JSON object, see `unflatten_from_json_lines`.
+///
+/// # Arguments
+///
+/// * `reader` - The source of the JSON Lines input.
+/// * `opts` - The reading options (`JsonLinesOptions`).
+///
+/// # Returns
+///
+/// A Result containing the unflattened documents, along with the skipped lines when `skip_errors` is set
+/// (`JsonLinesOutput`), or an error (`errors::Error`).
+///
+pub fn unflatten_from_json_lines_with(reader: &mut impl BufRead, opts: &JsonLinesOptions) -> Result<JsonLinesOutput, errors::Error> {
+    let mut values = vec![];
+    let mut failures = vec![];
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let parsed = serde_json::from_str::<Value>(&line)
+            .map_err(|_| errors::Error::FormatError)
+            .and_then(|flat| match flat {
+                Value::Object(map) => unflatten(&map),
+                _ => Err(errors::Error::NotAnObject),
+            });
+        match parsed {
+            Ok(value) => values.push(value),
+            Err(e) => failures.push((i, e)),
+        }
+    }
+
+    if !failures.is_empty() && !opts.skip_errors {
+        return Err(errors::Error::BatchErrors(failures));
+    }
+    Ok((values, failures))
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    #[test]
+    fn flattening_to_json_lines() {
+        let values = vec![
+            json!({ "a": { "b": 1 } }),
+            json!({ "c": [true, null] }),
+        ];
+
+        let mut output = vec![];
+        let written = flatten_to_json_lines(values.into_iter(), &mut output).unwrap();
+
+        assert_eq!(written, 2);
+        assert_eq!(String::from_utf8(output).unwrap(), "{\"a.b\":1}\n{\"c[0]\":true,\"c[1]\":null}\n");
+    }
+
+    #[test]
+    fn unflattening_from_json_lines() {
+        let input = "{\"a.b\":1}\n\n{\"c[0]\":true,\"c[1]\":null}\n";
+
+        let values = unflatten_from_json_lines(&mut input.as_bytes()).unwrap();
+        assert_eq!(values, vec![json!({ "a": { "b": 1 } }), json!({ "c": [true, null] })]);
+    }
+
+    #[test]
+    fn unflattening_from_json_lines_with_errors() {
+        let input = "{\"a\":1}\nnot json\n[1]\n{\"b\":2}\n{\"c\":1,\"c.d\":2}\n";
+
+        match unflatten_from_json_lines(&mut input.as_bytes()).err().unwrap() {
+            errors::Error::BatchErrors(failures) => {
+                assert_eq!(failures.iter().map(|(i, _)| *i).collect::<Vec<_>>(), vec![1, 2, 4]);
+            }
+            e => panic!("Expected BatchErrors, got {}", e),
+        }
+
+        let opts = JsonLinesOptions { skip_errors: true };
+        let (values, failures) = unflatten_from_json_lines_with(&mut input.as_bytes(), &opts).unwrap();
+        assert_eq!(values, vec![json!({ "a": 1 }), json!({ "b": 2 })]);
+        assert_eq!(failures[0].1.to_string(), errors::Error::FormatError.to_string());
+        assert_eq!(failures[1].1.to_string(), errors::Error::NotAnObject.to_string());
+    }
+
+    #[test]
+    fn flattening_to_json_lines_with_errors() {
+        let values = vec![
+            json!({ "a": 1 }),
+            json!(1),
+            json!({ "b": 2 }),
+            json!("c"),
+        ];
+
+        let mut output = vec![];
+        let err = flatten_to_json_lines(values.into_iter(), &mut output).err().unwrap();
+
+        assert_eq!(String::from_utf8(output).unwrap(), "{\"a\":1}\n{\"b\":2}\n");
+        match err {
+            errors::Error::BatchErrors(failures) => {
+                assert_eq!(failures.iter().map(|(i, _)| *i).collect::<Vec<_>>(), vec![1, 3]);
+                assert_eq!(failures[0].1.to_string(), errors::Error::NotAnObject.to_string());
+            }
+            e => panic!("Expected BatchErrors, got {}", e),
+        }
+    }
+}
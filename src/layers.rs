@@ -0,0 +1,167 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+
+use std::collections::BTreeMap;
+use serde_json::{Map, Value};
+use crate::errors;
+use crate::flattening::flatten;
+use crate::unflattening::unflatten;
+
+
+enum LayerData {
+    Nested(Value),
+    Flat(Map<String, Value>),
+}
+
+/// Configuration layers merged by flat key, later layers taking precedence over earlier ones.
+#[derive(Default)]
+pub struct Layers {
+    layers: Vec<(String, LayerData)>,
+}
+
+/// The result of merging configuration layers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Resolved {
+    /// The merged configuration.
+    pub value: Value,
+    /// For each flat key of the merged configuration, the name of the layer that supplied its value.
+    pub provenance: BTreeMap<String, String>,
+}
+
+impl Layers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a layer from a nested JSON object.
+    pub fn add(mut self, name: &str, value: Value) -> Self {
+        self.layers.push((name.to_owned(), LayerData::Nested(value)));
+        self
+    }
+
+    /// Adds a layer from an already flattened JSON structure (e.g. environment variables).
+    pub fn add_flat(mut self, name: &str, data: Map<String, Value>) -> Self {
+        self.layers.push((name.to_owned(), LayerData::Flat(data)));
+        self
+    }
+
+    /// Merges the layers.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the merged configuration (`serde_json::Value`) or an error (`errors::Error`).
+    ///
+    pub fn resolve(&self) -> Result<Value, errors::Error> {
+        self.resolve_with_provenance().map(|resolved| resolved.value)
+    }
+
+    /// Merges the layers, also tracking which layer supplied each value.
+    ///
+    /// A value replaces whatever earlier layers set at the same key, below it (a scalar replacing an object)
+    /// or above it (an object replacing a scalar).
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the merged configuration with its provenance (`Resolved`) or an error (`errors::Error`).
+    ///
+    pub fn resolve_with_provenance(&self) -> Result<Resolved, errors::Error> {
+        let mut merged = Map::new();
+        let mut provenance = BTreeMap::new();
+
+        for (name, data) in &self.layers {
+            let flattened;
+            let flat = match data {
+                LayerData::Nested(value) => {
+                    flattened = flatten(value)?;
+                    &flattened
+                }
+                LayerData::Flat(flat) => flat,
+            };
+
+            for (key, value) in flat {
+                let overridden: Vec<String> = merged.keys()
+                    .filter(|existing| is_nested_in(existing, key) || is_nested_in(key, existing))
+                    .cloned()
+                    .collect();
+                for existing in overridden {
+                    merged.remove(&existing);
+                    provenance.remove(&existing);
+                }
+                merged.insert(key.clone(), value.clone());
+                provenance.insert(key.clone(), name.clone());
+            }
+        }
+
+        Ok(Resolved { value: unflatten(&merged)?, provenance })
+    }
+}
+
+fn is_nested_in(key: &str, parent: &str) -> bool {
+    key.strip_prefix(parent).is_some_and(|rest| rest.starts_with(['.', '[']))
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    #[test]
+    fn resolving_layers() {
+        let mut env = Map::new();
+        env.insert("db.port".to_owned(), json!(6543));
+        env.insert("features[1]".to_owned(), json!("beta"));
+
+        let resolved = Layers::new()
+            .add("defaults", json!({
+                "db": { "host": "localhost", "port": 5432 },
+                "features": ["alpha", "stable"],
+                "log": { "level": "info" }
+            }))
+            .add_flat("env", env)
+            .add("file", json!({ "log": "debug", "db": { "host": "db.internal" } }))
+            .resolve_with_provenance()
+            .unwrap();
+
+        let expected = json!({
+            "db": { "host": "db.internal", "port": 6543 },
+            "features": ["alpha", "beta"],
+            "log": "debug"
+        });
+
+        println!(
+            "got:\n{}\nexpected:\n{}\n",
+            serde_json::to_string_pretty(&resolved.value).unwrap(),
+            serde_json::to_string_pretty(&expected).unwrap()
+        );
+
+        assert_eq!(resolved.value, expected);
+        assert_eq!(resolved.provenance, BTreeMap::from([
+            ("db.host".to_owned(), "file".to_owned()),
+            ("db.port".to_owned(), "env".to_owned()),
+            ("features[0]".to_owned(), "defaults".to_owned()),
+            ("features[1]".to_owned(), "env".to_owned()),
+            ("log".to_owned(), "file".to_owned()),
+        ]));
+    }
+
+    #[test]
+    fn resolving_invalid_layers() {
+        let resolved = Layers::new().add("defaults", json!(1)).resolve();
+        assert_eq!(resolved.err().unwrap().to_string(), errors::Error::NotAnObject.to_string());
+    }
+}
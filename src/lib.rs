@@ -16,4 +16,29 @@
 
 pub mod flattening;
 pub mod unflattening;
-pub mod errors;
\ No newline at end of file
+pub mod errors;
+pub mod path;
+pub mod pattern;
+pub mod flattener;
+pub mod compare;
+pub mod roundtrip;
+pub mod layers;
+pub mod json_lines;
+pub mod metrics;
+pub mod mongo;
+pub mod schema;
+pub mod config;
+pub mod sink;
+pub mod chunked;
+pub mod skeleton;
+pub mod typed;
+pub mod streaming;
+pub mod serializing;
+pub mod flat_map;
+pub mod progress;
+pub mod dotted;
+pub mod properties;
+pub mod explode;
+pub mod traverse;
+pub mod edit;
+pub mod codegen;
\ No newline at end of file
@@ -0,0 +1,321 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+
+use serde_json::{Map, Value};
+use crate::errors;
+use crate::flattening::{Collision, CollisionTracker, flatten};
+
+
+/// Options for extracting numeric leaves.
+#[derive(Debug, Clone)]
+pub struct NumericLeavesOptions {
+    /// Converts boolean leaves to `0` and `1`.
+    pub coerce_bools: bool,
+    /// Parses string leaves holding a number (e.g. `"3.5"`).
+    pub parse_strings: bool,
+    /// Transformation applied to every flat key, `prometheus_metric_name` by default.
+    pub key_transform: fn(&str) -> String,
+}
+
+impl Default for NumericLeavesOptions {
+    fn default() -> Self {
+        NumericLeavesOptions {
+            coerce_bools: false,
+            parse_strings: false,
+            key_transform: prometheus_metric_name,
+        }
+    }
+}
+
+/// Flattens a JSON Value and keeps only its numeric leaves, e.g. to export them as gauges.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be flattened (`serde_json::Value`).
+/// * `opts` - The extraction options (`NumericLeavesOptions`).
+///
+/// # Returns
+///
+/// The transformed flat keys with their numeric values, in document order. Values that are neither objects nor arrays
+/// have no leaves.
+/// A key produced by several leaves (e.g. `a.b` and `a_b`) appears once per leaf, see `numeric_leaves_with_report`.
+///
+pub fn numeric_leaves(value: &Value, opts: &NumericLeavesOptions) -> Vec<(String, f64)> {
+    numeric_leaves_with_report(value, opts).0
+}
+
+/// Extracts the numeric leaves of a JSON Value, see `numeric_leaves`, reporting the keys that the key transform
+/// produced for several leaves.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be flattened (`serde_json::Value`).
+/// * `opts` - The extraction options (`NumericLeavesOptions`).
+///
+/// # Returns
+///
+/// The transformed flat keys with their numeric values, in document order, with the collisions (`Vec<Collision>`),
+/// whose source paths are the flat keys of the leaves.
+///
+pub fn numeric_leaves_with_report(value: &Value, opts: &NumericLeavesOptions) -> (Vec<(String, f64)>, Vec<Collision>) {
+    let mut sources = CollisionTracker::default();
+    let leaves = flatten(value).unwrap_or_default().into_iter().filter_map(|(key, value)| {
+        let number = match value {
+            Value::Number(n) => n.as_f64(),
+            Value::Bool(b) if opts.coerce_bools => Some(if b { 1.0 } else { 0.0 }),
+            Value::String(s) if opts.parse_strings => s.trim().parse::<f64>().ok(),
+            _ => None,
+        }?;
+        let name = (opts.key_transform)(&key);
+        sources.record(&name, key);
+        Some((name, number))
+    }).collect();
+    (leaves, sources.into_collisions())
+}
+
+/// Turns a flat key into a valid Prometheus metric name (`[a-zA-Z_:][a-zA-Z0-9_:]*`),
+/// replacing every other character with `_`.
+pub fn prometheus_metric_name(key: &str) -> String {
+    let mut name: String = key.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == ':' { c } else { '_' })
+        .collect();
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    name
+}
+
+/// An OpenTelemetry attribute value: a string, bool, int or float, or a homogeneous array of them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttrValue {
+    String(String),
+    Bool(bool),
+    I64(i64),
+    F64(f64),
+    StringArray(Vec<String>),
+    BoolArray(Vec<bool>),
+    I64Array(Vec<i64>),
+    F64Array(Vec<f64>),
+}
+
+/// What to do with values that cannot be represented as attributes (nulls and mixed arrays).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Unrepresentable {
+    /// Omit the value and report its path.
+    #[default]
+    Drop,
+    /// Store the JSON representation of the value as a string.
+    Stringify,
+}
+
+/// Options for converting a JSON Value into OpenTelemetry attributes.
+#[derive(Debug, Clone, Default)]
+pub struct OtelOptions {
+    pub unrepresentable: Unrepresentable,
+    /// Attributes with longer keys are dropped.
+    pub max_key_length: Option<usize>,
+    /// Attributes beyond this count are dropped.
+    pub max_attributes: Option<usize>,
+}
+
+/// OpenTelemetry attributes along with the flat keys that had to be dropped.
+pub type OtelAttributes = (Vec<(String, AttrValue)>, Vec<String>);
+
+/// Flattens a JSON Value into OpenTelemetry attributes.
+///
+/// Objects are flattened with the usual flat keys, while non-empty arrays of scalars of a single type are kept
+/// as array attributes. Other arrays, and an array root, are flattened with indices (`[0]` for the first element of
+/// an array root).
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be flattened (`serde_json::Value`).
+/// * `opts` - The conversion options (`OtelOptions`).
+///
+/// # Returns
+///
+/// A Result containing the attributes (`Vec<(String, AttrValue)>`) or an error (`errors::Error`).
+///
+pub fn to_otel_attributes(value: &Value, opts: &OtelOptions) -> Result<Vec<(String, AttrValue)>, errors::Error> {
+    to_otel_attributes_with_dropped(value, opts).map(|(attributes, _)| attributes)
+}
+
+/// Same as `to_otel_attributes`, also returning the flat keys that were dropped.
+pub fn to_otel_attributes_with_dropped(value: &Value, opts: &OtelOptions) -> Result<OtelAttributes, errors::Error> {
+    let mut result = (vec![], vec![]);
+    match value {
+        Value::Object(map) => otel_object(&mut result, None, map, opts),
+        Value::Array(array) => {
+            for (i, v) in array.iter().enumerate() {
+                otel_value(&mut result, format!("[{}]", i), v, opts);
+            }
+        }
+        _ => return Err(errors::Error::NotAnObject),
+    }
+    Ok(result)
+}
+
+fn otel_object(result: &mut OtelAttributes, property: Option<&str>, map: &Map<String, Value>, opts: &OtelOptions) {
+    for (prop, value) in map {
+        let key = property.map_or_else(|| prop.clone(), |parent_key| format!("{}.{}", parent_key, prop));
+        otel_value(result, key, value, opts);
+    }
+}
+
+fn otel_value(result: &mut OtelAttributes, key: String, value: &Value, opts: &OtelOptions) {
+    let attribute = match value {
+        Value::Object(map) => return otel_object(result, Some(&key), map, opts),
+        Value::Array(array) if array.iter().any(|v| v.is_object() || v.is_array()) => {
+            for (i, v) in array.iter().enumerate() {
+                otel_value(result, format!("{}[{}]", key, i), v, opts);
+            }
+            return;
+        }
+        Value::Array(array) if array.is_empty() => return,
+        Value::Array(array) => scalar_array(array),
+        Value::String(s) => Some(AttrValue::String(s.clone())),
+        Value::Bool(b) => Some(AttrValue::Bool(*b)),
+        Value::Number(n) => n.as_i64().map(AttrValue::I64).or_else(|| n.as_f64().map(AttrValue::F64)),
+        Value::Null => None,
+    };
+
+    let attribute = match (attribute, opts.unrepresentable) {
+        (Some(attribute), _) => attribute,
+        (None, Unrepresentable::Stringify) => AttrValue::String(value.to_string()),
+        (None, Unrepresentable::Drop) => return result.1.push(key),
+    };
+
+    let too_long = opts.max_key_length.is_some_and(|max| key.chars().count() > max);
+    let too_many = opts.max_attributes.is_some_and(|max| result.0.len() >= max);
+    if too_long || too_many {
+        result.1.push(key);
+    } else {
+        result.0.push((key, attribute));
+    }
+}
+
+fn scalar_array(array: &[Value]) -> Option<AttrValue> {
+    if let Some(strings) = array.iter().map(|v| v.as_str().map(str::to_owned)).collect() {
+        Some(AttrValue::StringArray(strings))
+    } else if let Some(bools) = array.iter().map(Value::as_bool).collect() {
+        Some(AttrValue::BoolArray(bools))
+    } else if let Some(ints) = array.iter().map(Value::as_i64).collect() {
+        Some(AttrValue::I64Array(ints))
+    } else {
+        array.iter().map(Value::as_f64).collect::<Option<_>>().map(AttrValue::F64Array)
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    #[test]
+    fn extracting_numeric_leaves() {
+        let json = json!({
+            "cpu": { "load": [0.5, 0.25], "cores": 8 },
+            "up": true,
+            "name": "node-1",
+            "disk": { "free": "1024", "9p": null }
+        });
+
+        let leaves = numeric_leaves(&json, &NumericLeavesOptions::default());
+        assert_eq!(leaves, vec![
+            ("cpu_load_0_".to_owned(), 0.5),
+            ("cpu_load_1_".to_owned(), 0.25),
+            ("cpu_cores".to_owned(), 8.0),
+        ]);
+
+        let opts = NumericLeavesOptions { coerce_bools: true, parse_strings: true, ..Default::default() };
+        let leaves = numeric_leaves(&json, &opts);
+        assert_eq!(&leaves[3..], &[("up".to_owned(), 1.0), ("disk_free".to_owned(), 1024.0)]);
+
+        assert_eq!(numeric_leaves(&json!([1, 2]), &opts), vec![("_0_".to_owned(), 1.0), ("_1_".to_owned(), 2.0)]);
+        assert!(numeric_leaves(&json!(1), &opts).is_empty());
+    }
+
+    #[test]
+    fn reporting_numeric_leaf_collisions() {
+        let json = json!({ "a": { "b": 1, "c": "x" }, "a_b": 2, "a-b": 3, "a_c": 4 });
+
+        let (leaves, collisions) = numeric_leaves_with_report(&json, &NumericLeavesOptions::default());
+        assert_eq!(leaves, vec![
+            ("a_b".to_owned(), 1.0),
+            ("a_b".to_owned(), 2.0),
+            ("a_b".to_owned(), 3.0),
+            ("a_c".to_owned(), 4.0),
+        ]);
+        assert_eq!(collisions, vec![Collision {
+            output_key: "a_b".to_owned(),
+            source_paths: vec!["a.b".to_owned(), "a_b".to_owned(), "a-b".to_owned()],
+        }]);
+        assert_eq!(numeric_leaves(&json, &NumericLeavesOptions::default()), leaves);
+    }
+
+    #[test]
+    fn converting_to_otel_attributes() {
+        let json = json!({
+            "http": { "method": "GET", "status": 200, "duration": 0.5, "ok": true },
+            "tags": ["a", "b"],
+            "ratios": [1, 0.5],
+            "mixed": [1, "a"],
+            "user": null,
+            "items": [{ "id": 1 }, [true]]
+        });
+
+        let (attributes, dropped) = to_otel_attributes_with_dropped(&json, &OtelOptions::default()).unwrap();
+        assert_eq!(attributes, vec![
+            ("http.method".to_owned(), AttrValue::String("GET".to_owned())),
+            ("http.status".to_owned(), AttrValue::I64(200)),
+            ("http.duration".to_owned(), AttrValue::F64(0.5)),
+            ("http.ok".to_owned(), AttrValue::Bool(true)),
+            ("tags".to_owned(), AttrValue::StringArray(vec!["a".to_owned(), "b".to_owned()])),
+            ("ratios".to_owned(), AttrValue::F64Array(vec![1.0, 0.5])),
+            ("items[0].id".to_owned(), AttrValue::I64(1)),
+            ("items[1]".to_owned(), AttrValue::BoolArray(vec![true])),
+        ]);
+        assert_eq!(dropped, vec!["mixed", "user"]);
+
+        let opts = OtelOptions { unrepresentable: Unrepresentable::Stringify, ..Default::default() };
+        let attributes = to_otel_attributes(&json, &opts).unwrap();
+        assert!(attributes.contains(&("mixed".to_owned(), AttrValue::String("[1,\"a\"]".to_owned()))));
+        assert!(attributes.contains(&("user".to_owned(), AttrValue::String("null".to_owned()))));
+    }
+
+    #[test]
+    fn limiting_otel_attributes() {
+        let json = json!({ "a": 1, "long_key": 2, "b": 3, "c": 4 });
+
+        let opts = OtelOptions { max_key_length: Some(4), max_attributes: Some(2), ..Default::default() };
+        let (attributes, dropped) = to_otel_attributes_with_dropped(&json, &opts).unwrap();
+        assert_eq!(attributes, vec![("a".to_owned(), AttrValue::I64(1)), ("b".to_owned(), AttrValue::I64(3))]);
+        assert_eq!(dropped, vec!["long_key", "c"]);
+
+        let attributes = to_otel_attributes(&json!([1, { "a": [true] }]), &OtelOptions::default()).unwrap();
+        assert_eq!(attributes, vec![("[0]".to_owned(), AttrValue::I64(1)), ("[1].a".to_owned(), AttrValue::BoolArray(vec![true]))]);
+        assert_eq!(to_otel_attributes(&json!(1), &opts).err().unwrap().to_string(), errors::Error::NotAnObject.to_string());
+    }
+
+    #[test]
+    fn sanitizing_prometheus_metric_names() {
+        assert_eq!(prometheus_metric_name("a.b[0].c-d"), "a_b_0__c_d");
+        assert_eq!(prometheus_metric_name("9p:x"), "_9p:x");
+        assert_eq!(prometheus_metric_name(""), "_");
+    }
+}
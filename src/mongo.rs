@@ -0,0 +1,181 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+
+use serde_json::{Map, Value};
+use crate::errors;
+use crate::path::{self, PathSegment};
+
+
+/// Computes a MongoDB update document turning `before` into `after`.
+///
+/// Changed paths are expressed in Mongo dot notation (`a.0.b`). Arrays whose length changes are replaced
+/// as a whole, since unsetting an array element in Mongo leaves a `null` in its place.
+///
+/// # Arguments
+///
+/// * `before` - The current JSON document.
+/// * `after` - The desired JSON document.
+///
+/// # Returns
+///
+/// A Result containing the update (`{"$set": {...}, "$unset": {...}}`, omitting empty operators) or an error
+/// (`errors::Error`), e.g. `InvalidKeyName` when a property name contains `.` or `$`, which Mongo forbids.
+///
+pub fn to_mongo_update(before: &Value, after: &Value) -> Result<Value, errors::Error> {
+    if !before.is_object() || !after.is_object() {
+        return Err(errors::Error::NotAnObject);
+    }
+    check_key_names(before, &mut vec![])?;
+    check_key_names(after, &mut vec![])?;
+
+    let mut set = Map::new();
+    let mut unset = Map::new();
+    diff_values(before, after, &mut vec![], &mut set, &mut unset);
+
+    let mut update = Map::new();
+    if !set.is_empty() {
+        update.insert("$set".to_owned(), Value::Object(set));
+    }
+    if !unset.is_empty() {
+        update.insert("$unset".to_owned(), Value::Object(unset));
+    }
+    Ok(Value::Object(update))
+}
+
+fn diff_values(before: &Value, after: &Value, path: &mut Vec<PathSegment>, set: &mut Map<String, Value>, unset: &mut Map<String, Value>) {
+    match (before, after) {
+        (Value::Object(b), Value::Object(a)) => {
+            for k in b.keys() {
+                if !a.contains_key(k) {
+                    path.push(PathSegment::Key(k.clone()));
+                    unset.insert(mongo_path(path), Value::String(String::new()));
+                    path.pop();
+                }
+            }
+            for (k, v) in a {
+                path.push(PathSegment::Key(k.clone()));
+                match b.get(k) {
+                    Some(old) => diff_values(old, v, path, set, unset),
+                    None => {
+                        set.insert(mongo_path(path), v.clone());
+                    }
+                }
+                path.pop();
+            }
+        }
+        (Value::Array(b), Value::Array(a)) if b.len() == a.len() => {
+            for (i, (old, new)) in b.iter().zip(a).enumerate() {
+                path.push(PathSegment::Index(i));
+                diff_values(old, new, path, set, unset);
+                path.pop();
+            }
+        }
+        _ => {
+            if before != after {
+                set.insert(mongo_path(path), after.clone());
+            }
+        }
+    }
+}
+
+fn mongo_path(segments: &[PathSegment]) -> String {
+    segments.iter().map(|s| match s {
+        PathSegment::Key(k) => k.clone(),
+        PathSegment::Index(i) => i.to_string(),
+    }).collect::<Vec<_>>().join(".")
+}
+
+fn check_key_names(value: &Value, path: &mut Vec<PathSegment>) -> Result<(), errors::Error> {
+    match value {
+        Value::Object(map) => {
+            for (k, v) in map {
+                path.push(PathSegment::Key(k.clone()));
+                if k.contains(['.', '$']) {
+                    return Err(errors::Error::InvalidKeyName(path::render_flat_key(path)));
+                }
+                check_key_names(v, path)?;
+                path.pop();
+            }
+        }
+        Value::Array(array) => {
+            for (i, v) in array.iter().enumerate() {
+                path.push(PathSegment::Index(i));
+                check_key_names(v, path)?;
+                path.pop();
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    #[test]
+    fn generating_mongo_updates() {
+        let before = json!({
+            "name": "John",
+            "address": { "city": "Turin", "zip": "10100" },
+            "tags": ["a", "b"],
+            "items": [{ "qty": 1 }, { "qty": 2 }],
+            "old": true
+        });
+        let after = json!({
+            "name": "John",
+            "address": { "city": "Milan", "zip": "10100" },
+            "tags": ["a"],
+            "items": [{ "qty": 1 }, { "qty": 3 }],
+            "new": { "x": 1 }
+        });
+
+        let update = to_mongo_update(&before, &after).unwrap();
+        let expected = json!({
+            "$set": {
+                "address.city": "Milan",
+                "tags": ["a"],
+                "items.1.qty": 3,
+                "new": { "x": 1 }
+            },
+            "$unset": { "old": "" }
+        });
+
+        println!(
+            "got:\n{}\nexpected:\n{}\n",
+            serde_json::to_string_pretty(&update).unwrap(),
+            serde_json::to_string_pretty(&expected).unwrap()
+        );
+
+        assert_eq!(update, expected);
+        assert_eq!(to_mongo_update(&after, &after).unwrap(), json!({}));
+    }
+
+    #[test]
+    fn rejecting_invalid_mongo_key_names() {
+        let before = json!({ "a": [{ "b": 1 }] });
+        let after = json!({ "a": [{ "b": 1, "$c": 2 }] });
+
+        assert_eq!(
+            to_mongo_update(&before, &after).err().unwrap().to_string(),
+            errors::Error::InvalidKeyName("a[0].$c".to_owned()).to_string()
+        );
+        assert!(to_mongo_update(&json!({ "x.y": 1 }), &json!({})).is_err());
+    }
+}
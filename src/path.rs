@@ -0,0 +1,423 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use crate::errors;
+
+
+/// A single component of a flat key: an object property or an array index.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum PathSegment {
+    Key(String),
+    Index(usize),
+}
+
+/// Parses a flat key (e.g. `a.b[0].c`) into its path segments.
+///
+/// Property names containing `.`, `[` or `]` can be written as quoted segments, `a["b.c"]` or `a['b.c']`,
+/// where a backslash escapes the next character.
+///
+/// # Arguments
+///
+/// * `key` - The flat key, as produced by `flatten`.
+///
+/// # Returns
+///
+/// A Result containing the list of segments (`Vec<PathSegment>`) or an error (`errors::Error`),
+/// `UnterminatedQuote` with the position of the opening quote when a quoted segment is not closed.
+///
+pub fn parse_flat_key(key: &str) -> Result<Vec<PathSegment>, errors::Error> {
+    let mut segments = Vec::new();
+    let mut rest = key;
+
+    while !rest.is_empty() {
+        if let Some(r) = rest.strip_prefix('[') {
+            if r.starts_with(['"', '\'']) {
+                let start = key.len() - r.len();
+                let (name, len) = parse_quoted(r).ok_or(errors::Error::UnterminatedQuote(start))?;
+                segments.push(PathSegment::Key(name));
+                rest = r[len..].strip_prefix(']').ok_or(errors::Error::InvalidProperty)?;
+                continue;
+            }
+            let end = r.find(']').ok_or(errors::Error::InvalidProperty)?;
+            let digits = &r[..end];
+            if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(errors::Error::InvalidProperty);
+            }
+            let index = digits.parse::<usize>().map_err(|_| errors::Error::InvalidProperty)?;
+            segments.push(PathSegment::Index(index));
+            rest = &r[end + 1..];
+        } else {
+            let r = if segments.is_empty() {
+                rest
+            } else {
+                rest.strip_prefix('.').ok_or(errors::Error::InvalidProperty)?
+            };
+            let end = r.find(['.', '[', ']']).unwrap_or(r.len());
+            if end == 0 {
+                return Err(errors::Error::InvalidProperty);
+            }
+            segments.push(PathSegment::Key(r[..end].to_owned()));
+            rest = &r[end..];
+        }
+    }
+
+    Ok(segments)
+}
+
+/// Parses a flat key written by hand, tolerating whitespace around names and indices (when `trim` is set) and
+/// empty segments such as `a..b` or `a.[0]` (when `collapse` is set).
+pub(crate) fn parse_flat_key_lenient(key: &str, trim: bool, collapse: bool) -> Result<Vec<PathSegment>, errors::Error> {
+    let clean = |s: &'_ str| if trim { s.trim().to_owned() } else { s.to_owned() };
+    let mut segments = Vec::new();
+    let mut rest = if trim { key.trim() } else { key };
+    // Whether a name is expected, at the start of the key or after a `.`.
+    let mut expect_name = true;
+
+    while !rest.is_empty() {
+        if let Some(r) = rest.strip_prefix('[') {
+            if expect_name && !segments.is_empty() && !collapse {
+                return Err(errors::Error::InvalidProperty);
+            }
+            if r.starts_with(['"', '\'']) {
+                let start = key.len() - r.len();
+                let (name, len) = parse_quoted(r).ok_or(errors::Error::UnterminatedQuote(start))?;
+                segments.push(PathSegment::Key(name));
+                rest = r[len..].strip_prefix(']').ok_or(errors::Error::InvalidProperty)?;
+            } else {
+                let end = r.find(']').ok_or(errors::Error::InvalidProperty)?;
+                let digits = clean(&r[..end]);
+                if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err(errors::Error::InvalidProperty);
+                }
+                segments.push(PathSegment::Index(digits.parse().map_err(|_| errors::Error::InvalidProperty)?));
+                rest = &r[end + 1..];
+            }
+            expect_name = false;
+        } else if let Some(r) = rest.strip_prefix('.') {
+            if expect_name && !collapse {
+                return Err(errors::Error::InvalidProperty);
+            }
+            rest = r;
+            expect_name = true;
+        } else if rest.starts_with(']') {
+            return Err(errors::Error::InvalidProperty);
+        } else {
+            let end = rest.find(['.', '[', ']']).unwrap_or(rest.len());
+            let name = clean(&rest[..end]);
+            if !name.is_empty() {
+                segments.push(PathSegment::Key(name));
+            } else if !collapse {
+                return Err(errors::Error::InvalidProperty);
+            }
+            rest = &rest[end..];
+            expect_name = false;
+        }
+    }
+    if expect_name && !segments.is_empty() && !collapse {
+        return Err(errors::Error::InvalidProperty);
+    }
+
+    Ok(segments)
+}
+
+/// Reads a quoted name at the start of `input`, returning it unescaped with the length consumed.
+fn parse_quoted(input: &str) -> Option<(String, usize)> {
+    let mut chars = input.char_indices();
+    let (_, quote) = chars.next()?;
+    let mut name = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => name.push(chars.next()?.1),
+            c if c == quote => return Some((name, i + c.len_utf8())),
+            c => name.push(c),
+        }
+    }
+    None
+}
+
+/// Returns whether a property name must be quoted to appear in a flat key.
+pub(crate) fn needs_quoting(name: &str) -> bool {
+    name.is_empty() || name.contains(['.', '[', ']'])
+}
+
+/// Renders a property name as a quoted segment (`["b.c"]`).
+pub(crate) fn quote_key(name: &str) -> String {
+    let escaped = name.replace('\\', "\\\\").replace('"', "\\\"");
+    format!("[\"{}\"]", escaped)
+}
+
+/// Renders path segments back into a flat key, the inverse of `parse_flat_key`.
+///
+/// Property names that cannot be written plainly are rendered as quoted segments.
+pub fn render_flat_key(segments: &[PathSegment]) -> String {
+    let mut key = String::new();
+    for segment in segments {
+        match segment {
+            PathSegment::Key(k) if needs_quoting(k) => {
+                key.push_str(&quote_key(k));
+            }
+            PathSegment::Key(k) => {
+                if !key.is_empty() {
+                    key.push('.');
+                }
+                key.push_str(k);
+            }
+            PathSegment::Index(i) => {
+                key.push_str(&format!("[{}]", i));
+            }
+        }
+    }
+    key
+}
+
+/// Splits a flat key into its components as plain strings, property names as they are and indices as their
+/// decimal representation (`a.b[0]` gives `["a", "b", "0"]`).
+///
+/// # Arguments
+///
+/// * `flat_key` - The flat key, as produced by `flatten`.
+///
+/// # Returns
+///
+/// A Result containing the components (`Vec<String>`) or an error (`errors::Error`), as for `parse_flat_key`.
+///
+pub fn flatten_path_segments(flat_key: &str) -> Result<Vec<String>, errors::Error> {
+    Ok(parse_flat_key(flat_key)?.into_iter()
+        .map(|segment| match segment {
+            PathSegment::Key(k) => k,
+            PathSegment::Index(i) => i.to_string(),
+        })
+        .collect())
+}
+
+/// Notation of the flat keys of a map, see `convert_keys`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyStyle {
+    /// `a.b[0].c`, the keys written by `flatten`, with property names written as they are.
+    #[default]
+    Dotted,
+    /// `a.b[0]["c.d"]`, the grammar of `parse_flat_key`, quoting the property names that need it.
+    Quoted,
+    /// `a.b.0.c`, indices written as dot-separated segments.
+    DotIndex,
+    /// `/a/b/0/c`, a JSON Pointer, escaping `~` as `~0` and `/` as `~1`.
+    Pointer,
+    /// `a_b_0_c`, the keys of python's `flatten_json`.
+    ///
+    /// The notation is lossy: property names are written as they are, so names containing `_` (`first_name`) are
+    /// read back as nested properties and names made of digits as indices, as `flatten_json` does.
+    UnderscoreIndex,
+}
+
+/// Converts the keys of a flat map from a notation to another, without unflattening it.
+///
+/// # Arguments
+///
+/// * `data` - The flattened JSON structure represented as a key-value map (`serde_json::Map<String, Value>`).
+/// * `from` - The notation of the keys of `data` (`KeyStyle`).
+/// * `to` - The notation of the keys of the result (`KeyStyle`).
+///
+/// # Returns
+///
+/// A Result containing the converted map (`serde_json::Map<String, Value>`) or an error (`errors::Error`):
+/// `InvalidKeyName` for a key that cannot be written in the target notation (e.g. a property name containing `.`
+/// in `Dotted`, or made of digits in `DotIndex` and `Pointer`, where it would read as an index), `DuplicateKey` if
+/// two keys are converted to the same key.
+///
+pub fn convert_keys(data: &Map<String, Value>, from: &KeyStyle, to: &KeyStyle) -> Result<Map<String, Value>, errors::Error> {
+    let mut converted = Map::new();
+    for (key, value) in data {
+        let segments = parse_key(key, *from)?;
+        let new_key = render_key(&segments, *to).ok_or_else(|| errors::Error::InvalidKeyName(key.clone()))?;
+        if converted.contains_key(&new_key) {
+            return Err(errors::Error::DuplicateKey(new_key));
+        }
+        converted.insert(new_key, value.clone());
+    }
+    Ok(converted)
+}
+
+fn parse_key(key: &str, style: KeyStyle) -> Result<Vec<PathSegment>, errors::Error> {
+    let parts: Vec<&str> = match style {
+        KeyStyle::Dotted | KeyStyle::Quoted => return parse_flat_key(key),
+        _ if key.is_empty() => return Ok(vec![]),
+        KeyStyle::DotIndex => key.split('.').collect(),
+        KeyStyle::UnderscoreIndex => key.split('_').collect(),
+        KeyStyle::Pointer => key.strip_prefix('/').ok_or(errors::Error::InvalidProperty)?.split('/').collect(),
+    };
+    parts.into_iter().map(|part| {
+        if part.is_empty() && style == KeyStyle::DotIndex {
+            return Err(errors::Error::InvalidProperty);
+        }
+        if is_index(part) {
+            return part.parse().map(PathSegment::Index).map_err(|_| errors::Error::InvalidProperty);
+        }
+        Ok(PathSegment::Key(match style {
+            KeyStyle::Pointer => part.replace("~1", "/").replace("~0", "~"),
+            _ => part.to_owned(),
+        }))
+    }).collect()
+}
+
+/// Renders path segments in a notation, or returns `None` if a property name cannot be written in it.
+fn render_key(segments: &[PathSegment], style: KeyStyle) -> Option<String> {
+    if style == KeyStyle::Quoted {
+        return Some(render_flat_key(segments));
+    }
+    let mut key = String::new();
+    for segment in segments {
+        match (style, segment) {
+            (KeyStyle::Dotted, PathSegment::Key(k)) if needs_quoting(k) => return None,
+            (KeyStyle::DotIndex, PathSegment::Key(k)) if k.is_empty() || k.contains('.') || is_index(k) => return None,
+            (KeyStyle::Pointer, PathSegment::Key(k)) if is_index(k) => return None,
+            (KeyStyle::Dotted | KeyStyle::DotIndex, PathSegment::Key(k)) => {
+                if !key.is_empty() {
+                    key.push('.');
+                }
+                key.push_str(k);
+            }
+            (KeyStyle::Dotted, PathSegment::Index(i)) => key.push_str(&format!("[{}]", i)),
+            (KeyStyle::DotIndex, PathSegment::Index(i)) => {
+                if !key.is_empty() {
+                    key.push('.');
+                }
+                key.push_str(&i.to_string());
+            }
+            (KeyStyle::Pointer, PathSegment::Key(k)) => {
+                key.push('/');
+                key.push_str(&k.replace('~', "~0").replace('/', "~1"));
+            }
+            (KeyStyle::Pointer, PathSegment::Index(i)) => key.push_str(&format!("/{}", i)),
+            (KeyStyle::UnderscoreIndex, segment) => {
+                if !key.is_empty() {
+                    key.push('_');
+                }
+                match segment {
+                    PathSegment::Key(k) => key.push_str(k),
+                    PathSegment::Index(i) => key.push_str(&i.to_string()),
+                }
+            }
+            (KeyStyle::Quoted, _) => unreachable!(),
+        }
+    }
+    Some(key)
+}
+
+/// Returns whether a segment of a `DotIndex` or `Pointer` key is an array index, written without leading zeros.
+fn is_index(part: &str) -> bool {
+    !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()) && (part == "0" || !part.starts_with('0'))
+}
+
+pub(crate) fn lookup<'a>(value: &'a Value, segments: &[PathSegment]) -> Option<&'a Value> {
+    segments.iter().try_fold(value, |cur, segment| match segment {
+        PathSegment::Key(k) => cur.as_object()?.get(k),
+        PathSegment::Index(i) => cur.as_array()?.get(*i),
+    })
+}
+
+pub(crate) fn lookup_mut<'a>(value: &'a mut Value, segments: &[PathSegment]) -> Option<&'a mut Value> {
+    segments.iter().try_fold(value, |cur, segment| match segment {
+        PathSegment::Key(k) => cur.as_object_mut()?.get_mut(k),
+        PathSegment::Index(i) => cur.as_array_mut()?.get_mut(*i),
+    })
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsing_flat_keys() {
+        let segments = parse_flat_key("a.b[0][12].c").unwrap();
+        assert_eq!(segments, vec![
+            PathSegment::Key("a".to_owned()),
+            PathSegment::Key("b".to_owned()),
+            PathSegment::Index(0),
+            PathSegment::Index(12),
+            PathSegment::Key("c".to_owned()),
+        ]);
+        assert_eq!(render_flat_key(&segments), "a.b[0][12].c");
+    }
+
+    #[test]
+    fn parsing_invalid_flat_keys() {
+        for key in ["a..b", "a[x]", "a[]", "a]b", ".a", "a[0]b", "a[", "a[\"b\"c", "a[\"b\"]c"] {
+            assert!(parse_flat_key(key).is_err(), "{} should not parse", key);
+        }
+    }
+
+    #[test]
+    fn parsing_quoted_segments() {
+        let segments = parse_flat_key(r#"a["b.c"]['d[0]'].e["x\"y"]"#).unwrap();
+        assert_eq!(segments, vec![
+            PathSegment::Key("a".to_owned()),
+            PathSegment::Key("b.c".to_owned()),
+            PathSegment::Key("d[0]".to_owned()),
+            PathSegment::Key("e".to_owned()),
+            PathSegment::Key("x\"y".to_owned()),
+        ]);
+        assert_eq!(render_flat_key(&segments), r#"a["b.c"]["d[0]"].e.x"y"#);
+        assert_eq!(parse_flat_key(&render_flat_key(&segments)).unwrap(), segments);
+
+        assert_eq!(
+            parse_flat_key(r#"a.b["c.d"#).err().unwrap().to_string(),
+            errors::Error::UnterminatedQuote(4).to_string()
+        );
+    }
+
+    #[test]
+    fn splitting_flat_keys_into_strings() {
+        assert_eq!(flatten_path_segments(r#"a.b[0]["c.d"][12]"#).unwrap(), vec!["a", "b", "0", "c.d", "12"]);
+        assert_eq!(flatten_path_segments("").unwrap(), Vec::<String>::new());
+        assert_eq!(flatten_path_segments("a[x]").err().unwrap().to_string(), errors::Error::InvalidProperty.to_string());
+    }
+
+    #[test]
+    fn converting_key_styles() {
+        let data: Map<String, Value> = serde_json::from_str(r#"{ "a.b[0].c": 1, "a.d": 2, "e~f/g": 3 }"#).unwrap();
+
+        let pointers = convert_keys(&data, &KeyStyle::Dotted, &KeyStyle::Pointer).unwrap();
+        assert_eq!(pointers.keys().collect::<Vec<_>>(), vec!["/a/b/0/c", "/a/d", "/e~0f~1g"]);
+        let dot_index = convert_keys(&pointers, &KeyStyle::Pointer, &KeyStyle::DotIndex).unwrap();
+        assert_eq!(dot_index.keys().collect::<Vec<_>>(), vec!["a.b.0.c", "a.d", "e~f/g"]);
+        assert_eq!(convert_keys(&dot_index, &KeyStyle::DotIndex, &KeyStyle::Dotted).unwrap(), data);
+
+        let data: Map<String, Value> = serde_json::from_str(r#"{ "a[\"b.c\"]": 1, "a['d']": 2 }"#).unwrap();
+        let quoted = convert_keys(&data, &KeyStyle::Quoted, &KeyStyle::Quoted).unwrap();
+        assert_eq!(quoted.keys().collect::<Vec<_>>(), vec!["a[\"b.c\"]", "a.d"]);
+        assert_eq!(
+            convert_keys(&data, &KeyStyle::Quoted, &KeyStyle::Dotted).err().unwrap().to_string(),
+            errors::Error::InvalidKeyName("a[\"b.c\"]".to_owned()).to_string()
+        );
+        assert_eq!(
+            convert_keys(&data, &KeyStyle::Quoted, &KeyStyle::DotIndex).err().unwrap().to_string(),
+            errors::Error::InvalidKeyName("a[\"b.c\"]".to_owned()).to_string()
+        );
+
+        let data: Map<String, Value> = serde_json::from_str(r#"{ "a[0]": 1, "a[00]": 2, "/b/07": 3 }"#).unwrap();
+        assert_eq!(
+            convert_keys(&data, &KeyStyle::Dotted, &KeyStyle::Pointer).err().unwrap().to_string(),
+            errors::Error::DuplicateKey("/a/0".to_owned()).to_string()
+        );
+        let data: Map<String, Value> = serde_json::from_str(r#"{ "/b/07": 3, "/c/1": 4 }"#).unwrap();
+        assert_eq!(convert_keys(&data, &KeyStyle::Pointer, &KeyStyle::Dotted).unwrap().keys().collect::<Vec<_>>(), vec!["b.07", "c[1]"]);
+    }
+}
@@ -0,0 +1,262 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+
+use std::fmt;
+use std::str::FromStr;
+use crate::errors;
+use crate::path::{self, PathSegment};
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Literal(PathSegment),
+    AnySegment,
+    AnyIndex,
+    AnyDepth,
+}
+
+/// A compiled glob over flat keys.
+///
+/// Patterns use the flat key syntax, where `*` matches any single segment, `[*]` any single array index
+/// and `**` any number of segments (including none), e.g. `user.*.name`, `items[*].id` or `**.updated_at`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathPattern {
+    source: String,
+    tokens: Vec<Token>,
+}
+
+impl PathPattern {
+    /// Compiles a glob pattern.
+    ///
+    /// # Arguments
+    ///
+    /// * `pattern` - The glob pattern.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the compiled pattern (`PathPattern`) or an error (`errors::Error`).
+    ///
+    pub fn compile(pattern: &str) -> Result<PathPattern, errors::Error> {
+        let mut tokens = Vec::new();
+        let mut rest = pattern;
+
+        while !rest.is_empty() {
+            if let Some(r) = rest.strip_prefix('[') {
+                let end = r.find(']').ok_or(errors::Error::InvalidPattern)?;
+                let token = match &r[..end] {
+                    "*" => Token::AnyIndex,
+                    digits => match path::parse_flat_key(&format!("[{}]", digits)) {
+                        Ok(mut segments) if segments.len() == 1 => Token::Literal(segments.remove(0)),
+                        _ => return Err(errors::Error::InvalidPattern),
+                    },
+                };
+                tokens.push(token);
+                rest = &r[end + 1..];
+            } else {
+                let r = if tokens.is_empty() {
+                    rest
+                } else {
+                    rest.strip_prefix('.').ok_or(errors::Error::InvalidPattern)?
+                };
+                let end = r.find(['.', '[', ']']).unwrap_or(r.len());
+                let token = match &r[..end] {
+                    "" => return Err(errors::Error::InvalidPattern),
+                    "*" => Token::AnySegment,
+                    "**" => Token::AnyDepth,
+                    name if name.contains('*') => return Err(errors::Error::InvalidPattern),
+                    name => Token::Literal(PathSegment::Key(name.to_owned())),
+                };
+                tokens.push(token);
+                rest = &r[end..];
+            }
+        }
+
+        Ok(PathPattern { source: pattern.to_owned(), tokens })
+    }
+
+    /// Checks whether the pattern matches a parsed flat key.
+    pub fn matches(&self, segments: &[PathSegment]) -> bool {
+        match_tokens(&self.tokens, segments, false)
+    }
+
+    /// Checks whether the pattern could match the given path or any path below it.
+    pub fn matches_prefix(&self, segments: &[PathSegment]) -> bool {
+        match_tokens(&self.tokens, segments, true)
+    }
+
+    /// Returns the indices matched by the `[*]` wildcards in a parsed flat key, in order, if the pattern matches it.
+    pub(crate) fn captured_indices(&self, segments: &[PathSegment]) -> Option<Vec<usize>> {
+        let mut captured = Vec::new();
+        capture_tokens(&self.tokens, segments, &mut captured).then_some(captured)
+    }
+
+    /// Returns the path obtained by replacing the `[*]` wildcards with `indices`, in order, if the pattern has no
+    /// other wildcards and `indices` covers all of them.
+    pub(crate) fn bind_indices(&self, indices: &[usize]) -> Option<Vec<PathSegment>> {
+        let mut indices = indices.iter();
+        self.tokens.iter().map(|token| match token {
+            Token::Literal(segment) => Some(segment.clone()),
+            Token::AnyIndex => indices.next().map(|i| PathSegment::Index(*i)),
+            Token::AnySegment | Token::AnyDepth => None,
+        }).collect()
+    }
+
+    /// Returns the pattern source.
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+}
+
+impl FromStr for PathPattern {
+    type Err = errors::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        PathPattern::compile(s)
+    }
+}
+
+impl fmt::Display for PathPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+/// Checks whether a flat key matches a compiled glob pattern.
+///
+/// # Arguments
+///
+/// * `key` - The flat key (e.g. `a.b[0].c`).
+/// * `pattern` - The compiled glob pattern (`PathPattern`).
+///
+/// # Returns
+///
+/// `true` if the key is valid and matches the pattern, `false` otherwise.
+///
+pub fn flat_key_matches(key: &str, pattern: &PathPattern) -> bool {
+    path::parse_flat_key(key).is_ok_and(|segments| pattern.matches(&segments))
+}
+
+fn match_tokens(tokens: &[Token], segments: &[PathSegment], prefix: bool) -> bool {
+    if prefix && segments.is_empty() {
+        return true;
+    }
+    match tokens.split_first() {
+        None => segments.is_empty(),
+        Some((Token::AnyDepth, rest)) => (0..=segments.len()).any(|skip| match_tokens(rest, &segments[skip..], prefix)),
+        Some((token, rest)) => match segments.split_first() {
+            Some((segment, segments_rest)) => {
+                let matches = match token {
+                    Token::Literal(literal) => literal == segment,
+                    Token::AnySegment => true,
+                    Token::AnyIndex => matches!(segment, PathSegment::Index(_)),
+                    Token::AnyDepth => unreachable!(),
+                };
+                matches && match_tokens(rest, segments_rest, prefix)
+            }
+            None => false,
+        },
+    }
+}
+
+fn capture_tokens(tokens: &[Token], segments: &[PathSegment], captured: &mut Vec<usize>) -> bool {
+    match tokens.split_first() {
+        None => segments.is_empty(),
+        Some((Token::AnyDepth, rest)) => (0..=segments.len()).any(|skip| capture_tokens(rest, &segments[skip..], captured)),
+        Some((token, rest)) => match segments.split_first() {
+            Some((segment, segments_rest)) => {
+                let len = captured.len();
+                let matches = match (token, segment) {
+                    (Token::Literal(literal), segment) => literal == segment,
+                    (Token::AnySegment, _) => true,
+                    (Token::AnyIndex, PathSegment::Index(i)) => {
+                        captured.push(*i);
+                        true
+                    }
+                    _ => false,
+                };
+                if matches && capture_tokens(rest, segments_rest, captured) {
+                    return true;
+                }
+                captured.truncate(len);
+                false
+            }
+            None => false,
+        },
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matching_literal_patterns() {
+        let pattern = PathPattern::compile("meta.request_id").unwrap();
+        assert!(flat_key_matches("meta.request_id", &pattern));
+        assert!(!flat_key_matches("meta.request_id.x", &pattern));
+        assert!(!flat_key_matches("meta", &pattern));
+
+        let pattern = PathPattern::compile("items[1].id").unwrap();
+        assert!(flat_key_matches("items[1].id", &pattern));
+        assert!(!flat_key_matches("items[0].id", &pattern));
+    }
+
+    #[test]
+    fn matching_wildcard_patterns() {
+        let pattern = PathPattern::compile("user.*.name").unwrap();
+        assert!(flat_key_matches("user.a.name", &pattern));
+        assert!(flat_key_matches("user[3].name", &pattern));
+        assert!(!flat_key_matches("user.name", &pattern));
+
+        let pattern = PathPattern::compile("items[*].id").unwrap();
+        assert!(flat_key_matches("items[0].id", &pattern));
+        assert!(!flat_key_matches("items.x.id", &pattern));
+
+        let pattern = PathPattern::compile("**.updated_at").unwrap();
+        assert!(flat_key_matches("updated_at", &pattern));
+        assert!(flat_key_matches("a.b[2].updated_at", &pattern));
+        assert!(!flat_key_matches("a.updated_at.x", &pattern));
+
+        let pattern = PathPattern::compile("a.**").unwrap();
+        assert!(flat_key_matches("a", &pattern));
+        assert!(flat_key_matches("a.b[0]", &pattern));
+        assert!(!flat_key_matches("b.a", &pattern));
+    }
+
+    #[test]
+    fn matching_pattern_prefixes() {
+        let pattern = PathPattern::compile("a.*.c").unwrap();
+        assert!(pattern.matches_prefix(&[]));
+        assert!(pattern.matches_prefix(&[PathSegment::Key("a".to_owned())]));
+        assert!(pattern.matches_prefix(&[PathSegment::Key("a".to_owned()), PathSegment::Index(1)]));
+        assert!(!pattern.matches_prefix(&[PathSegment::Key("b".to_owned())]));
+
+        let pattern = PathPattern::compile("**.id").unwrap();
+        assert!(pattern.matches_prefix(&[PathSegment::Key("x".to_owned()), PathSegment::Index(1)]));
+    }
+
+    #[test]
+    fn compiling_invalid_patterns() {
+        for pattern in ["a..b", "a[x]", "a*", "a[", "[*", ".a"] {
+            assert_eq!(
+                PathPattern::compile(pattern).err().unwrap().to_string(),
+                errors::Error::InvalidPattern.to_string()
+            );
+        }
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use serde_json::Value;
+use crate::errors;
+
+
+/// Counters of a running flatten or unflatten operation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Progress {
+    /// Number of leaves processed so far.
+    pub leaves: usize,
+    /// Size of the processed leaves as JSON text, in bytes.
+    pub bytes: usize,
+}
+
+/// Callback receiving the `Progress` of an operation every `every` leaves, and once more when it completes.
+#[derive(Clone)]
+pub struct ProgressHook {
+    every: usize,
+    callback: Arc<Mutex<dyn FnMut(Progress) + Send>>,
+}
+
+impl ProgressHook {
+    pub fn new(every: usize, callback: impl FnMut(Progress) + Send + 'static) -> Self {
+        ProgressHook { every: every.max(1), callback: Arc::new(Mutex::new(callback)) }
+    }
+
+    fn report(&self, progress: Progress) {
+        if let Ok(mut callback) = self.callback.lock() {
+            callback(progress);
+        }
+    }
+}
+
+impl fmt::Debug for ProgressHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProgressHook").field("every", &self.every).finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for ProgressHook {
+    fn eq(&self, other: &Self) -> bool {
+        self.every == other.every && Arc::ptr_eq(&self.callback, &other.callback)
+    }
+}
+
+/// Flag shared with a running operation, which aborts with `Error::Cancelled` once it is set.
+///
+/// The flag is checked before each leaf is emitted, so a sink that was being written has received complete
+/// entries only: every leaf preceding the cancellation, in document order, and nothing after it.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl From<Arc<AtomicBool>> for CancelToken {
+    fn from(flag: Arc<AtomicBool>) -> Self {
+        CancelToken(flag)
+    }
+}
+
+impl PartialEq for CancelToken {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// Applies the hooks of an operation to each leaf, doing nothing when none are set.
+#[derive(Default)]
+pub(crate) struct Tracker<'a> {
+    progress: Option<&'a ProgressHook>,
+    cancel: Option<&'a CancelToken>,
+    current: Progress,
+}
+
+impl<'a> Tracker<'a> {
+    pub(crate) fn new(progress: Option<&'a ProgressHook>, cancel: Option<&'a CancelToken>) -> Self {
+        Tracker { progress, cancel, current: Progress::default() }
+    }
+
+    pub(crate) fn leaf(&mut self, value: &Value) -> Result<(), errors::Error> {
+        if self.progress.is_none() && self.cancel.is_none() {
+            return Ok(());
+        }
+        if self.cancel.is_some_and(CancelToken::is_cancelled) {
+            return Err(errors::Error::Cancelled);
+        }
+        if let Some(progress) = self.progress {
+            self.current.leaves += 1;
+            self.current.bytes += match value {
+                Value::String(s) => s.len() + 2,
+                _ => value.to_string().len(),
+            };
+            if self.current.leaves.is_multiple_of(progress.every) {
+                progress.report(self.current);
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn finish(&self) {
+        if let Some(progress) = self.progress {
+            if !self.current.leaves.is_multiple_of(progress.every) {
+                progress.report(self.current);
+            }
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use crate::config::{Config, flatten_with, unflatten_with};
+    use crate::flattener::Flattener;
+    use crate::flattening::FlattenOptions;
+    use crate::unflattening::UnflattenOptions;
+    use super::*;
+
+    #[test]
+    fn reporting_progress() {
+        let reports = Arc::new(Mutex::new(vec![]));
+        let collected = reports.clone();
+        let hook = ProgressHook::new(2, move |progress| collected.lock().unwrap().push(progress));
+        let config = Config {
+            flatten: FlattenOptions::new().progress(hook.clone()),
+            unflatten: UnflattenOptions::new().progress(hook),
+            ..Default::default()
+        };
+        let json = json!({ "a": "bc", "d": [1, null] });
+
+        let flat = flatten_with(&json, &config).unwrap();
+        assert_eq!(*reports.lock().unwrap(), vec![
+            Progress { leaves: 2, bytes: 5 },
+            Progress { leaves: 3, bytes: 9 },
+        ]);
+
+        reports.lock().unwrap().clear();
+        assert_eq!(unflatten_with(&flat, &config).unwrap(), json);
+        assert_eq!(reports.lock().unwrap().last(), Some(&Progress { leaves: 3, bytes: 9 }));
+    }
+
+    #[test]
+    fn cancelling_operations() {
+        let cancel = CancelToken::new();
+        let stop = cancel.clone();
+        let hook = ProgressHook::new(2, move |_| stop.cancel());
+        let flattener = Flattener::new(FlattenOptions::new().progress(hook).cancel(cancel.clone()));
+
+        let mut sink = vec![];
+        let err = flattener.flatten_into(&json!({ "a": 1, "b": 2, "c": 3 }), &mut sink).err().unwrap();
+        assert_eq!(err.to_string(), errors::Error::Cancelled.to_string());
+        assert_eq!(sink, vec![("a".to_owned(), json!(1)), ("b".to_owned(), json!(2))]);
+
+        let config = Config { unflatten: UnflattenOptions::new().cancel(cancel), ..Default::default() };
+        let flat = json!({ "a": 1 }).as_object().unwrap().clone();
+        assert_eq!(unflatten_with(&flat, &config).err().unwrap().to_string(), errors::Error::Cancelled.to_string());
+    }
+}
@@ -0,0 +1,237 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+
+use std::io::{BufRead, Write};
+use serde_json::{Map, Value};
+use crate::errors;
+use crate::flattening::flatten;
+use crate::unflattening::unflatten;
+
+
+/// Prefix of the comments giving the JSON type of the following entry.
+const TYPE_COMMENT: &str = "# type: ";
+
+/// Options for writing `.properties` files.
+#[derive(Debug, Clone, Default)]
+pub struct PropertiesOptions {
+    /// Emits a `# type: <type>` comment above each entry, so that the JSON types are restored when reading.
+    pub include_type_comments: bool,
+}
+
+/// Flattens a JSON Value into a Java `.properties` file, one `key=value` entry per leaf.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be flattened (`serde_json::Value`).
+/// * `writer` - The destination of the properties.
+/// * `opts` - The writing options (`PropertiesOptions`).
+///
+/// # Returns
+///
+/// A Result that is an error (`errors::Error`) if the value cannot be flattened, or `Io` if writing failed.
+///
+pub fn flatten_to_properties(value: &Value, writer: &mut impl Write, opts: &PropertiesOptions) -> Result<(), errors::Error> {
+    for (key, leaf) in flatten(value)? {
+        if opts.include_type_comments {
+            writeln!(writer, "{}{}", TYPE_COMMENT, type_name(&leaf))?;
+        }
+        let text = match leaf {
+            Value::String(s) => s,
+            leaf => leaf.to_string(),
+        };
+        writeln!(writer, "{}={}", escape(&key, true), escape(&text, false))?;
+    }
+    Ok(())
+}
+
+/// Reads a Java `.properties` file and unflattens its entries.
+///
+/// Values are read as strings, unless preceded by a type comment written by `flatten_to_properties`.
+///
+/// # Arguments
+///
+/// * `reader` - The source of the properties.
+///
+/// # Returns
+///
+/// A Result containing the reconstructed JSON object (`serde_json::Value`) or an error (`errors::Error`),
+/// `FormatError` if a value does not match its type comment, or `Io` if reading failed.
+///
+pub fn unflatten_from_properties(reader: &mut impl BufRead) -> Result<Value, errors::Error> {
+    let mut flat = Map::new();
+    let mut declared_type = None;
+    let mut lines = reader.lines();
+
+    while let Some(line) = lines.next() {
+        let mut line = line?.trim_start().to_owned();
+        if let Some(name) = line.strip_prefix(TYPE_COMMENT) {
+            declared_type = Some(name.trim().to_owned());
+            continue;
+        }
+        if line.is_empty() || line.starts_with(['#', '!']) {
+            continue;
+        }
+        // A line ending with an odd number of backslashes continues on the next one.
+        while line.chars().rev().take_while(|c| *c == '\\').count() % 2 == 1 {
+            line.pop();
+            match lines.next() {
+                Some(next) => line.push_str(next?.trim_start()),
+                None => break,
+            }
+        }
+
+        let (key, text) = split_entry(&line);
+        let text = unescape(text);
+        let value = match declared_type.take().as_deref() {
+            None | Some("string") => Value::String(text),
+            Some("null") if text == "null" => Value::Null,
+            Some("boolean" | "number") => match serde_json::from_str::<Value>(&text) {
+                Ok(value) if value.is_boolean() || value.is_number() => value,
+                _ => return Err(errors::Error::FormatError),
+            },
+            _ => return Err(errors::Error::FormatError),
+        };
+        flat.insert(unescape(key), value);
+    }
+
+    unflatten(&flat)
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::String(_) => "string",
+        Value::Number(_) => "number",
+        Value::Bool(_) => "boolean",
+        Value::Null => "null",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn escape(s: &str, key: bool) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for (i, c) in s.chars().enumerate() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            '=' | ':' | ' ' if key => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '#' | '!' | ' ' if i == 0 => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Splits an entry at the first unescaped `=` or `:`, trimming the whitespace around it.
+fn split_entry(line: &str) -> (&str, &str) {
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '=' | ':' => return (line[..i].trim_end(), line[i + 1..].trim_start()),
+            _ => {}
+        }
+    }
+    (line.trim_end(), "")
+}
+
+fn unescape(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some('t') => unescaped.push('\t'),
+            Some('u') => {
+                let code: String = chars.by_ref().take(4).collect();
+                if let Some(c) = u32::from_str_radix(&code, 16).ok().and_then(char::from_u32) {
+                    unescaped.push(c);
+                }
+            }
+            Some(c) => unescaped.push(c),
+            None => {}
+        }
+    }
+    unescaped
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    #[test]
+    fn flattening_to_properties() {
+        let json = json!({ "db": { "host": "localhost", "port": 5432 }, "a=b": " x\ny", "flags": [true, null] });
+
+        let mut output = vec![];
+        flatten_to_properties(&json, &mut output, &PropertiesOptions::default()).unwrap();
+        let properties = String::from_utf8(output).unwrap();
+        let expected = "db.host=localhost\ndb.port=5432\na\\=b=\\ x\\ny\nflags[0]=true\nflags[1]=null\n";
+
+        println!("got:\n{}\nexpected:\n{}\n", properties, expected);
+
+        assert_eq!(properties, expected);
+        assert_eq!(
+            unflatten_from_properties(&mut properties.as_bytes()).unwrap(),
+            json!({ "db": { "host": "localhost", "port": "5432" }, "a=b": " x\ny", "flags": ["true", "null"] })
+        );
+    }
+
+    #[test]
+    fn roundtripping_properties_with_type_comments() {
+        let json = json!({ "id": "123", "count": 123, "ratio": -1.5, "ok": false, "none": null });
+
+        let mut output = vec![];
+        let opts = PropertiesOptions { include_type_comments: true };
+        flatten_to_properties(&json, &mut output, &opts).unwrap();
+        let properties = String::from_utf8(output).unwrap();
+        assert!(properties.starts_with("# type: string\nid=123\n# type: number\ncount=123\n"));
+
+        assert_eq!(unflatten_from_properties(&mut properties.as_bytes()).unwrap(), json);
+    }
+
+    #[test]
+    fn unflattening_from_properties() {
+        let input = "! comment\n# comment\n\n  a.b : 1\na.c=multi \\\n    line\n# type: number\nd=x\n";
+        assert_eq!(
+            unflatten_from_properties(&mut input.as_bytes()).err().unwrap().to_string(),
+            errors::Error::FormatError.to_string()
+        );
+
+        let input = &input[..input.find("# type").unwrap()];
+        assert_eq!(
+            unflatten_from_properties(&mut input.as_bytes()).unwrap(),
+            json!({ "a": { "b": "1", "c": "multi line" } })
+        );
+    }
+}
@@ -0,0 +1,204 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+
+use serde_json::{Map, Value};
+use crate::compare::{self, CompareOptions};
+use crate::flattener::Flattener;
+use crate::flattening::FlattenOptions;
+use crate::path::{self, PathSegment};
+
+
+/// A problem found while checking a round trip.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RoundtripIssue {
+    /// A step of the round trip failed with the given error message.
+    Failed(String),
+    /// The result differs from the input, first at the flat key `path`.
+    Diverged {
+        path: String,
+        expected: Option<Value>,
+        actual: Option<Value>,
+    },
+}
+
+/// The outcome of `check_roundtrip`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RoundtripReport {
+    /// Issue found by flattening and then unflattening the input.
+    pub forward: Option<RoundtripIssue>,
+    /// Issue found by unflattening and then flattening the input, checked only when the input is a flat map.
+    pub reverse: Option<RoundtripIssue>,
+}
+
+impl RoundtripReport {
+    pub fn is_ok(&self) -> bool {
+        self.forward.is_none() && self.reverse.is_none()
+    }
+}
+
+/// Checks that a JSON Value survives flattening and unflattening unchanged.
+///
+/// When the input is a flat map (an object with no nested objects or arrays), the reverse round trip
+/// (unflatten, then flatten) is checked as well.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to check (`serde_json::Value`).
+/// * `opts` - The options used for flattening, unflattening with the matching options (see
+///   `flattener::unflatten_options_for`) (`FlattenOptions`).
+///
+/// # Returns
+///
+/// A report (`RoundtripReport`) describing the first divergence of each direction, if any.
+///
+pub fn check_roundtrip(value: &Value, opts: &FlattenOptions) -> RoundtripReport {
+    let flattener = Flattener::new(opts.clone());
+
+    let forward = match flattener.flatten(value).and_then(|flat| flattener.unflatten(&flat)) {
+        Ok(actual) => first_divergence(value, &actual),
+        Err(e) => Some(RoundtripIssue::Failed(e.to_string())),
+    };
+
+    let reverse = match value {
+        Value::Object(map) if is_flat(map) => match flattener.unflatten(map).and_then(|nested| flattener.flatten(&nested)) {
+            Ok(actual) => first_divergence(value, &Value::Object(actual)),
+            Err(e) => Some(RoundtripIssue::Failed(e.to_string())),
+        },
+        _ => None,
+    };
+
+    RoundtripReport { forward, reverse }
+}
+
+fn first_divergence(expected: &Value, actual: &Value) -> Option<RoundtripIssue> {
+    if expected == actual {
+        return None;
+    }
+
+    let diff = compare::diff(expected, actual, &CompareOptions::new());
+    let order = crate::flattening::flatten(expected).unwrap_or_default();
+    let left_key = order.keys().find(|k| diff.left_only.contains_key(*k) || diff.changed.iter().any(|(c, _, _)| c == *k));
+
+    let path = match (left_key, diff.right_only.keys().next()) {
+        (Some(key), _) | (None, Some(key)) => key.clone(),
+        (None, None) => structural_divergence(expected, actual, &mut vec![]).map(|p| path::render_flat_key(&p)).unwrap_or_default(),
+    };
+
+    let lookup = |value: &Value| value.get(&path).cloned().or_else(|| {
+        path::parse_flat_key(&path).ok().and_then(|segments| path::lookup(value, &segments).cloned())
+    });
+    Some(RoundtripIssue::Diverged { expected: lookup(expected), actual: lookup(actual), path })
+}
+
+/// Finds differences the flat representation cannot show, such as empty containers.
+fn structural_divergence(expected: &Value, actual: &Value, path: &mut Vec<PathSegment>) -> Option<Vec<PathSegment>> {
+    let child = |segment: PathSegment, e: Option<&Value>, a: Option<&Value>, path: &mut Vec<PathSegment>| {
+        path.push(segment);
+        let found = match (e, a) {
+            (Some(e), Some(a)) => structural_divergence(e, a, path),
+            (None, None) => None,
+            _ => Some(path.clone()),
+        };
+        path.pop();
+        found
+    };
+
+    match (expected, actual) {
+        (Value::Object(e), Value::Object(a)) => e.keys().chain(a.keys())
+            .find_map(|k| child(PathSegment::Key(k.clone()), e.get(k), a.get(k), path)),
+        (Value::Array(e), Value::Array(a)) => (0..e.len().max(a.len()))
+            .find_map(|i| child(PathSegment::Index(i), e.get(i), a.get(i), path)),
+        (e, a) if e == a => None,
+        _ => Some(path.clone()),
+    }
+}
+
+fn is_flat(map: &Map<String, Value>) -> bool {
+    map.values().all(|v| !v.is_object() && !v.is_array())
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    #[test]
+    fn checking_successful_roundtrips() {
+        let report = check_roundtrip(&json!({ "a": { "b": [1, { "c": null }] } }), &FlattenOptions::default());
+        assert!(report.is_ok());
+
+        let report = check_roundtrip(&json!({ "a.b": 1, "a.c[0]": true }), &FlattenOptions::default());
+        assert!(report.reverse.is_none());
+
+        let report = check_roundtrip(&json!([{ "name": "a" }, [1, { "b": null }]]), &FlattenOptions::default());
+        assert!(report.is_ok());
+
+        let report = check_roundtrip(&json!({ "[0].name": "a", "[1]": 2 }), &FlattenOptions::default());
+        assert!(report.reverse.is_none());
+    }
+
+    #[test]
+    fn checking_diverging_roundtrips() {
+        let report = check_roundtrip(&json!({ "a": { "b": 1, "c": {} } }), &FlattenOptions::default());
+        assert_eq!(report.forward, Some(RoundtripIssue::Diverged {
+            path: "a.c".to_owned(),
+            expected: Some(json!({})),
+            actual: None,
+        }));
+
+        let report = check_roundtrip(&json!({ "a[1]": 1 }), &FlattenOptions::default());
+        assert_eq!(report.reverse, Some(RoundtripIssue::Diverged {
+            path: "a[1]".to_owned(),
+            expected: Some(json!(1)),
+            actual: None,
+        }));
+
+        let report = check_roundtrip(&json!({ "a.b": 1, "a[0]": 2 }), &FlattenOptions::default());
+        assert_eq!(report.reverse, Some(RoundtripIssue::Failed(crate::errors::Error::FormatError.to_string())));
+
+        let report = check_roundtrip(&json!({ "x": { "a.b": 1 } }), &FlattenOptions::default());
+        assert!(matches!(report.forward, Some(RoundtripIssue::Diverged { path, .. }) if path == "x[\"a.b\"]"));
+        assert!(check_roundtrip(&json!({ "x": { "a.b": 1 } }), &FlattenOptions::new().quote_keys(true)).is_ok());
+
+        let report = check_roundtrip(&json!({ "a.b": 1 }), &FlattenOptions::default());
+        assert!(matches!(report.forward, Some(RoundtripIssue::Diverged { path, .. }) if path == "[\"a.b\"]"));
+        assert!(check_roundtrip(&json!({ "a.b": 1 }), &FlattenOptions::new().quote_keys(true)).is_ok());
+
+        let offset = FlattenOptions::new().array_index_offset(1);
+        assert!(check_roundtrip(&json!({ "a": [1, 2, [3, { "b": [4] }]] }), &offset).is_ok());
+        assert!(check_roundtrip(&json!({ "a[1]": 1, "a[2].b": 2 }), &offset).reverse.is_none());
+        let report = check_roundtrip(&json!({ "a[2]": 1 }), &offset);
+        assert_eq!(report.reverse, Some(RoundtripIssue::Diverged {
+            path: "a[2]".to_owned(),
+            expected: Some(json!(1)),
+            actual: None,
+        }));
+        let report = check_roundtrip(&json!({ "a[0]": 1 }), &offset);
+        assert_eq!(report.reverse, Some(RoundtripIssue::Failed(crate::errors::Error::FormatError.to_string())));
+
+        let report = check_roundtrip(&json!({ "a": [1], "b": [2, 3] }), &FlattenOptions::new().single_element_arrays_as_scalar(true));
+        assert_eq!(report.forward, Some(RoundtripIssue::Diverged {
+            path: "a[0]".to_owned(),
+            expected: Some(json!(1)),
+            actual: None,
+        }));
+
+        let report = check_roundtrip(&json!(1), &FlattenOptions::default());
+        assert_eq!(report.forward, Some(RoundtripIssue::Failed(crate::errors::Error::NotAnObject.to_string())));
+    }
+}
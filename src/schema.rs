@@ -0,0 +1,297 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+
+use serde_json::{Map, Value, json};
+use crate::codegen::Shape;
+use crate::errors;
+use crate::flattening::flatten;
+use crate::path::{self, PathSegment};
+
+
+/// Schema leaf value marking a field as required.
+pub const REQUIRED: &str = "required";
+
+/// Flattens only the paths of a JSON Value that are described by a schema.
+///
+/// The schema is a JSON document with the same shape as the expected values: its flat keys form the allow-list,
+/// where array indices match any index (`items[0].id` allows `items[3].id`). A leaf set to `"required"` marks a
+/// field that must be present, any other leaf value marks an optional field.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be flattened (`serde_json::Value`).
+/// * `schema` - The schema describing the allowed fields (`serde_json::Value`).
+///
+/// # Returns
+///
+/// A Result containing the projected flat structure (`serde_json::Map<String, Value>`) or an error (`errors::Error`),
+/// `SchemaValidationFailed` naming the first missing required field.
+///
+pub fn flatten_intersect_schema(value: &Value, schema: &Value) -> Result<Map<String, Value>, errors::Error> {
+    let fields = flatten(schema)?.into_iter()
+        .map(|(key, marker)| Ok((path::parse_flat_key(&key)?, key, marker.as_str() == Some(REQUIRED))))
+        .collect::<Result<Vec<(Vec<PathSegment>, String, bool)>, errors::Error>>()?;
+
+    let mut found = vec![false; fields.len()];
+    let mut projected = Map::new();
+
+    for (key, leaf) in flatten(value)? {
+        let segments = match path::parse_flat_key(&key) {
+            Ok(segments) => segments,
+            Err(_) => continue,
+        };
+        let mut allowed = false;
+        for (i, (field, _, _)) in fields.iter().enumerate() {
+            if schema_field_matches(field, &segments) {
+                found[i] = true;
+                allowed = true;
+            }
+        }
+        if allowed {
+            projected.insert(key, leaf);
+        }
+    }
+
+    if let Some(((_, key, _), _)) = fields.iter().zip(found).find(|((_, _, required), found)| *required && !found) {
+        return Err(errors::Error::SchemaValidationFailed(key.clone()));
+    }
+
+    Ok(projected)
+}
+
+/// Flattens a JSON Value, checking that all the required paths are present.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be flattened (`serde_json::Value`).
+/// * `required_paths` - The flat keys that must be present, in the syntax produced by `flatten` (e.g. `db.hosts[0]`).
+///
+/// # Returns
+///
+/// A Result containing a flattened JSON structure (`serde_json::Map<String, Value>`) or an error (`errors::Error`),
+/// `RequiredPathMissing` naming the first required path that is missing.
+///
+pub fn flatten_and_validate_required(value: &Value, required_paths: &[&str]) -> Result<Map<String, Value>, errors::Error> {
+    let flat = flatten(value)?;
+    if let Some(missing) = required_paths.iter().find(|path| !flat.contains_key(**path)) {
+        return Err(errors::Error::RequiredPathMissing(missing.to_string()));
+    }
+    Ok(flat)
+}
+
+/// Infers a JSON Schema (draft-07) describing the structure of sample documents.
+///
+/// An array is taken as a list of documents, the schema accepting the structure of each of them: properties missing
+/// from some documents are not `required` and values of different types are combined with `anyOf`.
+///
+/// # Arguments
+///
+/// * `value` - The sample document, or an array of sample documents (`serde_json::Value`).
+///
+/// # Returns
+///
+/// A Result containing the schema (`serde_json::Value`) or an error (`errors::Error`), `NotAnObject` if a document
+/// is neither an object nor an array.
+///
+pub fn flatten_to_json_schema(value: &Value) -> Result<Value, errors::Error> {
+    let shape = match value {
+        Value::Array(documents) => documents.iter()
+            .try_fold(Shape::Unknown, |shape, document| Ok::<Shape, errors::Error>(shape.merge(Shape::of_document(document)?)))?,
+        document => Shape::of_document(document)?,
+    };
+    let mut schema = json!({ "$schema": "http://json-schema.org/draft-07/schema#" });
+    if let (Value::Object(schema), Value::Object(inferred)) = (&mut schema, json_schema(&shape)) {
+        schema.extend(inferred);
+    }
+    Ok(schema)
+}
+
+fn json_schema(shape: &Shape) -> Value {
+    match shape {
+        Shape::Unknown => json!({}),
+        Shape::Array(items) => json!({ "type": "array", "items": json_schema(items) }),
+        Shape::Object(fields) => {
+            let properties: Map<String, Value> = fields.iter()
+                .map(|field| (field.name.clone(), json_schema(&field.shape)))
+                .collect();
+            let required: Vec<&str> = fields.iter().filter(|field| !field.optional).map(|field| field.name.as_str()).collect();
+            let mut schema = json!({ "type": "object", "properties": properties });
+            if !required.is_empty() {
+                schema["required"] = json!(required);
+            }
+            schema
+        }
+        Shape::Union(members) => match members.iter().map(type_name).collect::<Option<Vec<&str>>>() {
+            Some(types) => json!({ "type": types }),
+            None => json!({ "anyOf": members.iter().map(json_schema).collect::<Vec<Value>>() }),
+        },
+        scalar => json!({ "type": type_name(scalar) }),
+    }
+}
+
+/// The JSON Schema type of the shapes not needing further keywords.
+fn type_name(shape: &Shape) -> Option<&'static str> {
+    match shape {
+        Shape::Null => Some("null"),
+        Shape::Bool => Some("boolean"),
+        Shape::Integer => Some("integer"),
+        Shape::Number => Some("number"),
+        Shape::String => Some("string"),
+        _ => None,
+    }
+}
+
+/// Checks whether the path of a leaf is described by a schema field, array indices matching any index.
+fn schema_field_matches(field: &[PathSegment], segments: &[PathSegment]) -> bool {
+    field.len() == segments.len() && field.iter().zip(segments).all(|pair| match pair {
+        (PathSegment::Key(expected), PathSegment::Key(key)) => expected == key,
+        (PathSegment::Index(_), PathSegment::Index(_)) => true,
+        _ => false,
+    })
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    #[test]
+    fn flattening_intersecting_schema() {
+        let schema = json!({
+            "user": { "name": "required", "email": "optional" },
+            "items": [{ "id": "required" }]
+        });
+        let json = json!({
+            "user": { "name": "John", "password": "secret" },
+            "items": [{ "id": 1, "internal": true }, { "id": 2 }],
+            "debug": true
+        });
+
+        let flat = flatten_intersect_schema(&json, &schema).unwrap();
+        let expected = json!({
+            "user.name": "John",
+            "items[0].id": 1,
+            "items[1].id": 2
+        });
+
+        println!(
+            "got:\n{}\nexpected:\n{}\n",
+            serde_json::to_string_pretty(&flat).unwrap(),
+            serde_json::to_string_pretty(&expected).unwrap()
+        );
+
+        assert_eq!(serde_json::to_value(&flat).unwrap(), expected);
+    }
+
+    #[test]
+    fn flattening_with_missing_required_fields() {
+        let schema = json!({ "user": { "name": "required", "email": "optional" } });
+        let json = json!({ "user": { "email": "john@example.com" } });
+
+        assert_eq!(
+            flatten_intersect_schema(&json, &schema).err().unwrap().to_string(),
+            errors::Error::SchemaValidationFailed("user.name".to_owned()).to_string()
+        );
+    }
+
+    #[test]
+    fn flattening_intersecting_schema_with_glob_characters() {
+        let schema = json!({ "a*b": "required", "c?": "optional", "**": [{ "id": "optional" }] });
+        let json = json!({ "a*b": 1, "aXb": 2, "c?": 3, "cd": 4, "**": [{ "id": 5 }], "x": [{ "id": 6 }] });
+
+        let flat = flatten_intersect_schema(&json, &schema).unwrap();
+        assert_eq!(serde_json::to_value(&flat).unwrap(), json!({ "a*b": 1, "c?": 3, "**[0].id": 5 }));
+
+        assert_eq!(
+            flatten_intersect_schema(&json!({ "aXb": 2 }), &schema).err().unwrap().to_string(),
+            errors::Error::SchemaValidationFailed("a*b".to_owned()).to_string()
+        );
+    }
+
+    #[test]
+    fn flattening_with_required_paths() {
+        let json = json!({ "db": { "host": "localhost", "ports": [5432] }, "debug": false });
+
+        let flat = flatten_and_validate_required(&json, &["db.host", "db.ports[0]"]).unwrap();
+        assert_eq!(flat, flatten(&json).unwrap());
+
+        assert_eq!(
+            flatten_and_validate_required(&json, &["db.host", "db.user", "db.password"]).err().unwrap().to_string(),
+            errors::Error::RequiredPathMissing("db.user".to_owned()).to_string()
+        );
+    }
+
+    #[test]
+    fn inferring_json_schema() {
+        let json = json!({ "user": { "name": "John", "age": 30 }, "tags": ["a"], "none": [] });
+        let expected = json!({
+            "$schema": "http://json-schema.org/draft-07/schema#",
+            "type": "object",
+            "properties": {
+                "user": {
+                    "type": "object",
+                    "properties": { "name": { "type": "string" }, "age": { "type": "integer" } },
+                    "required": ["name", "age"]
+                },
+                "tags": { "type": "array", "items": { "type": "string" } },
+                "none": { "type": "array", "items": {} }
+            },
+            "required": ["user", "tags", "none"]
+        });
+        let schema = flatten_to_json_schema(&json).unwrap();
+
+        println!(
+            "got:\n{}\nexpected:\n{}\n",
+            serde_json::to_string_pretty(&schema).unwrap(),
+            serde_json::to_string_pretty(&expected).unwrap()
+        );
+
+        assert_eq!(schema, expected);
+    }
+
+    #[test]
+    fn inferring_json_schema_from_many_documents() {
+        let documents = json!([
+            { "id": 1, "score": 2, "note": null, "value": "a" },
+            { "id": 2, "score": 2.5, "note": "x", "value": { "k": true } }
+        ]);
+        let expected = json!({
+            "$schema": "http://json-schema.org/draft-07/schema#",
+            "type": "object",
+            "properties": {
+                "id": { "type": "integer" },
+                "score": { "type": "number" },
+                "note": { "type": ["null", "string"] },
+                "value": { "anyOf": [
+                    { "type": "string" },
+                    { "type": "object", "properties": { "k": { "type": "boolean" } }, "required": ["k"] }
+                ] }
+            },
+            "required": ["id", "score", "note", "value"]
+        });
+        assert_eq!(flatten_to_json_schema(&documents).unwrap(), expected);
+
+        let documents = json!([{ "id": 1 }, { "name": "John" }]);
+        assert_eq!(flatten_to_json_schema(&documents).unwrap()["required"], Value::Null);
+
+        assert_eq!(
+            flatten_to_json_schema(&json!([{ "id": 1 }, 2])).err().unwrap().to_string(),
+            errors::Error::NotAnObject.to_string()
+        );
+    }
+}
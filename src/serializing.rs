@@ -0,0 +1,274 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+
+use serde::ser::{Serialize, SerializeMap, SerializeSeq, Serializer};
+use serde_json::{Map, Value};
+use crate::errors;
+use crate::path::PathSegment;
+use crate::pattern::PathPattern;
+use crate::unflattening::{self, UnflattenOptions, UnflattenReport};
+
+
+type Entry<'a> = (Vec<PathSegment>, &'a Value);
+
+/// Unflattens a flattened JSON structure straight into a JSON string, without building the nested `Value`.
+///
+/// # Arguments
+///
+/// * `data` - The flattened JSON structure (`serde_json::Map<String, Value>`).
+/// * `options` - The options controlling the unflattening (`UnflattenOptions`).
+///
+/// # Returns
+///
+/// A Result containing the same string as `serde_json::to_string(&unflatten(data)?)` or an error (`errors::Error`).
+///
+pub fn unflatten_to_string(data: &Map<String, Value>, options: &UnflattenOptions) -> Result<String, errors::Error> {
+    let mut output = Vec::new();
+    unflatten_to_serializer(data, options, &mut serde_json::Serializer::new(&mut output))?;
+    String::from_utf8(output).map_err(|_| errors::Error::FormatError)
+}
+
+/// Unflattens a flattened JSON structure by driving a serializer, without building the nested `Value`.
+///
+/// The structure is checked before anything is serialized, so an invalid structure leaves the serializer untouched.
+///
+/// # Arguments
+///
+/// * `data` - The flattened JSON structure (`serde_json::Map<String, Value>`).
+/// * `options` - The options controlling the unflattening (`UnflattenOptions`).
+/// * `serializer` - The serializer receiving the reconstructed document.
+///
+/// # Returns
+///
+/// A Result containing the output of the serializer or an error (`errors::Error`), `FormatError` if the serializer fails.
+///
+pub fn unflatten_to_serializer<S: Serializer>(data: &Map<String, Value>, options: &UnflattenOptions, serializer: S) -> Result<S::Ok, errors::Error> {
+    let mut report = UnflattenReport::default();
+    let mut entries = data.iter()
+        .map(|(k, v)| {
+            unflattening::check_leaf(k, v, options, &mut report)?;
+            Ok((unflattening::parse_segments(k, options)?, v))
+        })
+        .collect::<Result<Vec<Entry>, errors::Error>>()?;
+    unflattening::sort_entries(&mut entries, options);
+
+    if entries.is_empty() {
+        return Map::new().serialize(serializer).map_err(|_| errors::Error::FormatError);
+    }
+    check(&entries, 0)?;
+
+    let patterns = options.single_element_arrays.iter()
+        .map(|pattern| PathPattern::compile(pattern))
+        .collect::<Result<Vec<PathPattern>, errors::Error>>()?;
+    let node = Node { entries: &entries, depth: 0, wrapped: false, patterns: &patterns, options };
+    node.serialize(serializer).map_err(|_| errors::Error::FormatError)
+}
+
+/// Groups the entries, sorted by `unflattening::sort_entries`, sharing their segment at `depth`.
+fn groups<'e, 'a>(entries: &'e [Entry<'a>], depth: usize) -> impl Iterator<Item = &'e [Entry<'a>]> {
+    entries.chunk_by(move |a, b| a.0[depth] == b.0[depth])
+}
+
+/// Reports the errors `unflatten` would return when building the same entries.
+fn check(entries: &[Entry], depth: usize) -> Result<(), errors::Error> {
+    if entries[0].0.len() == depth {
+        // A leaf, which must not also be a duplicate or a container.
+        return if entries.len() == 1 { Ok(()) } else { Err(errors::Error::FormatError) };
+    }
+
+    let is_array = matches!(entries[0].0[depth], PathSegment::Index(_));
+    let mut len = 0;
+    for group in groups(entries, depth) {
+        match group[0].0[depth] {
+            PathSegment::Index(index) if is_array => {
+                // Leaves are appended whatever their index, containers only at the end of the array.
+                let leaf = group.len() == 1 && group[0].0.len() == depth + 1;
+                if !leaf && index > len {
+                    return Err(errors::Error::FormatError);
+                }
+                len += 1;
+            }
+            PathSegment::Key(_) if !is_array => {}
+            _ => return Err(errors::Error::FormatError),
+        }
+        check(group, depth + 1)?;
+    }
+    Ok(())
+}
+
+struct Node<'e, 'a> {
+    entries: &'e [Entry<'a>],
+    depth: usize,
+    wrapped: bool,
+    patterns: &'e [PathPattern],
+    options: &'e UnflattenOptions,
+}
+
+impl<'e, 'a> Node<'e, 'a> {
+    fn child(&self, entries: &'e [Entry<'a>]) -> Node<'e, 'a> {
+        Node { entries, depth: self.depth + 1, wrapped: false, patterns: self.patterns, options: self.options }
+    }
+}
+
+impl Serialize for Node<'_, '_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let path = &self.entries[0].0[..self.depth];
+
+        if self.entries[0].0.len() == self.depth {
+            let value = self.entries[0].1;
+            if !value.is_object() && !value.is_array() {
+                if !path.is_empty() && self.patterns.iter().any(|pattern| pattern.matches(path)) {
+                    return [value].serialize(serializer);
+                }
+                return value.serialize(serializer);
+            }
+            // Non-scalar leaves get the same treatment as the rest of the document.
+            let mut value = value.clone();
+            unflattening::wrap_single_elements(&mut value, &mut path.to_vec(), self.patterns);
+            if self.options.infer_arrays {
+                unflattening::infer_arrays(&mut value, self.options.allow_sparse);
+            }
+            return value.serialize(serializer);
+        }
+
+        let is_object = matches!(self.entries[0].0[self.depth], PathSegment::Key(_));
+        if is_object && !self.wrapped && !path.is_empty() && self.patterns.iter().any(|pattern| pattern.matches(path)) {
+            let inner = Node { wrapped: true, ..*self };
+            return [inner].serialize(serializer);
+        }
+
+        let groups: Vec<&[Entry]> = groups(self.entries, self.depth).collect();
+        if !is_object {
+            let mut seq = serializer.serialize_seq(Some(groups.len()))?;
+            for group in groups {
+                seq.serialize_element(&self.child(group))?;
+            }
+            return seq.end();
+        }
+
+        if self.options.infer_arrays {
+            if let Some(slots) = inferred_array(&groups, self.depth, self.options.allow_sparse) {
+                let mut seq = serializer.serialize_seq(Some(slots.len()))?;
+                for slot in slots {
+                    match slot {
+                        Some(group) => seq.serialize_element(&self.child(group))?,
+                        None => seq.serialize_element(&Value::Null)?,
+                    }
+                }
+                return seq.end();
+            }
+        }
+
+        let mut map = serializer.serialize_map(Some(groups.len()))?;
+        for group in groups {
+            if let PathSegment::Key(key) = &group[0].0[self.depth] {
+                map.serialize_entry(key, &self.child(group))?;
+            }
+        }
+        map.end()
+    }
+}
+
+/// Returns the elements of the array an object with these property groups turns into, if it does.
+fn inferred_array<'e, 'a>(groups: &[&'e [Entry<'a>]], depth: usize, allow_sparse: bool) -> Option<Vec<Option<&'e [Entry<'a>]>>> {
+    let indices = groups.iter()
+        .map(|group| match &group[0].0[depth] {
+            PathSegment::Key(k) => k.parse::<usize>().ok().filter(|i| i.to_string() == *k),
+            PathSegment::Index(_) => None,
+        })
+        .collect::<Option<Vec<usize>>>()?;
+
+    let len = indices.iter().max().map_or(0, |max| max + 1);
+    if len != indices.len() && !allow_sparse {
+        return None;
+    }
+
+    let mut slots = vec![None; len];
+    for (i, group) in indices.into_iter().zip(groups) {
+        slots[i] = Some(*group);
+    }
+    Some(slots)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use crate::unflattening::{unflatten, unflatten_with_report};
+    use super::*;
+
+    #[test]
+    fn unflattening_to_strings() {
+        let corpus = [
+            json!({}),
+            json!({ "a.b[1]": 2, "a.b[0]": 1, "c": "d", "a.e": null }),
+            json!({ "x[0][1]": 1, "x[0][0]": 0, "x[1].y": true, "x[7]": "sparse leaf" }),
+            json!({ "[1].a": 1, "[0]": [1, { "b": 2 }] }),
+            json!({ "": 1, "[\"a.b\"].c": 2, "z.10": 1, "z.2": 2 }),
+        ];
+
+        for data in corpus {
+            let data = data.as_object().unwrap();
+            let expected = serde_json::to_string(&unflatten(data).unwrap()).unwrap();
+            assert_eq!(unflatten_to_string(data, &UnflattenOptions::new()).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn unflattening_to_strings_with_options() {
+        let data = json!({
+            "a.0": "x",
+            "a.1": { "0": true },
+            "b.c": 1,
+            "d.0": 1,
+            "d.2": 3,
+            "e[0].f": 1,
+            "e[1].f": { "1": 2 }
+        });
+        let data = data.as_object().unwrap();
+
+        let options = [
+            UnflattenOptions::new().infer_arrays(true),
+            UnflattenOptions::new().infer_arrays(true).allow_sparse(true),
+            UnflattenOptions::new().single_element_array("b").single_element_array("e[*].f").single_element_array("a.1"),
+            UnflattenOptions::new().single_element_array("a").single_element_array("e[*].f.1").infer_arrays(true),
+        ];
+
+        for options in options {
+            let (expected, _) = unflatten_with_report(data, &options).unwrap();
+            assert_eq!(unflatten_to_string(data, &options).unwrap(), serde_json::to_string(&expected).unwrap());
+        }
+    }
+
+    #[test]
+    fn unflattening_invalid_structures_to_strings() {
+        let corpus = [
+            json!({ "a": 1, "a.b": 2 }),
+            json!({ "a.b": 1, "a[0]": 2 }),
+            json!({ "a[0]": 1, "b": 2, "[0]": 3 }),
+            json!({ "a[0]": 1, "a[2].b": 2 }),
+            json!({ "a[0]": 1, "a[00]": 2 }),
+            json!({ "a..b": 1 }),
+        ];
+
+        for data in corpus {
+            let data = data.as_object().unwrap();
+            let expected = unflatten(data).err().unwrap().to_string();
+            assert_eq!(unflatten_to_string(data, &UnflattenOptions::new()).err().unwrap().to_string(), expected, "{:?}", data);
+        }
+    }
+}
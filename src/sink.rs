@@ -0,0 +1,134 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+
+use std::borrow::Cow;
+use std::io::Write;
+use serde_json::{Map, Value, json};
+use crate::errors;
+
+
+/// Destination of the flat key-value pairs produced by `flattening::flatten_into`.
+pub trait FlatSink {
+    /// Stores a leaf value under its flat key.
+    fn put(&mut self, key: &str, value: Value) -> Result<(), errors::Error>;
+}
+
+/// Origin of the flat key-value pairs consumed by `unflattening::unflatten_from`.
+pub trait FlatSource {
+    /// Returns the flat key-value pairs, in any order.
+    fn entries(&self) -> impl Iterator<Item = (Cow<'_, str>, Cow<'_, Value>)>;
+}
+
+/// Values put twice under the same key are merged into an array, as `flatten` does.
+impl FlatSink for Map<String, Value> {
+    fn put(&mut self, key: &str, value: Value) -> Result<(), errors::Error> {
+        if let Some(v) = self.get_mut(key) {
+            if let Some(existing_array) = v.as_array_mut() {
+                existing_array.push(value);
+            } else {
+                let v = v.take();
+                self[key] = json!([v, value]);
+            }
+        } else {
+            self.insert(key.to_string(), value);
+        }
+        Ok(())
+    }
+}
+
+impl FlatSink for Vec<(String, Value)> {
+    fn put(&mut self, key: &str, value: Value) -> Result<(), errors::Error> {
+        self.push((key.to_owned(), value));
+        Ok(())
+    }
+}
+
+impl FlatSource for Map<String, Value> {
+    fn entries(&self) -> impl Iterator<Item = (Cow<'_, str>, Cow<'_, Value>)> {
+        self.iter().map(|(k, v)| (Cow::Borrowed(k.as_str()), Cow::Borrowed(v)))
+    }
+}
+
+impl FlatSource for Vec<(String, Value)> {
+    fn entries(&self) -> impl Iterator<Item = (Cow<'_, str>, Cow<'_, Value>)> {
+        self.iter().map(|(k, v)| (Cow::Borrowed(k.as_str()), Cow::Borrowed(v)))
+    }
+}
+
+/// Writes the flat pairs as a single JSON object while they are produced, without building a map.
+///
+/// Unlike the `Map` sink, keys put twice are written twice.
+pub struct StreamingWriter<W: Write> {
+    writer: W,
+    written: usize,
+}
+
+impl<W: Write> StreamingWriter<W> {
+    pub fn new(writer: W) -> Self {
+        StreamingWriter { writer, written: 0 }
+    }
+
+    /// Closes the JSON object and returns the underlying writer.
+    pub fn finish(mut self) -> Result<W, errors::Error> {
+        if self.written == 0 {
+            self.writer.write_all(b"{")?;
+        }
+        self.writer.write_all(b"}")?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> FlatSink for StreamingWriter<W> {
+    fn put(&mut self, key: &str, value: Value) -> Result<(), errors::Error> {
+        self.writer.write_all(if self.written == 0 { b"{" } else { b"," })?;
+        serde_json::to_writer(&mut self.writer, key).map_err(std::io::Error::from)?;
+        self.writer.write_all(b":")?;
+        serde_json::to_writer(&mut self.writer, &value).map_err(std::io::Error::from)?;
+        self.written += 1;
+        Ok(())
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flattening::flatten_into;
+    use crate::unflattening::unflatten_from;
+
+    #[test]
+    fn flattening_into_sinks() {
+        let json = json!({ "a": { "b": [1, null] }, "c": "d" });
+
+        let mut pairs: Vec<(String, Value)> = vec![];
+        flatten_into(&json, &mut pairs).unwrap();
+        assert_eq!(pairs, vec![
+            ("a.b[0]".to_owned(), json!(1)),
+            ("a.b[1]".to_owned(), json!(null)),
+            ("c".to_owned(), json!("d")),
+        ]);
+        assert_eq!(unflatten_from(&pairs).unwrap(), json);
+
+        let mut writer = StreamingWriter::new(vec![]);
+        flatten_into(&json, &mut writer).unwrap();
+        let output = writer.finish().unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "{\"a.b[0]\":1,\"a.b[1]\":null,\"c\":\"d\"}");
+
+        let writer = StreamingWriter::new(vec![]);
+        assert_eq!(writer.finish().unwrap(), b"{}");
+    }
+}
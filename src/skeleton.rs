@@ -0,0 +1,216 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use crate::errors;
+use crate::flattening::flatten;
+use crate::path::{self, PathSegment};
+
+
+/// A flattened document together with the shape of its containers.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FlattenedDoc {
+    /// The flat leaves, as returned by `flatten`.
+    pub leaves: Map<String, Value>,
+    /// The flat key of every container (`""` for the root), mapped to the names of their properties, in order, for
+    /// objects or to the length of arrays.
+    pub skeleton: Value,
+}
+
+/// Flattens a JSON Value, also recording the structure of its containers.
+///
+/// The skeleton keeps what the leaves alone lose: empty objects and arrays, array lengths, the order of the
+/// properties and whether a container is an object or an array.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be flattened (`serde_json::Value`).
+///
+/// # Returns
+///
+/// A Result containing the leaves and the skeleton (`FlattenedDoc`) or an error (`errors::Error`).
+///
+pub fn flatten_with_skeleton(value: &Value) -> Result<FlattenedDoc, errors::Error> {
+    let leaves = flatten(value)?;
+    let mut skeleton = Map::new();
+    record_containers(value, &mut vec![], &mut skeleton);
+    Ok(FlattenedDoc { leaves, skeleton: Value::Object(skeleton) })
+}
+
+fn record_containers(value: &Value, path: &mut Vec<PathSegment>, skeleton: &mut Map<String, Value>) {
+    match value {
+        Value::Object(map) => {
+            skeleton.insert(path::render_flat_key(path), map.keys().map(|k| Value::from(k.as_str())).collect());
+            for (k, v) in map {
+                path.push(PathSegment::Key(k.clone()));
+                record_containers(v, path, skeleton);
+                path.pop();
+            }
+        }
+        Value::Array(array) => {
+            skeleton.insert(path::render_flat_key(path), Value::from(array.len()));
+            for (i, v) in array.iter().enumerate() {
+                path.push(PathSegment::Index(i));
+                record_containers(v, path, skeleton);
+                path.pop();
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Rebuilds a JSON Value from its leaves and skeleton.
+///
+/// # Arguments
+///
+/// * `doc` - The leaves and skeleton returned by `flatten_with_skeleton` (`FlattenedDoc`).
+///
+/// # Returns
+///
+/// A Result containing the reconstructed original JSON Value (`serde_json::Value`) or an error (`errors::Error`),
+/// `FormatError` when the leaves do not fit the skeleton.
+///
+pub fn unflatten_with_skeleton(doc: &FlattenedDoc) -> Result<Value, errors::Error> {
+    let skeleton = doc.skeleton.as_object().ok_or(errors::Error::FormatError)?;
+    let mut containers = skeleton.iter()
+        .map(|(key, shape)| Ok((path::parse_flat_key(key)?, shape)))
+        .collect::<Result<Vec<(Vec<PathSegment>, &Value)>, errors::Error>>()?;
+    // Parents sort before their children.
+    containers.sort_by(|a, b| a.0.cmp(&b.0));
+
+    // Every slot of a container holds one of the other containers or a leaf.
+    let placed = (doc.leaves.len() + skeleton.len()).saturating_sub(1);
+    let mut slots = 0;
+    let mut root: Option<Value> = None;
+    for (segments, shape) in containers {
+        let container = match shape {
+            Value::Array(names) => names.iter()
+                .map(|name| Some((name.as_str()?.to_owned(), Value::Null)))
+                .collect::<Option<Map<String, Value>>>()
+                .filter(|map| map.len() == names.len())
+                .map(Value::Object)
+                .ok_or(errors::Error::FormatError)?,
+            Value::Number(len) => {
+                let len = len.as_u64().filter(|len| *len <= placed as u64).ok_or(errors::Error::FormatError)?;
+                Value::Array(vec![Value::Null; len as usize])
+            }
+            _ => return Err(errors::Error::FormatError),
+        };
+        slots += match &container {
+            Value::Object(map) => map.len(),
+            Value::Array(array) => array.len(),
+            _ => 0,
+        };
+        match &mut root {
+            None if segments.is_empty() => root = Some(container),
+            Some(root) if !segments.is_empty() => place(root, &segments, container)?,
+            _ => return Err(errors::Error::FormatError),
+        }
+    }
+
+    let mut root = root.ok_or(errors::Error::FormatError)?;
+    for (key, leaf) in &doc.leaves {
+        place(&mut root, &path::parse_flat_key(key)?, leaf.clone())?;
+    }
+    if slots != placed {
+        return Err(errors::Error::FormatError);
+    }
+    Ok(root)
+}
+
+fn place(root: &mut Value, segments: &[PathSegment], value: Value) -> Result<(), errors::Error> {
+    let (last, parents) = segments.split_last().ok_or(errors::Error::FormatError)?;
+    let parent = parents.iter().try_fold(root, |cur, segment| match segment {
+        PathSegment::Key(k) => cur.as_object_mut()?.get_mut(k),
+        PathSegment::Index(i) => cur.as_array_mut()?.get_mut(*i),
+    }).ok_or(errors::Error::FormatError)?;
+
+    match (parent, last) {
+        (Value::Object(map), PathSegment::Key(k)) => {
+            *map.get_mut(k).ok_or(errors::Error::FormatError)? = value;
+        }
+        (Value::Array(array), PathSegment::Index(i)) => {
+            *array.get_mut(*i).ok_or(errors::Error::FormatError)? = value;
+        }
+        _ => return Err(errors::Error::FormatError),
+    }
+    Ok(())
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    #[test]
+    fn roundtripping_with_skeleton() {
+        let json = json!({
+            "a": [1, {}, [], null],
+            "b": { "0": "x", "c": { "d": [] } },
+            "e": "f"
+        });
+
+        let doc = flatten_with_skeleton(&json).unwrap();
+        let expected = json!({
+            "": ["a", "b", "e"],
+            "a": 4,
+            "a[1]": [],
+            "a[2]": 0,
+            "b": ["0", "c"],
+            "b.c": ["d"],
+            "b.c.d": 0
+        });
+
+        println!(
+            "got:\n{}\nexpected:\n{}\n",
+            serde_json::to_string_pretty(&doc.skeleton).unwrap(),
+            serde_json::to_string_pretty(&expected).unwrap()
+        );
+
+        assert_eq!(doc.skeleton, expected);
+        assert_eq!(unflatten_with_skeleton(&doc).unwrap(), json);
+
+        let array = json!([[], { "a": 1 }]);
+        assert_eq!(unflatten_with_skeleton(&flatten_with_skeleton(&array).unwrap()).unwrap(), array);
+
+        let json = json!({ "a": 1, "z": {}, "m": [2], "b": { "y": [], "x": 3 } });
+        let unflat = unflatten_with_skeleton(&flatten_with_skeleton(&json).unwrap()).unwrap();
+        assert_eq!(serde_json::to_string(&unflat).unwrap(), serde_json::to_string(&json).unwrap());
+    }
+
+    #[test]
+    fn unflattening_with_mismatching_skeleton() {
+        let mut doc = flatten_with_skeleton(&json!({ "a": [1, 2] })).unwrap();
+        doc.skeleton = json!({ "": ["a"], "a": 1 });
+
+        assert_eq!(unflatten_with_skeleton(&doc).err().unwrap().to_string(), errors::Error::FormatError.to_string());
+
+        for skeleton in [
+            json!({ "": 1000000000000000000u64 }),
+            json!({ "": ["a"], "a": 3 }),
+            json!({ "": ["a", "b"], "a": 2 }),
+            json!({ "": ["a", "a"], "a": 2 }),
+            json!({ "": {}, "a": 2 }),
+            json!({}),
+        ] {
+            doc.skeleton = skeleton;
+            assert_eq!(unflatten_with_skeleton(&doc).err().unwrap().to_string(), errors::Error::FormatError.to_string());
+        }
+    }
+}
@@ -0,0 +1,490 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
+use std::ops::Range;
+use serde::de::{self, DeserializeSeed, MapAccess, SeqAccess, Visitor};
+use serde_json::{Deserializer, Map, Number, Value};
+use crate::errors;
+use crate::sink::FlatSink;
+
+
+/// Flattens a JSON text without building a `serde_json::Value` first.
+///
+/// The result is the same as `flatten(&serde_json::from_str(s)?)`: for objects repeating a property name, the
+/// last value is kept, in the position of the first one.
+///
+/// # Arguments
+///
+/// * `s` - The JSON text.
+///
+/// # Returns
+///
+/// A Result containing a flattened JSON structure (`serde_json::Map<String, Value>`) or an error (`errors::Error`),
+/// `FormatError` if the text is not valid JSON.
+///
+pub fn flatten_from_str(s: &str) -> Result<Map<String, Value>, errors::Error> {
+    collect_map(flatten_with_policy(&mut Deserializer::from_str(s), DuplicateJsonKey::default())?)
+}
+
+/// Flattens a JSON text given as bytes, see `flatten_from_str`.
+pub fn flatten_from_slice(bytes: &[u8]) -> Result<Map<String, Value>, errors::Error> {
+    collect_map(flatten_with_policy(&mut Deserializer::from_slice(bytes), DuplicateJsonKey::default())?)
+}
+
+/// Flattens a JSON text read from `reader`, see `flatten_from_str`.
+pub fn flatten_from_reader(reader: impl Read) -> Result<Map<String, Value>, errors::Error> {
+    collect_map(flatten_with_policy(&mut Deserializer::from_reader(reader), DuplicateJsonKey::default())?)
+}
+
+/// Handling of objects repeating a property name in a JSON text.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicateJsonKey {
+    /// The last value is kept in the position of the first one, as `serde_json::from_str` does.
+    #[default]
+    LastWins,
+    /// The first value is kept, the following ones are skipped.
+    FirstWins,
+    /// The text is rejected with `DuplicateKey`, naming the flat key of the repeated property.
+    Error,
+    /// All the values are kept, in document order under the same flat key.
+    CollectAll,
+}
+
+/// Flattens a JSON text, handling objects repeating a property name as selected by `duplicates`.
+///
+/// With `DuplicateJsonKey::CollectAll` the values are merged into an array, and with `DuplicateJsonKey::LastWins`
+/// the result is the one of `flatten_from_str`.
+///
+/// # Arguments
+///
+/// * `s` - The JSON text.
+/// * `duplicates` - How repeated property names are handled (`DuplicateJsonKey`).
+///
+/// # Returns
+///
+/// A Result containing a flattened JSON structure (`serde_json::Map<String, Value>`) or an error (`errors::Error`),
+/// `FormatError` if the text is not valid JSON.
+///
+pub fn flatten_from_str_with_policy(s: &str, duplicates: DuplicateJsonKey) -> Result<Map<String, Value>, errors::Error> {
+    collect_map(flatten_pairs_from_str(s, duplicates)?)
+}
+
+/// Flattens a JSON text into flat key-value pairs in document order, handling objects repeating a property name
+/// as selected by `duplicates`.
+///
+/// With `DuplicateJsonKey::CollectAll` a repeated property produces one pair per value, under the same flat key.
+///
+/// # Arguments
+///
+/// * `s` - The JSON text.
+/// * `duplicates` - How repeated property names are handled (`DuplicateJsonKey`).
+///
+/// # Returns
+///
+/// A Result containing the flat key-value pairs (`Vec<(String, Value)>`) or an error (`errors::Error`),
+/// `FormatError` if the text is not valid JSON.
+///
+pub fn flatten_pairs_from_str(s: &str, duplicates: DuplicateJsonKey) -> Result<Vec<(String, Value)>, errors::Error> {
+    flatten_with_policy(&mut Deserializer::from_str(s), duplicates)
+}
+
+/// Location of a leaf value in a JSON text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    /// Byte offset of the first character of the value.
+    pub start: usize,
+    /// Byte offset following the last character of the value.
+    pub end: usize,
+    /// Line of the value, starting from 1.
+    pub line: usize,
+    /// Column of the value in characters, starting from 1.
+    pub col: usize,
+}
+
+/// Flattens a JSON text, locating each leaf value in the text.
+///
+/// Entries are returned in document order, and objects repeating a property name produce one entry per value.
+///
+/// # Arguments
+///
+/// * `input` - The JSON text.
+///
+/// # Returns
+///
+/// A Result containing the flat keys with their value and location (`Vec<(String, Value, Span)>`) or an error
+/// (`errors::Error`), `FormatError` if the text is not valid JSON.
+///
+pub fn flatten_with_spans(input: &str) -> Result<Vec<(String, Value, Span)>, errors::Error> {
+    // Checking the text first lets the scanner assume well-formed JSON.
+    serde_json::from_str::<de::IgnoredAny>(input).map_err(|_| errors::Error::FormatError)?;
+    if !matches!(input.trim_start().as_bytes().first(), Some(b'{' | b'[')) {
+        return Err(errors::Error::NotAnObject);
+    }
+
+    let mut scanner = Scanner { input, pos: 0, line: 1, line_start: 0, key: String::new(), entries: vec![] };
+    scanner.node(true)?;
+    Ok(scanner.entries)
+}
+
+/// Walks a well-formed JSON text, recording the location of its leaves.
+struct Scanner<'a> {
+    input: &'a str,
+    pos: usize,
+    line: usize,
+    line_start: usize,
+    key: String,
+    entries: Vec<(String, Value, Span)>,
+}
+
+impl Scanner<'_> {
+    fn peek(&self) -> u8 {
+        self.input.as_bytes()[self.pos]
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b) = self.input.as_bytes().get(self.pos).filter(|b| b.is_ascii_whitespace()) {
+            if *b == b'\n' {
+                self.line += 1;
+                self.line_start = self.pos + 1;
+            }
+            self.pos += 1;
+        }
+    }
+
+    /// Returns the end of the token starting at the current position.
+    fn token_end(&self) -> usize {
+        let bytes = self.input.as_bytes();
+        let mut end = self.pos + 1;
+        if bytes[self.pos] == b'"' {
+            while bytes[end] != b'"' {
+                end += if bytes[end] == b'\\' { 2 } else { 1 };
+            }
+            end + 1
+        } else {
+            while end < bytes.len() && !matches!(bytes[end], b',' | b']' | b'}') && !bytes[end].is_ascii_whitespace() {
+                end += 1;
+            }
+            end
+        }
+    }
+
+    fn token<T: de::DeserializeOwned>(&mut self) -> Result<(T, usize), errors::Error> {
+        let start = self.pos;
+        self.pos = self.token_end();
+        let token = serde_json::from_str(&self.input[start..self.pos]).map_err(|_| errors::Error::FormatError)?;
+        Ok((token, start))
+    }
+
+    fn node(&mut self, root: bool) -> Result<(), errors::Error> {
+        self.skip_whitespace();
+        let close = match self.peek() {
+            b'{' => b'}',
+            b'[' => b']',
+            _ => {
+                let (value, start) = self.token::<Value>()?;
+                let col = self.input[self.line_start..start].chars().count() + 1;
+                let span = Span { start, end: self.pos, line: self.line, col };
+                self.entries.push((self.key.clone(), value, span));
+                return Ok(());
+            }
+        };
+
+        self.pos += 1;
+        let len = self.key.len();
+        for i in 0.. {
+            self.skip_whitespace();
+            if self.peek() == close {
+                break;
+            }
+            if close == b'}' {
+                let (k, _) = self.token::<String>()?;
+                self.skip_whitespace();
+                self.pos += 1; // ':'
+                if !root {
+                    self.key.push('.');
+                }
+                self.key.push_str(&k);
+            } else {
+                self.key.push_str(&format!("[{}]", i));
+            }
+            self.node(false)?;
+            self.key.truncate(len);
+            self.skip_whitespace();
+            if self.peek() == b',' {
+                self.pos += 1;
+            }
+        }
+        self.pos += 1;
+        Ok(())
+    }
+}
+
+/// Collects flat key-value pairs, merging the values of a repeated key into an array as `flatten` does.
+fn collect_map(pairs: Vec<(String, Value)>) -> Result<Map<String, Value>, errors::Error> {
+    let mut flat = Map::new();
+    for (key, value) in pairs {
+        flat.put(&key, value)?;
+    }
+    Ok(flat)
+}
+
+/// Flattens the JSON text read by `de` into flat key-value pairs in document order.
+fn flatten_with_policy<'de, R: serde_json::de::Read<'de>>(de: &mut Deserializer<R>, duplicates: DuplicateJsonKey) -> Result<Vec<(String, Value)>, errors::Error> {
+    let mut walker = Walker { pairs: vec![], key: String::new(), depth: 0, error: None, duplicates };
+    let result = Node { walker: &mut walker }.deserialize(&mut *de).and_then(|_| de.end());
+
+    match (result, walker.error) {
+        (Ok(()), _) => Ok(walker.pairs),
+        (Err(_), Some(e)) => Err(e),
+        (Err(e), None) if e.is_io() => Err(errors::Error::Io(e.into())),
+        (Err(_), None) => Err(errors::Error::FormatError),
+    }
+}
+
+/// The flat key of the node being visited, shared by the nested visitors.
+struct Walker {
+    pairs: Vec<(String, Value)>,
+    key: String,
+    depth: usize,
+    error: Option<errors::Error>,
+    duplicates: DuplicateJsonKey,
+}
+
+impl Walker {
+    fn leaf<E: de::Error>(&mut self, value: Value) -> Result<(), E> {
+        if self.depth == 0 {
+            return Err(self.fail(errors::Error::NotAnObject));
+        }
+        self.pairs.push((self.key.clone(), value));
+        Ok(())
+    }
+
+    fn fail<E: de::Error>(&mut self, e: errors::Error) -> E {
+        self.error = Some(e);
+        E::custom("flattening failed")
+    }
+}
+
+struct Node<'w> {
+    walker: &'w mut Walker,
+}
+
+impl<'de> DeserializeSeed<'de> for Node<'_> {
+    type Value = ();
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for Node<'_> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a JSON value")
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<(), E> {
+        self.walker.leaf(Value::Bool(v))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<(), E> {
+        self.walker.leaf(Value::from(v))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<(), E> {
+        self.walker.leaf(Value::from(v))
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<(), E> {
+        self.walker.leaf(Number::from_f64(v).map_or(Value::Null, Value::Number))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<(), E> {
+        self.walker.leaf(Value::String(v.to_owned()))
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> Result<(), E> {
+        self.walker.leaf(Value::String(v))
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<(), E> {
+        self.walker.leaf(Value::Null)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let len = self.walker.key.len();
+        let root = self.walker.depth == 0;
+        self.walker.depth += 1;
+        // Pairs produced by each property, to find and replace repeated ones.
+        let mut seen: HashMap<String, Range<usize>> = HashMap::new();
+        while let Some(k) = map.next_key::<String>()? {
+            if !root {
+                self.walker.key.push('.');
+            }
+            self.walker.key.push_str(&k);
+            let previous = match self.walker.duplicates {
+                DuplicateJsonKey::CollectAll => None,
+                _ => seen.get(&k).cloned(),
+            };
+            match (previous, self.walker.duplicates) {
+                (Some(_), DuplicateJsonKey::FirstWins) => {
+                    map.next_value::<de::IgnoredAny>()?;
+                }
+                (Some(_), DuplicateJsonKey::Error) => {
+                    let key = self.walker.key.clone();
+                    return Err(self.walker.fail(errors::Error::DuplicateKey(key)));
+                }
+                (Some(previous), _) => {
+                    // The new pairs take the place of the previous ones, shifting the properties that follow.
+                    let start = self.walker.pairs.len();
+                    map.next_value_seed(Node { walker: &mut *self.walker })?;
+                    let pairs: Vec<_> = self.walker.pairs.drain(start..).collect();
+                    let replaced = previous.start..previous.start + pairs.len();
+                    self.walker.pairs.splice(previous.clone(), pairs);
+                    for range in seen.values_mut().filter(|range| range.start >= previous.end) {
+                        *range = range.start + replaced.len() - previous.len()..range.end + replaced.len() - previous.len();
+                    }
+                    seen.insert(k, replaced);
+                }
+                (None, _) => {
+                    let start = self.walker.pairs.len();
+                    map.next_value_seed(Node { walker: &mut *self.walker })?;
+                    if self.walker.duplicates != DuplicateJsonKey::CollectAll {
+                        seen.insert(k, start..self.walker.pairs.len());
+                    }
+                }
+            }
+            self.walker.key.truncate(len);
+        }
+        self.walker.depth -= 1;
+        Ok(())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let len = self.walker.key.len();
+        self.walker.depth += 1;
+        for i in 0.. {
+            self.walker.key.push_str(&format!("[{}]", i));
+            let found = seq.next_element_seed(Node { walker: &mut *self.walker })?;
+            self.walker.key.truncate(len);
+            if found.is_none() {
+                break;
+            }
+        }
+        self.walker.depth -= 1;
+        Ok(())
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use crate::flattening::flatten;
+    use super::*;
+
+    #[test]
+    fn flattening_from_text() {
+        let corpus = [
+            r#"{}"#,
+            r#"{"a":1}"#,
+            r#"{"z":{"y":[1,2.5,-3,null,true,"s"]},"a":{}}"#,
+            r#"{"x":[[],[{}],[[1]],{"":{"k":"v"}}]}"#,
+            r#"{"a.b":1,"a":{"b":2}}"#,
+            r#"{"u":18446744073709551615,"f":1e300,"e":"é\n"}"#,
+            r#"[{"a":1},[2]]"#,
+            r#"{"a":1,"a":2}"#,
+            r#"{"a":{"x":1,"x":[2,3]},"b":2,"a":{"y":{"z":4,"z":5},"w":6},"c":7}"#,
+            r#"{"a.b":1,"a":{"b":2},"a":{"b":3,"c":4}}"#,
+        ];
+
+        for text in corpus {
+            let expected = flatten(&serde_json::from_str(text).unwrap()).unwrap();
+            let flat = flatten_from_str(text).unwrap();
+            assert_eq!(flat.keys().collect::<Vec<_>>(), expected.keys().collect::<Vec<_>>(), "{}", text);
+            assert_eq!(flat, expected, "{}", text);
+            assert_eq!(flatten_from_slice(text.as_bytes()).unwrap(), expected, "{}", text);
+            assert_eq!(flatten_from_reader(text.as_bytes()).unwrap(), expected, "{}", text);
+        }
+    }
+
+    #[test]
+    fn flattening_text_with_duplicate_keys() {
+        let text = r#"{"a":{"x":1},"b":2,"a":{"y":3},"c":[{"d":4,"d":5}]}"#;
+
+        let pairs = |duplicates| flatten_pairs_from_str(text, duplicates).unwrap().into_iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<String>>();
+        assert_eq!(pairs(DuplicateJsonKey::LastWins), vec!["a.y=3", "b=2", "c[0].d=5"]);
+        assert_eq!(pairs(DuplicateJsonKey::FirstWins), vec!["a.x=1", "b=2", "c[0].d=4"]);
+        assert_eq!(pairs(DuplicateJsonKey::CollectAll), vec!["a.x=1", "b=2", "a.y=3", "c[0].d=4", "c[0].d=5"]);
+
+        let flat = flatten_from_str_with_policy(text, DuplicateJsonKey::default()).unwrap();
+        assert_eq!(flat, flatten_from_str(text).unwrap());
+        let flat = flatten_from_str_with_policy(text, DuplicateJsonKey::CollectAll).unwrap();
+        assert_eq!(Value::Object(flat), serde_json::json!({ "a.x": 1, "b": 2, "a.y": 3, "c[0].d": [4, 5] }));
+
+        let text = r#"{"a":1,"b":2,"a":3}"#;
+        let flat = flatten_from_str_with_policy(text, DuplicateJsonKey::LastWins).unwrap();
+        assert_eq!(flat.into_iter().collect::<Vec<_>>(), vec![("a".to_owned(), serde_json::json!(3)), ("b".to_owned(), serde_json::json!(2))]);
+        let expected: Map<String, Value> = serde_json::from_str(text).unwrap();
+        assert_eq!(flatten_from_str(text).unwrap().keys().collect::<Vec<_>>(), expected.keys().collect::<Vec<_>>());
+        let pairs = flatten_pairs_from_str(r#"{"a":{"x":1,"y":2},"b":3,"a":{"z":4},"c":5,"b":6}"#, DuplicateJsonKey::LastWins).unwrap();
+        assert_eq!(pairs.into_iter().map(|(key, _)| key).collect::<Vec<_>>(), vec!["a.z", "b", "c"]);
+
+        assert_eq!(
+            flatten_pairs_from_str(r#"{"sig":{"alg":"none","alg":"ES256"}}"#, DuplicateJsonKey::Error).err().unwrap().to_string(),
+            errors::Error::DuplicateKey("sig.alg".to_owned()).to_string()
+        );
+        assert_eq!(flatten_pairs_from_str(r#"{"a":1,"b":{"a":2}}"#, DuplicateJsonKey::Error).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn flattening_with_spans() {
+        let text = "{\n  \"user\": {\n    \"name\": \"Jöhn \\\"J\\\"\",\n    \"age\": -1.5e3\n  },\n  \"tags\": [true, null]\n}";
+
+        let entries = flatten_with_spans(text).unwrap();
+        let keys: Vec<&str> = entries.iter().map(|(key, _, _)| key.as_str()).collect();
+        assert_eq!(keys, vec!["user.name", "user.age", "tags[0]", "tags[1]"]);
+
+        let (_, age, span) = &entries[1];
+        assert_eq!(*age, serde_json::json!(-1500.0));
+        assert_eq!(&text[span.start..span.end], "-1.5e3");
+        assert_eq!((span.line, span.col), (4, 12));
+
+        let (_, name, span) = &entries[0];
+        assert_eq!(*name, serde_json::json!("Jöhn \"J\""));
+        assert_eq!((span.line, span.col), (3, 13));
+        assert_eq!(entries[3].2, Span { start: text.len() - 7, end: text.len() - 3, line: 6, col: 18 });
+
+        let flat: Map<String, Value> = entries.into_iter().map(|(key, value, _)| (key, value)).collect();
+        assert_eq!(flat, flatten_from_str(text).unwrap());
+
+        assert_eq!(flatten_with_spans("1").err().unwrap().to_string(), errors::Error::NotAnObject.to_string());
+        assert_eq!(flatten_with_spans("{\"a\":").err().unwrap().to_string(), errors::Error::FormatError.to_string());
+    }
+
+    #[test]
+    fn flattening_invalid_text() {
+        assert_eq!(flatten_from_str("1").err().unwrap().to_string(), errors::Error::NotAnObject.to_string());
+        assert_eq!(flatten_from_str(r#"{"a":"#).err().unwrap().to_string(), errors::Error::FormatError.to_string());
+        assert_eq!(flatten_from_str(r#"{"a":1} x"#).err().unwrap().to_string(), errors::Error::FormatError.to_string());
+    }
+}
@@ -0,0 +1,895 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+
+use std::fmt;
+use std::iter::Enumerate;
+use std::ops::ControlFlow;
+use std::slice;
+use serde_json::{Map, Value, map};
+use crate::path::{self, PathSegment};
+use crate::pattern::PathPattern;
+
+
+/// Path from the root of a document to one of its nodes.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct KeyPath(Vec<PathSegment>);
+
+impl KeyPath {
+    /// Creates the empty path, pointing at the root.
+    pub fn new() -> Self {
+        KeyPath(Vec::new())
+    }
+
+    pub fn segments(&self) -> &[PathSegment] {
+        &self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Renders the path as `flatten` writes its keys, without quoting property names.
+    pub fn to_flat_key(&self) -> String {
+        let mut key = String::new();
+        for (i, segment) in self.0.iter().enumerate() {
+            match segment {
+                PathSegment::Key(k) => {
+                    if i > 0 {
+                        key.push('.');
+                    }
+                    key.push_str(k);
+                }
+                PathSegment::Index(i) => key.push_str(&format!("[{}]", i)),
+            }
+        }
+        key
+    }
+
+    pub(crate) fn push(&mut self, segment: PathSegment) {
+        self.0.push(segment);
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<PathSegment> {
+        self.0.pop()
+    }
+}
+
+impl From<Vec<PathSegment>> for KeyPath {
+    fn from(segments: Vec<PathSegment>) -> Self {
+        KeyPath(segments)
+    }
+}
+
+/// Renders the path as a flat key that `path::parse_flat_key` reads back, quoting property names when needed.
+impl fmt::Display for KeyPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&path::render_flat_key(&self.0))
+    }
+}
+
+/// Iterates over the leaves of a JSON Value, in document order.
+///
+/// Empty objects and arrays have no leaves; a scalar value is its own single leaf, at the empty path.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be traversed (`serde_json::Value`).
+///
+/// # Returns
+///
+/// An iterator over the leaves (`&serde_json::Value`) and their paths (`KeyPath`).
+///
+pub fn leaves(value: &Value) -> impl Iterator<Item = (KeyPath, &Value)> {
+    Leaves::of(value)
+}
+
+/// Iterates over the leaves of a JSON Value whose path matches a pattern, in document order.
+///
+/// Subtrees that the pattern cannot match are not visited at all.
+pub(crate) fn leaves_matching<'a>(value: &'a Value, pattern: &'a PathPattern) -> impl Iterator<Item = (KeyPath, &'a Value)> {
+    Leaves { pattern: Some(pattern), ..Leaves::of(value) }
+}
+
+enum Frame<'a> {
+    Object(map::Iter<'a>),
+    Array(Enumerate<slice::Iter<'a, Value>>),
+}
+
+impl<'a> Frame<'a> {
+    fn of(value: &'a Value) -> Option<Self> {
+        match value {
+            Value::Object(map) => Some(Frame::Object(map.iter())),
+            Value::Array(array) => Some(Frame::Array(array.iter().enumerate())),
+            _ => None,
+        }
+    }
+}
+
+/// Segment of the path to the current node, borrowing property names from the document.
+#[derive(Clone, Copy)]
+enum Step<'a> {
+    Key(&'a str),
+    Index(usize),
+}
+
+/// Depth-first iterator over the leaves of a document.
+struct Leaves<'a> {
+    path: Vec<Step<'a>>,
+    stack: Vec<Frame<'a>>,
+    root: Option<&'a Value>,
+    at_leaf: bool,
+    /// Pattern the paths of the visited nodes have to match, or `None` to visit every node.
+    pattern: Option<&'a PathPattern>,
+}
+
+impl<'a> Leaves<'a> {
+    fn of(value: &'a Value) -> Self {
+        Leaves { path: Vec::new(), stack: Vec::new(), root: Some(value), at_leaf: false, pattern: None }
+    }
+
+    /// Moves to the next leaf, whose path is left in `path` until the following call.
+    fn advance(&mut self) -> Option<&'a Value> {
+        if self.at_leaf {
+            self.path.pop();
+            self.at_leaf = false;
+        }
+        if let Some(root) = self.root.take() {
+            match Frame::of(root) {
+                Some(frame) => self.stack.push(frame),
+                None => return Some(root).filter(|_| self.accepts(false)),
+            }
+        }
+
+        loop {
+            let child = match self.stack.last_mut()? {
+                Frame::Object(iter) => iter.next().map(|(k, v)| (Step::Key(k.as_str()), v)),
+                Frame::Array(iter) => iter.next().map(|(i, v)| (Step::Index(i), v)),
+            };
+
+            match child {
+                Some((step, value)) => {
+                    self.path.push(step);
+                    let frame = Frame::of(value);
+                    if !self.accepts(frame.is_some()) {
+                        self.path.pop();
+                        continue;
+                    }
+                    match frame {
+                        Some(frame) => self.stack.push(frame),
+                        None => {
+                            self.at_leaf = true;
+                            return Some(value);
+                        }
+                    }
+                }
+                None => {
+                    self.stack.pop();
+                    if !self.stack.is_empty() {
+                        self.path.pop();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns whether the node at `path` can be or lead to a leaf matching the pattern.
+    fn accepts(&self, container: bool) -> bool {
+        let Some(pattern) = self.pattern else { return true };
+        let path = self.key_path();
+        if container {
+            pattern.matches_prefix(path.segments())
+        } else {
+            pattern.matches(path.segments())
+        }
+    }
+
+    fn key_path(&self) -> KeyPath {
+        KeyPath(self.path.iter().map(|step| match step {
+            Step::Key(k) => PathSegment::Key(k.to_string()),
+            Step::Index(i) => PathSegment::Index(*i),
+        }).collect())
+    }
+}
+
+impl<'a> Iterator for Leaves<'a> {
+    type Item = (KeyPath, &'a Value);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let leaf = self.advance()?;
+        Some((self.key_path(), leaf))
+    }
+}
+
+/// Iterates over the leaves of a JSON Value in document order, starting from the leaf at `path`.
+///
+/// Returns `None` if `path` does not lead to a leaf. Only the containers along `path` are visited to find it.
+pub(crate) fn leaves_from<'a>(value: &'a Value, path: &[PathSegment]) -> Option<impl Iterator<Item = (KeyPath, &'a Value)>> {
+    let mut leaves = Leaves { path: Vec::new(), stack: Vec::new(), root: None, at_leaf: true, pattern: None };
+    let mut node = value;
+    for segment in path {
+        let (step, child, frame) = match (node, segment) {
+            (Value::Object(map), PathSegment::Key(k)) => {
+                let mut iter = map.iter();
+                let (key, child) = iter.by_ref().find(|(key, _)| *key == k)?;
+                (Step::Key(key.as_str()), child, Frame::Object(iter))
+            }
+            (Value::Array(array), PathSegment::Index(i)) => {
+                let mut iter = array.iter().enumerate();
+                let (_, child) = iter.nth(*i)?;
+                (Step::Index(*i), child, Frame::Array(iter))
+            }
+            _ => return None,
+        };
+        leaves.path.push(step);
+        leaves.stack.push(frame);
+        node = child;
+    }
+    if path.is_empty() || Frame::of(node).is_some() {
+        return None;
+    }
+    Some(std::iter::once((KeyPath(path.to_vec()), node)).chain(leaves))
+}
+
+/// Returns the path of the first leaf of a JSON Value, in document order, matching a predicate.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be searched (`serde_json::Value`).
+/// * `pred` - The predicate called with the value of each leaf.
+///
+/// # Returns
+///
+/// The path of the first matching leaf (`KeyPath`), if any.
+///
+pub fn find_path(value: &Value, pred: impl Fn(&Value) -> bool) -> Option<KeyPath> {
+    let mut leaves = Leaves::of(value);
+    while let Some(leaf) = leaves.advance() {
+        if pred(leaf) {
+            return Some(leaves.key_path());
+        }
+    }
+    None
+}
+
+/// Returns the paths of all the leaves of a JSON Value matching a predicate, in document order.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be searched (`serde_json::Value`).
+/// * `pred` - The predicate called with the value of each leaf.
+///
+/// # Returns
+///
+/// The paths of the matching leaves (`Vec<KeyPath>`).
+///
+pub fn find_all(value: &Value, pred: impl Fn(&Value) -> bool) -> Vec<KeyPath> {
+    let mut paths = Vec::new();
+    let mut leaves = Leaves::of(value);
+    while let Some(leaf) = leaves.advance() {
+        if pred(leaf) {
+            paths.push(leaves.key_path());
+        }
+    }
+    paths
+}
+
+enum FrameMut<'a> {
+    Object(map::IterMut<'a>),
+    Array(Enumerate<slice::IterMut<'a, Value>>),
+}
+
+/// Calls a function on every leaf of a JSON Value, in document order, allowing the leaf to be rewritten in place.
+///
+/// Only leaves are handed out, so objects and arrays cannot be added, removed or reordered. A scalar value is its
+/// own single leaf, at the empty path.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value whose leaves are visited (`serde_json::Value`).
+/// * `f` - The function called with the path (`KeyPath`) and the value of each leaf.
+///
+pub fn for_each_leaf_mut(value: &mut Value, mut f: impl FnMut(&KeyPath, &mut Value)) {
+    let mut path = KeyPath::new();
+    let mut stack = match value {
+        Value::Object(map) => vec![FrameMut::Object(map.iter_mut())],
+        Value::Array(array) => vec![FrameMut::Array(array.iter_mut().enumerate())],
+        leaf => return f(&path, leaf),
+    };
+
+    while let Some(frame) = stack.last_mut() {
+        let child = match frame {
+            FrameMut::Object(iter) => iter.next().map(|(k, v)| (PathSegment::Key(k.clone()), v)),
+            FrameMut::Array(iter) => iter.next().map(|(i, v)| (PathSegment::Index(i), v)),
+        };
+
+        match child {
+            Some((segment, value)) => {
+                path.push(segment);
+                match value {
+                    Value::Object(map) => stack.push(FrameMut::Object(map.iter_mut())),
+                    Value::Array(array) => stack.push(FrameMut::Array(array.iter_mut().enumerate())),
+                    leaf => {
+                        f(&path, leaf);
+                        path.pop();
+                    }
+                }
+            }
+            None => {
+                stack.pop();
+                if !stack.is_empty() {
+                    path.pop();
+                }
+            }
+        }
+    }
+}
+
+/// What `visit` does after a callback of a `JsonVisitor`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VisitControl {
+    /// Goes on with the traversal.
+    Continue,
+    /// Does not visit the children of the container just entered; the same as `Continue` for other callbacks.
+    SkipChildren,
+    /// Ends the traversal.
+    Stop,
+}
+
+/// Callbacks of a depth-first traversal of a document, see `visit`.
+///
+/// Every callback receives the path of the current node and does nothing by default.
+pub trait JsonVisitor {
+    fn enter_object(&mut self, _path: &KeyPath, _object: &Map<String, Value>) -> VisitControl {
+        VisitControl::Continue
+    }
+
+    fn exit_object(&mut self, _path: &KeyPath, _object: &Map<String, Value>) -> VisitControl {
+        VisitControl::Continue
+    }
+
+    fn enter_array(&mut self, _path: &KeyPath, _array: &[Value]) -> VisitControl {
+        VisitControl::Continue
+    }
+
+    fn exit_array(&mut self, _path: &KeyPath, _array: &[Value]) -> VisitControl {
+        VisitControl::Continue
+    }
+
+    fn leaf(&mut self, _path: &KeyPath, _value: &Value) -> VisitControl {
+        VisitControl::Continue
+    }
+}
+
+enum VisitFrame<'a> {
+    Object(&'a Map<String, Value>, map::Iter<'a>),
+    Array(&'a [Value], Enumerate<slice::Iter<'a, Value>>),
+}
+
+/// Visits a JSON Value depth-first, in document order, root included.
+///
+/// Every entered container is exited, also when its children are skipped, unless the traversal is stopped.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be visited (`serde_json::Value`).
+/// * `visitor` - The callbacks called on each node (`JsonVisitor`).
+///
+/// # Returns
+///
+/// `ControlFlow::Break` if a callback stopped the traversal, `ControlFlow::Continue` otherwise.
+///
+pub fn visit(value: &Value, visitor: &mut impl JsonVisitor) -> ControlFlow<()> {
+    let mut path = KeyPath::new();
+    let mut stack = Vec::new();
+    enter_node(value, &path, visitor, &mut stack)?;
+
+    while let Some(frame) = stack.last_mut() {
+        let child = match frame {
+            VisitFrame::Object(_, iter) => iter.next().map(|(k, v)| (PathSegment::Key(k.clone()), v)),
+            VisitFrame::Array(_, iter) => iter.next().map(|(i, v)| (PathSegment::Index(i), v)),
+        };
+
+        match child {
+            Some((segment, value)) => {
+                path.push(segment);
+                if !enter_node(value, &path, visitor, &mut stack)? {
+                    path.pop();
+                }
+            }
+            None => {
+                let control = match stack.pop() {
+                    Some(VisitFrame::Object(object, _)) => visitor.exit_object(&path, object),
+                    Some(VisitFrame::Array(array, _)) => visitor.exit_array(&path, array),
+                    None => VisitControl::Continue,
+                };
+                proceed(control)?;
+                if !stack.is_empty() {
+                    path.pop();
+                }
+            }
+        }
+    }
+    ControlFlow::Continue(())
+}
+
+/// Enters a node, returning whether a frame was pushed for its children.
+fn enter_node<'a>(value: &'a Value, path: &KeyPath, visitor: &mut impl JsonVisitor, stack: &mut Vec<VisitFrame<'a>>) -> ControlFlow<(), bool> {
+    match value {
+        Value::Object(object) => match visitor.enter_object(path, object) {
+            VisitControl::Continue => {
+                stack.push(VisitFrame::Object(object, object.iter()));
+                ControlFlow::Continue(true)
+            }
+            control => {
+                proceed(control)?;
+                proceed(visitor.exit_object(path, object))?;
+                ControlFlow::Continue(false)
+            }
+        },
+        Value::Array(array) => match visitor.enter_array(path, array) {
+            VisitControl::Continue => {
+                stack.push(VisitFrame::Array(array, array.iter().enumerate()));
+                ControlFlow::Continue(true)
+            }
+            control => {
+                proceed(control)?;
+                proceed(visitor.exit_array(path, array))?;
+                ControlFlow::Continue(false)
+            }
+        },
+        leaf => {
+            proceed(visitor.leaf(path, leaf))?;
+            ControlFlow::Continue(false)
+        }
+    }
+}
+
+fn proceed(control: VisitControl) -> ControlFlow<()> {
+    match control {
+        VisitControl::Stop => ControlFlow::Break(()),
+        VisitControl::Continue | VisitControl::SkipChildren => ControlFlow::Continue(()),
+    }
+}
+
+/// How an array element is removed from a document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ArrayRemoval {
+    /// Removes the element, shifting the following ones (`[1, 2, 3]` without `2` is `[1, 3]`).
+    #[default]
+    Splice,
+    /// Replaces the element with `null`, keeping the indices of the following ones.
+    Null,
+}
+
+/// Options for removing leaves from a document.
+#[derive(Debug, Clone)]
+pub struct PruneOptions {
+    /// Whether objects and arrays left empty by removals are removed too, `true` by default. Containers that were
+    /// empty to begin with are kept, and so is the root.
+    pub prune_empty: bool,
+    /// How removed array elements are handled, `ArrayRemoval::Splice` by default.
+    pub array_removal: ArrayRemoval,
+}
+
+impl Default for PruneOptions {
+    fn default() -> Self {
+        PruneOptions { prune_empty: true, array_removal: ArrayRemoval::Splice }
+    }
+}
+
+/// Builds a copy of a JSON Value with each leaf replaced by the output of a function, with the default
+/// `PruneOptions`.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be copied (`serde_json::Value`).
+/// * `f` - The function called with the path (`KeyPath`) and the value of each leaf, returning the new leaf or
+///   `None` to remove it.
+///
+/// # Returns
+///
+/// The new document (`serde_json::Value`), `null` if the root itself is a removed leaf.
+///
+pub fn map_leaves(value: &Value, f: impl Fn(&KeyPath, &Value) -> Option<Value>) -> Value {
+    map_leaves_with_options(value, f, &PruneOptions::default())
+}
+
+/// Builds a copy of a JSON Value with each leaf replaced by the output of a function, see `map_leaves`.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be copied (`serde_json::Value`).
+/// * `f` - The function called with the path (`KeyPath`) and the value of each leaf, returning the new leaf or
+///   `None` to remove it.
+/// * `opts` - How removed leaves and the containers they leave empty are handled (`PruneOptions`).
+///
+/// # Returns
+///
+/// The new document (`serde_json::Value`), `null` if the root itself is a removed leaf.
+///
+pub fn map_leaves_with_options(value: &Value, f: impl Fn(&KeyPath, &Value) -> Option<Value>, opts: &PruneOptions) -> Value {
+    let mut path = KeyPath::new();
+    match value {
+        Value::Object(map) => Value::Object(map_object(map, &mut path, &f, opts)),
+        Value::Array(array) => Value::Array(map_array(array, &mut path, &f, opts)),
+        leaf => f(&path, leaf).unwrap_or(Value::Null),
+    }
+}
+
+fn map_object(map: &Map<String, Value>, path: &mut KeyPath, f: &impl Fn(&KeyPath, &Value) -> Option<Value>, opts: &PruneOptions) -> Map<String, Value> {
+    let mut mapped = Map::new();
+    for (k, v) in map {
+        path.push(PathSegment::Key(k.clone()));
+        if let Some(v) = map_node(v, path, f, opts) {
+            mapped.insert(k.clone(), v);
+        }
+        path.pop();
+    }
+    mapped
+}
+
+fn map_array(array: &[Value], path: &mut KeyPath, f: &impl Fn(&KeyPath, &Value) -> Option<Value>, opts: &PruneOptions) -> Vec<Value> {
+    let mut mapped = Vec::with_capacity(array.len());
+    for (i, v) in array.iter().enumerate() {
+        path.push(PathSegment::Index(i));
+        match map_node(v, path, f, opts) {
+            Some(v) => mapped.push(v),
+            None if opts.array_removal == ArrayRemoval::Null => mapped.push(Value::Null),
+            None => {}
+        }
+        path.pop();
+    }
+    mapped
+}
+
+fn map_node(value: &Value, path: &mut KeyPath, f: &impl Fn(&KeyPath, &Value) -> Option<Value>, opts: &PruneOptions) -> Option<Value> {
+    match value {
+        Value::Object(map) => {
+            let mapped = map_object(map, path, f, opts);
+            (!opts.prune_empty || map.is_empty() || !mapped.is_empty()).then_some(Value::Object(mapped))
+        }
+        Value::Array(array) => {
+            let mapped = map_array(array, path, f, opts);
+            (!opts.prune_empty || array.is_empty() || !mapped.is_empty()).then_some(Value::Array(mapped))
+        }
+        leaf => f(path, leaf),
+    }
+}
+
+/// Removes in place the leaves of a JSON Value that a predicate rejects, with the default `PruneOptions`: array
+/// elements are spliced out and containers left empty are removed.
+///
+/// A rejected scalar root becomes `null`; a root object or array left empty is kept.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be filtered (`serde_json::Value`).
+/// * `f` - The predicate called with the path (`KeyPath`) and the value of each leaf, `true` to keep it.
+///
+pub fn retain_leaves(value: &mut Value, f: impl Fn(&KeyPath, &Value) -> bool) {
+    retain_leaves_with_options(value, f, &PruneOptions::default());
+}
+
+/// Removes in place the leaves of a JSON Value that a predicate rejects, see `retain_leaves`.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be filtered (`serde_json::Value`).
+/// * `f` - The predicate called with the path (`KeyPath`) and the value of each leaf, `true` to keep it.
+/// * `opts` - How removed leaves and the containers they leave empty are handled (`PruneOptions`).
+///
+pub fn retain_leaves_with_options(value: &mut Value, f: impl Fn(&KeyPath, &Value) -> bool, opts: &PruneOptions) {
+    let mut path = KeyPath::new();
+    if !retain_node(value, &mut path, &f, opts) && !value.is_object() && !value.is_array() {
+        *value = Value::Null;
+    }
+}
+
+/// Filters a node, returning whether it has to be kept in its parent.
+fn retain_node(value: &mut Value, path: &mut KeyPath, f: &impl Fn(&KeyPath, &Value) -> bool, opts: &PruneOptions) -> bool {
+    match value {
+        Value::Object(map) => {
+            let was_empty = map.is_empty();
+            map.retain(|k, v| {
+                path.push(PathSegment::Key(k.clone()));
+                let keep = retain_node(v, path, f, opts);
+                path.pop();
+                keep
+            });
+            !opts.prune_empty || was_empty || !map.is_empty()
+        }
+        Value::Array(array) => {
+            let was_empty = array.is_empty();
+            let mut i = 0;
+            array.retain_mut(|v| {
+                path.push(PathSegment::Index(i));
+                i += 1;
+                let keep = retain_node(v, path, f, opts);
+                path.pop();
+                if !keep && opts.array_removal == ArrayRemoval::Null {
+                    *v = Value::Null;
+                    return true;
+                }
+                keep
+            });
+            !opts.prune_empty || was_empty || !array.is_empty()
+        }
+        leaf => f(path, leaf),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use crate::flattening::flatten;
+    use super::*;
+
+    #[test]
+    fn iterating_over_leaves() {
+        let json = json!({
+            "a": { "b": [1, { "c.d": null }], "e": {} },
+            "f": "g",
+            "h": [[true], []]
+        });
+
+        let got: Vec<(String, Value)> = leaves(&json).map(|(path, leaf)| (path.to_string(), leaf.clone())).collect();
+        let expected = vec![
+            ("a.b[0]".to_owned(), json!(1)),
+            ("a.b[1][\"c.d\"]".to_owned(), Value::Null),
+            ("f".to_owned(), json!("g")),
+            ("h[0][0]".to_owned(), json!(true)),
+        ];
+        assert_eq!(got, expected);
+
+        let keys: Vec<String> = leaves(&json).map(|(path, _)| path.to_flat_key()).collect();
+        assert_eq!(keys, flatten(&json).unwrap().keys().cloned().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn iterating_over_leaves_of_scalars_and_empty_containers() {
+        let json = json!("a");
+        let got: Vec<(KeyPath, &Value)> = leaves(&json).collect();
+        assert_eq!(got, vec![(KeyPath::new(), &json)]);
+
+        assert_eq!(leaves(&json!({})).count(), 0);
+        assert_eq!(leaves(&json!([[], {}])).count(), 0);
+        assert_eq!(leaves(&json!([[1, 2], 3])).filter(|(path, _)| path.len() == 2).count(), 2);
+    }
+
+    #[test]
+    fn iterating_over_leaves_matching_a_pattern() {
+        let json = json!({
+            "items": [{ "id": 1, "tags": ["x"] }, { "id": 2, "meta": { "id": 3 } }],
+            "id": 4
+        });
+
+        let pattern = PathPattern::compile("items[*].id").unwrap();
+        let keys: Vec<String> = leaves_matching(&json, &pattern).map(|(path, _)| path.to_flat_key()).collect();
+        assert_eq!(keys, vec!["items[0].id", "items[1].id"]);
+
+        let pattern = PathPattern::compile("**.id").unwrap();
+        let got: Vec<&Value> = leaves_matching(&json, &pattern).map(|(_, leaf)| leaf).collect();
+        assert_eq!(got, vec![&json!(1), &json!(2), &json!(3), &json!(4)]);
+
+        assert_eq!(leaves_matching(&json!("a"), &pattern).count(), 0);
+    }
+
+    #[test]
+    fn editing_leaves_in_place() {
+        let mut json = json!({
+            "name": "  John ",
+            "scores": [1.234, { "best": 9.876 }],
+            "tags": [" a", []]
+        });
+
+        let mut paths = vec![];
+        for_each_leaf_mut(&mut json, |path, leaf| {
+            paths.push(path.to_string());
+            match leaf {
+                Value::String(s) => *leaf = json!(s.trim()),
+                Value::Number(n) => *leaf = json!((n.as_f64().unwrap() * 10.0).round() / 10.0),
+                _ => {}
+            }
+        });
+
+        assert_eq!(paths, vec!["name", "scores[0]", "scores[1].best", "tags[0]"]);
+        assert_eq!(json, json!({ "name": "John", "scores": [1.2, { "best": 9.9 }], "tags": ["a", []] }));
+
+        let mut scalar = json!(1);
+        for_each_leaf_mut(&mut scalar, |path, leaf| {
+            assert!(path.is_empty());
+            *leaf = json!(2);
+        });
+        assert_eq!(scalar, json!(2));
+    }
+
+    #[test]
+    fn visiting_subtree_sizes() {
+        #[derive(Default)]
+        struct Sizes {
+            open: Vec<usize>,
+            sizes: Vec<(String, usize)>,
+        }
+
+        impl Sizes {
+            fn close(&mut self, path: &KeyPath) {
+                let size = self.open.pop().unwrap();
+                if let Some(parent) = self.open.last_mut() {
+                    *parent += size;
+                }
+                self.sizes.push((path.to_string(), size));
+            }
+        }
+
+        impl JsonVisitor for Sizes {
+            fn enter_object(&mut self, path: &KeyPath, _: &Map<String, Value>) -> VisitControl {
+                self.open.push(0);
+                if path.to_string() == "skipped" { VisitControl::SkipChildren } else { VisitControl::Continue }
+            }
+
+            fn exit_object(&mut self, path: &KeyPath, _: &Map<String, Value>) -> VisitControl {
+                self.close(path);
+                VisitControl::Continue
+            }
+
+            fn enter_array(&mut self, _: &KeyPath, _: &[Value]) -> VisitControl {
+                self.open.push(0);
+                VisitControl::Continue
+            }
+
+            fn exit_array(&mut self, path: &KeyPath, _: &[Value]) -> VisitControl {
+                self.close(path);
+                VisitControl::Continue
+            }
+
+            fn leaf(&mut self, _: &KeyPath, _: &Value) -> VisitControl {
+                *self.open.last_mut().unwrap() += 1;
+                VisitControl::Continue
+            }
+        }
+
+        let json = json!({ "a": { "b": [1, 2], "c": 3 }, "skipped": { "d": 4 }, "e": [] });
+        let mut sizes = Sizes::default();
+        assert_eq!(visit(&json, &mut sizes), ControlFlow::Continue(()));
+        assert_eq!(sizes.sizes, vec![
+            ("a.b".to_owned(), 2),
+            ("a".to_owned(), 3),
+            ("skipped".to_owned(), 0),
+            ("e".to_owned(), 0),
+            ("".to_owned(), 3),
+        ]);
+    }
+
+    #[test]
+    fn stopping_visits_early() {
+        struct FirstNull(Option<KeyPath>);
+
+        impl JsonVisitor for FirstNull {
+            fn leaf(&mut self, path: &KeyPath, value: &Value) -> VisitControl {
+                if value.is_null() {
+                    self.0 = Some(path.clone());
+                    return VisitControl::Stop;
+                }
+                VisitControl::Continue
+            }
+        }
+
+        let json = json!({ "a": [1, { "b": null }], "c": null });
+        let mut visitor = FirstNull(None);
+        assert_eq!(visit(&json, &mut visitor), ControlFlow::Break(()));
+        assert_eq!(visitor.0.unwrap().to_string(), "a[1].b");
+    }
+
+    #[test]
+    fn mapping_leaves() {
+        let json = json!({
+            "user": { "name": "John", "email": "john@example.com" },
+            "contacts": [{ "email": "a@example.com" }, { "phone": "123" }],
+            "tags": ["a", "", "b"],
+            "empty": {}
+        });
+        let anonymize = |path: &KeyPath, leaf: &Value| match path.segments().last() {
+            Some(PathSegment::Key(k)) if k == "email" => None,
+            _ if leaf == "" => None,
+            _ => Some(leaf.clone()),
+        };
+
+        let mapped = map_leaves(&json, anonymize);
+        let expected = json!({
+            "user": { "name": "John" },
+            "contacts": [{ "phone": "123" }],
+            "tags": ["a", "b"],
+            "empty": {}
+        });
+
+        println!(
+            "got:\n{}\nexpected:\n{}\n",
+            serde_json::to_string_pretty(&mapped).unwrap(),
+            serde_json::to_string_pretty(&expected).unwrap()
+        );
+
+        assert_eq!(mapped, expected);
+        assert_eq!(json["user"]["email"], json!("john@example.com"));
+
+        let opts = PruneOptions { prune_empty: false, array_removal: ArrayRemoval::Null };
+        assert_eq!(map_leaves_with_options(&json, anonymize, &opts), json!({
+            "user": { "name": "John" },
+            "contacts": [{}, { "phone": "123" }],
+            "tags": ["a", null, "b"],
+            "empty": {}
+        }));
+
+        assert_eq!(map_leaves(&json!({ "a": [{ "b": "" }] }), anonymize), json!({}));
+        assert_eq!(map_leaves(&json!(""), anonymize), Value::Null);
+    }
+
+    #[test]
+    fn retaining_leaves() {
+        let json = json!({
+            "name": "John",
+            "nickname": "",
+            "address": { "street": null, "zip": 0 },
+            "scores": [0, 1, null, 2],
+            "nested": [[""], {}]
+        });
+        let meaningful = |_: &KeyPath, leaf: &Value| !(leaf.is_null() || leaf == "" || leaf == 0);
+
+        let mut retained = json.clone();
+        retain_leaves(&mut retained, meaningful);
+        let expected = json!({ "name": "John", "scores": [1, 2], "nested": [{}] });
+
+        println!(
+            "got:\n{}\nexpected:\n{}\n",
+            serde_json::to_string_pretty(&retained).unwrap(),
+            serde_json::to_string_pretty(&expected).unwrap()
+        );
+
+        assert_eq!(retained, expected);
+
+        let mut retained = json.clone();
+        retain_leaves_with_options(&mut retained, meaningful, &PruneOptions { prune_empty: true, array_removal: ArrayRemoval::Null });
+        assert_eq!(retained, json!({ "name": "John", "scores": [null, 1, null, 2], "nested": [[null], {}] }));
+
+        let mut empty = json!({ "a": { "b": null }, "c": [""] });
+        retain_leaves(&mut empty, meaningful);
+        assert_eq!(empty, json!({}));
+
+        let mut scalar = json!("");
+        retain_leaves(&mut scalar, meaningful);
+        assert_eq!(scalar, Value::Null);
+    }
+
+    #[test]
+    fn finding_leaves_by_value() {
+        let json = json!({
+            "owner": { "id": "u-42", "email": "john@example.com" },
+            "members": [{ "id": "u-7" }, { "email": "ann@example.com" }]
+        });
+
+        assert_eq!(find_path(&json, |leaf| leaf == "u-7").unwrap().to_string(), "members[0].id");
+        assert_eq!(find_path(&json, |leaf| leaf == "u-0"), None);
+
+        let emails = find_all(&json, |leaf| leaf.as_str().is_some_and(|s| s.contains('@')));
+        assert_eq!(emails.iter().map(KeyPath::to_string).collect::<Vec<_>>(), vec!["owner.email", "members[1].email"]);
+
+        assert_eq!(find_all(&json!(1), |leaf| leaf == 1), vec![KeyPath::new()]);
+    }
+}
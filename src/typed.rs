@@ -0,0 +1,212 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use crate::errors;
+use crate::flattening::flatten;
+use crate::path;
+use crate::pattern::PathPattern;
+use crate::unflattening::unflatten;
+
+
+/// A flat leaf value that keeps its JSON type when exported to string-only systems.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum TypedValue {
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Null,
+}
+
+/// Flat keys with their typed leaf values, in document order.
+pub type TypedMap = Vec<(String, TypedValue)>;
+
+impl From<TypedValue> for Value {
+    fn from(typed: TypedValue) -> Self {
+        match typed {
+            TypedValue::Str(s) => Value::String(s),
+            TypedValue::Int(i) => Value::from(i),
+            TypedValue::Float(f) => Value::from(f),
+            TypedValue::Bool(b) => Value::Bool(b),
+            TypedValue::Null => Value::Null,
+        }
+    }
+}
+
+impl TypedValue {
+    /// Converts a scalar JSON Value, returning `None` for objects and arrays.
+    ///
+    /// Integers that do not fit an `i64` become `Float`.
+    pub fn from_value(value: &Value) -> Option<Self> {
+        Some(match value {
+            Value::String(s) => TypedValue::Str(s.clone()),
+            Value::Number(n) => match n.as_i64() {
+                Some(i) => TypedValue::Int(i),
+                None => TypedValue::Float(n.as_f64()?),
+            },
+            Value::Bool(b) => TypedValue::Bool(*b),
+            Value::Null => TypedValue::Null,
+            Value::Object(_) | Value::Array(_) => return None,
+        })
+    }
+}
+
+/// Flattens a JSON Value into a key-value map whose values keep their JSON type.
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be flattened (`serde_json::Value`).
+///
+/// # Returns
+///
+/// A Result containing the flat keys mapped to typed values (`TypedMap`) or an error (`errors::Error`).
+///
+pub fn flatten_with_type_hints(value: &Value) -> Result<TypedMap, errors::Error> {
+    flatten(value)?.into_iter()
+        .map(|(key, leaf)| match TypedValue::from_value(&leaf) {
+            Some(typed) => Ok((key, typed)),
+            None => Err(errors::Error::NotAValue(key)),
+        })
+        .collect()
+}
+
+/// Unflattens a map of typed values produced by `flatten_with_type_hints`.
+///
+/// # Arguments
+///
+/// * `data` - The flat keys mapped to typed values (`TypedMap`).
+///
+/// # Returns
+///
+/// A Result containing the reconstructed original JSON object (`serde_json::Value`) or an error (`errors::Error`).
+///
+pub fn unflatten_from_typed_values(data: &TypedMap) -> Result<Value, errors::Error> {
+    let flat: Map<String, Value> = data.iter()
+        .map(|(key, typed)| (key.clone(), Value::from(typed.clone())))
+        .collect();
+    unflatten(&flat)
+}
+
+/// Transformation applied to a flat leaf value.
+pub type CoercionFn = fn(&Value) -> Value;
+
+/// Coerces the leaves whose flat key matches `pattern` with `coerce`.
+#[derive(Debug, Clone)]
+pub struct CoercionRule {
+    pub pattern: PathPattern,
+    pub coerce: CoercionFn,
+}
+
+impl CoercionRule {
+    pub fn new(pattern: PathPattern, coerce: CoercionFn) -> Self {
+        CoercionRule { pattern, coerce }
+    }
+}
+
+/// Flattens a JSON Value, transforming the leaves matched by coercion rules (e.g. numeric strings at `**.id`
+/// into integers).
+///
+/// # Arguments
+///
+/// * `value` - The JSON Value to be flattened (`serde_json::Value`).
+/// * `rules` - The coercion rules, the first rule matching a flat key being applied (`CoercionRule`).
+///
+/// # Returns
+///
+/// A Result containing a flattened JSON structure (`serde_json::Map<String, Value>`) or an error (`errors::Error`).
+///
+pub fn flatten_with_type_coercion(value: &Value, rules: &[CoercionRule]) -> Result<Map<String, Value>, errors::Error> {
+    Ok(flatten(value)?.into_iter().map(|(key, leaf)| {
+        let rule = path::parse_flat_key(&key).ok()
+            .and_then(|segments| rules.iter().find(|rule| rule.pattern.matches(&segments)));
+        let leaf = match rule {
+            Some(rule) => (rule.coerce)(&leaf),
+            None => leaf,
+        };
+        (key, leaf)
+    }).collect())
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    #[test]
+    fn roundtripping_typed_values() {
+        let json = json!({
+            "name": "42",
+            "port": 8080,
+            "ratio": 0.5,
+            "big": u64::MAX,
+            "tls": { "enabled": false, "cert": null }
+        });
+
+        let typed = flatten_with_type_hints(&json).unwrap();
+        assert_eq!(typed, vec![
+            ("name".to_owned(), TypedValue::Str("42".to_owned())),
+            ("port".to_owned(), TypedValue::Int(8080)),
+            ("ratio".to_owned(), TypedValue::Float(0.5)),
+            ("big".to_owned(), TypedValue::Float(u64::MAX as f64)),
+            ("tls.enabled".to_owned(), TypedValue::Bool(false)),
+            ("tls.cert".to_owned(), TypedValue::Null),
+        ]);
+
+        let mut expected = json.clone();
+        expected["big"] = json!(u64::MAX as f64);
+        let unflat = unflatten_from_typed_values(&typed).unwrap();
+        assert_eq!(unflat, expected);
+        assert_eq!(serde_json::to_string(&unflat).unwrap(), serde_json::to_string(&expected).unwrap());
+    }
+
+    #[test]
+    fn flattening_with_type_coercion() {
+        fn to_integer(value: &Value) -> Value {
+            value.as_str().and_then(|s| s.parse::<i64>().ok()).map_or_else(|| value.clone(), Value::from)
+        }
+        fn to_string(value: &Value) -> Value {
+            Value::String(value.to_string())
+        }
+
+        let json = json!({ "user": { "id": "42", "zip": "01234" }, "items": [{ "id": "7" }, { "id": "x" }], "id": 1 });
+        let rules = [
+            CoercionRule::new(PathPattern::compile("user.zip").unwrap(), to_string),
+            CoercionRule::new(PathPattern::compile("**.id").unwrap(), to_integer),
+            CoercionRule::new(PathPattern::compile("user.*").unwrap(), to_string),
+        ];
+
+        let flat = flatten_with_type_coercion(&json, &rules).unwrap();
+        let expected = json!({
+            "user.id": 42,
+            "user.zip": "\"01234\"",
+            "items[0].id": 7,
+            "items[1].id": "x",
+            "id": 1
+        });
+
+        println!(
+            "got:\n{}\nexpected:\n{}\n",
+            serde_json::to_string_pretty(&flat).unwrap(),
+            serde_json::to_string_pretty(&expected).unwrap()
+        );
+
+        assert_eq!(serde_json::to_value(&flat).unwrap(), expected);
+    }
+}
@@ -14,12 +14,253 @@
 
 
 
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::rc::Rc;
+use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value, json};
 use crate::errors;
+use crate::flat_map::{DuplicateKeyPolicy, FlatMap};
+use crate::flattener;
+use crate::flattening::{self, Collision, CollisionTracker, KeyMapping, MultiValueMap, OPTIONS_VERSION, SegmentWhitespace};
+use crate::path::{self, PathSegment};
+use crate::pattern::PathPattern;
+use crate::progress::{CancelToken, ProgressHook, Tracker};
+use crate::sink::FlatSource;
 
 
+/// Options controlling how flattened JSON structures are unflattened.
+///
+/// Like `FlattenOptions`, these can be stored in configuration files and loaded back with `from_value`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UnflattenOptions {
+    /// Version of the options format that produced these options.
+    #[serde(default = "flattening::options_version")]
+    pub version: u32,
+    /// How objects and arrays found as values of the flat map are handled.
+    pub leaves: LeafPolicy,
+    /// Whether objects whose keys are exactly `"0"` to `"n-1"` (e.g. from `a.0` keys) are turned into arrays.
+    pub infer_arrays: bool,
+    /// Whether `infer_arrays` also converts objects with gaps in their numeric keys, filling the gaps with `null`.
+    pub allow_sparse: bool,
+    /// Glob patterns (see `PathPattern`) of the flat keys whose value is a single-element array collapsed by
+    /// `FlattenOptions::single_element_arrays_as_scalar`; the values found there are wrapped back into arrays.
+    pub single_element_arrays: Vec<String>,
+    /// Offset subtracted from the array indices of the flat keys, matching `FlattenOptions::array_index_offset`.
+    pub array_index_offset: i64,
+    /// Maximum length of a flat key, in bytes, checked before the key is parsed.
+    pub max_key_bytes: Option<usize>,
+    /// Maximum number of segments of a flat key (`a.b[0]` has 3).
+    pub max_segments: Option<usize>,
+    /// How property names with leading or trailing whitespace in the flat keys (`" user . name "`) are handled.
+    /// The values of keys trimmed to the same path are merged into an array and reported in `UnflattenReport::collisions`.
+    pub segment_whitespace: SegmentWhitespace,
+    /// Whether property names differing only by case (`User.Name` and `user.name`) are merged into one.
+    pub key_case: KeyCase,
+    /// How entries merged by `key_case` with different values are handled, `DuplicateKeyPolicy::Error` by default.
+    pub case_conflicts: DuplicateKeyPolicy,
+    /// How a flat key nested below the path of another one (`a` and `a.b`) is handled.
+    pub path_conflicts: PathConflicts,
+    /// Whether the properties of objects are sorted by name instead of keeping the order of their first flat key.
+    pub sort_keys: bool,
+    /// Callback notified of the progress of the operation, not serialized.
+    #[serde(skip)]
+    pub progress: Option<ProgressHook>,
+    /// Flag aborting the operation with `Error::Cancelled`, not serialized.
+    #[serde(skip)]
+    pub cancel: Option<CancelToken>,
+}
+
+impl Default for UnflattenOptions {
+    fn default() -> Self {
+        UnflattenOptions {
+            version: OPTIONS_VERSION,
+            leaves: LeafPolicy::default(),
+            infer_arrays: false,
+            allow_sparse: false,
+            single_element_arrays: vec![],
+            array_index_offset: 0,
+            max_key_bytes: None,
+            max_segments: None,
+            segment_whitespace: SegmentWhitespace::default(),
+            key_case: KeyCase::default(),
+            case_conflicts: DuplicateKeyPolicy::Error,
+            path_conflicts: PathConflicts::default(),
+            sort_keys: false,
+            progress: None,
+            cancel: None,
+        }
+    }
+}
+
+/// Handling of non-scalar values in a flat map, which `flatten` never produces.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LeafPolicy {
+    /// Objects and arrays are inserted as they are, and noted in the report.
+    #[default]
+    Lenient,
+    /// Objects and arrays are rejected with `Error::NotAValue`.
+    Strict,
+}
+
+/// Matching of property names in the flat keys.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyCase {
+    /// Names differing by case are different properties.
+    #[default]
+    Sensitive,
+    /// Names differing only by case are the same property, spelled as in the first entry where it appears.
+    FoldFirstSeen,
+    /// Names differing only by case are the same property, spelled in lowercase.
+    FoldLowercase,
+}
+
+/// Handling of flat keys conflicting with the path of another key, e.g. `a` and `a.b`, or `a[0]` and `a.b`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PathConflicts {
+    /// The data is rejected with `Error::FormatError`.
+    #[default]
+    Error,
+    /// The shallowest value is kept, whatever the order of the keys, and the keys going through a container of the
+    /// other kind are dropped, as the npm `flat` package does by default. An empty object or array is a container
+    /// that later keys can fill.
+    KeepShallowest,
+}
+
+/// A problem found while unflattening that did not prevent building the result.
+#[derive(Debug, Clone, PartialEq)]
+pub enum UnflattenWarning {
+    /// The value at this flat key is an object or an array.
+    NonScalarLeaf(String),
+    /// The property names of this flat key were merged with differently cased ones, see `UnflattenOptions::key_case`.
+    FoldedKey(String),
+}
+
+/// Warnings collected by `unflatten_with_report`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UnflattenReport {
+    pub warnings: Vec<UnflattenWarning>,
+    /// Paths reached by more than one flat key once `SegmentWhitespace::Trim` is applied, whose values were merged
+    /// into an array, as `flatten` does.
+    pub collisions: Vec<Collision>,
+}
+
+impl UnflattenOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets how objects and arrays found as values of the flat map are handled.
+    pub fn leaves(mut self, leaves: LeafPolicy) -> Self {
+        self.leaves = leaves;
+        self
+    }
+
+    /// Sets whether objects with numeric keys are turned into arrays.
+    pub fn infer_arrays(mut self, infer_arrays: bool) -> Self {
+        self.infer_arrays = infer_arrays;
+        self
+    }
+
+    /// Sets whether inferred arrays may have gaps, filled with `null`.
+    pub fn allow_sparse(mut self, allow_sparse: bool) -> Self {
+        self.allow_sparse = allow_sparse;
+        self
+    }
+
+    /// Adds a glob pattern of flat keys holding a collapsed single-element array.
+    pub fn single_element_array(mut self, pattern: &str) -> Self {
+        self.single_element_arrays.push(pattern.to_owned());
+        self
+    }
+
+    /// Sets the offset subtracted from the array indices of the flat keys.
+    pub fn array_index_offset(mut self, array_index_offset: i64) -> Self {
+        self.array_index_offset = array_index_offset;
+        self
+    }
+
+    /// Sets the maximum length of a flat key, in bytes.
+    pub fn max_key_bytes(mut self, max_key_bytes: usize) -> Self {
+        self.max_key_bytes = Some(max_key_bytes);
+        self
+    }
+
+    /// Sets the maximum number of segments of a flat key.
+    pub fn max_segments(mut self, max_segments: usize) -> Self {
+        self.max_segments = Some(max_segments);
+        self
+    }
+
+    /// Sets how whitespace around property names is handled.
+    pub fn segment_whitespace(mut self, segment_whitespace: SegmentWhitespace) -> Self {
+        self.segment_whitespace = segment_whitespace;
+        self
+    }
+
+    /// Sets whether property names differing only by case are merged.
+    pub fn key_case(mut self, key_case: KeyCase) -> Self {
+        self.key_case = key_case;
+        self
+    }
+
+    /// Sets how case-merged entries with different values are handled.
+    pub fn case_conflicts(mut self, case_conflicts: DuplicateKeyPolicy) -> Self {
+        self.case_conflicts = case_conflicts;
+        self
+    }
+
+    /// Sets how flat keys conflicting with the path of another key are handled.
+    pub fn path_conflicts(mut self, path_conflicts: PathConflicts) -> Self {
+        self.path_conflicts = path_conflicts;
+        self
+    }
+
+    /// Sets whether the properties of objects are sorted by name.
+    pub fn sort_keys(mut self, sort_keys: bool) -> Self {
+        self.sort_keys = sort_keys;
+        self
+    }
+
+    /// Sets the callback notified of the progress of the operation.
+    pub fn progress(mut self, progress: ProgressHook) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Sets the flag that cancels the operation.
+    pub fn cancel(mut self, cancel: CancelToken) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// Loads options from their serialized JSON form.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The serialized options (`serde_json::Value`).
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the options (`UnflattenOptions`) or an error (`errors::Error`).
+    ///
+    pub fn from_value(value: &Value) -> Result<Self, errors::Error> {
+        let options: Self = serde_json::from_value(value.clone()).map_err(|_| errors::Error::FormatError)?;
+        flattening::check_options_version(options.version)?;
+        Ok(options)
+    }
+}
+
 /// Unflattens a flattened JSON structure into the original JSON object.
 ///
+/// Keys are processed in sorted order (property names lexicographically, array indices numerically),
+/// so the result does not depend on the order of the entries in `data`. When the keys start with an index
+/// (`[0].a`) the result is an array; mixing such keys with keys starting with a property name is an error.
+///
 /// # Arguments
 ///
 /// * `data` - The flattened JSON structure represented as a key-value map (`serde_json::Map<String, Value>`).
@@ -29,81 +270,705 @@ use crate::errors;
 /// A Result containing the reconstructed original JSON object (`serde_json::Value`) or an error (`errors::Error`).
 ///
 pub fn unflatten(data: &Map<String, Value>) -> Result<Value, errors::Error> {
-    let mut output = json!({});
-    
-    if data.is_empty() {
-        return Ok(output);
-    }
-
-    for (p, value) in data {
-        let mut cur = &mut output;
-        let mut property  = "";
-
-        let regex = regex::Regex::new(r"\.?([^.\[\]]+)|\[(\d+)\]").unwrap();
-
-        for c in regex.captures_iter(&p){
-            
-            let c2 = c.get(2).and_then(|m|  Some(m.as_str()));
-            
-            let value = if c2.is_some() {
-                Value::Array(vec![])
-            } else {
-                Value::Object(Map::new())
-            };
+    flattener::with_default(|flattener| flattener.unflatten(data))
+}
 
-            match cur {
-                Value::Array(a) => {
-                    let index = property.parse::<usize>().map_err(|_| errors::Error::InvalidProperty)?;
-                    if a.get(index).is_none() {
-                        a.push(value);
-                    }
-                    cur = cur.get_mut(index).ok_or(errors::Error::FormatError)?;
-                },
-                Value::Object(o) => {
-                    if o.get(property).is_none() {
-                        o.insert(property.to_owned(), value);
-                    } else if c2.is_some() && o.get(property).is_some_and(|f| f.is_object()) {
-                        return Err(errors::Error::FormatError)
-                    }
-                    
-                    cur = cur.get_mut(property).ok_or(errors::Error::Unspecified)?;
-                    
-                },
-                _ => return Err(errors::Error::InvalidType)
+/// Unflattens a flattened JSON structure, failing unless the result is a JSON object.
+///
+/// # Arguments
+///
+/// * `data` - The flattened JSON structure represented as a key-value map (`serde_json::Map<String, Value>`).
+///
+/// # Returns
+///
+/// A Result containing the reconstructed JSON object (`serde_json::Value`) or an error (`errors::Error`),
+/// `NotAnObject` if the keys describe an array (`[0].a`).
+///
+pub fn unflatten_to_serde_value(data: &Map<String, Value>) -> Result<Value, errors::Error> {
+    match unflatten(data)? {
+        object @ Value::Object(_) => Ok(object),
+        _ => Err(errors::Error::NotAnObject),
+    }
+}
+
+/// Unflattens a multi-valued map produced by `flatten_without_array_indices`.
+///
+/// Keys with a single value become scalar leaves and keys with several values become arrays, each key being
+/// nested on its own: `groups.cn` with two values gives `{ "groups": { "cn": [..] } }`, the array indices lost by
+/// flattening not being restored.
+///
+/// # Arguments
+///
+/// * `data` - The values by flat key (`MultiValueMap`), whose order is kept for the properties.
+///
+/// # Returns
+///
+/// A Result containing the reconstructed JSON object (`serde_json::Value`) or an error (`errors::Error`),
+/// `FormatError` if a key is both a leaf and the prefix of another key.
+///
+pub fn unflatten_from_multi_value_map(data: &MultiValueMap) -> Result<Value, errors::Error> {
+    let flat: Map<String, Value> = data.iter()
+        .map(|(key, values)| {
+            let value = match values.as_slice() {
+                [value] => value.clone(),
+                values => Value::Array(values.to_vec()),
             };
+            (key.clone(), value)
+        })
+        .collect();
+    unflatten(&flat)
+}
+
+/// Unflattens a batch of flattened JSON structures, sharing the parsed keys across the batch.
+///
+/// # Arguments
+///
+/// * `data` - The flattened JSON structures (`serde_json::Map<String, Value>`).
+///
+/// # Returns
+///
+/// The result of unflattening each structure, in order, identical to calling `unflatten` on each of them.
+///
+pub fn unflatten_many(data: &[Map<String, Value>]) -> Vec<Result<Value, errors::Error>> {
+    flattener::with_default(|flattener| flattener.unflatten_many(data))
+}
+
+/// Unflattens flat key-value pairs read from a source instead of a map, see `unflatten`.
+///
+/// # Arguments
+///
+/// * `source` - The origin of the flat key-value pairs (`FlatSource`).
+///
+/// # Returns
+///
+/// A Result containing the reconstructed original JSON object (`serde_json::Value`) or an error (`errors::Error`).
+///
+pub fn unflatten_from(source: &impl FlatSource) -> Result<Value, errors::Error> {
+    flattener::with_default(|flattener| flattener.unflatten_from(source))
+}
+
+/// Unflattens a flattened JSON structure, reporting problems that did not prevent building the result.
+///
+/// # Arguments
+///
+/// * `data` - The flattened JSON structure (`serde_json::Map<String, Value>`).
+/// * `options` - The options controlling the unflattening (`UnflattenOptions`).
+///
+/// # Returns
+///
+/// A Result containing the reconstructed original JSON object (`serde_json::Value`) with the warnings
+/// (`UnflattenReport`), or an error (`errors::Error`).
+///
+pub fn unflatten_with_report(data: &Map<String, Value>, options: &UnflattenOptions) -> Result<(Value, UnflattenReport), errors::Error> {
+    let mut report = UnflattenReport::default();
+    let value = unflatten_reporting(data, options, &mut report)?;
+    Ok((value, report))
+}
+
+pub(crate) fn unflatten_with_options(data: &impl FlatSource, options: &UnflattenOptions) -> Result<Value, errors::Error> {
+    unflatten_reporting(data, options, &mut UnflattenReport::default())
+}
 
-            if let Some(v2) = c2 {
-                property = v2;
-            } else if let Some(v1) = c.get(1).and_then(|m|  Some(m.as_str())){
-                property = v1;
-            } else {
-                return Err(errors::Error::InvalidProperty);
+/// State of an unflattening fed one entry at a time, see `unflatten_incremental`.
+#[derive(Debug, Default)]
+pub struct UnflattenState {
+    options: UnflattenOptions,
+    entries: ParsedEntries<'static, Vec<PathSegment>>,
+    report: UnflattenReport,
+}
+
+impl UnflattenState {
+    pub fn new(options: UnflattenOptions) -> Self {
+        UnflattenState { options, ..Default::default() }
+    }
+
+    /// Returns the warnings collected so far.
+    pub fn report(&self) -> &UnflattenReport {
+        &self.report
+    }
+}
+
+/// Adds a flat key-value pair to an incremental unflattening.
+///
+/// The key is parsed and checked right away, so invalid entries are reported as they arrive; the document
+/// itself is built by `unflatten_finalize`, so that the result does not depend on the order of the entries.
+///
+/// # Arguments
+///
+/// * `state` - The state of the unflattening (`UnflattenState`).
+/// * `key` - The flat key.
+/// * `val` - The value at the flat key (`serde_json::Value`).
+///
+/// # Returns
+///
+/// A Result that is an error (`errors::Error`) if the entry cannot be unflattened.
+///
+pub fn unflatten_incremental(state: &mut UnflattenState, key: String, val: Value) -> Result<(), errors::Error> {
+    let segments = parse_entry(&key, &val, &state.options, &mut state.report)?;
+    state.entries.push(&key, segments, Cow::Owned(val), &state.options);
+    Ok(())
+}
+
+/// Builds the document from the entries added with `unflatten_incremental`.
+///
+/// # Arguments
+///
+/// * `state` - The state of the unflattening (`UnflattenState`).
+///
+/// # Returns
+///
+/// A Result containing the reconstructed original JSON object (`serde_json::Value`) or an error (`errors::Error`).
+///
+pub fn unflatten_finalize(mut state: UnflattenState) -> Result<Value, errors::Error> {
+    let entries = state.entries.finish(&mut state.report);
+    let entries = fold_key_case(entries, &state.options, &mut state.report)?;
+    build(resolve_path_conflicts(entries, &state.options), &state.options)
+}
+
+/// Unflattener remembering parsed keys, for streams of documents sharing the same keys.
+///
+/// Keys are tokenized the first time they are seen; at most `capacity` keys are remembered,
+/// later keys are parsed on every use.
+#[derive(Debug, Default)]
+pub struct UnflattenCache {
+    options: UnflattenOptions,
+    capacity: usize,
+    parsed: HashMap<String, Rc<[PathSegment]>>,
+}
+
+impl UnflattenCache {
+    pub fn new(capacity: usize) -> Self {
+        UnflattenCache { capacity, ..Default::default() }
+    }
+
+    /// Sets the options used by `unflatten`, forgetting the keys parsed with the previous ones.
+    pub fn options(mut self, options: UnflattenOptions) -> Self {
+        self.options = options;
+        self.parsed.clear();
+        self
+    }
+
+    /// Returns the number of remembered keys.
+    pub fn len(&self) -> usize {
+        self.parsed.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.parsed.is_empty()
+    }
+
+    /// Remembers the keys of a flattened sample document ahead of time.
+    ///
+    /// The sample is flattened with the array index offset and the handling of whitespace of the cache's options,
+    /// so that its keys are the ones read back with them.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample` - A document with the expected shape (`serde_json::Value`).
+    ///
+    /// # Returns
+    ///
+    /// A Result that is an error (`errors::Error`) if the sample cannot be flattened.
+    ///
+    pub fn warm(&mut self, sample: &Value) -> Result<(), errors::Error> {
+        let options = flattening::FlattenOptions::new()
+            .array_index_offset(self.options.array_index_offset)
+            .segment_whitespace(self.options.segment_whitespace);
+        for key in flattening::flatten_with_options(sample, &options)?.keys() {
+            self.segments(key)?;
+        }
+        Ok(())
+    }
+
+    /// Unflattens a flattened JSON structure, see `unflatten`.
+    pub fn unflatten(&mut self, data: &Map<String, Value>) -> Result<Value, errors::Error> {
+        let mut report = UnflattenReport::default();
+        let mut entries = ParsedEntries::default();
+        for (k, v) in data {
+            check_leaf(k, v, &self.options, &mut report)?;
+            entries.push(k, self.segments(k)?, Cow::Borrowed(v), &self.options);
+        }
+        let entries = entries.finish(&mut report);
+        if self.options.key_case == KeyCase::Sensitive && self.options.path_conflicts == PathConflicts::Error {
+            return build(entries, &self.options);
+        }
+        let entries = entries.into_iter().map(|(segments, value)| (segments.to_vec(), value)).collect();
+        let entries = fold_key_case(entries, &self.options, &mut report)?;
+        build(resolve_path_conflicts(entries, &self.options), &self.options)
+    }
+
+    fn segments(&mut self, key: &str) -> Result<Rc<[PathSegment]>, errors::Error> {
+        if let Some(segments) = self.parsed.get(key) {
+            return Ok(segments.clone());
+        }
+        let segments: Rc<[PathSegment]> = parse_segments(key, &self.options)?.into();
+        if self.parsed.len() < self.capacity {
+            self.parsed.insert(key.to_owned(), segments.clone());
+        }
+        Ok(segments)
+    }
+}
+
+fn unflatten_reporting(data: &impl FlatSource, options: &UnflattenOptions, report: &mut UnflattenReport) -> Result<Value, errors::Error> {
+    let mut entries = ParsedEntries::default();
+    for (k, v) in data.entries() {
+        entries.push(&k, parse_entry(&k, &v, options, report)?, v, options);
+    }
+    let entries = fold_key_case(entries.finish(report), options, report)?;
+    build(resolve_path_conflicts(entries, options), options)
+}
+
+/// Parsed entries in the order of their keys. With `SegmentWhitespace::Trim`, the values of the keys trimmed to the
+/// same path are merged into an array, as `flatten` does, and the keys are reported as collisions.
+#[derive(Debug)]
+struct ParsedEntries<'a, P> {
+    entries: Vec<(P, Cow<'a, Value>)>,
+    /// Position of each path in `entries`, tracked only when segments are trimmed.
+    positions: HashMap<P, usize>,
+    sources: CollisionTracker,
+}
+
+impl<P> Default for ParsedEntries<'_, P> {
+    fn default() -> Self {
+        ParsedEntries { entries: Vec::new(), positions: HashMap::new(), sources: CollisionTracker::default() }
+    }
+}
+
+impl<'a, P: AsRef<[PathSegment]> + Clone + Eq + std::hash::Hash> ParsedEntries<'a, P> {
+    fn push(&mut self, key: &str, segments: P, value: Cow<'a, Value>, options: &UnflattenOptions) {
+        if options.segment_whitespace != SegmentWhitespace::Trim {
+            self.entries.push((segments, value));
+            return;
+        }
+        if !self.sources.record(&path::render_flat_key(segments.as_ref()), key.to_owned()) {
+            self.positions.insert(segments.clone(), self.entries.len());
+            self.entries.push((segments, value));
+            return;
+        }
+        match self.entries[self.positions[&segments]].1.to_mut() {
+            Value::Array(array) => array.push(value.into_owned()),
+            existing => *existing = json!([existing.take(), value.into_owned()]),
+        }
+    }
+
+    fn finish(self, report: &mut UnflattenReport) -> Vec<(P, Cow<'a, Value>)> {
+        report.collisions.extend(self.sources.into_collisions());
+        self.entries
+    }
+}
+
+/// Drops the entries conflicting with the path of another entry, as selected by `options.path_conflicts`.
+fn resolve_path_conflicts<'a>(entries: Vec<Entry<'a>>, options: &UnflattenOptions) -> Vec<Entry<'a>> {
+    if options.path_conflicts == PathConflicts::Error {
+        return entries;
+    }
+
+    #[derive(Clone, Copy, PartialEq)]
+    enum Node {
+        Leaf,
+        Object,
+        Array,
+    }
+    let container = |segment: &PathSegment| match segment {
+        PathSegment::Key(_) => Node::Object,
+        PathSegment::Index(_) => Node::Array,
+    };
+
+    // What is found at each path set so far, entries being visited in order.
+    let mut nodes: HashMap<Vec<PathSegment>, Node> = HashMap::new();
+    let mut kept: Vec<Option<Entry>> = Vec::with_capacity(entries.len());
+    'entries: for (segments, value) in entries {
+        for (depth, segment) in segments.iter().enumerate() {
+            if nodes.get(&segments[..depth]).is_some_and(|node| *node != container(segment)) {
+                continue 'entries;
+            }
+        }
+        if nodes.contains_key(&segments) {
+            nodes.retain(|path, _| !path.starts_with(&segments));
+            for entry in kept.iter_mut() {
+                if entry.as_ref().is_some_and(|(path, _)| path.starts_with(&segments)) {
+                    *entry = None;
+                }
+            }
+        }
+
+        for (depth, segment) in segments.iter().enumerate() {
+            nodes.entry(segments[..depth].to_vec()).or_insert(container(segment));
+        }
+        let node = match &*value {
+            Value::Object(map) if map.is_empty() => Node::Object,
+            Value::Array(array) if array.is_empty() => Node::Array,
+            _ => Node::Leaf,
+        };
+        nodes.insert(segments.clone(), node);
+        kept.push(Some((segments, value)));
+    }
+    kept.into_iter().flatten().collect()
+}
+
+/// A parsed flat key with its leaf value.
+type Entry<'a> = (Vec<PathSegment>, Cow<'a, Value>);
+
+/// Merges the property names differing only by case, as selected by `options.key_case`, resolving the entries
+/// that end up at the same path with `options.case_conflicts`.
+fn fold_key_case<'a>(entries: Vec<Entry<'a>>, options: &UnflattenOptions, report: &mut UnflattenReport) -> Result<Vec<Entry<'a>>, errors::Error> {
+    if options.key_case == KeyCase::Sensitive {
+        return Ok(entries);
+    }
+
+    // Spelling of each property, by folded parent path and lowercase name.
+    let mut spellings: HashMap<(Vec<PathSegment>, String), String> = HashMap::new();
+    let mut positions: HashMap<Vec<PathSegment>, usize> = HashMap::new();
+    let mut folded: Vec<Entry> = Vec::with_capacity(entries.len());
+    for (segments, value) in entries {
+        let mut path = Vec::with_capacity(segments.len());
+        for segment in &segments {
+            let segment = match segment {
+                PathSegment::Key(k) => {
+                    let name = spellings.entry((path.clone(), k.to_lowercase())).or_insert_with(|| match options.key_case {
+                        KeyCase::FoldLowercase => k.to_lowercase(),
+                        _ => k.clone(),
+                    });
+                    PathSegment::Key(name.clone())
+                }
+                PathSegment::Index(i) => PathSegment::Index(*i),
             };
+            path.push(segment);
+        }
+        if path != segments {
+            report.warnings.push(UnflattenWarning::FoldedKey(path::render_flat_key(&segments)));
+        }
+
+        match positions.get(&path) {
+            None => {
+                positions.insert(path.clone(), folded.len());
+                folded.push((path, value));
+            }
+            Some(&i) if folded[i].1 == value => {}
+            Some(&i) => match options.case_conflicts {
+                DuplicateKeyPolicy::Merge => match folded[i].1.to_mut() {
+                    Value::Array(array) => array.push(value.into_owned()),
+                    existing => *existing = json!([existing.take(), value.into_owned()]),
+                },
+                DuplicateKeyPolicy::KeepFirst => {}
+                DuplicateKeyPolicy::KeepLast => folded[i].1 = value,
+                DuplicateKeyPolicy::Error => return Err(errors::Error::DuplicateKey(path::render_flat_key(&path))),
+            },
+        }
+    }
+    Ok(folded)
+}
 
+fn parse_entry(key: &str, value: &Value, options: &UnflattenOptions, report: &mut UnflattenReport) -> Result<Vec<PathSegment>, errors::Error> {
+    check_leaf(key, value, options, report)?;
+    parse_segments(key, options)
+}
+
+pub(crate) fn check_leaf(key: &str, value: &Value, options: &UnflattenOptions, report: &mut UnflattenReport) -> Result<(), errors::Error> {
+    if value.is_object() || value.is_array() {
+        match options.leaves {
+            LeafPolicy::Strict => return Err(errors::Error::NotAValue(key.to_owned())),
+            LeafPolicy::Lenient => report.warnings.push(UnflattenWarning::NonScalarLeaf(key.to_owned())),
         }
+    }
+    Ok(())
+}
 
-        match cur {
-            Value::Array(a) => {
-                a.push(value.clone());
+pub(crate) fn parse_segments(key: &str, options: &UnflattenOptions) -> Result<Vec<PathSegment>, errors::Error> {
+    if options.max_key_bytes.is_some_and(|max| key.len() > max) {
+        return Err(errors::Error::KeyLimitExceeded(key.to_owned()));
+    }
+    let mut segments = match options.segment_whitespace {
+        SegmentWhitespace::Preserve => path::parse_flat_key(key)?,
+        SegmentWhitespace::Trim => path::parse_flat_key_lenient(key, true, false)?,
+        SegmentWhitespace::Error => {
+            let segments = path::parse_flat_key(key)?;
+            if path::parse_flat_key_lenient(key, true, false)? != segments {
+                return Err(errors::Error::InvalidKeyName(key.to_owned()));
+            }
+            segments
+        }
+    };
+    if options.max_segments.is_some_and(|max| segments.len() > max) {
+        return Err(errors::Error::KeyLimitExceeded(key.to_owned()));
+    }
+    if options.array_index_offset != 0 {
+        for segment in segments.iter_mut() {
+            if let PathSegment::Index(i) = segment {
+                *i = usize::try_from(*i as i64 - options.array_index_offset).map_err(|_| errors::Error::FormatError)?;
+            }
+        }
+    }
+    // The empty key names an empty property of the root object.
+    Ok(if segments.is_empty() { vec![PathSegment::Key(String::new())] } else { segments })
+}
+
+/// Sorts entries so that containers are built in order: array elements by index and object properties by their
+/// first appearance, or by name with `options.sort_keys`. Entries below the same path end up next to each other.
+pub(crate) fn sort_entries<P: AsRef<[PathSegment]>, V>(entries: &mut [(P, V)], options: &UnflattenOptions) {
+    if options.sort_keys {
+        entries.sort_by(|a, b| a.0.as_ref().cmp(b.0.as_ref()));
+        return;
+    }
+
+    let mut ranks: HashMap<Vec<PathSegment>, usize> = HashMap::new();
+    for (segments, _) in entries.iter() {
+        let segments = segments.as_ref();
+        for (depth, segment) in segments.iter().enumerate() {
+            if matches!(segment, PathSegment::Key(_)) && !ranks.contains_key(&segments[..=depth]) {
+                ranks.insert(segments[..=depth].to_vec(), ranks.len());
+            }
+        }
+    }
+    entries.sort_by_cached_key(|(segments, _)| {
+        let segments = segments.as_ref();
+        segments.iter().enumerate().map(|(depth, segment)| match segment {
+            PathSegment::Key(_) => ranks[&segments[..=depth]],
+            PathSegment::Index(i) => *i,
+        }).collect::<Vec<usize>>()
+    });
+}
+
+fn build<P: AsRef<[PathSegment]>>(mut entries: Vec<(P, Cow<Value>)>, options: &UnflattenOptions) -> Result<Value, errors::Error> {
+    // The root is created by the first key: an object, or an array when the keys start with an index.
+    let mut output: Option<Value> = None;
+    let mut tracker = Tracker::new(options.progress.as_ref(), options.cancel.as_ref());
+    sort_entries(&mut entries, options);
+
+    for (segments, value) in entries {
+        tracker.leaf(&value)?;
+        let segments = segments.as_ref();
+        let root = output.get_or_insert_with(|| empty_container(&segments[0]));
+        if root.is_array() != matches!(segments[0], PathSegment::Index(_)) {
+            return Err(errors::Error::FormatError);
+        }
+
+        let mut cur = root;
+        for (segment, next) in segments.iter().zip(&segments[1..]) {
+            cur = descend(cur, segment, empty_container(next))?;
+        }
+
+        match (cur, &segments[segments.len() - 1]) {
+            (Value::Array(a), PathSegment::Index(index)) => {
+                if *index < a.len() {
+                    return Err(errors::Error::FormatError);
+                }
+                a.push(value.into_owned());
             },
-            Value::Object(o) => {
-                if o.contains_key(property) {
+            (Value::Object(o), PathSegment::Key(key)) => {
+                if o.contains_key(key) {
                     return Err(errors::Error::FormatError);
                 }
-                o.insert(property.to_owned(), value.clone());
+                o.insert(key.clone(), value.into_owned());
             },
-            _ => return Err(errors::Error::InvalidType)
-            
+            _ => return Err(errors::Error::FormatError)
+        }
+    }
+    let mut output = output.unwrap_or_else(|| json!({}));
+    if !options.single_element_arrays.is_empty() {
+        let patterns = options.single_element_arrays.iter()
+            .map(|pattern| PathPattern::compile(pattern))
+            .collect::<Result<Vec<PathPattern>, errors::Error>>()?;
+        wrap_single_elements(&mut output, &mut vec![], &patterns);
+    }
+    if options.infer_arrays {
+        infer_arrays(&mut output, options.allow_sparse);
+    }
+    tracker.finish();
+    Ok(output)
+}
+
+/// Wraps the values at the flat keys matching `patterns` into single-element arrays, innermost first.
+pub(crate) fn wrap_single_elements(value: &mut Value, path: &mut Vec<PathSegment>, patterns: &[PathPattern]) {
+    match value {
+        Value::Object(map) => {
+            for (k, v) in map.iter_mut() {
+                path.push(PathSegment::Key(k.clone()));
+                wrap_single_elements(v, path, patterns);
+                path.pop();
+            }
         }
+        Value::Array(array) => {
+            for (i, v) in array.iter_mut().enumerate() {
+                path.push(PathSegment::Index(i));
+                wrap_single_elements(v, path, patterns);
+                path.pop();
+            }
+        }
+        _ => {}
+    }
+
+    if !path.is_empty() && !value.is_array() && patterns.iter().any(|pattern| pattern.matches(path)) {
+        *value = Value::Array(vec![value.take()]);
+    }
+}
+
+/// Turns objects whose keys are all array indices into arrays, innermost first.
+pub(crate) fn infer_arrays(value: &mut Value, allow_sparse: bool) {
+    match value {
+        Value::Object(map) => {
+            map.values_mut().for_each(|v| infer_arrays(v, allow_sparse));
+
+            let indices: Option<Vec<usize>> = map.keys()
+                .map(|k| k.parse::<usize>().ok().filter(|i| i.to_string() == *k))
+                .collect();
+            let Some(indices) = indices.filter(|indices| !indices.is_empty()) else { return };
+
+            let len = indices.iter().max().map_or(0, |max| max + 1);
+            if len != indices.len() && !allow_sparse {
+                return;
+            }
+
+            let mut array = vec![Value::Null; len];
+            for (i, v) in indices.into_iter().zip(std::mem::take(map).into_iter().map(|(_, v)| v)) {
+                array[i] = v;
+            }
+            *value = Value::Array(array);
+        }
+        Value::Array(array) => array.iter_mut().for_each(|v| infer_arrays(v, allow_sparse)),
+        _ => {}
+    }
+}
+
+fn empty_container(segment: &PathSegment) -> Value {
+    match segment {
+        PathSegment::Index(_) => Value::Array(vec![]),
+        PathSegment::Key(_) => Value::Object(Map::new()),
+    }
+}
+
+/// Moves to the child `property` of `cur`, creating it as `container` if missing.
+fn descend<'a>(cur: &'a mut Value, property: &PathSegment, container: Value) -> Result<&'a mut Value, errors::Error> {
+    let child = match (cur, property) {
+        (Value::Array(a), PathSegment::Index(index)) => {
+            if a.get(*index).is_none() {
+                a.push(container.clone());
+            }
+            a.get_mut(*index).ok_or(errors::Error::FormatError)?
+        },
+        (Value::Object(o), PathSegment::Key(key)) => {
+            o.entry(key.clone()).or_insert_with(|| container.clone())
+        },
+        _ => return Err(errors::Error::FormatError)
+    };
+
+    if child.is_array() != container.is_array() {
+        return Err(errors::Error::FormatError);
+    }
+    Ok(child)
+}
+
+/// Unflattens a flattened JSON structure whose keys were sanitized by `flatten_sanitized`.
+///
+/// # Arguments
+///
+/// * `data` - The flattened JSON structure with sanitized keys (`serde_json::Map<String, Value>`).
+/// * `mapping` - The mapping from sanitized to original keys returned by `flatten_sanitized`.
+///
+/// # Returns
+///
+/// A Result containing the reconstructed original JSON object (`serde_json::Value`) or an error (`errors::Error`).
+/// Keys missing from the mapping are used as they are.
+///
+pub fn unflatten_with_mapping(data: &Map<String, Value>, mapping: &KeyMapping) -> Result<Value, errors::Error> {
+    let restored: Map<String, Value> = data.iter()
+        .map(|(k, v)| (mapping.get(k).unwrap_or(k).clone(), v.clone()))
+        .collect();
+    unflatten(&restored)
+}
+
+/// Groups flat rows sharing the same identity keys into nested documents, the inverse of `explode`.
+///
+/// Rows with the same values for `group_by` (e.g. the rows of a SQL join) form one document: the keys under
+/// `child_path` (e.g. `orders.id` for the child path `orders`) build one child per row, collected in an array at
+/// `child_path`, while the other keys are shared by the rows of the group. Rows whose child keys are all null,
+/// as produced by outer joins, add no child.
+///
+/// # Arguments
+///
+/// * `rows` - The flat rows (`serde_json::Map<String, Value>`).
+/// * `group_by` - The flat keys identifying a group, e.g. `user.id`.
+/// * `child_path` - The flat key of the array of children, made of property names only.
+///
+/// # Returns
+///
+/// A Result containing the array of documents, in the order their groups first appear (`serde_json::Value`),
+/// or an error (`errors::Error`), `DuplicateKey` if the rows of a group disagree on a shared key.
+///
+pub fn group_nest(rows: &[Map<String, Value>], group_by: &[&str], child_path: &str) -> Result<Value, errors::Error> {
+    group_nest_with_policy(rows, group_by, child_path, DuplicateKeyPolicy::Error)
+}
+
+/// Groups flat rows into nested documents, see `group_nest`, resolving the shared keys the rows of a group
+/// disagree on with `policy`.
+///
+/// # Arguments
+///
+/// * `rows` - The flat rows (`serde_json::Map<String, Value>`).
+/// * `group_by` - The flat keys identifying a group, e.g. `user.id`.
+/// * `child_path` - The flat key of the array of children, made of property names only.
+/// * `policy` - The handling of conflicting shared keys (`DuplicateKeyPolicy`).
+///
+/// # Returns
+///
+/// A Result containing the array of documents (`serde_json::Value`) or an error (`errors::Error`).
+///
+pub fn group_nest_with_policy(rows: &[Map<String, Value>], group_by: &[&str], child_path: &str, policy: DuplicateKeyPolicy) -> Result<Value, errors::Error> {
+    let child_segments = path::parse_flat_key(child_path)?;
+    if child_segments.is_empty() || child_segments.iter().any(|segment| matches!(segment, PathSegment::Index(_))) {
+        return Err(errors::Error::InvalidProperty);
+    }
+    let prefix = format!("{}.", child_path);
 
+    let mut index: HashMap<String, usize> = HashMap::new();
+    let mut groups: Vec<(FlatMap, Vec<Value>)> = vec![];
+    for row in rows {
+        let identity: Vec<&Value> = group_by.iter().map(|key| row.get(*key).unwrap_or(&Value::Null)).collect();
+        let identity = serde_json::to_string(&identity).map_err(|_| errors::Error::FormatError)?;
+        let i = *index.entry(identity).or_insert_with(|| {
+            groups.push((FlatMap::new().with_duplicate_policy(policy), vec![]));
+            groups.len() - 1
+        });
+        let (shared, children) = &mut groups[i];
+
+        let mut child = Map::new();
+        for (key, value) in row {
+            match key.strip_prefix(&prefix) {
+                Some(child_key) => {
+                    child.insert(child_key.to_owned(), value.clone());
+                }
+                None if shared.get(key) == Some(value) => {}
+                None => shared.insert(key.clone(), value.clone())?,
+            }
+        }
+        if child.values().any(|value| !value.is_null()) {
+            children.push(unflatten(&child)?);
+        }
     }
-    return output.get("").ok_or(errors::Error::InvalidProperty).cloned()
+
+    let documents = groups.into_iter().map(|(shared, children)| {
+        let mut document = unflatten(shared.as_map())?;
+        let (last, parents) = child_segments.split_last().ok_or(errors::Error::InvalidProperty)?;
+        let mut cur = &mut document;
+        for segment in parents {
+            cur = descend(cur, segment, json!({}))?;
+        }
+        match (cur, last) {
+            (Value::Object(o), PathSegment::Key(key)) if !o.contains_key(key) => {
+                o.insert(key.clone(), Value::Array(children));
+                Ok(document)
+            }
+            _ => Err(errors::Error::FormatError),
+        }
+    }).collect::<Result<Vec<Value>, errors::Error>>()?;
+    Ok(Value::Array(documents))
 }
-    
+
 #[cfg(test)]
 mod tests {
     use serde_json::json;
-    use crate::flattening::flatten;
+    use crate::flattener::Flattener;
+    use crate::flattening::{FlattenOptions, flatten};
     use super::*;
 
     #[test]
@@ -216,6 +1081,239 @@ mod tests {
 
     }
 
+    #[test]
+    fn serializing_unflatten_options() {
+        let options = UnflattenOptions::new();
+        let serialized = serde_json::to_value(&options).unwrap();
+        assert_eq!(UnflattenOptions::from_value(&serialized).unwrap(), options);
+        assert_eq!(UnflattenOptions::from_value(&json!({})).unwrap(), options);
+        assert!(UnflattenOptions::from_value(&json!({ "version": OPTIONS_VERSION + 1 })).is_err());
+        assert!(UnflattenOptions::from_value(&json!({ "version": "1" })).is_err());
+
+        let options = UnflattenOptions::from_value(&json!({ "leaves": "strict" })).unwrap();
+        assert_eq!(options, UnflattenOptions::new().leaves(LeafPolicy::Strict));
+    }
+
+    #[test]
+    fn unflattening_with_inferred_arrays() {
+        let json: Value = json!({
+            "a.1": "y",
+            "a.0": "x",
+            "b.0.c": 1,
+            "b.1": { "0": true, "1": false },
+            "d.0": 1,
+            "d.2": 3,
+            "e.01": 1
+        });
+        let map = json.as_object().unwrap();
+
+        let (unflat, _) = unflatten_with_report(map, &UnflattenOptions::new().infer_arrays(true)).unwrap();
+        let expected = json!({
+            "a": ["x", "y"],
+            "b": [{ "c": 1 }, [true, false]],
+            "d": { "0": 1, "2": 3 },
+            "e": { "01": 1 }
+        });
+
+        println!(
+            "got:\n{}\nexpected:\n{}\n",
+            serde_json::to_string_pretty(&unflat).unwrap(),
+            serde_json::to_string_pretty(&expected).unwrap()
+        );
+
+        assert_eq!(unflat, expected);
+
+        let options = UnflattenOptions::new().infer_arrays(true).allow_sparse(true);
+        let (unflat, _) = unflatten_with_report(map, &options).unwrap();
+        assert_eq!(unflat["d"], json!([1, null, 3]));
+    }
+
+    #[test]
+    fn unflattening_incrementally() {
+        let mut state = UnflattenState::new(UnflattenOptions::new());
+        unflatten_incremental(&mut state, "a.b[1]".to_owned(), json!(2)).unwrap();
+        unflatten_incremental(&mut state, "a.b[0]".to_owned(), json!(1)).unwrap();
+        unflatten_incremental(&mut state, "c".to_owned(), json!([true])).unwrap();
+        assert_eq!(
+            unflatten_incremental(&mut state, "a..b".to_owned(), json!(3)).err().unwrap().to_string(),
+            errors::Error::InvalidProperty.to_string()
+        );
+
+        assert_eq!(state.report().warnings, vec![UnflattenWarning::NonScalarLeaf("c".to_owned())]);
+        assert_eq!(unflatten_finalize(state).unwrap(), json!({ "a": { "b": [1, 2] }, "c": [true] }));
+
+        let mut state = UnflattenState::default();
+        unflatten_incremental(&mut state, "a".to_owned(), json!(1)).unwrap();
+        unflatten_incremental(&mut state, "a".to_owned(), json!(2)).unwrap();
+        assert!(unflatten_finalize(state).is_err());
+    }
+
+    #[test]
+    fn unflattening_with_cache() {
+        let mut cache = UnflattenCache::new(3);
+        cache.warm(&json!({ "a": { "b": [1, 2] } })).unwrap();
+        assert_eq!(cache.len(), 2);
+
+        for i in 0..3 {
+            let json = json!({ "a": { "b": [i, i + 1] }, "c": i, "d": i });
+            let unflat = cache.unflatten(&flatten(&json).unwrap()).unwrap();
+            assert_eq!(unflat, json);
+        }
+        assert_eq!(cache.len(), 3);
+
+        let json: Value = json!({ "a..b": 1 });
+        assert!(cache.unflatten(json.as_object().unwrap()).is_err());
+
+        let mut cache = cache.options(UnflattenOptions::new().array_index_offset(1));
+        assert!(cache.is_empty());
+        let json = json!({ "a": [1, 2] });
+        cache.warm(&json).unwrap();
+        assert_eq!(cache.len(), 2);
+        let flat = json!({ "a[1]": 1, "a[2]": 2 });
+        assert_eq!(cache.unflatten(flat.as_object().unwrap()).unwrap(), json);
+    }
+
+    #[test]
+    fn roundtripping_single_element_arrays() {
+        let json: Value = json!({
+            "a": [{ "b": [1] }],
+            "c": [2, 3],
+            "d": ["x"],
+            "e": [{ "f": [true] }, { "f": [false] }]
+        });
+
+        let options = flattening::FlattenOptions::new().single_element_arrays_as_scalar(true);
+        let flat = flattening::flatten_with_options(&json, &options).unwrap();
+        let expected = json!({
+            "a.b": 1,
+            "c[0]": 2,
+            "c[1]": 3,
+            "d": "x",
+            "e[0].f": true,
+            "e[1].f": false
+        });
+
+        println!(
+            "got:\n{}\nexpected:\n{}\n",
+            serde_json::to_string_pretty(&flat).unwrap(),
+            serde_json::to_string_pretty(&expected).unwrap()
+        );
+
+        assert_eq!(serde_json::to_value(&flat).unwrap(), expected);
+
+        let options = UnflattenOptions::new()
+            .single_element_array("a")
+            .single_element_array("a.b")
+            .single_element_array("d")
+            .single_element_array("e[*].f");
+        let (unflat, _) = unflatten_with_report(&flat, &options).unwrap();
+        assert_eq!(unflat, json);
+
+        let options = UnflattenOptions::new().single_element_array("a[");
+        assert!(unflatten_with_report(&flat, &options).is_err());
+    }
+
+    #[test]
+    fn unflattening_non_scalar_leaves() {
+        let json: Value = json!({ "a.b": { "c": 1 }, "d": [1, 2], "e": 3 });
+        let map = json.as_object().unwrap();
+
+        let (unflat, report) = unflatten_with_report(map, &UnflattenOptions::new()).unwrap();
+        assert_eq!(unflat, json!({ "a": { "b": { "c": 1 } }, "d": [1, 2], "e": 3 }));
+        assert_eq!(report.warnings, vec![
+            UnflattenWarning::NonScalarLeaf("a.b".to_owned()),
+            UnflattenWarning::NonScalarLeaf("d".to_owned()),
+        ]);
+
+        let strict = UnflattenOptions::new().leaves(LeafPolicy::Strict);
+        assert_eq!(
+            unflatten_with_report(map, &strict).err().unwrap().to_string(),
+            errors::Error::NotAValue("a.b".to_owned()).to_string()
+        );
+    }
+
+    #[test]
+    fn unflattening_nulls_in_arrays() {
+        let json: Value = json!({
+            "arr[1]": 1,
+            "arr[0]": null,
+            "nested[0][0]": null,
+            "nested[1].x": null
+        });
+
+        if let Value::Object(map) = json {
+            let unflat = unflatten(&map).unwrap();
+            assert_eq!(unflat, json!({ "arr": [null, 1], "nested": [[null], { "x": null }] }));
+        } else {
+            panic!("Expected an Object");
+        }
+
+        let json: Value = json!({
+            "arr[0]": null,
+            "arr[0].x": 1
+        });
+
+        if let Value::Object(map) = json {
+            let unflat_err = unflatten(&map);
+            assert_eq!(unflat_err.err().unwrap().to_string(), errors::Error::FormatError.to_string());
+        } else {
+            panic!("Expected an Object");
+        }
+    }
+
+    #[test]
+    fn unflattening_is_order_insensitive() {
+        let mut data = Map::new();
+        for i in (0..12).rev() {
+            data.insert(format!("arr[{}].v", i), json!(i));
+        }
+        data.insert("b".to_owned(), json!(1));
+        data.insert("a[1]".to_owned(), json!("y"));
+        data.insert("a[0]".to_owned(), json!("x"));
+
+        let unflat = unflatten(&data).unwrap();
+        let expected = json!({
+            "a": ["x", "y"],
+            "arr": (0..12).map(|i| json!({ "v": i })).collect::<Vec<_>>(),
+            "b": 1
+        });
+
+        println!(
+            "got:\n{}\nexpected:\n{}\n",
+            serde_json::to_string_pretty(&unflat).unwrap(),
+            serde_json::to_string_pretty(&expected).unwrap()
+        );
+
+        assert_eq!(unflat, expected);
+
+        // Properties keep the order of their first flat key, array elements are ordered by index.
+        assert_eq!(unflat.as_object().unwrap().keys().collect::<Vec<_>>(), vec!["arr", "b", "a"]);
+        let reversed: Map<String, Value> = data.into_iter().rev().collect();
+        let unflat_reversed = unflatten(&reversed).unwrap();
+        assert_eq!(unflat_reversed, unflat);
+        assert_eq!(unflat_reversed.as_object().unwrap().keys().collect::<Vec<_>>(), vec!["a", "b", "arr"]);
+
+        let json = json!({ "zeta": 1, "alpha": { "y": 2, "b": 3 } });
+        let unflat = unflatten(&flatten(&json).unwrap()).unwrap();
+        assert_eq!(serde_json::to_string(&unflat).unwrap(), serde_json::to_string(&json).unwrap());
+    }
+
+    #[test]
+    fn unflattening_with_mapping() {
+        let json: Value = json!({
+            "user": { "first.name": "John", "last_name": "Doe" },
+            "tags": ["a", ["b", "c"]],
+            "a_very_long_property_1": 1,
+            "a_very_long_property_2": 2
+        });
+
+        let opts = crate::flattening::SanitizeOptions { max_length: Some(10), ..Default::default() };
+        let (flat, mapping) = crate::flattening::flatten_sanitized(&json, &opts).unwrap();
+        let unflat = unflatten_with_mapping(&flat, &mapping).unwrap();
+
+        assert_eq!(unflat, unflatten(&flatten(&json).unwrap()).unwrap());
+    }
+
     #[test]
     fn unflattening_with_conflicts_1() {
        
@@ -250,4 +1348,297 @@ mod tests {
          
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn unflattening_quoted_keys() {
+        let json: Value = json!({
+            "a[\"b.c\"]": 1,
+            "a['d[0]'][1]": 3,
+            "a['d[0]'][0]": 2,
+            "e[\"x\\\"y\"]": 4
+        });
+
+        let unflat = unflatten(json.as_object().unwrap()).unwrap();
+        let expected = json!({ "a": { "b.c": 1, "d[0]": [2, 3] }, "e": { "x\"y": 4 } });
+
+        println!(
+            "got:\n{}\nexpected:\n{}\n",
+            serde_json::to_string_pretty(&unflat).unwrap(),
+            serde_json::to_string_pretty(&expected).unwrap()
+        );
+
+        assert_eq!(unflat, expected);
+
+        let json: Value = json!({ "a[\"b.c": 1 });
+        assert_eq!(
+            unflatten(json.as_object().unwrap()).err().unwrap().to_string(),
+            errors::Error::UnterminatedQuote(2).to_string()
+        );
+    }
+
+
+    #[test]
+    fn unflattening_array_roots() {
+        let json: Value = json!({
+            "[1].name": "b",
+            "[0].name": "a",
+            "[2][0]": 1
+        });
+
+        let unflat = unflatten(json.as_object().unwrap()).unwrap();
+        let expected = json!([{ "name": "a" }, { "name": "b" }, [1]]);
+
+        println!(
+            "got:\n{}\nexpected:\n{}\n",
+            serde_json::to_string_pretty(&unflat).unwrap(),
+            serde_json::to_string_pretty(&expected).unwrap()
+        );
+
+        assert_eq!(unflat, expected);
+        assert_eq!(flatten(&unflat).unwrap(), *json.as_object().unwrap());
+
+        let json: Value = json!({ "[0].name": "a", "name": "b" });
+        assert_eq!(unflatten(json.as_object().unwrap()).err().unwrap().to_string(), errors::Error::FormatError.to_string());
+    }
+
+
+    #[test]
+    fn unflattening_multi_value_maps() {
+        let json = json!({
+            "user": { "name": "John", "mail": ["a@example.com", "b@example.com"] },
+            "groups": [{ "cn": "admins" }, { "cn": "users" }]
+        });
+
+        let multi_valued = flattening::flatten_without_array_indices(&json).unwrap();
+        let expected = json!({
+            "user": { "name": "John", "mail": ["a@example.com", "b@example.com"] },
+            "groups": { "cn": ["admins", "users"] }
+        });
+        let unflat = unflatten_from_multi_value_map(&multi_valued).unwrap();
+        assert_eq!(unflat, expected);
+        assert_eq!(serde_json::to_string(&unflat).unwrap(), serde_json::to_string(&expected).unwrap());
+
+        let conflicting = vec![
+            ("a".to_owned(), vec![json!(1)]),
+            ("a.b".to_owned(), vec![json!(2), json!(3)]),
+        ];
+        assert_eq!(
+            unflatten_from_multi_value_map(&conflicting).err().unwrap().to_string(),
+            errors::Error::FormatError.to_string()
+        );
+    }
+
+    #[test]
+    fn unflattening_to_objects_only() {
+        let json: Value = json!({ "a.b": 1 });
+        assert_eq!(unflatten_to_serde_value(json.as_object().unwrap()).unwrap(), json!({ "a": { "b": 1 } }));
+        assert_eq!(unflatten_to_serde_value(&Map::new()).unwrap(), json!({}));
+
+        let json: Value = json!({ "[0].name": "a" });
+        assert_eq!(
+            unflatten_to_serde_value(json.as_object().unwrap()).err().unwrap().to_string(),
+            errors::Error::NotAnObject.to_string()
+        );
+    }
+
+
+    #[test]
+    fn unflattening_empty_keys() {
+        let json: Value = json!({ "": 1, "a": { "b": 2 }, "[\"\"].c": 3 });
+        assert_eq!(
+            unflatten(json.as_object().unwrap()).err().unwrap().to_string(),
+            errors::Error::FormatError.to_string()
+        );
+
+        let json: Value = json!({ "": 1, "a.b": 2 });
+        let unflat = unflatten(json.as_object().unwrap()).unwrap();
+        assert_eq!(unflat, json!({ "": 1, "a": { "b": 2 } }));
+
+        let json: Value = json!({ "[\"\"].c": 3, "[\"\"].d[0]": 4 });
+        let unflat = unflatten(json.as_object().unwrap()).unwrap();
+        assert_eq!(unflat, json!({ "": { "c": 3, "d": [4] } }));
+    }
+
+    #[test]
+    fn roundtripping_with_index_offset() {
+        let json = json!({ "a": [1, { "b": [true, null] }], "c": [[2]] });
+
+        let flat = flattening::flatten_with_index_offset(&json, 1).unwrap();
+        let expected = json!({ "a[1]": 1, "a[2].b[1]": true, "a[2].b[2]": null, "c[1][1]": 2 });
+        assert_eq!(serde_json::to_value(&flat).unwrap(), expected);
+
+        let options = UnflattenOptions::new().array_index_offset(1);
+        assert_eq!(unflatten_with_options(&flat, &options).unwrap(), json);
+        assert_eq!(
+            unflatten_with_options(&json!({ "a[0]": 1 }).as_object().unwrap().clone(), &options).err().unwrap().to_string(),
+            errors::Error::FormatError.to_string()
+        );
+        assert_eq!(
+            flattening::flatten_with_index_offset(&json, -1).err().unwrap().to_string(),
+            errors::Error::InvalidProperty.to_string()
+        );
+    }
+
+    #[test]
+    fn unflattening_with_key_limits() {
+        let json = json!({ "a.b[0]": 1, "long_property_name": 2 });
+        let data = json.as_object().unwrap();
+
+        let options = UnflattenOptions::new().max_key_bytes(18).max_segments(3);
+        assert!(unflatten_with_options(data, &options).is_ok());
+
+        let options = UnflattenOptions::new().max_key_bytes(10);
+        assert_eq!(
+            unflatten_with_options(data, &options).err().unwrap().to_string(),
+            errors::Error::KeyLimitExceeded("long_property_name".to_owned()).to_string()
+        );
+        let options = UnflattenOptions::new().max_segments(2);
+        assert_eq!(
+            unflatten_with_options(data, &options).err().unwrap().to_string(),
+            errors::Error::KeyLimitExceeded("a.b[0]".to_owned()).to_string()
+        );
+    }
+
+    #[test]
+    fn unflattening_with_segment_whitespace() {
+        let json = json!({ " user . name ": "John", "user[\" tag \"]": "a" });
+        let data = json.as_object().unwrap();
+
+        let options = UnflattenOptions::new().segment_whitespace(SegmentWhitespace::Trim);
+        assert_eq!(unflatten_with_options(data, &options).unwrap(), json!({ "user": { "name": "John", " tag ": "a" } }));
+
+        let options = UnflattenOptions::new().segment_whitespace(SegmentWhitespace::Error);
+        assert_eq!(
+            unflatten_with_options(data, &options).err().unwrap().to_string(),
+            errors::Error::InvalidKeyName(" user . name ".to_owned()).to_string()
+        );
+        let json = json!({ "user[\" tag \"]": "a" });
+        assert!(unflatten_with_options(json.as_object().unwrap(), &options).is_ok());
+
+        let json = json!({ "user.name": "John", "user . name": "Ann", "user.name ": "Bob", "id": 1 });
+        let data = json.as_object().unwrap();
+        assert!(unflatten_with_report(data, &UnflattenOptions::new()).unwrap().1.collisions.is_empty());
+
+        let options = UnflattenOptions::new().segment_whitespace(SegmentWhitespace::Trim);
+        let (unflat, report) = unflatten_with_report(data, &options).unwrap();
+        assert_eq!(serde_json::to_string(&unflat).unwrap(), r#"{"user":{"name":["John","Ann","Bob"]},"id":1}"#);
+        assert_eq!(report.collisions, vec![Collision {
+            output_key: "user.name".to_owned(),
+            source_paths: vec!["user.name".to_owned(), "user . name".to_owned(), "user.name ".to_owned()],
+        }]);
+
+        let mut state = UnflattenState::new(options.clone());
+        for (key, val) in data {
+            unflatten_incremental(&mut state, key.clone(), val.clone()).unwrap();
+        }
+        assert_eq!(unflatten_finalize(state).unwrap(), unflat);
+        assert_eq!(UnflattenCache::new(8).options(options).unflatten(data).unwrap(), unflat);
+    }
+
+    #[test]
+    fn unflattening_with_path_conflicts() {
+        let json = json!({ "b.z": 1, "b": 2, "a[0]": 3, "a.x": 4, "c": {}, "c.y": 5, "b.a": 6 });
+        let data = json.as_object().unwrap();
+        assert_eq!(unflatten(data).err().unwrap().to_string(), errors::Error::FormatError.to_string());
+
+        let options = UnflattenOptions::new().path_conflicts(PathConflicts::KeepShallowest);
+        let (unflat, _) = unflatten_with_report(data, &options).unwrap();
+        assert_eq!(serde_json::to_string(&unflat).unwrap(), r#"{"b":2,"a":[3],"c":{"y":5}}"#);
+        let flattener = Flattener::with_options(FlattenOptions::default(), options);
+        let unflat = flattener.unflatten_many([data, json!({ "a": 1, "a.b": 2 }).as_object().unwrap()]);
+        assert_eq!(serde_json::to_string(unflat[0].as_ref().unwrap()).unwrap(), r#"{"b":2,"a":[3],"c":{"y":5}}"#);
+        assert_eq!(unflat[1].as_ref().unwrap(), &json!({ "a": 1 }));
+        let npm = crate::config::Config::npm_flat_compat();
+        let flattener = Flattener::with_options(npm.flatten, npm.unflatten.clone());
+        let json = json!({ "a": 1, "a.b": 2 });
+        assert_eq!(
+            flattener.unflatten_many([json.as_object().unwrap()])[0].as_ref().unwrap(),
+            &unflatten_with_options(json.as_object().unwrap(), &npm.unflatten).unwrap()
+        );
+
+        let json = json!({ "z": 1, "y[1]": 2, "y[0]": 3, "x.b": 4, "x.a": 5 });
+        let (unflat, _) = unflatten_with_report(json.as_object().unwrap(), &UnflattenOptions::new()).unwrap();
+        assert_eq!(serde_json::to_string(&unflat).unwrap(), r#"{"z":1,"y":[3,2],"x":{"b":4,"a":5}}"#);
+        let (unflat, _) = unflatten_with_report(json.as_object().unwrap(), &UnflattenOptions::new().sort_keys(true)).unwrap();
+        assert_eq!(serde_json::to_string(&unflat).unwrap(), r#"{"x":{"a":5,"b":4},"y":[3,2],"z":1}"#);
+    }
+
+    #[test]
+    fn unflattening_with_case_folding() {
+        let json = json!({ "User.Name": "John", "user.name": "John", "USER.Age": 30, "user.Tags[0]": "a" });
+        let data = json.as_object().unwrap();
+
+        let options = UnflattenOptions::new().key_case(KeyCase::FoldFirstSeen);
+        let (unflat, report) = unflatten_with_report(data, &options).unwrap();
+        assert_eq!(unflat, json!({ "User": { "Name": "John", "Age": 30, "Tags": ["a"] } }));
+        assert_eq!(report.warnings, vec![
+            UnflattenWarning::FoldedKey("user.name".to_owned()),
+            UnflattenWarning::FoldedKey("USER.Age".to_owned()),
+            UnflattenWarning::FoldedKey("user.Tags[0]".to_owned()),
+        ]);
+
+        let options = UnflattenOptions::new().key_case(KeyCase::FoldLowercase);
+        assert_eq!(
+            unflatten_with_options(data, &options).unwrap(),
+            json!({ "user": { "name": "John", "age": 30, "tags": ["a"] } })
+        );
+
+        let json = json!({ "User.Name": "John", "user.name": "Ann" });
+        let data = json.as_object().unwrap();
+        let options = UnflattenOptions::new().key_case(KeyCase::FoldLowercase);
+        assert_eq!(
+            unflatten_with_options(data, &options).err().unwrap().to_string(),
+            errors::Error::DuplicateKey("user.name".to_owned()).to_string()
+        );
+        let options = options.case_conflicts(DuplicateKeyPolicy::Merge);
+        assert_eq!(unflatten_with_options(data, &options).unwrap(), json!({ "user": { "name": ["John", "Ann"] } }));
+        assert_eq!(unflatten(data).unwrap(), json!({ "User": { "Name": "John" }, "user": { "name": "Ann" } }));
+
+        let json = json!({ "User.Name": 1, "user.age": 2 });
+        let data = json.as_object().unwrap();
+        let options = UnflattenOptions::new().key_case(KeyCase::FoldLowercase);
+        let flattener = Flattener::with_options(FlattenOptions::default(), options.clone());
+        let unflat = flattener.unflatten_many([data, data]);
+        assert_eq!(unflat[0].as_ref().unwrap(), &unflatten_with_options(data, &options).unwrap());
+        assert_eq!(unflat[1].as_ref().unwrap(), &json!({ "user": { "name": 1, "age": 2 } }));
+    }
+
+    #[test]
+    fn nesting_grouped_rows() {
+        let rows: Vec<Map<String, Value>> = [
+            json!({ "user.id": 1, "user.name": "Ann", "orders.id": "a", "orders.total": 10 }),
+            json!({ "user.id": 2, "user.name": "Bob", "orders.id": null, "orders.total": null }),
+            json!({ "user.id": 1, "user.name": "Ann", "orders.id": "b", "orders.total": 20 }),
+        ].into_iter().map(|row| row.as_object().unwrap().clone()).collect();
+
+        let nested = group_nest(&rows, &["user.id"], "orders").unwrap();
+        let expected = json!([
+            { "user": { "id": 1, "name": "Ann" }, "orders": [{ "id": "a", "total": 10 }, { "id": "b", "total": 20 }] },
+            { "user": { "id": 2, "name": "Bob" }, "orders": [] }
+        ]);
+
+        println!(
+            "got:\n{}\nexpected:\n{}\n",
+            serde_json::to_string_pretty(&nested).unwrap(),
+            serde_json::to_string_pretty(&expected).unwrap()
+        );
+
+        assert_eq!(nested, expected);
+    }
+
+    #[test]
+    fn nesting_grouped_rows_with_conflicts() {
+        let rows: Vec<Map<String, Value>> = [
+            json!({ "id": 1, "name": "Ann", "items.sku": "x" }),
+            json!({ "id": 1, "name": "Anne", "items.sku": "y" }),
+        ].into_iter().map(|row| row.as_object().unwrap().clone()).collect();
+
+        assert_eq!(
+            group_nest(&rows, &["id"], "items").err().unwrap().to_string(),
+            errors::Error::DuplicateKey("name".to_owned()).to_string()
+        );
+        assert_eq!(
+            group_nest_with_policy(&rows, &["id"], "items", DuplicateKeyPolicy::KeepLast).unwrap(),
+            json!([{ "id": 1, "name": "Anne", "items": [{ "sku": "x" }, { "sku": "y" }] }])
+        );
+    }
+}